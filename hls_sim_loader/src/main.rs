@@ -1,6 +1,7 @@
 use libloading::{Library, Symbol};
 use std::error::Error;
 use std::fmt;
+use std::fs;
 use std::ffi::{c_int, c_uint}; // For C types: int, unsigned int
 
 // Rust representation of SimResult, match C implementation
@@ -21,6 +22,7 @@ enum SimulationErrorRust {
     OtherCError(c_int), // For unhandled C error codes
     LibraryLoadError(String),
     SymbolNotFound(String),
+    FifoCsvError(String),
     // Add more as needed
 }
 impl fmt::Display for SimulationErrorRust {
@@ -32,6 +34,7 @@ impl fmt::Display for SimulationErrorRust {
             SimulationErrorRust::OtherCError(code) => write!(f, "Unhandled C component error code: {}", code),
             SimulationErrorRust::LibraryLoadError(msg) => write!(f, "Failed to load library: {}", msg),
             SimulationErrorRust::SymbolNotFound(name) => write!(f, "Symbol '{}' not found in library", name),
+            SimulationErrorRust::FifoCsvError(msg) => write!(f, "Failed to read FIFO depths: {}", msg),
         }
     }
 }
@@ -41,8 +44,32 @@ impl Error for SimulationErrorRust {} // Make it a proper error type
 type SimulateHlsModuleFn = unsafe extern "C" fn(c_uint, c_int, c_uint, u64) -> SimResultC;
 type CppMangledFunctionFn = unsafe extern "C" fn(c_int, f32) -> c_int; // For the mangled function
 
+// Reads (fifo_id, depth) pairs from the CSV written by `llvm_ir_tool --fifo <function> --output <file>`
+fn read_fifo_depths(path: &str) -> Result<Vec<(c_uint, c_int)>, SimulationErrorRust> {
+    let text = fs::read_to_string(path).map_err(|e| SimulationErrorRust::FifoCsvError(format!("{}: {}", path, e)))?;
+    let mut lines = text.lines();
+    let header: Vec<&str> = lines.next().unwrap_or("").split(',').collect();
+    let column = |name: &str| {
+        header.iter().position(|h| h.trim() == name)
+            .ok_or_else(|| SimulationErrorRust::FifoCsvError(format!("{} has no '{}' column", path, name)))
+    };
+    let (id_col, depth_col) = (column("fifo_id")?, column("depth")?);
+    let mut depths = Vec::new();
+    for (i, line) in lines.enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let fields: Vec<&str> = line.split(',').collect();
+        let field = |col: usize| fields.get(col).map(|f| f.trim()).unwrap_or("");
+        let bad_row = || SimulationErrorRust::FifoCsvError(format!("{} line {}: '{}'", path, i + 2, line));
+        let id = field(id_col).parse().map_err(|_| bad_row())?;
+        let depth = field(depth_col).parse().map_err(|_| bad_row())?;
+        depths.push((id, depth));
+    }
+    Ok(depths)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let lib_path = "./libsim_component.so";
+    // Optional CSV of FIFO depths to simulate instead of the hard-coded test cases
+    let fifo_csv = std::env::args().nth(1);
 
     // Load the shared library
     println!("Rust: Attempting to load library from: {}", lib_path);
@@ -60,6 +87,20 @@ fn main() -> Result<(), Box<dyn Error>> {
     };
     println!("Rust: Symbol 'simulate_hls_module' resolved.\n");
 
+    // --- FIFO depths from llvm_ir_tool ---
+    if let Some(path) = fifo_csv {
+        println!("--- FIFO depths from {} ---", path);
+        for (fifo_id, depth) in read_fifo_depths(&path)? {
+            let result = unsafe { simulate_hls_module(fifo_id, depth, 0x1000, 50) };
+            match result.error_code {
+                0 => println!("Rust: FIFO {} (depth {}): Latency: {}, BRAMs: {}\n", fifo_id, depth, result.latency, result.bram_count),
+                1 => eprintln!("Rust: FIFO {} (depth {}): {}\n", fifo_id, depth, SimulationErrorRust::DeadlockDetected),
+                code => eprintln!("Rust: FIFO {} (depth {}): {}\n", fifo_id, depth, SimulationErrorRust::OtherCError(code)),
+            }
+        }
+        return Ok(());
+    }
+
     // --- Test Cases ---
 
     // Test Case 1: Successful simulation
@@ -0,0 +1,236 @@
+use crate::cfg::dominates;
+use crate::csynth::{parse_tables, sections, Report};
+use crate::interfaces::{fills_gaps, port_accesses, pragma_mode, PortAccess};
+use crate::ir_util::block_label;
+use crate::loops::LoopInfo;
+use crate::memory::is_opaque_call;
+use crate::pragmas::{collect, LoopMetadata};
+use crate::source::{hls_loop_names, source_name, Sources};
+use llvm_ir::{Function, HasDebugLoc, Module, Name};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Beats per burst request when nothing sets max_read_burst_length / max_write_burst_length.
+const DEFAULT_MAX_BURST: u64 = 16;
+
+/// Bundle Vitis puts m_axi ports in when their INTERFACE pragma names none.
+const DEFAULT_BUNDLE: &str = "gmem";
+
+/// The accesses of one loop that go through an argument in the same direction and step.
+struct Group<'a, 'f> {
+    param: &'f Name,
+    is_write: bool,
+    inner: Option<usize>,
+    // Elements per iteration, None for accesses that don't move linearly with the loop
+    step: Option<i64>,
+    accesses: Vec<&'a PortAccess<'f>>,
+}
+
+/// What a group of accesses turns into on the m_axi bus.
+struct Burst {
+    // Elements per burst, None if the trip count is unknown
+    length: Option<u64>,
+    across_loop: bool,
+    // Why the accesses don't burst across the iterations of their loop
+    breakers: Vec<String>,
+}
+
+/// Longest run of consecutive offsets: the accesses of one iteration that form a burst together.
+fn longest_run(offsets: &[i64]) -> u64 {
+    let mut sorted = offsets.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    let (mut best, mut run) = (0, 0);
+    for (i, &o) in sorted.iter().enumerate() {
+        run = if i > 0 && o == sorted[i - 1] + 1 { run + 1 } else { 1 };
+        best = best.max(run);
+    }
+    best
+}
+
+/// Decides whether a group bursts across the iterations of its loop the way Vitis infers bursts:
+/// the index must move through consecutive elements upwards, every iteration must do the accesses
+/// (no conditional branch around them), and nothing else may use the bundle inside the loop.
+fn burst(func: &Function, loop_info: &LoopInfo, idom: &[Option<usize>], group: &Group, others: &[(String, bool)]) -> Burst {
+    let offsets: Vec<i64> = group.accesses.iter().filter_map(|a| a.index.map(|(_, o)| o)).collect();
+    let within = Burst { length: Some(longest_run(&offsets).max(1)), across_loop: false, breakers: Vec::new() };
+    let Some(l) = group.inner else { return within };
+    let mut breakers = Vec::new();
+    match group.step {
+        None => breakers.push("the index is not a linear function of the loop counter (data-dependent or computed)".to_string()),
+        Some(0) => breakers.push("the same element is accessed in every iteration".to_string()),
+        Some(step) if step < 0 => breakers.push("addresses decrease from one iteration to the next".to_string()),
+        Some(step) if !fills_gaps(step, &offsets) => {
+            breakers.push(format!("the index moves {} elements per iteration, leaving gaps", step))
+        }
+        Some(_) => {}
+    }
+    let lp = &loop_info.loops[l];
+    if group.accesses.iter().any(|a| lp.latches.iter().any(|&latch| !dominates(idom, a.block, latch))) {
+        breakers.push("the access is in a conditional branch (HLS 214-232)".to_string());
+    }
+    for (other, is_write) in others {
+        let kind = if *is_write { "writes" } else { "reads" };
+        breakers.push(format!("{} of {} on the same bundle in the loop", kind, other));
+    }
+    if lp.blocks.iter().flat_map(|&b| &func.basic_blocks[b].instrs).any(is_opaque_call) {
+        breakers.push("the loop contains a function call".to_string());
+    }
+    if !breakers.is_empty() {
+        return Burst { breakers, ..within };
+    }
+    let length = loop_info.constant_trip_count(l).map(|trips| trips * group.step.unwrap_or(1).unsigned_abs());
+    Burst { length, across_loop: true, breakers }
+}
+
+/// `--bursts [function] [--max-burst <n>] [--report <csynth.rpt>] [--source-dir <dir>]...`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --bursts [function] [--max-burst <n>] [--report <csynth.rpt>] [--source-dir <dir>]...";
+    let mut func_name = None;
+    let mut max_burst = None;
+    let mut report_path = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        if !arg.starts_with("--") {
+            func_name = Some(arg);
+            continue;
+        }
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?;
+        match arg.as_str() {
+            "--max-burst" => max_burst = Some(value.parse::<u64>().map_err(|_| format!("Invalid burst length '{}'", value))?),
+            "--report" => report_path = Some(value),
+            "--source-dir" => search_dirs.push(PathBuf::from(value)),
+            _ => return Err(format!("Unknown option '{}'\n{}", arg, usage)),
+        }
+    }
+    // The report knows the burst limits synthesis used and what it inferred itself
+    let report_text = report_path.map(|p| fs::read_to_string(p).map_err(|e| format!("Failed to read {}: {}", p, e))).transpose()?;
+    let report = report_path.zip(report_text.as_deref()).map(|(p, text)| Report::parse(text).map_err(|e| format!("{}: {}", p, e))).transpose()?;
+    let report_max = report.as_ref().and_then(|r| {
+        let (_, table) = r.interfaces.iter().find(|(kind, _)| kind == "M_AXI")?;
+        table.rows.iter().find_map(|row| table.get(row, "Max Read Burst Length")?.parse::<u64>().ok())
+    });
+    let max_burst = max_burst.or(report_max).unwrap_or(DEFAULT_MAX_BURST);
+    let mut sources = Sources::new(search_dirs);
+    let metadata = LoopMetadata::load(module);
+
+    let functions: Vec<&Function> = match func_name {
+        Some(name) => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == *name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        None => module.functions.iter().collect(),
+    };
+    for func in functions {
+        let loop_info = LoopInfo::new(func);
+        let accesses = port_accesses(module, func, &loop_info);
+        if accesses.is_empty() {
+            continue;
+        }
+        let idom = loop_info.cfg.immediate_dominators();
+        let names = hls_loop_names(&loop_info, &mut sources);
+        let pragmas = collect(func, &loop_info, &metadata, &mut sources);
+        let interface = |param: &Name| pragmas.arrays.get(&block_label(param)).and_then(|ps| ps.iter().find(|p| p.name == "INTERFACE"));
+        let bundle_of = |param: &Name| interface(param).and_then(|p| p.option("bundle")).unwrap_or(DEFAULT_BUNDLE).to_string();
+
+        // Arguments without an INTERFACE pragma are taken to be m_axi, as --interfaces would suggest for arrays
+        let mut groups: Vec<Group> = Vec::new();
+        for acc in &accesses {
+            if interface(acc.param).and_then(pragma_mode).is_some_and(|mode| !mode.eq_ignore_ascii_case("m_axi")) {
+                continue;
+            }
+            let step = acc.index.map(|(step, _)| step);
+            match groups.iter_mut().find(|g| g.param == acc.param && g.is_write == acc.is_write && g.inner == acc.inner && g.step == step) {
+                Some(g) => g.accesses.push(acc),
+                None => groups.push(Group { param: acc.param, is_write: acc.is_write, inner: acc.inner, step, accesses: vec![acc] }),
+            }
+        }
+
+        println!("Function: {} (max burst {} beats)", func.name, max_burst);
+        let mut by_bundle: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        for (i, group) in groups.iter().enumerate() {
+            let bundle = bundle_of(group.param);
+            // Other groups sharing the bundle inside the same loop
+            let others: Vec<(String, bool)> = groups
+                .iter()
+                .enumerate()
+                .filter(|&(j, g)| j != i && g.inner.is_some() && g.inner == group.inner && bundle_of(g.param) == bundle)
+                .filter(|(_, g)| g.param != group.param || g.is_write != group.is_write)
+                .map(|(_, g)| (block_label(g.param), g.is_write))
+                .collect();
+            let b = burst(func, &loop_info, &idom, group, &others);
+            let counts = by_bundle.entry(bundle.clone()).or_default();
+            counts.1 += 1;
+            if b.across_loop {
+                counts.0 += 1;
+            }
+
+            let kind = if group.is_write { "writes" } else { "reads" };
+            let place = group.inner.map_or("outside loops".to_string(), |l| format!("in loop {} ({})", names[l], loop_info.describe(l)));
+            let lines: Vec<String> = group
+                .accesses
+                .iter()
+                .filter_map(|a| a.instr.get_debug_loc().as_ref().map(|loc| loc.line.to_string()))
+                .collect();
+            let lines = if lines.is_empty() { String::new() } else { format!(", line {}", lines.join(", ")) };
+            println!("  {} {} {} on m_axi_{}{}", block_label(group.param), kind, place, bundle, lines);
+            let bits = group.accesses.iter().map(|a| a.bits).max().unwrap_or(0);
+            let requests = |length: u64| {
+                if length > max_burst {
+                    format!(", split into {} requests of up to {} beats", length.div_ceil(max_burst), max_burst)
+                } else {
+                    String::new()
+                }
+            };
+            match (b.across_loop, b.length) {
+                (true, Some(length)) => {
+                    println!("    Burst of length {} and bit width {} across the loop{}", length, bits, requests(length))
+                }
+                (true, None) => println!("    Burst across the loop of variable length (unknown trip count) and bit width {}", bits),
+                (false, Some(length)) if length > 1 => println!(
+                    "    Burst of length {} and bit width {} within each iteration only{}",
+                    length,
+                    bits,
+                    requests(length)
+                ),
+                _ => println!("    No burst: one transaction per access"),
+            }
+            for breaker in &b.breakers {
+                println!("    Not across the loop: {}", breaker);
+            }
+        }
+        println!("  Summary:");
+        for (bundle, (across, total)) in &by_bundle {
+            println!("    m_axi_{}: {} of {} access group(s) burst across their loop", bundle, across, total);
+        }
+        println!();
+    }
+
+    if let (Some(path), Some(text)) = (report_path, &report_text) {
+        print_report_bursts(path, text);
+    }
+    Ok(())
+}
+
+/// The burst tables of the "M_AXI Burst Information" section, to compare against.
+fn print_report_bursts(path: &str, text: &str) {
+    let all = sections(text);
+    let Some((_, lines)) = all.iter().find(|(title, _)| title == "M_AXI Burst Information") else {
+        println!("{} has no M_AXI Burst Information section.", path);
+        return;
+    };
+    println!("Bursts reported by csynth ({}):", path);
+    for table in parse_tables(lines) {
+        for row in &table.rows {
+            let cell = |header| table.get(row, header).filter(|c| !c.is_empty()).unwrap_or("-");
+            let text = table.get(row, "Message").or(table.get(row, "Problem")).unwrap_or("");
+            // Drop the mangled signature Vitis leaves in front of the problem
+            let text = text.rsplit_once("] ").map_or(text, |(_, t)| t);
+            let text = text.split(". These bursts").next().unwrap_or(text);
+            let variable = table.get(row, "Variable").map_or(String::new(), |v| format!("{}: ", v));
+            println!("  {:<12} {:<16} {:<18} {}{}", cell("HW Interface"), cell("Loop"), cell("Location"), variable, text);
+        }
+    }
+}
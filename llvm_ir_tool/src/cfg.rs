@@ -0,0 +1,146 @@
+use crate::ir_util::successors;
+use llvm_ir::{Function, Name};
+use std::collections::HashMap;
+
+/// Control flow graph of a single function, with blocks referred to by their index in `func.basic_blocks`.
+pub struct Cfg<'f> {
+    pub names: Vec<&'f Name>,
+    pub succs: Vec<Vec<usize>>,
+    pub preds: Vec<Vec<usize>>,
+}
+
+impl<'f> Cfg<'f> {
+    pub fn new(func: &'f Function) -> Self {
+        let names: Vec<&Name> = func.basic_blocks.iter().map(|bb| &bb.name).collect();
+        let index: HashMap<&Name, usize> = names.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+        let mut succs = vec![Vec::new(); names.len()];
+        let mut preds = vec![Vec::new(); names.len()];
+        for (i, bb) in func.basic_blocks.iter().enumerate() {
+            for dest in successors(&bb.term) {
+                if let Some(&j) = index.get(dest)
+                    && !succs[i].contains(&j)
+                {
+                    succs[i].push(j);
+                    preds[j].push(i);
+                }
+            }
+        }
+        Cfg { names, succs, preds }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Blocks in reverse post-order starting from the entry block (unreachable blocks are left out).
+    pub fn reverse_post_order(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.num_blocks()];
+        let mut order = Vec::new();
+        if self.num_blocks() == 0 {
+            return order;
+        }
+        // Iterative DFS: (block, next successor to visit)
+        let mut stack = vec![(0usize, 0usize)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            if next < self.succs[block].len() {
+                stack.push((block, next + 1));
+                let succ = self.succs[block][next];
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(block);
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// Immediate dominator of every block (the entry block and unreachable blocks get None).
+    /// Uses the iterative algorithm from Cooper, Harvey and Kennedy.
+    pub fn immediate_dominators(&self) -> Vec<Option<usize>> {
+        let rpo = self.reverse_post_order();
+        let mut rpo_pos = vec![usize::MAX; self.num_blocks()];
+        for (pos, &b) in rpo.iter().enumerate() {
+            rpo_pos[b] = pos;
+        }
+        let mut idom: Vec<Option<usize>> = vec![None; self.num_blocks()];
+        if rpo.is_empty() {
+            return idom;
+        }
+        idom[rpo[0]] = Some(rpo[0]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in rpo.iter().skip(1) {
+                let mut new_idom: Option<usize> = None;
+                for &p in &self.preds[b] {
+                    if idom[p].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(current) => intersect(&idom, &rpo_pos, p, current),
+                    });
+                }
+                if new_idom.is_some() && idom[b] != new_idom {
+                    idom[b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[rpo[0]] = None;
+        idom
+    }
+
+    /// Dominance frontier of every block: the blocks where its dominance ends, which is where
+    /// values defined in it meet values from other paths and need a phi.
+    pub fn dominance_frontiers(&self, idom: &[Option<usize>]) -> Vec<Vec<usize>> {
+        let mut frontiers = vec![Vec::new(); self.num_blocks()];
+        for (b, preds) in self.preds.iter().enumerate() {
+            if preds.len() < 2 {
+                continue;
+            }
+            for &p in preds {
+                let mut runner = p;
+                while Some(runner) != idom[b] && (idom[runner].is_some() || runner == 0) {
+                    if !frontiers[runner].contains(&b) {
+                        frontiers[runner].push(b);
+                    }
+                    match idom[runner] {
+                        Some(up) => runner = up,
+                        None => break,
+                    }
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+// Walks up the dominator tree from both blocks until they meet
+fn intersect(idom: &[Option<usize>], rpo_pos: &[usize], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while rpo_pos[a] > rpo_pos[b] {
+            a = idom[a].unwrap_or(a);
+        }
+        while rpo_pos[b] > rpo_pos[a] {
+            b = idom[b].unwrap_or(b);
+        }
+    }
+    a
+}
+
+/// Returns true if block `a` dominates block `b`.
+pub fn dominates(idom: &[Option<usize>], a: usize, b: usize) -> bool {
+    let mut current = Some(b);
+    while let Some(block) = current {
+        if block == a {
+            return true;
+        }
+        current = idom[block];
+    }
+    false
+}
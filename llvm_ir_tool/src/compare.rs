@@ -0,0 +1,218 @@
+use crate::csynth::{PerfRow, Report};
+use std::fs;
+use std::path::Path;
+
+/// Output formats of the comparison.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Markdown,
+    Csv,
+}
+
+/// A metric of the top-level row, and whether a larger value is an improvement.
+struct Metric {
+    name: &'static str,
+    value: fn(&PerfRow) -> Option<f64>,
+    higher_is_better: bool,
+}
+
+const METRICS: [Metric; 8] = [
+    Metric { name: "Latency (cycles)", value: |r| r.latency.map(|v| v as f64), higher_is_better: false },
+    Metric { name: "Latency (ns)", value: |r| r.latency_ns, higher_is_better: false },
+    Metric { name: "Interval (II)", value: |r| r.interval.map(|v| v as f64), higher_is_better: false },
+    Metric { name: "Slack (ns)", value: |r| r.slack, higher_is_better: true },
+    Metric { name: "BRAM_18K", value: |r| Some(r.resources.bram as f64), higher_is_better: false },
+    Metric { name: "DSP", value: |r| Some(r.resources.dsp as f64), higher_is_better: false },
+    Metric { name: "FF", value: |r| Some(r.resources.ff as f64), higher_is_better: false },
+    Metric { name: "LUT", value: |r| Some(r.resources.lut as f64), higher_is_better: false },
+];
+
+/// Name of a report variant: the file name without the `csynth_` prefix, e.g. "no_pragma".
+fn variant_name(path: &str) -> String {
+    let stem = Path::new(path).file_stem().map_or(path.to_string(), |s| s.to_string_lossy().to_string());
+    stem.strip_prefix("csynth_").unwrap_or(&stem).to_string()
+}
+
+fn format_number(v: f64) -> String {
+    if v.fract() == 0.0 && v.abs() < 1e12 { format!("{}", v as i64) } else { format!("{:.2}", v) }
+}
+
+/// "value (+delta, +percent%)" against the baseline; the baseline itself is just the value.
+fn with_delta(value: Option<f64>, base: Option<f64>, is_baseline: bool) -> String {
+    let Some(v) = value else { return "-".to_string() };
+    let text = format_number(v);
+    match base {
+        _ if is_baseline => text,
+        Some(b) if b != 0.0 => format!("{} ({}{}, {:+.1}%)", text, if v >= b { "+" } else { "" }, format_number(v - b), (v - b) / b * 100.0),
+        Some(b) => format!("{} ({}{})", text, if v >= b { "+" } else { "" }, format_number(v - b)),
+        None => text,
+    }
+}
+
+/// Lays out a table as aligned text, a Markdown table or CSV.
+fn render(headers: &[String], rows: &[Vec<String>], format: Format) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Text => {
+            let widths: Vec<usize> = (0..headers.len())
+                .map(|c| rows.iter().map(|r| r[c].len()).chain([headers[c].len()]).max().unwrap_or(0))
+                .collect();
+            let line = |cells: &[String]| {
+                let padded: Vec<String> = cells
+                    .iter()
+                    .zip(&widths)
+                    .enumerate()
+                    .map(|(c, (cell, w))| if c == 0 { format!("{:<w$}", cell, w = *w) } else { format!("{:>w$}", cell, w = *w) })
+                    .collect();
+                format!("  {}\n", padded.join("  "))
+            };
+            out.push_str(&line(headers));
+            for row in rows {
+                out.push_str(&line(row));
+            }
+        }
+        Format::Markdown => {
+            out.push_str(&format!("| {} |\n", headers.join(" | ")));
+            let align: Vec<&str> = (0..headers.len()).map(|c| if c == 0 { "---" } else { "---:" }).collect();
+            out.push_str(&format!("| {} |\n", align.join(" | ")));
+            for row in rows {
+                out.push_str(&format!("| {} |\n", row.join(" | ")));
+            }
+        }
+        Format::Csv => {
+            let quote = |s: &String| if s.contains([',', '"']) { format!("\"{}\"", s.replace('"', "\"\"")) } else { s.clone() };
+            for row in std::iter::once(headers).chain(rows.iter().map(|r| r.as_slice())) {
+                let cells: Vec<String> = row.iter().map(quote).collect();
+                out.push_str(&cells.join(","));
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+/// `--compare <baseline.rpt> <report.rpt>... [--format text|markdown|csv] [--output <file>]`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run --compare <baseline.rpt> <report.rpt>... [--format text|markdown|csv] [--output <file>]";
+    let mut paths = Vec::new();
+    let mut format = Format::Text;
+    let mut output = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match rest.next().map(|s| s.as_str()) {
+                    Some("text") => Format::Text,
+                    Some("markdown") | Some("md") => Format::Markdown,
+                    Some("csv") => Format::Csv,
+                    other => return Err(format!("Invalid format '{}'\n{}", other.unwrap_or(""), usage)),
+                }
+            }
+            "--output" => output = Some(rest.next().ok_or_else(|| format!("Missing value for --output\n{}", usage))?),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+            path => paths.push(path),
+        }
+    }
+    if paths.len() < 2 {
+        return Err(format!("Give a baseline report and at least one report to compare with it\n{}", usage));
+    }
+    let reports = paths.iter().map(|p| Report::load(p)).collect::<Result<Vec<_>, _>>()?;
+    let names: Vec<String> = paths.iter().map(|p| variant_name(p)).collect();
+    let tops: Vec<&PerfRow> = reports
+        .iter()
+        .zip(&paths)
+        .map(|(r, p)| r.rows.first().ok_or_else(|| format!("{}: empty performance table", p)))
+        .collect::<Result<_, _>>()?;
+
+    let mut out = String::new();
+    if format == Format::Csv {
+        // Long form, one value per line, so it loads straight into a spreadsheet or pandas
+        let headers: Vec<String> =
+            ["variant", "metric", "value", "baseline", "delta", "delta_percent"].iter().map(|s| s.to_string()).collect();
+        let mut rows = Vec::new();
+        for (name, top) in names.iter().zip(&tops) {
+            for m in &METRICS {
+                let (v, b) = ((m.value)(top), (m.value)(tops[0]));
+                let opt = |x: Option<f64>| x.map_or(String::new(), format_number);
+                let delta = v.zip(b).map(|(v, b)| v - b);
+                let percent = v.zip(b).filter(|(_, b)| *b != 0.0).map(|(v, b)| format!("{:.2}", (v - b) / b * 100.0));
+                rows.push(vec![name.clone(), m.name.to_string(), opt(v), opt(b), opt(delta), percent.unwrap_or_default()]);
+            }
+        }
+        out.push_str(&render(&headers, &rows, format));
+    } else {
+        let mut headers = vec!["Metric".to_string()];
+        headers.extend(names.iter().enumerate().map(|(i, n)| if i == 0 { format!("{} (baseline)", n) } else { n.clone() }));
+        let mut rows = Vec::new();
+        for m in &METRICS {
+            let base = (m.value)(tops[0]);
+            let mut row = vec![m.name.to_string()];
+            row.extend(tops.iter().enumerate().map(|(i, top)| with_delta((m.value)(top), base, i == 0)));
+            rows.push(row);
+        }
+        // The best variant per metric, for a quick read
+        let winners: Vec<String> = METRICS
+            .iter()
+            .map(|m| {
+                let values: Vec<(usize, f64)> = tops.iter().enumerate().filter_map(|(i, t)| (m.value)(t).map(|v| (i, v))).collect();
+                let pick = if m.higher_is_better {
+                    values.iter().max_by(|a, b| a.1.total_cmp(&b.1))
+                } else {
+                    values.iter().min_by(|a, b| a.1.total_cmp(&b.1))
+                };
+                pick.map_or("-".to_string(), |(i, _)| names[*i].clone())
+            })
+            .collect();
+        headers.push("Best".to_string());
+        for (row, winner) in rows.iter_mut().zip(winners) {
+            row.push(winner);
+        }
+
+        if format == Format::Markdown {
+            out.push_str("### Synthesis results\n\n");
+        } else {
+            out.push_str(&format!("Synthesis results, deltas against {}\n\n", names[0]));
+        }
+        out.push_str(&render(&headers, &rows, format));
+
+        // Loops differ between variants (flattened, unrolled, moved into dataflow processes),
+        // so they are listed by name with "latency / II" where they exist
+        let mut loop_names: Vec<&str> = Vec::new();
+        for report in &reports {
+            for row in report.rows.iter().filter(|r| r.is_loop) {
+                if !loop_names.contains(&row.name.as_str()) {
+                    loop_names.push(&row.name);
+                }
+            }
+        }
+        if !loop_names.is_empty() {
+            let mut loop_headers = vec!["Loop (latency / II / trip count)".to_string()];
+            loop_headers.extend(names.iter().cloned());
+            let cell = |v: Option<u64>| v.map_or("-".to_string(), |v| v.to_string());
+            let loop_rows: Vec<Vec<String>> = loop_names
+                .iter()
+                .map(|loop_name| {
+                    let mut row = vec![loop_name.to_string()];
+                    row.extend(reports.iter().map(|r| {
+                        r.rows.iter().find(|row| row.is_loop && row.name == *loop_name).map_or("".to_string(), |row| {
+                            format!("{} / {} / {}", cell(row.latency), cell(row.interval), cell(row.trip_count))
+                        })
+                    }));
+                    row
+                })
+                .collect();
+            out.push_str(if format == Format::Markdown { "\n### Loops\n\n" } else { "\nLoops\n\n" });
+            out.push_str(&render(&loop_headers, &loop_rows, format));
+        }
+    }
+
+    match output {
+        Some(path) => {
+            fs::write(path, &out).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            println!("Wrote comparison of {} reports to {}", reports.len(), path);
+        }
+        None => print!("{}", out),
+    }
+    Ok(())
+}
@@ -0,0 +1,444 @@
+use crate::ir_util::block_label;
+use crate::loops::LoopInfo;
+use crate::resources::{self, Resources};
+use crate::schedule::{function_latency, loop_latency, schedule_function, ScheduleOptions};
+use crate::source::{hls_loop_names, source_name, Sources};
+use llvm_ir::Module;
+use regex::Regex;
+use std::fs;
+use std::path::PathBuf;
+
+/// An ASCII table of a csynth report. Headers spread over several lines are joined per column;
+/// cells are kept untrimmed because the indentation of the first column encodes nesting.
+pub struct Table {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Index of the column whose (joined) header is `header`, ignoring case.
+    pub fn column(&self, header: &str) -> Option<usize> {
+        self.headers.iter().position(|h| h.eq_ignore_ascii_case(header))
+    }
+
+    /// Trimmed cell of a row under `header`.
+    pub fn get<'t>(&self, row: &'t [String], header: &str) -> Option<&'t str> {
+        self.column(header).and_then(|c| row.get(c)).map(|cell| cell.trim())
+    }
+}
+
+/// One row of the "Performance & Resource Estimates" table.
+pub struct PerfRow {
+    pub name: String,
+    pub depth: usize,
+    pub is_loop: bool,
+    pub is_dataflow: bool,
+    pub issue: Option<String>,
+    pub slack: Option<f64>,
+    pub latency: Option<u64>,
+    pub latency_ns: Option<f64>,
+    pub iteration_latency: Option<u64>,
+    pub interval: Option<u64>,
+    pub trip_count: Option<u64>,
+    pub pipelined: String,
+    pub resources: Resources,
+}
+
+/// The parts of a `csynth.rpt` synthesis summary used by the analysis modes.
+pub struct Report {
+    pub top: String,
+    // "* Key: value" lines of the General Information block
+    pub info: Vec<(String, String)>,
+    pub rows: Vec<PerfRow>,
+    // Tables of the HW Interfaces section with their kind, e.g. ("M_AXI", ...)
+    pub interfaces: Vec<(String, Table)>,
+}
+
+impl Report {
+    pub fn load(path: &str) -> Result<Report, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Report::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Report, String> {
+        let sections = sections(text);
+        let top_re = Regex::new(r"^Synthesis Summary Report of '(.*)'$").unwrap();
+        let (top, summary) = sections
+            .iter()
+            .find_map(|(title, lines)| top_re.captures(title).map(|caps| (caps[1].to_string(), lines)))
+            .ok_or("no 'Synthesis Summary Report' section")?;
+
+        let info_re = Regex::new(r"^\s*\*\s*([^:]+):\s*(.*)$").unwrap();
+        let info = summary
+            .iter()
+            .take_while(|line| !line.starts_with("+ Performance"))
+            .filter_map(|line| info_re.captures(line).map(|caps| (caps[1].trim().to_string(), caps[2].trim().to_string())))
+            .collect();
+
+        let perf_start = summary
+            .iter()
+            .position(|line| line.starts_with("+ Performance & Resource Estimates"))
+            .ok_or("no 'Performance & Resource Estimates' table")?;
+        let perf = parse_tables(&summary[perf_start..]).into_iter().next().ok_or("empty 'Performance & Resource Estimates' table")?;
+        let rows = perf.rows.iter().map(|row| perf_row(&perf, row)).collect::<Result<_, _>>()?;
+
+        // HW Interfaces: "* KIND" followed by a table
+        let mut interfaces = Vec::new();
+        if let Some((_, lines)) = sections.iter().find(|(title, _)| title == "HW Interfaces") {
+            let mut i = 0;
+            while i < lines.len() {
+                if let Some(kind) = lines[i].strip_prefix("* ") {
+                    let end = lines[i + 1..].iter().position(|l| l.starts_with("* ")).map_or(lines.len(), |p| i + 1 + p);
+                    if let Some(table) = parse_tables(&lines[i + 1..end]).into_iter().next() {
+                        interfaces.push((kind.trim().to_string(), table));
+                    }
+                    i = end;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        Ok(Report { top, info, rows, interfaces })
+    }
+
+    /// A General Information value such as "Version" or "Target device".
+    pub fn info(&self, key: &str) -> Option<&str> {
+        self.info.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Splits a report into its `== Title` sections (the lines between the `====` banners).
+/// Blank lines are dropped, since some reports are saved double-spaced.
+pub fn sections(text: &str) -> Vec<(String, Vec<&str>)> {
+    let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+    let mut sections: Vec<(String, Vec<&str>)> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let banner = |l: &str| l.starts_with("====");
+        if banner(lines[i]) && i + 2 < lines.len() && banner(lines[i + 2]) && lines[i + 1].starts_with("== ") {
+            sections.push((lines[i + 1][3..].trim().to_string(), Vec::new()));
+            i += 3;
+            continue;
+        }
+        if let Some((_, body)) = sections.last_mut() {
+            body.push(lines[i]);
+        }
+        i += 1;
+    }
+    sections
+}
+
+/// Parses the ASCII tables in a block of lines. Rows are `|`-separated and the header is every
+/// row before the second `+---` border; the border after the rows or any other text ends a table.
+pub fn parse_tables(lines: &[&str]) -> Vec<Table> {
+    let mut tables = Vec::new();
+    let mut current: Option<(Table, usize)> = None;
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with("+-") {
+            match &mut current {
+                Some((table, borders)) if *borders < 3 || table.rows.is_empty() => *borders += 1,
+                _ => {
+                    tables.extend(current.take().map(|(table, _)| table));
+                    current = Some((Table { headers: Vec::new(), rows: Vec::new() }, 1));
+                }
+            }
+        } else if let Some((table, borders)) = &mut current
+            && line.starts_with('|')
+        {
+            let cells: Vec<String> = line.trim_matches('|').split('|').map(|c| c.to_string()).collect();
+            if *borders < 2 {
+                table.headers.resize(cells.len(), String::new());
+                for (header, cell) in table.headers.iter_mut().zip(&cells) {
+                    let cell = cell.trim();
+                    if !cell.is_empty() {
+                        if !header.is_empty() {
+                            header.push(' ');
+                        }
+                        header.push_str(cell);
+                    }
+                }
+            } else {
+                table.rows.push(cells);
+            }
+        } else if let Some((table, _)) = current.take() {
+            tables.push(table);
+        }
+    }
+    if let Some((table, _)) = current {
+        tables.push(table);
+    }
+    tables
+}
+
+/// A number in a report cell: "-" is None, "1030 (50%)" is 1030.
+fn number<T: std::str::FromStr>(cell: Option<&str>) -> Option<T> {
+    cell?.split_whitespace().next()?.parse().ok()
+}
+
+fn perf_row(table: &Table, row: &[String]) -> Result<PerfRow, String> {
+    let first = row.first().ok_or("empty row in the performance table")?;
+    let depth = first.len() - first.trim_start().len();
+    let (marker, name) = first.trim().split_at(first.trim().chars().next().map_or(0, |c| c.len_utf8()));
+    let name = name.trim();
+    let resource = |header| number::<u64>(table.get(row, header)).unwrap_or(0);
+    Ok(PerfRow {
+        name: name.trim_end_matches('*').to_string(),
+        depth,
+        is_loop: marker == "o",
+        is_dataflow: name.ends_with('*'),
+        issue: table.get(row, "Issue Type").filter(|s| *s != "-").map(|s| s.to_string()),
+        slack: number(table.get(row, "Slack")),
+        latency: number(table.get(row, "Latency (cycles)")),
+        latency_ns: number(table.get(row, "Latency (ns)")),
+        iteration_latency: number(table.get(row, "Iteration Latency")),
+        interval: number(table.get(row, "Interval")),
+        trip_count: number(table.get(row, "Trip Count")),
+        pipelined: table.get(row, "Pipelined").unwrap_or("-").to_string(),
+        resources: Resources { bram: resource("BRAM"), dsp: resource("DSP"), ff: resource("FF"), lut: resource("LUT") },
+    })
+}
+
+/// IR loops a report row stands for, outermost first. Loops are named VITIS_LOOP_<line>_<n> or
+/// by their C label, and a flattened nest is reported as the names of its loops joined by '_'.
+/// Returns an empty list if any part of the name can't be matched.
+pub fn match_loops(name: &str, loop_info: &LoopInfo, loop_names: &[String]) -> Vec<usize> {
+    let vitis_re = Regex::new(r"^VITIS_LOOP_(\d+)_(\d+)").unwrap();
+    let mut matched = Vec::new();
+    let mut rest = name;
+    while !rest.is_empty() {
+        let (l, len) = if let Some(caps) = vitis_re.captures(rest) {
+            // Joined by source line, preferring the loop whose own name is the same
+            let line: u32 = caps[1].parse().unwrap_or(0);
+            let at_line: Vec<usize> = (0..loop_info.loops.len()).filter(|&l| loop_info.line(l) == Some(line)).collect();
+            let l = at_line.iter().copied().find(|&l| loop_names[l] == caps[0]).or(at_line.first().copied());
+            (l, caps[0].len())
+        } else {
+            let label = (0..loop_names.len())
+                .filter(|&l| {
+                    rest.strip_prefix(loop_names[l].as_str()).is_some_and(|after| after.is_empty() || after.starts_with('_'))
+                })
+                .max_by_key(|&l| loop_names[l].len());
+            (label, label.map_or(0, |l| loop_names[l].len()))
+        };
+        let Some(l) = l else { return Vec::new() };
+        matched.push(l);
+        rest = rest[len..].strip_prefix('_').unwrap_or(&rest[len..]);
+    }
+    matched
+}
+
+/// `--csynth <report.rpt> [--function <name>] [--source-dir <dir>]... [schedule options]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = format!(
+        "Usage: cargo run <filename.ll> --csynth <csynth.rpt> [--function <name>] [--source-dir <dir>]... {}",
+        ScheduleOptions::USAGE
+    );
+    let path = args.first().filter(|a| !a.starts_with("--")).ok_or(usage.as_str())?;
+    let mut options = ScheduleOptions::new();
+    let mut func_name = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", flag, usage))?;
+        match flag.as_str() {
+            "--function" => func_name = Some(value.clone()),
+            "--source-dir" => search_dirs.push(PathBuf::from(value)),
+            _ if options.apply(flag, value)? => {}
+            _ => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+        }
+    }
+
+    let report = Report::load(path)?;
+    // The report names the top function as in the source; the IR may have it mangled
+    let func = match &func_name {
+        Some(name) => module.get_func_by_name(name).ok_or_else(|| format!("Function '{}' not found in module.", name))?,
+        None => module
+            .functions
+            .iter()
+            .find(|f| source_name(&f.name) == report.top)
+            .ok_or_else(|| format!("No function for top '{}' in the module, give one with --function", report.top))?,
+    };
+
+    let loop_info = LoopInfo::new(func);
+    let mut sources = Sources::new(search_dirs);
+    let loop_names = hls_loop_names(&loop_info, &mut sources);
+    let schedules = schedule_function(module, func, &options.model, options.clock);
+
+    println!(
+        "Synthesis report {} for '{}' ({}, {})",
+        path,
+        report.top,
+        report.info("Version").map_or("unknown version", |v| v.split(" (").next().unwrap_or(v)),
+        report.info("Target device").unwrap_or("unknown device")
+    );
+    println!("IR function '{}' ({:.2} ns per cycle after uncertainty)\n", func.name, options.clock.effective());
+
+    let cell = |v: Option<u64>| v.map_or("-".to_string(), |v| v.to_string());
+    let mut matched_loops = vec![false; loop_info.loops.len()];
+    let mut lines = Vec::new();
+    for row in &report.rows {
+        let marker = if row.is_loop { "o" } else { "+" };
+        let dataflow = if row.is_dataflow { "*" } else { "" };
+        let name = format!("{}{} {}{}", " ".repeat(row.depth), marker, row.name, dataflow);
+        let (ir_loop, ir_trip, ir_latency) = if row.is_loop {
+            let loops = match_loops(&row.name, &loop_info, &loop_names);
+            for &l in &loops {
+                matched_loops[l] = true;
+            }
+            match loops.as_slice() {
+                [] => ("?".to_string(), None, None),
+                [l] => (loop_info.describe(*l), loop_info.constant_trip_count(*l), loop_latency(&loop_info, &schedules, *l)),
+                [outer, ..] => {
+                    let headers: Vec<String> = loops.iter().map(|&l| block_label(loop_info.cfg.names[loop_info.loops[l].header])).collect();
+                    let source_lines: Vec<String> = loops.iter().map(|&l| cell(loop_info.line(l).map(u64::from))).collect();
+                    // A flattened nest runs the product of its trip counts
+                    let trip = loops.iter().try_fold(1u64, |acc, &l| loop_info.constant_trip_count(l).map(|t| acc * t));
+                    let descr = format!("{} (lines {})", headers.join("/"), source_lines.join(", "));
+                    (descr, trip, loop_latency(&loop_info, &schedules, *outer))
+                }
+            }
+        } else if row.depth == 0 {
+            ("(function)".to_string(), None, function_latency(&loop_info, &schedules))
+        } else {
+            (String::new(), None, None)
+        };
+        let issue = row.issue.as_ref().map_or(String::new(), |i| format!("  {} violation", i));
+        let numbers = format!(
+            "{:>8} {:>8} {:>10} {:>10} {:>8} {:>4} {:>9} {:>6}{}",
+            cell(row.trip_count),
+            cell(ir_trip),
+            cell(row.latency),
+            cell(ir_latency),
+            cell(row.iteration_latency),
+            cell(row.interval),
+            row.pipelined,
+            row.slack.map_or("-".to_string(), |s| format!("{:.2}", s)),
+            issue
+        );
+        lines.push((name, ir_loop, numbers));
+    }
+    let name_width = lines.iter().map(|(n, _, _)| n.len()).max().unwrap_or(0).max("Module / loop".len());
+    let loop_width = lines.iter().map(|(_, l, _)| l.len()).max().unwrap_or(0).max("IR loop".len());
+    println!(
+        "  {:<nw$} {:<lw$} {:>8} {:>8} {:>10} {:>10} {:>8} {:>4} {:>9} {:>6}",
+        "Module / loop", "IR loop", "Trip", "IR trip", "Latency", "IR lat.", "Iter.", "II", "Pipelined", "Slack",
+        nw = name_width,
+        lw = loop_width
+    );
+    for (name, ir_loop, numbers) in &lines {
+        println!("  {:<nw$} {:<lw$} {}", name, ir_loop, numbers, nw = name_width, lw = loop_width);
+    }
+
+    let unmatched: Vec<String> =
+        (0..loop_info.loops.len()).filter(|&l| !matched_loops[l]).map(|l| format!("{} ({})", loop_info.describe(l), loop_names[l])).collect();
+    if !unmatched.is_empty() {
+        println!("\n  IR loops without a row in the report (unrolled or inlined by HLS): {}", unmatched.join(", "));
+    }
+
+    if let Some(top) = report.rows.first() {
+        let estimate = resources::estimate(module, func, &schedules);
+        println!("\n  {:<10} {:>10} {:>10}", "Resource", "csynth", "IR est.");
+        for (name, actual, est) in [
+            ("BRAM_18K", top.resources.bram, estimate.bram),
+            ("DSP", top.resources.dsp, estimate.dsp),
+            ("FF", top.resources.ff, estimate.ff),
+            ("LUT", top.resources.lut, estimate.lut),
+        ] {
+            println!("  {:<10} {:>10} {:>10}", name, actual, est);
+        }
+    }
+
+    for (kind, table) in &report.interfaces {
+        println!("\nHW interface: {}", kind);
+        print_table(table);
+    }
+    Ok(())
+}
+
+/// Prints a report table with its columns re-aligned.
+pub fn print_table(table: &Table) {
+    let mut widths: Vec<usize> = table.headers.iter().map(|h| h.len()).collect();
+    for row in &table.rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.trim().len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells.iter().zip(&widths).map(|(c, w)| format!("{:<w$}", c, w = *w)).collect();
+        println!("  {}", padded.join("  ").trim_end());
+    };
+    line(table.headers.iter().map(|h| h.as_str()).collect());
+    for row in &table.rows {
+        line(row.iter().map(|c| c.trim()).collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Saved double-spaced, with a blank line between every table row
+    const INNER_PIPELINE: &str = include_str!("../../matrix_multiplication_project/csynth_ap_inner_loop_pipeline.rpt");
+    const M_AXI: &str = include_str!("../../box_blur_app/csynth_reports/csynth_m_axi.rpt");
+
+    #[test]
+    fn parses_double_spaced_reports() {
+        let report = Report::parse(INNER_PIPELINE).unwrap();
+        assert_eq!(report.top, "matrix_mult");
+        assert!(report.info("Version").unwrap().starts_with("2021.1"));
+        assert_eq!(report.info("Target device"), Some("xc7vx485t-ffg1157-1"));
+
+        let names: Vec<&str> = report.rows.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["matrix_mult", "grp_matrix_mult_Pipeline_product_loop_fu_464", "product_loop", "row_loop_col_loop"]);
+        let top = &report.rows[0];
+        assert_eq!((top.depth, top.is_loop, top.latency, top.interval), (0, false, Some(177), Some(178)));
+        assert_eq!(top.resources, Resources { bram: 0, dsp: 1, ff: 182, lut: 824 });
+        let product = &report.rows[2];
+        assert_eq!((product.depth, product.is_loop, product.pipelined.as_str()), (2, true, "yes"));
+        assert_eq!((product.iteration_latency, product.interval, product.trip_count), (Some(4), Some(1), Some(4)));
+        assert_eq!(product.slack, Some(7.04));
+        assert_eq!(report.rows[3].latency_ns, Some(1408.0));
+
+        assert!(report.interfaces.iter().any(|(kind, _)| kind == "REGISTER"));
+    }
+
+    #[test]
+    fn parses_issues_and_interfaces() {
+        let report = Report::parse(M_AXI).unwrap();
+        assert_eq!(report.top, "box_blur_filter");
+        assert_eq!(report.rows.len(), 8);
+        let ii = report.rows.iter().find(|r| r.name == "VITIS_LOOP_38_5").unwrap();
+        assert_eq!((ii.issue.as_deref(), ii.interval, ii.trip_count), (Some("II"), Some(3), Some(640)));
+        assert_eq!(report.rows[0].resources.bram, 8);
+
+        let kinds: Vec<&str> = report.interfaces.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["M_AXI", "S_AXILITE", "TOP LEVEL CONTROL"]);
+        let (_, m_axi) = &report.interfaces[0];
+        assert!(m_axi.column("Interface").is_some());
+        assert!(!m_axi.rows.is_empty());
+    }
+
+    #[test]
+    fn every_report_in_the_repo_parses() {
+        // With whether the top function is a DATAFLOW region, which reports no latency
+        for (text, dataflow) in [
+            (INNER_PIPELINE, false),
+            (M_AXI, false),
+            (include_str!("../../matrix_multiplication_project/csynth_ap_32_16.rpt"), true),
+            (include_str!("../../matrix_multiplication_project/csynth_ap_dataflow_outer_loop_pipeline.rpt"), true),
+            (include_str!("../../matrix_multiplication_project/csynth_no_pragma.rpt"), false),
+            (include_str!("../../box_blur_app/csynth_reports/csynth_axilite.rpt"), false),
+            (include_str!("../../box_blur_app/csynth_reports/csynth_no_buffer.rpt"), false),
+        ] {
+            let report = Report::parse(text).unwrap();
+            let top = &report.rows[0];
+            assert_eq!(top.is_dataflow, dataflow, "{}", report.top);
+            assert_eq!(top.latency.is_none(), dataflow, "{}", report.top);
+            assert!(top.slack.is_some());
+        }
+    }
+}
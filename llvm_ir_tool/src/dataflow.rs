@@ -0,0 +1,369 @@
+use crate::ir_util::{callee_name, instr_text, is_annotation_call, operands};
+use crate::loops::LoopInfo;
+use crate::memory::{access, MemBase, MemoryInfo};
+use crate::source::{function_pragmas, hls_loop_names, is_ap_operator, source_name, Sources};
+use llvm_ir::{Function, HasDebugLoc, Instruction, Module, Name, Operand, Terminator};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+
+/// How a process uses a buffer passed to it.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Use {
+    pub reads: bool,
+    pub writes: bool,
+}
+
+/// A function call in a dataflow region, with the buffers it reads and writes.
+pub struct Process<'f> {
+    pub call: &'f Instruction,
+    pub callee: String,
+    pub buffers: Vec<(MemBase<'f>, Use)>,
+}
+
+/// A rule of the canonical form that the region breaks.
+pub struct Violation {
+    pub line: Option<u32>,
+    pub what: String,
+    pub why: &'static str,
+}
+
+const NOT_A_CALL: &str = "a dataflow region may only contain variable declarations and function calls (HLS 214-114); move this code into a function and call it";
+const CONDITIONAL: &str = "processes must run unconditionally; move the condition into the called functions";
+const MULTI_PRODUCER: &str = "each buffer must have a single producer; give every process its own output buffer";
+const MULTI_CONSUMER: &str = "each buffer must have a single consumer; copy it in a separate process to feed more than one reader";
+const FEEDBACK: &str = "data must flow forward; a process can't read a buffer that it or a later process writes";
+const BYPASS: &str = "a buffer that skips over processes forces them to wait for each other; pass the data through the processes in between";
+
+/// How a function uses its pointer parameter `index`: its own loads and stores through it, and
+/// the uses of the functions it passes it on to. Declarations are assumed to read and write.
+pub fn param_use(module: &Module, func_name: &str, index: usize, depth: usize) -> Use {
+    let unknown = Use { reads: true, writes: true };
+    let Some(func) = module.get_func_by_name(func_name) else { return unknown };
+    let Some(param) = func.parameters.get(index) else { return unknown };
+    if depth > 8 {
+        return unknown;
+    }
+    let mem = MemoryInfo::new(func);
+    let mut result = Use::default();
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        if let Some(acc) = access(instr)
+            && mem.base_of(acc.address) == Some(MemBase::Param(&param.name))
+        {
+            if acc.is_write {
+                result.writes = true;
+            } else {
+                result.reads = true;
+            }
+        }
+        if let Instruction::Call(call) = instr
+            && !is_annotation_call(instr)
+        {
+            for (i, (arg, _)) in call.arguments.iter().enumerate() {
+                if mem.base_of(arg) == Some(MemBase::Param(&param.name)) {
+                    let inner = match callee_name(instr) {
+                        Some(callee) => param_use(module, callee, i, depth + 1),
+                        None => unknown,
+                    };
+                    result.reads |= inner.reads;
+                    result.writes |= inner.writes;
+                }
+            }
+        }
+    }
+    result
+}
+
+/// The processes of a region: every call outside a loop, in program order, with the buffers
+/// passed to it. Annotations and ap_int/ap_fixed operators are operations, not processes.
+pub fn processes<'f>(module: &Module, mem: &MemoryInfo<'f>, loop_info: &LoopInfo) -> Vec<Process<'f>> {
+    let mut result = Vec::new();
+    let top_level = mem.func.basic_blocks.iter().enumerate().filter(|(b, _)| loop_info.innermost(*b).is_none());
+    for instr in top_level.flat_map(|(_, bb)| &bb.instrs) {
+        let Instruction::Call(call) = instr else { continue };
+        if is_annotation_call(instr) || callee_name(instr).is_some_and(is_ap_operator) {
+            continue;
+        }
+        let callee = callee_name(instr).unwrap_or("<indirect>").to_string();
+        let mut buffers: Vec<(MemBase, Use)> = Vec::new();
+        for (i, (arg, _)) in call.arguments.iter().enumerate() {
+            let Some(base) = mem.base_of(arg) else { continue };
+            if matches!(base, MemBase::Alloca(name) if mem.is_scalar_alloca(name)) {
+                continue;
+            }
+            let u = param_use(module, &callee, i, 0);
+            match buffers.iter_mut().find(|(b, _)| *b == base) {
+                Some((_, existing)) => {
+                    existing.reads |= u.reads;
+                    existing.writes |= u.writes;
+                }
+                None => buffers.push((base, u)),
+            }
+        }
+        result.push(Process { call: instr, callee, buffers });
+    }
+    result
+}
+
+fn line_of(instr: &Instruction) -> Option<u32> {
+    instr.get_debug_loc().as_ref().map(|loc| loc.line).filter(|&l| l != 0)
+}
+
+/// Checks the canonical form of a dataflow region: only declarations and calls, no conditions,
+/// and buffers with one producer and one consumer that flow forward without skipping processes.
+pub fn check<'f>(module: &Module, func: &'f Function, sources: &mut Sources) -> (Vec<Process<'f>>, Vec<Violation>) {
+    let mem = MemoryInfo::new(func);
+    let loop_info = LoopInfo::new(func);
+    let loop_names = hls_loop_names(&loop_info, sources);
+    let mut violations = Vec::new();
+
+    for l in (0..loop_info.loops.len()).filter(|&l| loop_info.loops[l].parent.is_none()) {
+        violations.push(Violation {
+            line: loop_info.line(l),
+            what: format!("loop {} ({})", loop_info.describe(l), loop_names[l]),
+            why: NOT_A_CALL,
+        });
+    }
+    let in_loop: HashSet<usize> = loop_info.loops.iter().flat_map(|lp| lp.blocks.iter().copied()).collect();
+
+    // Values used only to pass arguments (reloaded pointers, array decays) are part of the calls
+    let mut users: HashMap<&Name, Vec<&Instruction>> = HashMap::new();
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        for op in operands(instr) {
+            if let Operand::LocalOperand { name, .. } = op {
+                users.entry(name).or_default().push(instr);
+            }
+        }
+    }
+    let params: HashSet<&Name> = func.parameters.iter().map(|p| &p.name).collect();
+    fn feeds_calls(instr: &Instruction, users: &HashMap<&Name, Vec<&Instruction>>, depth: usize) -> bool {
+        let Some(dest) = instr.try_get_result() else { return false };
+        depth < 8
+            && users.get(dest).is_some_and(|us| {
+                us.iter().all(|u| matches!(u, Instruction::Call(_)) || feeds_calls(u, users, depth + 1))
+            })
+    }
+
+    // Statements outside of calls, one violation per source line
+    let mut statements: BTreeMap<Option<u32>, Vec<String>> = BTreeMap::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        if in_loop.contains(&b) {
+            continue;
+        }
+        for instr in &bb.instrs {
+            let allowed = match instr {
+                Instruction::Alloca(_) | Instruction::Call(_) => true,
+                // Spilling an argument or initializing a declared variable with a constant
+                Instruction::Store(store) => {
+                    mem.base_of(&store.address).is_some_and(|base| matches!(base, MemBase::Alloca(n) if mem.is_scalar_alloca(n)))
+                        && match &store.value {
+                            Operand::LocalOperand { name, .. } => params.contains(name),
+                            Operand::ConstantOperand(_) => true,
+                            Operand::MetadataOperand => true,
+                        }
+                }
+                Instruction::Load(_)
+                | Instruction::GetElementPtr(_)
+                | Instruction::BitCast(_)
+                | Instruction::SExt(_)
+                | Instruction::ZExt(_)
+                | Instruction::Trunc(_) => feeds_calls(instr, &users, 0),
+                _ => false,
+            };
+            if !allowed {
+                statements.entry(line_of(instr)).or_default().push(instr_text(instr));
+            }
+        }
+        if matches!(bb.term, Terminator::CondBr(_) | Terminator::Switch(_) | Terminator::IndirectBr(_)) {
+            let line = bb.term.get_debug_loc().as_ref().map(|loc| loc.line);
+            violations.push(Violation { line, what: "conditional branch".to_string(), why: CONDITIONAL });
+        }
+    }
+    for (line, instrs) in statements {
+        let what = match instrs.as_slice() {
+            [one] => format!("statement `{}`", one),
+            [first, ..] => format!("{} statements, starting with `{}`", instrs.len(), first),
+            [] => continue,
+        };
+        violations.push(Violation { line, what, why: NOT_A_CALL });
+    }
+
+    let procs = processes(module, &mem, &loop_info);
+    let mut buffers: Vec<MemBase> = procs.iter().flat_map(|p| p.buffers.iter().map(|(b, _)| *b)).collect();
+    buffers.sort();
+    buffers.dedup();
+    for buffer in buffers {
+        let use_of = |p: &Process<'f>| p.buffers.iter().find(|(b, _)| *b == buffer).map(|(_, u)| *u);
+        let producers: Vec<usize> = (0..procs.len()).filter(|&i| use_of(&procs[i]).is_some_and(|u| u.writes)).collect();
+        let consumers: Vec<usize> = (0..procs.len()).filter(|&i| use_of(&procs[i]).is_some_and(|u| u.reads)).collect();
+        let names = |ids: &[usize]| ids.iter().map(|&i| procs[i].callee.as_str()).collect::<Vec<_>>().join(", ");
+        let line = |i: usize| line_of(procs[i].call);
+        if producers.len() > 1 {
+            violations.push(Violation {
+                line: line(producers[1]),
+                what: format!("{} is written by {}", buffer, names(&producers)),
+                why: MULTI_PRODUCER,
+            });
+        }
+        if consumers.len() > 1 {
+            violations.push(Violation {
+                line: line(consumers[1]),
+                what: format!("{} is read by {}", buffer, names(&consumers)),
+                why: MULTI_CONSUMER,
+            });
+        }
+        // A local buffer read before (or by) the process that produces it
+        if let (Some(&p), Some(&c)) = (producers.first(), consumers.first())
+            && matches!(buffer, MemBase::Alloca(_))
+            && c <= p
+        {
+            violations.push(Violation {
+                line: line(c),
+                what: format!("{} is read by {} but written by {}", buffer, procs[c].callee, procs[p].callee),
+                why: FEEDBACK,
+            });
+        }
+        // A channel from p to c that jumps over a process connected to both ends
+        if let (Some(&p), Some(&c)) = (producers.first(), consumers.last())
+            && c > p + 1
+        {
+            let connected = |i: usize, j: usize| {
+                procs[i].buffers.iter().any(|(b, u)| u.writes && procs[j].buffers.iter().any(|(b2, u2)| b == b2 && u2.reads))
+            };
+            if let Some(m) = (p + 1..c).find(|&m| connected(p, m) && connected(m, c)) {
+                violations.push(Violation {
+                    line: line(c),
+                    what: format!("{} goes from {} to {}, bypassing {}", buffer, procs[p].callee, procs[c].callee, procs[m].callee),
+                    why: BYPASS,
+                });
+            }
+        }
+    }
+    violations.sort_by_key(|v| v.line);
+    (procs, violations)
+}
+
+/// `--dataflow [function] [--source-dir <dir>]...`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --dataflow [function] [--source-dir <dir>]...";
+    let mut func_name = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--source-dir" => {
+                search_dirs.push(PathBuf::from(rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?))
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+            name => func_name = Some(name),
+        }
+    }
+    let mut sources = Sources::new(search_dirs);
+
+    // The given function, or every function with `#pragma HLS DATAFLOW` in its source
+    let mut regions: Vec<(&Function, Option<u32>)> = Vec::new();
+    for func in &module.functions {
+        let pragma = function_pragmas(func, &mut sources)
+            .into_iter()
+            .find(|(_, text)| text.to_ascii_lowercase().contains("dataflow"))
+            .map(|(line, _)| line);
+        let chosen = match func_name {
+            Some(name) => func.name == name || source_name(&func.name) == name,
+            None => pragma.is_some(),
+        };
+        if chosen {
+            regions.push((func, pragma));
+        }
+    }
+    if regions.is_empty() {
+        return Err(match func_name {
+            Some(name) => format!("Function '{}' not found in module.", name),
+            None => "No function with #pragma HLS DATAFLOW found (is the source file reachable? try --source-dir, or name the function)".to_string(),
+        });
+    }
+
+    for (func, pragma) in regions {
+        let marked = pragma.map_or(" (not marked DATAFLOW in the source)".to_string(), |line| format!(" (#pragma HLS DATAFLOW at line {})", line));
+        println!("DATAFLOW check for '{}'{}\n", func.name, marked);
+        let (procs, violations) = check(module, func, &mut sources);
+        if procs.is_empty() {
+            println!("  Processes: none");
+        } else {
+            println!("  Processes:");
+            for (i, p) in procs.iter().enumerate() {
+                let io: Vec<String> = p
+                    .buffers
+                    .iter()
+                    .map(|(b, u)| {
+                        let mode = match (u.reads, u.writes) {
+                            (true, true) => "inout",
+                            (false, true) => "out",
+                            _ => "in",
+                        };
+                        format!("{} {}", mode, b)
+                    })
+                    .collect();
+                let line = line_of(p.call).map_or(String::new(), |l| format!(" (line {})", l));
+                println!("    {}. {}({}){}", i + 1, p.callee, io.join(", "), line);
+            }
+        }
+        if violations.is_empty() {
+            println!("\n  Canonical form: OK");
+        } else {
+            println!("\n  Not in canonical form, {} issue(s):", violations.len());
+            for v in &violations {
+                let line = v.line.map_or(String::new(), |l| format!("line {}: ", l));
+                println!("    - {}{}", line, v.what);
+                println!("      {}", v.why);
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A region with two processes, a call inside a loop, and ap_int operators
+    const REGION: &str = "
+declare void @_Z4loadPiS_(ptr, ptr)
+declare void @_Z7computeP8ap_fixedILi16ELi8EES_(ptr, ptr)
+declare void @_Z4stepi(i32)
+declare void @_ZN11ap_int_baseILi32ELb1EEC2Ei(ptr, i32)
+declare i32 @_ZplILi32ELb1EE11ap_int_baseIXT_ELb1EERKS0_IXT_ELb1EEi(ptr, i32)
+
+define void @top(ptr %in, ptr %out) {
+entry:
+  %buf = alloca [16 x i32]
+  %acc = alloca i32
+  call void @_ZN11ap_int_baseILi32ELb1EEC2Ei(ptr %acc, i32 0)
+  call void @_Z4loadPiS_(ptr %in, ptr %buf)
+  %sum = call i32 @_ZplILi32ELb1EE11ap_int_baseIXT_ELb1EERKS0_IXT_ELb1EEi(ptr %acc, i32 1)
+  br label %for.cond
+for.cond:
+  %i = phi i32 [ 0, %entry ], [ %inc, %for.body ]
+  %cmp = icmp slt i32 %i, 4
+  br i1 %cmp, label %for.body, label %for.end
+for.body:
+  call void @_Z4stepi(i32 %i)
+  %inc = add nsw i32 %i, 1
+  br label %for.cond
+for.end:
+  call void @_Z7computeP8ap_fixedILi16ELi8EES_(ptr %buf, ptr %out)
+  ret void
+}
+";
+
+    #[test]
+    fn counts_only_top_level_processes() {
+        let module = Module::from_ir_str(REGION).unwrap();
+        let func = &module.functions[0];
+        let mem = MemoryInfo::new(func);
+        let procs = processes(&module, &mem, &LoopInfo::new(func));
+        let callees: Vec<&str> = procs.iter().map(|p| p.callee.as_str()).collect();
+        assert_eq!(callees, ["_Z4loadPiS_", "_Z7computeP8ap_fixedILi16ELi8EES_"]);
+        // The buffer passed between them is their channel
+        assert!(procs.iter().all(|p| p.buffers.iter().any(|(b, _)| matches!(b, MemBase::Alloca(n) if **n == Name::from("buf")))));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::cfg::Cfg;
+use crate::ir_util::{block_label, instr_text, operands, quote, term_operands};
+use crate::memory::{access, MemBase, MemoryInfo, Subscript};
+use crate::source::source_name;
+use llvm_ir::{Function, HasDebugLoc, Instruction, Module, Name, Operand};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+
+/// An instruction, terminator or parameter of the function.
+pub struct Node {
+    // "%13" for values, "<block>:<index>" for stores, calls and terminators
+    pub id: String,
+    pub block: Option<usize>,
+    pub text: String,
+    // Source line from the debug info
+    pub line: Option<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    // An SSA operand
+    Data,
+    // A store whose value a later load may read
+    Memory,
+}
+
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+    // The memory object for memory edges
+    pub label: String,
+}
+
+/// Data dependence graph of one function: SSA def-use edges plus store-to-load edges.
+pub struct Ddg {
+    pub name: String,
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+    // Block names, for grouping nodes
+    pub blocks: Vec<String>,
+}
+
+impl Ddg {
+    pub fn new(func: &Function) -> Ddg {
+        let mut nodes = Vec::new();
+        let mut values: HashMap<&Name, usize> = HashMap::new();
+        for param in &func.parameters {
+            values.insert(&param.name, nodes.len());
+            nodes.push(Node {
+                id: format!("%{}", block_label(&param.name)),
+                block: None,
+                text: format!("parameter {} %{}", param.ty, block_label(&param.name)),
+                line: None,
+            });
+        }
+        // Node of each instruction and terminator, by block and position (the terminator comes last)
+        let mut positions: Vec<Vec<usize>> = Vec::new();
+        for (b, bb) in func.basic_blocks.iter().enumerate() {
+            let mut in_block = Vec::new();
+            for (i, instr) in bb.instrs.iter().enumerate() {
+                let id = match instr.try_get_result() {
+                    Some(dest) => {
+                        values.insert(dest, nodes.len());
+                        format!("%{}", block_label(dest))
+                    }
+                    None => format!("{}:{}", block_label(&bb.name), i),
+                };
+                in_block.push(nodes.len());
+                let line = instr.get_debug_loc().as_ref().map(|loc| loc.line);
+                nodes.push(Node { id, block: Some(b), text: instr_text(instr), line });
+            }
+            in_block.push(nodes.len());
+            nodes.push(Node {
+                id: format!("{}:{}", block_label(&bb.name), bb.instrs.len()),
+                block: Some(b),
+                text: bb.term.to_string().replace(" (with debugloc)", ""),
+                line: bb.term.get_debug_loc().as_ref().map(|loc| loc.line),
+            });
+            positions.push(in_block);
+        }
+
+        let mut edges = Vec::new();
+        for (b, bb) in func.basic_blocks.iter().enumerate() {
+            let users = bb.instrs.iter().map(operands).chain(std::iter::once(term_operands(&bb.term)));
+            for (i, ops) in users.enumerate() {
+                for op in ops {
+                    if let Operand::LocalOperand { name, .. } = op
+                        && let Some(&def) = values.get(name)
+                    {
+                        edges.push(Edge { from: def, to: positions[b][i], kind: EdgeKind::Data, label: String::new() });
+                    }
+                }
+            }
+        }
+        edges.extend(memory_edges(func, &positions));
+        let blocks = func.basic_blocks.iter().map(|bb| block_label(&bb.name)).collect();
+        Ddg { name: func.name.clone(), nodes, edges, blocks }
+    }
+
+    /// Nodes named by `%13`, `13`, `<block>:<index>` or `line:<n>` (every node of a source line).
+    pub fn find(&self, spec: &str) -> Vec<usize> {
+        if let Some(line) = spec.strip_prefix("line:").and_then(|l| l.parse::<u32>().ok()) {
+            return (0..self.nodes.len()).filter(|&n| self.nodes[n].line == Some(line)).collect();
+        }
+        let id = if spec.contains(':') || spec.starts_with('%') { spec.to_string() } else { format!("%{}", spec) };
+        (0..self.nodes.len()).filter(|&n| self.nodes[n].id == id).collect()
+    }
+
+    /// Nodes reached from `start` along edges (forwards: to users, backwards: to what feeds them),
+    /// in breadth-first order with their distance. `max_depth` of 1 gives the direct neighbours.
+    pub fn slice(&self, start: &[usize], forward: bool, max_depth: Option<usize>) -> Vec<(usize, usize, EdgeKind)> {
+        let mut seen: HashSet<usize> = start.iter().copied().collect();
+        let mut queue: VecDeque<(usize, usize)> = start.iter().map(|&n| (n, 0)).collect();
+        let mut result = Vec::new();
+        while let Some((n, depth)) = queue.pop_front() {
+            if max_depth.is_some_and(|max| depth >= max) {
+                continue;
+            }
+            for edge in &self.edges {
+                let (from, to) = if forward { (edge.from, edge.to) } else { (edge.to, edge.from) };
+                if from == n && seen.insert(to) {
+                    result.push((to, depth + 1, edge.kind));
+                    queue.push_back((to, depth + 1));
+                }
+            }
+        }
+        result
+    }
+
+    fn location(&self, n: usize) -> String {
+        let node = &self.nodes[n];
+        let block = node.block.map_or("(parameter)".to_string(), |b| self.blocks[b].clone());
+        match node.line {
+            Some(line) => format!("{}, line {}", block, line),
+            None => block,
+        }
+    }
+
+    /// Graphviz, one cluster per basic block; memory edges are dashed and labelled with the object.
+    pub fn to_dot(&self, keep: &HashSet<usize>) -> String {
+        let mut out = format!("digraph {} {{\n  node [shape=box, fontname=\"monospace\"];\n", quote(&self.name));
+        let mut clusters: Vec<Vec<usize>> = vec![Vec::new(); self.blocks.len()];
+        let mut kept: Vec<usize> = keep.iter().copied().collect();
+        kept.sort();
+        for n in kept {
+            match self.nodes[n].block {
+                Some(b) => clusters[b].push(n),
+                None => out.push_str(&format!("  n{} [label={}, shape=ellipse];\n", n, quote(&self.nodes[n].text))),
+            }
+        }
+        for (b, members) in clusters.into_iter().enumerate() {
+            if members.is_empty() {
+                continue;
+            }
+            out.push_str(&format!("  subgraph cluster_{} {{\n    label={};\n", b, quote(&self.blocks[b])));
+            for n in members {
+                out.push_str(&format!("    n{} [label={}];\n", n, quote(&self.nodes[n].text)));
+            }
+            out.push_str("  }\n");
+        }
+        for edge in self.edges.iter().filter(|e| keep.contains(&e.from) && keep.contains(&e.to)) {
+            match edge.kind {
+                EdgeKind::Data => out.push_str(&format!("  n{} -> n{};\n", edge.from, edge.to)),
+                EdgeKind::Memory => {
+                    out.push_str(&format!("  n{} -> n{} [style=dashed, label={}];\n", edge.from, edge.to, quote(&edge.label)))
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_json(&self, keep: &HashSet<usize>) -> String {
+        let mut kept: Vec<usize> = keep.iter().copied().collect();
+        kept.sort();
+        let nodes: Vec<String> = kept
+            .iter()
+            .map(|&n| {
+                let node = &self.nodes[n];
+                format!(
+                    "    {{\"id\": {}, \"block\": {}, \"line\": {}, \"text\": {}}}",
+                    quote(&node.id),
+                    node.block.map_or("null".to_string(), |b| quote(&self.blocks[b])),
+                    node.line.map_or("null".to_string(), |l| l.to_string()),
+                    quote(&node.text)
+                )
+            })
+            .collect();
+        let edges: Vec<String> = self
+            .edges
+            .iter()
+            .filter(|e| keep.contains(&e.from) && keep.contains(&e.to))
+            .map(|e| {
+                let kind = if e.kind == EdgeKind::Data { "data" } else { "memory" };
+                let object = if e.label.is_empty() { String::new() } else { format!(", \"object\": {}", quote(&e.label)) };
+                format!(
+                    "    {{\"from\": {}, \"to\": {}, \"kind\": \"{}\"{}}}",
+                    quote(&self.nodes[e.from].id),
+                    quote(&self.nodes[e.to].id),
+                    kind,
+                    object
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"function\": {},\n  \"nodes\": [\n{}\n  ],\n  \"edges\": [\n{}\n  ]\n}}\n",
+            quote(&self.name),
+            nodes.join(",\n"),
+            edges.join(",\n")
+        )
+    }
+}
+
+/// Store-to-load edges: from each store, every load of the same object it can reach along the CFG.
+/// A store to a scalar local stops at the next store to it; array elements with different constant
+/// subscripts don't depend on each other.
+fn memory_edges(func: &Function, positions: &[Vec<usize>]) -> Vec<Edge> {
+    let mem = MemoryInfo::new(func);
+    let cfg = Cfg::new(func);
+    let constant = |address| {
+        mem.array_ref(address).and_then(|r| {
+            r.subscripts.iter().map(|s| if let Subscript::Const(c) = s { Some(*c) } else { None }).collect::<Option<Vec<i64>>>()
+        })
+    };
+    let mut edges = Vec::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        for (i, instr) in bb.instrs.iter().enumerate() {
+            let Instruction::Store(store) = instr else { continue };
+            let Some(base) = mem.base_of(&store.address) else { continue };
+            let scalar = matches!(base, MemBase::Alloca(name) if mem.is_scalar_alloca(name));
+            let element = constant(&store.address);
+            let label = base.to_string();
+
+            // Follows the CFG from just after the store; returns false where the value is overwritten
+            let scan = |block: usize, from: usize, edges: &mut Vec<Edge>| {
+                for (j, other) in func.basic_blocks[block].instrs.iter().enumerate().skip(from) {
+                    let Some(acc) = access(other) else { continue };
+                    if mem.base_of(acc.address) != Some(base) {
+                        continue;
+                    }
+                    let other_element = constant(acc.address);
+                    if element.is_some() && other_element.is_some() && element != other_element {
+                        continue;
+                    }
+                    if !acc.is_write {
+                        edges.push(Edge { from: positions[b][i], to: positions[block][j], kind: EdgeKind::Memory, label: label.clone() });
+                    } else if scalar {
+                        return false;
+                    }
+                }
+                true
+            };
+            let mut queue = Vec::new();
+            if scan(b, i + 1, &mut edges) {
+                queue.extend(cfg.succs[b].iter().copied());
+            }
+            let mut visited = HashSet::new();
+            while let Some(block) = queue.pop() {
+                if !visited.insert(block) {
+                    continue;
+                }
+                if scan(block, 0, &mut edges) {
+                    queue.extend(cfg.succs[block].iter().copied());
+                }
+            }
+        }
+    }
+    edges
+}
+
+/// `--ddg <function> [--uses <value>]... [--feeds <value>]... [--all] [--format text|dot|json] [--output <file>]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --ddg <function> [--uses <value>]... [--feeds <value>]... [--all] [--format text|dot|json] [--output <file>]
+  <value> is %13, %x.addr, <block>:<index> for instructions without a result, or line:<n> for a source line";
+    let func_name = args.first().filter(|a| !a.starts_with("--")).ok_or(usage)?;
+    let func = module
+        .get_func_by_name(func_name)
+        .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == *func_name))
+        .ok_or_else(|| format!("Function '{}' not found in module.", func_name))?;
+
+    let mut queries: Vec<(bool, &String)> = Vec::new();
+    let mut transitive = false;
+    let mut format = None;
+    let mut output = None;
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        if flag == "--all" {
+            transitive = true;
+            continue;
+        }
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", flag, usage))?;
+        match flag.as_str() {
+            "--uses" => queries.push((true, value)),
+            "--feeds" => queries.push((false, value)),
+            "--format" => match value.as_str() {
+                "text" | "dot" | "json" => format = Some(value.as_str()),
+                _ => return Err(format!("Unknown format '{}'\n{}", value, usage)),
+            },
+            "--output" => output = Some(value),
+            _ => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+        }
+    }
+
+    let ddg = Ddg::new(func);
+    let max_depth = if transitive { None } else { Some(1) };
+    // Queries print as text unless a graph format is asked for, which then covers only their slice
+    let format = format.unwrap_or(if queries.is_empty() { "dot" } else { "text" });
+    let mut keep: HashSet<usize> = if queries.is_empty() { (0..ddg.nodes.len()).collect() } else { HashSet::new() };
+    let mut text = String::new();
+    for (forward, spec) in &queries {
+        let start = ddg.find(spec);
+        if start.is_empty() {
+            return Err(format!("No value or instruction '{}' in {}\n{}", spec, func.name, usage));
+        }
+        let reached = ddg.slice(&start, *forward, max_depth);
+        keep.extend(start.iter().copied());
+        keep.extend(reached.iter().map(|(n, _, _)| *n));
+
+        let what = if *forward { "Uses of" } else { "What feeds" };
+        if let [n] = start[..] {
+            text.push_str(&format!("{} {} ({}):\n  {}\n", what, ddg.nodes[n].id, ddg.location(n), ddg.nodes[n].text));
+        } else {
+            text.push_str(&format!("{} {} ({} instructions):\n", what, spec, start.len()));
+            for &n in &start {
+                text.push_str(&format!("  {:<68} {}\n", ddg.nodes[n].text, ddg.location(n)));
+            }
+        }
+        if reached.is_empty() {
+            text.push_str("  (nothing)\n");
+        }
+        for (n, depth, kind) in reached {
+            let via = if kind == EdgeKind::Memory { "memory" } else { "data" };
+            let step = if transitive { format!("{:>3} ", depth) } else { String::new() };
+            text.push_str(&format!("  -> {}{:<7} {:<60} {}\n", step, via, ddg.nodes[n].text, ddg.location(n)));
+        }
+        text.push('\n');
+    }
+
+    let result = match format {
+        "dot" => ddg.to_dot(&keep),
+        "json" => ddg.to_json(&keep),
+        _ => text,
+    };
+    match output {
+        Some(path) => {
+            fs::write(path, result).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            println!("Wrote {} nodes of {} to {}", keep.len(), func.name, path);
+        }
+        None => print!("{}", result),
+    }
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use crate::ir_util::block_label;
+use crate::loops::LoopInfo;
+use crate::memory::{access, MemBase, MemoryInfo};
+use crate::ports::{array_configs, busiest_bank, suggest_partition, Partition, PartitionKind};
+use crate::source::{function_loc, hls_loop_names, source_name, Sources};
+use crate::unroll::unroll;
+use llvm_ir::{Instruction, Module, Operand};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Loops whose fully unrolled body has at most this many iterations are unrolled by default.
+const DEFAULT_MAX_UNROLL: u64 = 8;
+
+/// One line of the generated script and the reason for it.
+struct Directive {
+    tcl: String,
+    reason: String,
+}
+
+/// Decides which loops to unroll and which to pipeline. A loop is unrolled if its trip count is
+/// constant, its inner loops are unrolled and the total number of copies stays within `max_unroll`;
+/// a loop whose inner loops are all unrolled is pipelined instead (outermost loops are always
+/// pipelined rather than unrolled, so the function keeps a loop to schedule).
+fn choose_loops(loop_info: &LoopInfo, max_unroll: u64) -> (Vec<bool>, Vec<bool>) {
+    let n = loop_info.loops.len();
+    let children: Vec<Vec<usize>> = (0..n).map(|l| (0..n).filter(|&c| loop_info.loops[c].parent == Some(l)).collect()).collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by_key(|&l| std::cmp::Reverse(loop_info.loops[l].depth));
+
+    let mut copies: Vec<Option<u64>> = vec![None; n];
+    let mut unrolled = vec![false; n];
+    let mut pipelined = vec![false; n];
+    for l in order {
+        let inner_unrolled = children[l].iter().all(|&c| copies[c].is_some());
+        let inner = children[l].iter().filter_map(|&c| copies[c]).max().unwrap_or(1);
+        let total = loop_info.constant_trip_count(l).and_then(|t| t.checked_mul(inner));
+        let fits = inner_unrolled && total.is_some_and(|t| t <= max_unroll);
+        if fits && loop_info.loops[l].parent.is_some() {
+            unrolled[l] = true;
+            copies[l] = total;
+        } else {
+            pipelined[l] = inner_unrolled;
+        }
+    }
+    (unrolled, pipelined)
+}
+
+/// Name of an array as written in the source: an unnamed argument spilled at -O0 is named
+/// after its `<name>.addr` slot.
+fn array_name(mem: &MemoryInfo, base: MemBase) -> String {
+    let name = base.to_string();
+    if !matches!(base, MemBase::Param(_)) || !name.chars().all(|c| c.is_ascii_digit()) {
+        return name;
+    }
+    for instr in mem.func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        if let Instruction::Store(store) = instr
+            && matches!(&store.value, Operand::LocalOperand { name: value, .. } if value.to_string() == format!("%{}", name))
+            && let Some(MemBase::Alloca(slot)) = mem.base_of(&store.address)
+            && let Some(stripped) = block_label(slot).strip_suffix(".addr")
+        {
+            return stripped.to_string();
+        }
+    }
+    name
+}
+
+/// `--directives <function> [--output <file>] [--max-unroll <n>] [--source-dir <dir>]...`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --directives <function> [--output <file>] [--max-unroll <n>] [--source-dir <dir>]...";
+    let func_name = args.first().filter(|a| !a.starts_with("--")).ok_or(usage)?;
+    let func = module
+        .get_func_by_name(func_name)
+        .ok_or_else(|| format!("Function '{}' not found in module.", func_name))?;
+
+    let mut output = PathBuf::from("directives.tcl");
+    let mut max_unroll = DEFAULT_MAX_UNROLL;
+    let mut search_dirs = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", flag, usage))?;
+        match flag.as_str() {
+            "--output" => output = PathBuf::from(value),
+            "--max-unroll" => max_unroll = value.parse().map_err(|_| format!("Invalid unroll limit '{}'", value))?,
+            "--source-dir" => search_dirs.push(PathBuf::from(value)),
+            _ => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+        }
+    }
+
+    let loop_info = LoopInfo::new(func);
+    let mut sources = Sources::new(search_dirs);
+    let loop_names = hls_loop_names(&loop_info, &mut sources);
+    let top = source_name(&func.name);
+    let (unrolled, pipelined) = choose_loops(&loop_info, max_unroll);
+
+    let mut directives = Vec::new();
+    let mut notes = Vec::new();
+    for l in 0..loop_info.loops.len() {
+        let location = format!("{}/{}", top, loop_names[l]);
+        let trips = loop_info.constant_trip_count(l).map_or("unknown".to_string(), |t| t.to_string());
+        if unrolled[l] {
+            directives.push(Directive {
+                tcl: format!("set_directive_unroll \"{}\"", location),
+                reason: format!("{}: {} iterations, small enough to unroll", loop_info.describe(l), trips),
+            });
+        } else if pipelined[l] {
+            let inner = if loop_info.loops.iter().any(|lp| lp.parent == Some(l)) { ", inner loops unrolled" } else { "" };
+            directives.push(Directive {
+                tcl: format!("set_directive_pipeline -II 1 \"{}\"", location),
+                reason: format!("{}: {} iterations{}", loop_info.describe(l), trips, inner),
+            });
+        }
+        if (unrolled[l] || pipelined[l]) && loop_names[l].starts_with("VITIS_LOOP_") {
+            notes.push(format!("{} has no label in the source; add '{}:' in front of it", loop_info.describe(l), loop_names[l]));
+        }
+    }
+
+    // Unroll the chosen loops, innermost first, so the subscripts in the pipelined bodies become constants
+    let mut work = func.clone();
+    let mut order: Vec<usize> = (0..loop_info.loops.len()).filter(|&l| unrolled[l]).collect();
+    order.sort_by_key(|&l| std::cmp::Reverse(loop_info.loops[l].depth));
+    for l in order {
+        let header = block_label(loop_info.cfg.names[loop_info.loops[l].header]);
+        let current = LoopInfo::new(&work);
+        let Some(found) = current.find_by_header(&header) else { continue };
+        let Some(trips) = current.constant_trip_count(found) else { continue };
+        match unroll(&work, found, trips) {
+            Ok(f) => work = f,
+            Err(e) => notes.push(format!("{} could not be unrolled here ({}); partitions ignore it", loop_info.describe(l), e)),
+        }
+    }
+
+    // With II=1 every access of one iteration is issued in the same cycle of the pipeline,
+    // so each bank must have a port for every access that may hit it
+    let mem = MemoryInfo::new(&work);
+    let mut configs = array_configs(&mem, &HashMap::new(), &HashMap::new());
+    let work_info = LoopInfo::new(&work);
+    let mut partitioned: Vec<(MemBase, Partition, String)> = Vec::new();
+    for l in (0..loop_info.loops.len()).filter(|&l| pipelined[l]) {
+        let header = block_label(loop_info.cfg.names[loop_info.loops[l].header]);
+        let Some(wl) = work_info.find_by_header(&header) else { continue };
+        let mut by_array: HashMap<MemBase, Vec<&Instruction>> = HashMap::new();
+        for &b in &work_info.loops[wl].blocks {
+            for instr in &work.basic_blocks[b].instrs {
+                if let Some(acc) = access(instr)
+                    && mem.is_array_access(&acc)
+                    && let Some(base) = mem.base_of(acc.address)
+                {
+                    by_array.entry(base).or_default().push(instr);
+                }
+            }
+        }
+        let mut arrays: Vec<_> = by_array.into_iter().collect();
+        arrays.sort_by_key(|(base, _)| *base);
+        for (base, instrs) in arrays {
+            let Some(config) = configs.get_mut(&base) else { continue };
+            loop {
+                let keys: Vec<_> = instrs
+                    .iter()
+                    .map(|instr| {
+                        let array_ref = access(instr).and_then(|acc| mem.array_ref(acc.address));
+                        config.bank_of(array_ref.as_ref().map(|r| r.subscripts.as_slice()))
+                    })
+                    .collect();
+                let busiest: Vec<&Instruction> = busiest_bank(&keys).into_iter().map(|i| instrs[i]).collect();
+                if config.is_registers() || busiest.len() <= config.ports {
+                    break;
+                }
+                let Some(p) = suggest_partition(&mem, &busiest, &config.dims)
+                    .filter(|p| !config.partitions.iter().any(|q| q.dim == p.dim))
+                else {
+                    notes.push(format!(
+                        "{} accesses to {} in {} may hit the same bank with {} port(s); their subscripts are not constant",
+                        busiest.len(),
+                        base,
+                        loop_info.describe(l),
+                        config.ports
+                    ));
+                    break;
+                };
+                let reason = format!(
+                    "{} accesses per iteration of {} may hit one bank with {} port(s)",
+                    busiest.len(),
+                    loop_info.describe(l),
+                    config.ports
+                );
+                config.partitions.push(p);
+                partitioned.push((base, p, reason));
+            }
+        }
+    }
+    let original = MemoryInfo::new(func);
+    for (base, p, reason) in &partitioned {
+        let kind = match p.kind {
+            PartitionKind::Complete => "-type complete".to_string(),
+            PartitionKind::Cyclic(f) => format!("-type cyclic -factor {}", f),
+            PartitionKind::Block(f) => format!("-type block -factor {}", f),
+        };
+        directives.push(Directive {
+            tcl: format!("set_directive_array_partition {} -dim {} \"{}\" {}", kind, p.dim, top, array_name(&original, *base)),
+            reason: reason.clone(),
+        });
+    }
+
+    let source = function_loc(func).map_or(module.source_file_name.clone(), |loc| loc.filename.clone());
+    let mut tcl = format!("# Directives for {} ({}), generated by llvm_ir_tool --directives\n", top, source);
+    for d in &directives {
+        tcl.push_str(&format!("\n# {}\n{}\n", d.reason, d.tcl));
+    }
+    fs::write(&output, &tcl).map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+
+    print!("{}", tcl);
+    println!("\nWrote {} directive(s) to {}", directives.len(), output.display());
+    println!("Source it from csynth.tcl (after set_directive_top) with: source {}", output.display());
+    for note in &notes {
+        println!("  Note: {}", note);
+    }
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use crate::interp::{allocation_of, Interpreter, MemEvent, Trace, Value};
+use crate::ir_util::block_label;
+use crate::profile::parse_arguments;
+use crate::source::source_name;
+use llvm_ir::Module;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+/// Depth Vitis gives a FIFO that has no STREAM pragma.
+const DEFAULT_DEPTH: u64 = 2;
+
+/// How a consumer reads the elements its producer wrote.
+#[derive(Clone, Copy, PartialEq)]
+enum Order {
+    // Every element once, in the order written: a FIFO works
+    Sequential,
+    Reordered,
+    // Some element is read more than once
+    Reread,
+    // Not every element written is read, or the other way around
+    Partial,
+}
+
+impl Order {
+    fn describe(self) -> &'static str {
+        match self {
+            Order::Sequential => "in order",
+            Order::Reordered => "reordered (needs a ping-pong buffer)",
+            Order::Reread => "read more than once (needs a ping-pong buffer)",
+            Order::Partial => "not all elements read",
+        }
+    }
+}
+
+/// A buffer written by one process and read by a later one.
+struct Channel {
+    name: String,
+    producer: usize,
+    consumer: usize,
+    // Bits per access
+    width: u64,
+    // Byte offset of each access and when it happens, in instructions since its process started
+    writes: Vec<(u64, u64)>,
+    reads: Vec<(u64, u64)>,
+    order: Order,
+    // Smallest depth that lets both sides finish, and the depth at which the producer never waits
+    min_depth: u64,
+    no_stall_depth: u64,
+}
+
+fn classify(writes: &[(u64, u64)], reads: &[(u64, u64)]) -> Order {
+    let distinct = |accesses: &[(u64, u64)]| {
+        let mut offsets: Vec<u64> = accesses.iter().map(|(o, _)| *o).collect();
+        offsets.sort();
+        offsets.dedup();
+        offsets.len() == accesses.len()
+    };
+    if !distinct(reads) {
+        Order::Reread
+    } else if !distinct(writes) || writes.len() != reads.len() {
+        Order::Partial
+    } else if writes.iter().zip(reads).all(|(w, r)| w.0 == r.0) {
+        Order::Sequential
+    } else {
+        let mut w: Vec<u64> = writes.iter().map(|(o, _)| *o).collect();
+        let mut r: Vec<u64> = reads.iter().map(|(o, _)| *o).collect();
+        w.sort();
+        r.sort();
+        if w == r { Order::Reordered } else { Order::Partial }
+    }
+}
+
+/// Depths for the in-order channels between one producer and one consumer. Each side runs in its
+/// own program order and blocks on a full or empty FIFO. Before the consumer's j-th read can go
+/// ahead, the producer must have done every write up to the one it waits for, and whatever the
+/// consumer hasn't read of those must fit in the FIFOs: that gives the minimum depths. Timing each
+/// side by the instructions it executes, a consumer that waits for data falls behind, and the
+/// highest occupancy while the producer never waits gives the no-stall depths.
+fn size_channels(channels: &mut [&mut Channel]) {
+    // Both sides' accesses across the channels, in their own order
+    let mut produced: Vec<(u64, usize, u64)> =
+        channels.iter().enumerate().flat_map(|(c, ch)| ch.writes.iter().map(move |&(o, t)| (t, c, o))).collect();
+    let mut consumed: Vec<(u64, usize, u64)> =
+        channels.iter().enumerate().flat_map(|(c, ch)| ch.reads.iter().map(move |&(o, t)| (t, c, o))).collect();
+    produced.sort();
+    consumed.sort();
+    let position: HashMap<(usize, u64), usize> = produced.iter().enumerate().map(|(p, &(_, c, o))| ((c, o), p)).collect();
+
+    let n = channels.len();
+    // written[c][p]: writes to channel c among the first p producer accesses
+    let mut written = vec![vec![0u64; produced.len() + 1]; n];
+    for (p, &(_, c, _)) in produced.iter().enumerate() {
+        for (k, counts) in written.iter_mut().enumerate() {
+            counts[p + 1] = counts[p] + u64::from(k == c);
+        }
+    }
+    let mut read = vec![0u64; n];
+    let mut min_depth = vec![0u64; n];
+    for &(_, c, o) in &consumed {
+        let Some(&p) = position.get(&(c, o)) else { continue };
+        for k in 0..n {
+            min_depth[k] = min_depth[k].max(written[k][p + 1].saturating_sub(read[k] + u64::from(k == c)));
+        }
+        read[c] += 1;
+    }
+
+    // When each read really happens once the consumer waits for data
+    let write_time: HashMap<(usize, u64), u64> = produced.iter().map(|&(t, c, o)| ((c, o), t)).collect();
+    let mut shift = 0;
+    let mut read_times: Vec<Vec<u64>> = vec![Vec::new(); n];
+    for &(t, c, o) in &consumed {
+        let ready = write_time.get(&(c, o)).map_or(0, |w| w + 1);
+        let at = (t + shift).max(ready);
+        shift = at - t;
+        read_times[c].push(at);
+    }
+    for (c, ch) in channels.iter_mut().enumerate() {
+        let mut events: Vec<(u64, i64)> = ch.writes.iter().map(|&(_, t)| (t, 1)).collect();
+        events.extend(read_times[c].iter().map(|&t| (t, -1)));
+        // Writes first at equal times, so the count errs on the full side
+        events.sort_by_key(|&(t, delta)| (t, -delta));
+        let (mut level, mut peak) = (0i64, 0i64);
+        for (_, delta) in events {
+            level += delta;
+            peak = peak.max(level);
+        }
+        ch.min_depth = min_depth[c].max(1);
+        ch.no_stall_depth = (peak as u64).max(ch.min_depth);
+    }
+}
+
+/// Groups the traced accesses of the processes by buffer and keeps the one-producer, one-consumer ones.
+fn channels(trace: &Trace, names: &HashMap<u64, String>) -> (Vec<Channel>, Vec<String>) {
+    let mut by_buffer: BTreeMap<u64, Vec<&MemEvent>> = BTreeMap::new();
+    for event in trace.events.iter().filter(|e| e.process.is_some()) {
+        by_buffer.entry(allocation_of(event.addr).0).or_default().push(event);
+    }
+    let mut result = Vec::new();
+    let mut notes = Vec::new();
+    for (id, events) in by_buffer {
+        let Some(name) = names.get(&id) else { continue };
+        let mut writers: Vec<usize> = events.iter().filter(|e| e.is_write).filter_map(|e| e.process).collect();
+        let mut readers: Vec<usize> = events.iter().filter(|e| !e.is_write).filter_map(|e| e.process).collect();
+        writers.sort();
+        writers.dedup();
+        readers.sort();
+        readers.dedup();
+        let callee = |p: usize| source_name(trace.calls[p].0);
+        match (writers.as_slice(), readers.as_slice()) {
+            // Inputs and outputs of the region
+            ([], _) | (_, []) => {}
+            ([w], [r]) if w < r => {
+                let local = |e: &&MemEvent| (allocation_of(e.addr).1, e.step - trace.calls[e.process.unwrap()].1);
+                let writes: Vec<(u64, u64)> = events.iter().filter(|e| e.is_write).map(local).collect();
+                let reads: Vec<(u64, u64)> = events.iter().filter(|e| !e.is_write).map(local).collect();
+                let width = events.iter().map(|e| e.bytes * 8).max().unwrap_or(0);
+                let order = classify(&writes, &reads);
+                result.push(Channel {
+                    name: name.clone(),
+                    producer: *w,
+                    consumer: *r,
+                    width,
+                    writes,
+                    reads,
+                    order,
+                    min_depth: 0,
+                    no_stall_depth: 0,
+                });
+            }
+            ([_], [_]) => notes.push(format!("{} is read before it is written; not a forward channel", name)),
+            (ws, rs) => notes.push(format!(
+                "{} has {} producer(s) ({}) and {} consumer(s) ({}); a FIFO needs one of each",
+                name,
+                ws.len(),
+                ws.iter().map(|&p| callee(p)).collect::<Vec<_>>().join(", "),
+                rs.len(),
+                rs.iter().map(|&p| callee(p)).collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+    (result, notes)
+}
+
+/// `--fifo <function> [args...] [--output <file>]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --fifo <function> [args...] [--output <file>]";
+    let mut positional = Vec::new();
+    let mut output = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--output" => output = Some(rest.next().ok_or_else(|| format!("Missing value for --output\n{}", usage))?),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let func_name = positional.first().ok_or(usage)?;
+    let func = module
+        .get_func_by_name(func_name)
+        .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == *func_name))
+        .ok_or_else(|| format!("Function '{}' not found in module.", func_name))?;
+
+    let mut interp = Interpreter::new(module)?;
+    let call_args = parse_arguments(&mut interp, func, &positional[1..])?;
+    // Buffers are named after the parameter or local array they were allocated for
+    let mut names: HashMap<u64, String> = HashMap::new();
+    for (param, value) in func.parameters.iter().zip(&call_args) {
+        if let Value::Ptr(addr) = value {
+            names.insert(allocation_of(*addr).0, block_label(&param.name));
+        }
+    }
+    interp.trace = Some(Trace::default());
+    interp.call_function(&func.name, call_args)?;
+    let trace = interp.trace.take().unwrap_or_default();
+    for (name, id) in &trace.allocas {
+        names.insert(*id, block_label(name));
+    }
+
+    let (mut found, notes) = channels(&trace, &names);
+    let mut pairs: BTreeMap<(usize, usize), Vec<&mut Channel>> = BTreeMap::new();
+    for ch in found.iter_mut().filter(|ch| ch.order == Order::Sequential) {
+        pairs.entry((ch.producer, ch.consumer)).or_default().push(ch);
+    }
+    for group in pairs.values_mut() {
+        size_channels(group);
+    }
+
+    println!("Dataflow region '{}': {} process call(s), {} instructions executed", func.name, trace.calls.len(), interp.steps);
+    let callee = |p: usize| source_name(trace.calls[p].0);
+    if found.is_empty() {
+        println!("  No buffer is written by one process and read by a later one.");
+    } else {
+        println!(
+            "  {:<14} {:<32} {:>8} {:>6} {:>10} {:>10}  Order",
+            "Buffer", "Producer -> consumer", "Elements", "Width", "Min depth", "No stall"
+        );
+        for ch in &found {
+            let depth = |d: u64| if ch.order == Order::Sequential { d.to_string() } else { "-".to_string() };
+            println!(
+                "  {:<14} {:<32} {:>8} {:>6} {:>10} {:>10}  {}",
+                ch.name,
+                format!("{} -> {}", callee(ch.producer), callee(ch.consumer)),
+                ch.writes.len(),
+                ch.width,
+                depth(ch.min_depth),
+                depth(ch.no_stall_depth),
+                ch.order.describe()
+            );
+        }
+        let streams: Vec<&Channel> = found.iter().filter(|ch| ch.order == Order::Sequential).collect();
+        if !streams.is_empty() {
+            println!("\n  Pragmas for the streamable buffers (no-stall depth, at least {}):", DEFAULT_DEPTH);
+            for ch in &streams {
+                println!("    #pragma HLS STREAM variable={} depth={}", ch.name, ch.no_stall_depth.max(DEFAULT_DEPTH));
+            }
+        }
+    }
+    for note in &notes {
+        println!("  Note: {}", note);
+    }
+    println!("  Rates are counted in executed IR instructions, so depths for pipelined loops are upper bounds.");
+
+    if let Some(path) = output {
+        // One line per FIFO, numbered in the order printed, for simulators that take depths as input
+        let mut csv = String::from("fifo_id,buffer,producer,consumer,width,elements,min_depth,depth\n");
+        for (i, ch) in found.iter().filter(|ch| ch.order == Order::Sequential).enumerate() {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                i,
+                ch.name,
+                callee(ch.producer),
+                callee(ch.consumer),
+                ch.width,
+                ch.writes.len(),
+                ch.min_depth,
+                ch.no_stall_depth.max(DEFAULT_DEPTH)
+            ));
+        }
+        fs::write(path, csv).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        println!("Wrote FIFO depths to {}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, writes: &[(u64, u64)], reads: &[(u64, u64)]) -> Channel {
+        Channel {
+            name: name.to_string(),
+            producer: 0,
+            consumer: 1,
+            width: 32,
+            writes: writes.to_vec(),
+            reads: reads.to_vec(),
+            order: classify(writes, reads),
+            min_depth: 0,
+            no_stall_depth: 0,
+        }
+    }
+
+    #[test]
+    fn classifies_read_orders() {
+        let writes = [(0, 0), (4, 1), (8, 2)];
+        assert!(classify(&writes, &[(0, 5), (4, 6), (8, 7)]) == Order::Sequential);
+        assert!(classify(&writes, &[(8, 5), (0, 6), (4, 7)]) == Order::Reordered);
+        assert!(classify(&writes, &[(0, 5), (4, 6), (4, 7), (8, 8)]) == Order::Reread);
+        assert!(classify(&writes, &[(0, 5), (4, 6)]) == Order::Partial);
+    }
+
+    #[test]
+    fn sizes_in_order_channels() {
+        // A consumer as fast as its producer needs one slot, a slow one lets the producer run ahead
+        let mut fast = channel("fast", &[(0, 0), (4, 1), (8, 2), (12, 3)], &[(0, 0), (4, 1), (8, 2), (12, 3)]);
+        size_channels(&mut [&mut fast]);
+        assert_eq!((fast.min_depth, fast.no_stall_depth), (1, 2));
+
+        let mut slow = channel("slow", &[(0, 0), (4, 1), (8, 2), (12, 3)], &[(0, 0), (4, 10), (8, 20), (12, 30)]);
+        size_channels(&mut [&mut slow]);
+        assert_eq!((slow.min_depth, slow.no_stall_depth), (1, 3));
+    }
+
+    #[test]
+    fn sizes_cross_ordered_channels_together() {
+        // The producer writes all of a before b, the consumer wants b[0] first: a has to hold both
+        // of its elements or the two processes deadlock
+        let mut a = channel("a", &[(0, 0), (4, 1)], &[(0, 1), (4, 3)]);
+        let mut b = channel("b", &[(0, 2), (4, 3)], &[(0, 0), (4, 2)]);
+        size_channels(&mut [&mut a, &mut b]);
+        assert_eq!((a.min_depth, b.min_depth), (2, 1));
+        assert!(a.no_stall_depth >= a.min_depth && b.no_stall_depth >= b.min_depth);
+    }
+
+    #[test]
+    fn groups_accesses_into_channels() {
+        let addr = |buffer: u64, offset: u64| (buffer << 32) + offset;
+        assert_eq!(allocation_of(addr(5, 8)), (5, 8));
+        let event = |process, buffer, offset, is_write, step| MemEvent { process: Some(process), addr: addr(buffer, offset), bytes: 4, is_write, step };
+        let trace = Trace {
+            events: vec![
+                event(0, 6, 0, false, 1),
+                event(0, 5, 0, true, 2),
+                event(0, 5, 4, true, 3),
+                event(0, 7, 0, false, 4),
+                event(1, 5, 4, false, 101),
+                event(1, 5, 0, false, 102),
+                event(1, 7, 0, true, 103),
+                // Not a named buffer
+                event(1, 8, 0, true, 104),
+            ],
+            calls: vec![("_Z7producev", 0), ("_Z7consumev", 100)],
+            allocas: HashMap::new(),
+        };
+        let names: HashMap<u64, String> = [(5, "tmp"), (6, "in"), (7, "back")].into_iter().map(|(id, n)| (id, n.to_string())).collect();
+        let (found, notes) = channels(&trace, &names);
+
+        // `in` is only read, an input of the region
+        assert_eq!(found.len(), 1);
+        let tmp = &found[0];
+        assert_eq!((tmp.name.as_str(), tmp.producer, tmp.consumer, tmp.width), ("tmp", 0, 1, 32));
+        // Times count from the start of each process
+        assert_eq!((tmp.writes.clone(), tmp.reads.clone()), (vec![(0, 2), (4, 3)], vec![(4, 1), (0, 2)]));
+        assert!(tmp.order == Order::Reordered);
+        assert_eq!(notes, ["back is read before it is written; not a forward channel"]);
+    }
+}
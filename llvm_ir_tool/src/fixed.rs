@@ -0,0 +1,314 @@
+use crate::ir_util::{const_int, instr_text, operands};
+use crate::loops::LoopInfo;
+use crate::pragmas::{collect, LoopMetadata};
+use crate::resources::{definitions, mul_dsps, source_width, type_bits};
+use crate::source::{source_name, Sources};
+use llvm_ir::{Function, HasDebugLoc, Instruction, Module, Name, Operand};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// How far to follow a product through additions and casts looking for its rounding shift.
+const MAX_CHAIN: usize = 8;
+
+/// A multiplier and the fixed-point format it works in.
+struct FixedMul<'f> {
+    instr: &'f Instruction,
+    block: usize,
+    // Widths of the operands before they were extended to the product width, and their signedness
+    operand_bits: [u64; 2],
+    signed: bool,
+    product_bits: u64,
+    // Right shift that drops the extra fraction bits of the product, and the width it is cut to
+    shift: Option<u64>,
+    result_bits: Option<u64>,
+}
+
+impl FixedMul<'_> {
+    /// `ap_fixed<W,I>` of the result when the product is rounded back to a fixed format.
+    fn format(&self) -> Option<String> {
+        let (width, fraction) = (self.result_bits?, self.shift?);
+        let kind = if self.signed { "ap_fixed" } else { "ap_ufixed" };
+        Some(format!("{}<{},{}>", kind, width, width as i64 - fraction as i64))
+    }
+
+    /// DSPs for the operands at their width before extension.
+    fn dsps(&self) -> u64 {
+        mul_dsps(self.operand_bits[0].max(self.operand_bits[1]))
+    }
+
+    /// DSPs if the format were `width` bits wide: operands in the result's format scale with it,
+    /// others keep their width.
+    fn dsps_as(&self, width: u64) -> u64 {
+        let scale = |bits: u64| if Some(bits) == self.result_bits { width } else { bits };
+        mul_dsps(scale(self.operand_bits[0]).max(scale(self.operand_bits[1])))
+    }
+}
+
+/// Follows a product through additions, casts and -O0 stores and reloads of a local to the
+/// constant right shift and truncation that round it back to the working format.
+fn quantization<'f>(
+    func: &'f Function,
+    users: &HashMap<&'f Name, Vec<&'f Instruction>>,
+    module: &Module,
+    product: &'f Name,
+) -> Option<(u64, u64)> {
+    let mut frontier = vec![product];
+    for _ in 0..MAX_CHAIN {
+        let mut next = Vec::new();
+        for value in frontier {
+            for user in users.get(value).into_iter().flatten() {
+                let shift = match user {
+                    Instruction::AShr(i) if is_local(&i.operand0, value) => const_int(&i.operand1),
+                    Instruction::LShr(i) if is_local(&i.operand0, value) => const_int(&i.operand1),
+                    Instruction::Add(_) | Instruction::Sub(_) | Instruction::SExt(_) | Instruction::ZExt(_) => {
+                        next.extend(user.try_get_result());
+                        continue;
+                    }
+                    Instruction::Store(store) => {
+                        next.extend(reloads(func, &store.address));
+                        continue;
+                    }
+                    _ => continue,
+                };
+                let (Some(shift), Some(dest)) = (shift, user.try_get_result()) else { continue };
+                let width = users
+                    .get(dest)
+                    .into_iter()
+                    .flatten()
+                    .find_map(|u| match u {
+                        Instruction::Trunc(t) => Some(type_bits(&t.to_type)),
+                        _ => None,
+                    })
+                    .unwrap_or_else(|| type_bits(&module.type_of(*user)));
+                return Some((shift as u64, width));
+            }
+        }
+        if next.is_empty() {
+            break;
+        }
+        frontier = next;
+    }
+    None
+}
+
+// Loads of the local variable a value was stored to
+fn reloads<'f>(func: &'f Function, address: &Operand) -> Vec<&'f Name> {
+    let Operand::LocalOperand { name: slot, .. } = address else { return Vec::new() };
+    func.basic_blocks
+        .iter()
+        .flat_map(|bb| &bb.instrs)
+        .filter_map(|instr| match instr {
+            Instruction::Load(load) if is_local(&load.address, slot) => Some(&load.dest),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_local(op: &Operand, name: &Name) -> bool {
+    matches!(op, Operand::LocalOperand { name: n, .. } if n == name)
+}
+
+/// Multipliers of a function that work on extended operands or are rounded back with a shift,
+/// i.e. the fixed-point products `ap_fixed` lowers to. Multiplications by a constant (address
+/// arithmetic, scaling) are left out.
+fn fixed_muls<'f>(module: &Module, func: &'f Function) -> Vec<FixedMul<'f>> {
+    let defs = definitions(func);
+    let mut users: HashMap<&Name, Vec<&Instruction>> = HashMap::new();
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        for op in operands(instr) {
+            if let Operand::LocalOperand { name, .. } = op {
+                users.entry(name).or_default().push(instr);
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        for instr in &bb.instrs {
+            let Instruction::Mul(mul) = instr else { continue };
+            if const_int(&mul.operand0).is_some() || const_int(&mul.operand1).is_some() {
+                continue;
+            }
+            let (a, b_) = (source_width(&defs, module, &mul.operand0), source_width(&defs, module, &mul.operand1));
+            let product_bits = type_bits(&module.type_of(instr));
+            let quantized = quantization(func, &users, module, &mul.dest);
+            let extended = a.0 < product_bits || b_.0 < product_bits;
+            if !extended && quantized.is_none() {
+                continue;
+            }
+            result.push(FixedMul {
+                instr,
+                block: b,
+                operand_bits: [a.0, b_.0],
+                signed: a.1.unwrap_or(true) || b_.1.unwrap_or(true),
+                product_bits,
+                shift: quantized.map(|(shift, _)| shift),
+                result_bits: quantized.map(|(_, width)| width),
+            });
+        }
+    }
+    result
+}
+
+// Copies of a block after unrolling: the product of the factors of the unrolled loops around it
+fn unrolled_copies(loop_info: &LoopInfo, unroll: &[Option<u64>], block: usize) -> u64 {
+    (0..loop_info.loops.len())
+        .filter(|&l| loop_info.loops[l].blocks.contains(&block))
+        .filter_map(|l| unroll[l])
+        .product()
+}
+
+/// Parses the `W,I` of `--as 16,8`.
+fn parse_format(text: &str) -> Option<(u64, i64)> {
+    let (w, i) = text.split_once(',')?;
+    Some((w.trim().parse().ok()?, i.trim().parse().ok()?))
+}
+
+/// `--fixed [function] [--as <W>,<I>] [--source-dir <dir>]...`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --fixed [function] [--as <W>,<I>] [--source-dir <dir>]...";
+    let mut func_name = None;
+    let mut target = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--as" => {
+                let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?;
+                target = Some(parse_format(value).ok_or_else(|| format!("Invalid format '{}', expected W,I\n{}", value, usage))?);
+            }
+            "--source-dir" => {
+                search_dirs.push(PathBuf::from(rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?))
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+            name => func_name = Some(name),
+        }
+    }
+    let mut sources = Sources::new(search_dirs);
+    let metadata = LoopMetadata::load(module);
+
+    let functions: Vec<&Function> = match func_name {
+        Some(name) => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        None => module.functions.iter().collect(),
+    };
+    let mut found = false;
+    for func in functions {
+        let muls = fixed_muls(module, func);
+        if muls.is_empty() {
+            continue;
+        }
+        found = true;
+        let loop_info = LoopInfo::new(func);
+        let pragmas = collect(func, &loop_info, &metadata, &mut sources);
+        // Unroll factor of each loop: UNROLL factor=n, or the trip count for a full unroll
+        let unroll: Vec<Option<u64>> = (0..loop_info.loops.len())
+            .map(|l| {
+                let p = pragmas.loops[l].iter().find(|p| p.name == "UNROLL" && p.option("off").is_none())?;
+                p.option("factor").and_then(|f| f.parse().ok()).or_else(|| loop_info.constant_trip_count(l))
+            })
+            .collect();
+
+        println!("Function: {}", func.name);
+        let (mut total, mut total_as) = (0, 0);
+        for m in &muls {
+            let copies = unrolled_copies(&loop_info, &unroll, m.block);
+            let line = m.instr.get_debug_loc().as_ref().map_or("-".to_string(), |loc| loc.line.to_string());
+            println!("  line {:<5} {}", line, instr_text(m.instr));
+            let sign = if m.signed { "signed" } else { "unsigned" };
+            let rounding = match (m.shift, m.result_bits) {
+                (Some(shift), Some(bits)) => format!(", >> {} and cut to {} bits", shift, bits),
+                _ => ", kept at full precision".to_string(),
+            };
+            println!(
+                "    {} x {} bits ({}) -> {}-bit product{}",
+                m.operand_bits[0], m.operand_bits[1], sign, m.product_bits, rounding
+            );
+            let format = m.format().map_or(String::new(), |f| format!("{}, ", f));
+            let times = if copies > 1 { format!(" x {} unrolled copies", copies) } else { String::new() };
+            println!("    {}{} DSP(s){}", format, m.dsps(), times);
+            total += m.dsps() * copies;
+            if let Some((width, _)) = target {
+                total_as += m.dsps_as(width) * copies;
+            }
+        }
+        println!("  Total: {} multiplier(s), {} DSP(s)", muls.len(), total);
+        if let Some((width, int_bits)) = target {
+            let change = total_as as i64 - total as i64;
+            println!("  As ap_fixed<{},{}>: {} DSP(s) ({:+})", width, int_bits, total_as, change);
+        }
+        println!();
+    }
+    if !found {
+        println!("No fixed-point multipliers found.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::op_resources;
+
+    // matrix_mult's `sum += A[i][k] * B[k][j]` on ap_fixed<16,8> at -O0: the operands are
+    // sign-extended to a 32-bit product that is shifted back by the 8 fraction bits
+    const FIXED_MAC: &str = "
+define i16 @mac(i16 %a, i16 %b, i8 %c, i8 %d) {
+entry:
+  %sum = alloca i32
+  %a.ext = sext i16 %a to i32
+  %b.ext = sext i16 %b to i32
+  %prod = mul nsw i32 %a.ext, %b.ext
+  store i32 %prod, ptr %sum
+  %reload = load i32, ptr %sum
+  %shifted = ashr i32 %reload, 8
+  %result = trunc i32 %shifted to i16
+  %c.ext = zext i8 %c to i32
+  %d.ext = zext i8 %d to i32
+  %wide = mul i32 %c.ext, %d.ext
+  %scaled = mul i32 %wide, 3
+  ret i16 %result
+}
+";
+
+    #[test]
+    fn finds_fixed_point_products() {
+        let module = Module::from_ir_str(FIXED_MAC).unwrap();
+        let muls = fixed_muls(&module, &module.functions[0]);
+        // The multiplication by a constant is not a fixed-point product
+        assert_eq!(muls.len(), 2);
+
+        let mac = &muls[0];
+        assert_eq!((mac.operand_bits, mac.signed, mac.product_bits), ([16, 16], true, 32));
+        assert_eq!((mac.shift, mac.result_bits), (Some(8), Some(16)));
+        assert_eq!(mac.format().as_deref(), Some("ap_fixed<16,8>"));
+
+        let wide = &muls[1];
+        assert_eq!((wide.operand_bits, wide.signed), ([8, 8], false));
+        assert_eq!(wide.format(), None);
+    }
+
+    #[test]
+    fn sizes_multipliers_by_operand_width() {
+        let module = Module::from_ir_str(FIXED_MAC).unwrap();
+        let muls = fixed_muls(&module, &module.functions[0]);
+        let mac = &muls[0];
+        assert_eq!(mac.dsps(), 1);
+        // The resource estimate sizes it by the operands before extension too
+        assert_eq!(op_resources(&module, &definitions(&module.functions[0]), mac.instr).dsp, 1);
+        assert_eq!(mul_dsps(32), 3);
+        // Widening the format widens the operands in it
+        assert_eq!(mac.dsps_as(24), 2);
+        assert_eq!(mac.dsps_as(36), 6);
+        assert_eq!(muls[1].dsps_as(24), 1);
+    }
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!(parse_format("16,8"), Some((16, 8)));
+        assert_eq!(parse_format(" 24, -2 "), Some((24, -2)));
+        assert_eq!(parse_format("16"), None);
+    }
+}
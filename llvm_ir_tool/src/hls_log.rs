@@ -0,0 +1,321 @@
+use crate::csynth::match_loops;
+use crate::loops::LoopInfo;
+use crate::source::{function_loc, hls_loop_names, source_name, Sources};
+use llvm_ir::{DebugLoc, Function, HasDebugLoc, Module};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Severity of a log message, most severe first so sorting puts errors on top.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Error,
+    CriticalWarning,
+    Warning,
+    Info,
+}
+
+impl Severity {
+    fn parse(s: &str) -> Option<Severity> {
+        match s {
+            "ERROR" => Some(Severity::Error),
+            "CRITICAL WARNING" => Some(Severity::CriticalWarning),
+            "WARNING" => Some(Severity::Warning),
+            "INFO" => Some(Severity::Info),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "ERROR",
+            Severity::CriticalWarning => "CRITICAL WARNING",
+            Severity::Warning => "WARNING",
+            Severity::Info => "INFO",
+        }
+    }
+}
+
+/// A `file:line[:col]` location quoted in a message.
+pub struct SourceLoc {
+    pub file: String,
+    pub line: u32,
+    pub col: Option<u32>,
+}
+
+/// One `SEVERITY: [ID] text` message of a vitis_hls.log.
+pub struct Diagnostic {
+    pub severity: Severity,
+    // e.g. "HLS 214-114"
+    pub id: String,
+    pub text: String,
+    pub location: Option<SourceLoc>,
+    // Loop and function named in the text as 'name'
+    pub loop_name: Option<String>,
+    pub function: Option<String>,
+    // Help link from the "Resolution:" line that follows some messages
+    pub resolution: Option<String>,
+    pub log_line: usize,
+}
+
+/// Parses the messages of a Vitis HLS log.
+pub fn parse_log(text: &str) -> Vec<Diagnostic> {
+    let message_re = Regex::new(r"^(ERROR|CRITICAL WARNING|WARNING|INFO): \[([A-Z]+ [\d-]+)\]\s*(.*)$").unwrap();
+    let location_re = Regex::new(r"([\w./-]+\.(?:cpp|cc|cxx|c|hpp|h)):(\d+)(?::(\d+))?").unwrap();
+    let loop_re = Regex::new(r"loop\s+'([^']+)'").unwrap();
+    let function_re = Regex::new(r"function\s+'([^']+)'").unwrap();
+    let url_re = Regex::new(r"(?:https?://)?www\.\S+").unwrap();
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if let Some(rest) = line.strip_prefix("Resolution:") {
+            if let Some(last) = diagnostics.last_mut()
+                && last.log_line == i
+            {
+                last.resolution = url_re.find(rest).map(|m| {
+                    let url = m.as_str();
+                    if url.starts_with("http") { url.to_string() } else { format!("https://{}", url) }
+                });
+            }
+            continue;
+        }
+        let Some(caps) = message_re.captures(line) else { continue };
+        let Some(severity) = Severity::parse(&caps[1]) else { continue };
+        let text = caps[3].trim().to_string();
+        let location = location_re.captures(&text).map(|c| SourceLoc {
+            file: c[1].to_string(),
+            line: c[2].parse().unwrap_or(0),
+            col: c.get(3).and_then(|m| m.as_str().parse().ok()),
+        });
+        diagnostics.push(Diagnostic {
+            severity,
+            id: caps[2].to_string(),
+            loop_name: loop_re.captures(&text).map(|c| c[1].to_string()),
+            function: function_re.captures(&text).map(|c| c[1].to_string()),
+            text,
+            location,
+            resolution: None,
+            // 1-based, so the "Resolution:" line right after it has this index
+            log_line: i + 1,
+        });
+    }
+    diagnostics
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path).file_name().and_then(|f| f.to_str()).unwrap_or(path)
+}
+
+/// A function with debug info and its loops, with the names Vitis gives them.
+struct FunctionLoops<'m> {
+    func: &'m Function,
+    filename: &'m str,
+    loop_info: LoopInfo<'m>,
+    names: Vec<String>,
+}
+
+fn function_loops<'m>(module: &'m Module, sources: &mut Sources) -> Vec<FunctionLoops<'m>> {
+    let mut functions = Vec::new();
+    for func in &module.functions {
+        let Some(func_loc) = function_loc(func) else { continue };
+        let loop_info = LoopInfo::new(func);
+        let names = hls_loop_names(&loop_info, sources);
+        functions.push(FunctionLoops { func, filename: &func_loc.filename, loop_info, names });
+    }
+    functions
+}
+
+/// IR functions and loops a message refers to, from its source location, loop name and function name.
+fn cross_reference(diag: &Diagnostic, functions: &[FunctionLoops]) -> Vec<String> {
+    let mut refs = Vec::new();
+    for FunctionLoops { func, filename, loop_info, names } in functions {
+        let mut mentioned = diag.function.as_deref() == Some(source_name(&func.name).as_str());
+        let mut loops = Vec::new();
+        if let Some(loc) = &diag.location
+            && file_name(&loc.file) == file_name(filename)
+            && covers_line(func, loc.line)
+        {
+            mentioned = true;
+            // The innermost loop with code on that line
+            let innermost = (0..func.basic_blocks.len())
+                .filter(|&b| block_has_line(&func.basic_blocks[b], loc.line))
+                .filter_map(|b| loop_info.innermost(b))
+                .max_by_key(|&l| loop_info.loops[l].depth);
+            loops.extend(innermost);
+        }
+        if let Some(loop_name) = &diag.loop_name {
+            for l in match_loops(loop_name, loop_info, names) {
+                if !loops.contains(&l) {
+                    loops.push(l);
+                }
+            }
+        }
+        if loops.is_empty() && mentioned {
+            refs.push(format!("function {}", func.name));
+        }
+        for l in loops {
+            refs.push(format!("function {}, loop {} ({})", func.name, loop_info.describe(l), names[l]));
+        }
+    }
+    refs
+}
+
+/// True if the function has code on a source line, or the line is within the span of its code.
+fn covers_line(func: &Function, line: u32) -> bool {
+    let lines: Vec<u32> = func
+        .basic_blocks
+        .iter()
+        .flat_map(|bb| bb.instrs.iter().filter_map(|i| i.get_debug_loc().as_ref()).chain(bb.term.get_debug_loc().as_ref()))
+        .map(|loc| loc.line)
+        .filter(|&l| l != 0)
+        .chain(func.debugloc.as_ref().map(|loc| loc.line))
+        .collect();
+    lines.iter().min().is_some_and(|&min| line >= min) && lines.iter().max().is_some_and(|&max| line <= max)
+}
+
+fn block_has_line(bb: &llvm_ir::BasicBlock, line: u32) -> bool {
+    bb.instrs.iter().filter_map(|i| i.get_debug_loc().as_ref()).any(|loc| loc.line == line)
+}
+
+/// `--hls-log <vitis_hls.log> [--all] [--source-dir <dir>]...`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --hls-log <vitis_hls.log> [--all] [--source-dir <dir>]...";
+    let path = args.first().filter(|a| !a.starts_with("--")).ok_or(usage)?;
+    let mut show_info = false;
+    let mut search_dirs = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        match flag.as_str() {
+            "--all" => show_info = true,
+            "--source-dir" => {
+                search_dirs.push(PathBuf::from(rest.next().ok_or_else(|| format!("Missing value for {}\n{}", flag, usage))?))
+            }
+            _ => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+        }
+    }
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let diagnostics = parse_log(&text);
+    // Locations in the log are relative to the project directory, where the log is written
+    search_dirs.extend(Path::new(path).parent().map(|dir| dir.to_path_buf()));
+    let mut sources = Sources::new(search_dirs);
+    let functions = function_loops(module, &mut sources);
+
+    let count = |s: Severity| diagnostics.iter().filter(|d| d.severity == s).count();
+    println!(
+        "{}: {} error(s), {} critical warning(s), {} warning(s), {} info message(s)",
+        path,
+        count(Severity::Error),
+        count(Severity::CriticalWarning),
+        count(Severity::Warning),
+        count(Severity::Info)
+    );
+
+    // Grouped by message ID, most severe first, then in log order
+    let mut groups: BTreeMap<(Severity, usize, &str), Vec<&Diagnostic>> = BTreeMap::new();
+    let mut first_seen: BTreeMap<&str, usize> = BTreeMap::new();
+    for d in diagnostics.iter().filter(|d| show_info || d.severity != Severity::Info) {
+        let first = *first_seen.entry(&d.id).or_insert(d.log_line);
+        groups.entry((d.severity, first, &d.id)).or_default().push(d);
+    }
+    for ((severity, _, id), group) in &groups {
+        let times = if group.len() > 1 { format!(" ({}x)", group.len()) } else { String::new() };
+        println!("\n{} [{}]{}", severity.label(), id, times);
+        if let Some(url) = group.iter().find_map(|d| d.resolution.as_ref()) {
+            println!("  Help: {}", url);
+        }
+        for d in group {
+            println!("  log line {}: {}", d.log_line, d.text);
+            if let Some(loc) = &d.location {
+                let source_loc = DebugLoc { line: loc.line, col: loc.col, filename: loc.file.clone(), directory: None };
+                let column = loc.col.map_or(String::new(), |c| format!(":{}", c));
+                match sources.file(&source_loc).and_then(|lines| lines.get((loc.line as usize).checked_sub(1)?)) {
+                    Some(code) => println!("    {}:{}{} | {}", loc.file, loc.line, column, code.trim()),
+                    None => println!("    {}:{}{}", loc.file, loc.line, column),
+                }
+            }
+            for r in cross_reference(d, &functions) {
+                println!("    IR: {}", r);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATRIX_LOG: &str = include_str!("../../matrix_multiplication_project/vitis_hls.log");
+    const BOX_BLUR_LOG: &str = include_str!("../../box_blur_app/vitis_hls.log");
+
+    // One loop on line 40 of box_blur.cpp whose body stores on line 81
+    const BOX_BLUR_IR: &str = r#"
+define void @box_blur_filter(ptr %out) !dbg !5 {
+entry:
+  br label %for.cond, !dbg !6
+for.cond:
+  %i = phi i32 [ 0, %entry ], [ %inc, %for.body ]
+  %cmp = icmp slt i32 %i, 4, !dbg !7
+  br i1 %cmp, label %for.body, label %for.end, !dbg !7
+for.body:
+  %p = getelementptr i32, ptr %out, i32 %i, !dbg !8
+  store i32 %i, ptr %p, !dbg !8
+  %inc = add i32 %i, 1, !dbg !7
+  br label %for.cond, !dbg !7
+for.end:
+  ret void, !dbg !9
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2, !3}
+!0 = distinct !DICompileUnit(language: DW_LANG_C_plus_plus_14, file: !1, producer: "clang", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug)
+!1 = !DIFile(filename: "src/box_blur.cpp", directory: "/nonexistent")
+!2 = !{i32 7, !"Dwarf Version", i32 5}
+!3 = !{i32 2, !"Debug Info Version", i32 3}
+!4 = !DISubroutineType(types: !{null})
+!5 = distinct !DISubprogram(name: "box_blur_filter", scope: !1, file: !1, line: 20, type: !4, scopeLine: 20, spFlags: DISPFlagDefinition, unit: !0)
+!6 = !DILocation(line: 20, column: 1, scope: !5)
+!7 = !DILocation(line: 40, column: 5, scope: !5)
+!8 = !DILocation(line: 81, column: 7, scope: !5)
+!9 = !DILocation(line: 90, column: 1, scope: !5)
+"#;
+
+    #[test]
+    fn parses_messages_with_locations_and_help() {
+        let diagnostics = parse_log(MATRIX_LOG);
+        assert_eq!(diagnostics.len(), 286);
+        let dataflow = diagnostics.iter().find(|d| d.id == "HLS 214-114").unwrap();
+        assert_eq!((dataflow.severity, dataflow.log_line), (Severity::Warning, 35));
+        let loc = dataflow.location.as_ref().unwrap();
+        assert_eq!((loc.file.as_str(), loc.line, loc.col), ("matrix_mult.cpp", 18, Some(2)));
+        assert_eq!(
+            dataflow.resolution.as_deref(),
+            Some("https://www.xilinx.com/cgi-bin/docs/rdoc?v=2021.1;t=hls+guidance;d=214-114.html")
+        );
+
+        let flatten = diagnostics.iter().find(|d| d.id == "HLS 200-960").unwrap();
+        assert_eq!(flatten.loop_name.as_deref(), Some("row_loop"));
+        assert_eq!(flatten.function.as_deref(), Some("Loop_row_loop_proc"));
+        assert!(flatten.location.is_none());
+    }
+
+    #[test]
+    fn maps_messages_to_ir_loops() {
+        let module = Module::from_ir_str(BOX_BLUR_IR).unwrap();
+        let functions = function_loops(&module, &mut Sources::new(Vec::new()));
+        let diagnostics = parse_log(BOX_BLUR_LOG);
+
+        // Found both from the store's line and from the loop name
+        let violation = diagnostics.iter().find(|d| d.id == "HLS 200-885").unwrap();
+        assert_eq!(violation.loop_name.as_deref(), Some("VITIS_LOOP_40_5"));
+        assert_eq!(cross_reference(violation, &functions), ["function box_blur_filter, loop for.cond (line 40) (VITIS_LOOP_40_1)"]);
+
+        // Line 14 is above the function's code, so only a message naming the function refers to it
+        let pragma = diagnostics.iter().find(|d| d.id == "HLS 207-5528").unwrap();
+        assert_eq!(pragma.location.as_ref().map(|l| l.line), Some(14));
+        assert!(cross_reference(pragma, &functions).is_empty());
+        let top = Diagnostic { location: None, function: Some("box_blur_filter".to_string()), ..parse_log(BOX_BLUR_LOG).remove(0) };
+        assert_eq!(cross_reference(&top, &functions), ["function box_blur_filter"]);
+    }
+}
@@ -0,0 +1,540 @@
+use crate::ir_util::{block_label, callee_name, operands};
+use crate::loops::{Counter, LoopInfo};
+use crate::memory::{access, MemBase, MemoryInfo, Subscript};
+use crate::pragmas::{collect, LoopMetadata, Pragma};
+use crate::ranges::{analyze, ValueRanges};
+use crate::resources::type_bits;
+use crate::source::{source_name, Sources};
+use llvm_ir::{Function, Instruction, Module, Name, Operand, Type};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// How far to follow an index expression back through its definitions.
+const MAX_DEPTH: usize = 16;
+
+/// How the accesses to an argument walk through it, from the easiest to stream to the hardest.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Pattern {
+    // Outside of any loop
+    Single,
+    // Consecutive elements from one iteration to the next
+    Sequential,
+    // A fixed distance apart with gaps in between
+    Strided,
+    // The same element in every iteration of the innermost loop
+    Repeated,
+    // Data-dependent or not a linear function of the loop counter
+    Random,
+}
+
+impl Pattern {
+    fn describe(self) -> &'static str {
+        match self {
+            Pattern::Single => "single",
+            Pattern::Sequential => "sequential",
+            Pattern::Strided => "strided",
+            Pattern::Repeated => "repeated",
+            Pattern::Random => "random",
+        }
+    }
+}
+
+/// A top-level argument and the interface it should get.
+struct Port {
+    name: String,
+    dims: Vec<Option<u64>>,
+    reads: usize,
+    writes: usize,
+    element_bits: u64,
+    pattern: Pattern,
+    // Elements between the lowest and highest index accessed, if the indices are bounded
+    depth: Option<u64>,
+}
+
+impl Port {
+    fn direction(&self) -> &'static str {
+        match (self.reads > 0, self.writes > 0) {
+            (true, false) => "read-only",
+            (false, true) => "write-only",
+            (true, true) => "read-write",
+            (false, false) => "unused",
+        }
+    }
+
+    /// `s_axilite` for a value passed through a pointer, `axis` for data that is only read or only
+    /// written in order, `m_axi` for anything that needs addresses.
+    fn mode(&self) -> &'static str {
+        if self.pattern == Pattern::Single && self.depth.is_some_and(|d| d <= 1) {
+            "s_axilite"
+        } else if self.pattern <= Pattern::Sequential && (self.reads == 0) != (self.writes == 0) {
+            "axis"
+        } else {
+            "m_axi"
+        }
+    }
+}
+
+/// Follows array subscripts back to the counters of the loops around an access.
+struct Indexing<'a, 'f> {
+    loop_info: &'a LoopInfo<'f>,
+    mem: &'a MemoryInfo<'f>,
+    defs: HashMap<&'f Name, (usize, &'f Instruction)>,
+    counters: Vec<Option<Counter<'f>>>,
+    ranges: ValueRanges<'f>,
+}
+
+impl<'f> Indexing<'_, 'f> {
+    // Values a loop counter has inside the loops around `block`
+    fn counter_bounds(&self, var: &Name, block: usize) -> Option<(i128, i128)> {
+        (0..self.loop_info.loops.len())
+            .filter(|&l| self.loop_info.loops[l].blocks.contains(&block))
+            .filter_map(|l| self.counters[l].as_ref().filter(|c| c.var == var))
+            .find_map(|c| c.bounds())
+            .map(|(lo, hi)| (lo as i128, hi as i128))
+    }
+
+    /// Range of an index used in `block`. Loop counters only take the values they have in the
+    /// body, not the one that ends the loop; anything else gets its --ranges range.
+    fn range(&self, op: &Operand, block: usize, depth: usize) -> Option<(i128, i128)> {
+        if let Some(c) = self.mem.const_value(op) {
+            return Some((c as i128, c as i128));
+        }
+        let Operand::LocalOperand { name, .. } = op else { return None };
+        if let Some(bounds) = self.counter_bounds(name, block) {
+            return Some(bounds);
+        }
+        let fallback = self.ranges.values.get(name).map(|r| (r.lo, r.hi));
+        let Some((_, instr)) = self.defs.get(name) else { return fallback };
+        if depth > MAX_DEPTH {
+            return fallback;
+        }
+        let both = |a: &Operand, b: &Operand, f: fn(i128, i128) -> i128| {
+            let ((a0, a1), (b0, b1)) = (self.range(a, block, depth + 1)?, self.range(b, block, depth + 1)?);
+            let corners = [f(a0, b0), f(a0, b1), f(a1, b0), f(a1, b1)];
+            Some((*corners.iter().min()?, *corners.iter().max()?))
+        };
+        let derived = match instr {
+            Instruction::SExt(i) => self.range(&i.operand, block, depth + 1),
+            Instruction::ZExt(i) => self.range(&i.operand, block, depth + 1),
+            Instruction::Add(i) => both(&i.operand0, &i.operand1, |a, b| a.saturating_add(b)),
+            Instruction::Sub(i) => both(&i.operand0, &i.operand1, |a, b| a.saturating_sub(b)),
+            Instruction::Mul(i) => both(&i.operand0, &i.operand1, |a, b| a.saturating_mul(b)),
+            Instruction::Load(load) => match &load.address {
+                Operand::LocalOperand { name: slot, .. } => {
+                    self.counter_bounds(slot, block).or_else(|| self.ranges.variables.get(slot).map(|r| (r.lo, r.hi)))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        derived.or(fallback)
+    }
+
+    /// An index as `coef * counter + offset` for the counter of loop `l`. Terms that don't change
+    /// inside the loop are left out of the offset, so accesses that share them can be compared.
+    fn linear(&self, op: &Operand, l: usize, depth: usize) -> Option<(i64, i64)> {
+        if let Some(c) = self.mem.const_value(op) {
+            return Some((0, c));
+        }
+        let Operand::LocalOperand { name, .. } = op else { return None };
+        let counter = self.counters[l].as_ref()?;
+        if name == counter.var {
+            return Some((1, 0));
+        }
+        let lp = &self.loop_info.loops[l];
+        let Some(&(b, instr)) = self.defs.get(name) else { return Some((0, 0)) };
+        if !lp.blocks.contains(&b) {
+            return Some((0, 0));
+        }
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let sub = |op: &Operand| self.linear(op, l, depth + 1);
+        match instr {
+            Instruction::SExt(i) => sub(&i.operand),
+            Instruction::ZExt(i) => sub(&i.operand),
+            Instruction::Trunc(i) => sub(&i.operand),
+            Instruction::Add(i) => {
+                let (a, b) = (sub(&i.operand0)?, sub(&i.operand1)?);
+                Some((a.0 + b.0, a.1 + b.1))
+            }
+            Instruction::Sub(i) => {
+                let (a, b) = (sub(&i.operand0)?, sub(&i.operand1)?);
+                Some((a.0 - b.0, a.1 - b.1))
+            }
+            Instruction::Mul(i) => {
+                let (a, b) = (sub(&i.operand0)?, sub(&i.operand1)?);
+                match (self.mem.const_value(&i.operand0), self.mem.const_value(&i.operand1)) {
+                    (Some(k), _) => Some((k * b.0, k * b.1)),
+                    (_, Some(k)) => Some((k * a.0, k * a.1)),
+                    _ if a.0 == 0 && b.0 == 0 => Some((0, 0)),
+                    _ => None,
+                }
+            }
+            Instruction::Shl(i) => {
+                let (a, k) = (sub(&i.operand0)?, self.mem.const_value(&i.operand1)?);
+                Some((a.0 << k, a.1 << k))
+            }
+            // A -O0 local is invariant if the loop never stores to it
+            Instruction::Load(load) => match &load.address {
+                Operand::LocalOperand { name: slot, .. } if slot == counter.var => Some((1, 0)),
+                Operand::LocalOperand { name: slot, .. } if self.mem.is_scalar_alloca(slot) => {
+                    let stored = lp.blocks.iter().flat_map(|&b| &self.loop_info.func.basic_blocks[b].instrs).any(|i| {
+                        matches!(i, Instruction::Store(s) if matches!(&s.address, Operand::LocalOperand { name, .. } if name == slot))
+                    });
+                    (!stored).then_some((0, 0))
+                }
+                _ => None,
+            },
+            _ => operands(instr).into_iter().all(|op| sub(op).is_some_and(|(coef, _)| coef == 0)).then_some((0, 0)),
+        }
+    }
+}
+
+/// Distance in elements between neighbours of each dimension, None if a size is unknown.
+fn strides(dims: &[Option<u64>]) -> Option<Vec<i64>> {
+    let mut result = vec![1i64; dims.len()];
+    for d in (0..dims.len().saturating_sub(1)).rev() {
+        result[d] = result[d + 1] * dims[d + 1]? as i64;
+    }
+    Some(result)
+}
+
+/// A load or store to a pointer argument.
+pub struct PortAccess<'f> {
+    pub instr: &'f Instruction,
+    pub block: usize,
+    pub param: &'f Name,
+    pub is_write: bool,
+    pub bits: u64,
+    // Innermost loop around the access
+    pub inner: Option<usize>,
+    // False when the address isn't an element of the argument's array with known strides
+    pub subscripted: bool,
+    // Elements the index moves per iteration of that loop and its constant offset, if it is linear
+    pub index: Option<(i64, i64)>,
+    // Highest element index reached, None if unbounded
+    pub highest: Option<i128>,
+}
+
+/// Every load and store to a pointer argument of a function, with how its index moves.
+pub fn port_accesses<'f>(module: &Module, func: &'f Function, loop_info: &LoopInfo<'f>) -> Vec<PortAccess<'f>> {
+    let mem = MemoryInfo::new(func);
+    let mut defs = HashMap::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        for instr in &bb.instrs {
+            if let Some(dest) = instr.try_get_result() {
+                defs.insert(dest, (b, instr));
+            }
+        }
+    }
+    let indexing = Indexing {
+        loop_info,
+        mem: &mem,
+        defs,
+        counters: (0..loop_info.loops.len()).map(|l| loop_info.counter(l)).collect(),
+        ranges: analyze(module, func),
+    };
+
+    let mut result = Vec::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        for instr in &bb.instrs {
+            let Some(acc) = access(instr) else { continue };
+            let Some(MemBase::Param(param)) = mem.base_of(acc.address) else { continue };
+            let bits = match instr {
+                Instruction::Store(store) => type_bits(&module.type_of(&store.value)),
+                _ => type_bits(&module.type_of(instr)),
+            };
+            let inner = loop_info.innermost(b);
+            let mut port_access = PortAccess {
+                instr,
+                block: b,
+                param,
+                is_write: acc.is_write,
+                bits,
+                inner,
+                subscripted: false,
+                index: None,
+                highest: None,
+            };
+            let subscripts = mem.array_ref(acc.address).map(|r| r.subscripts);
+            let (Some(subscripts), Some(strides)) = (subscripts, strides(&mem.dims(MemBase::Param(param)))) else {
+                result.push(port_access);
+                continue;
+            };
+            let terms: Vec<(&Subscript, i64)> = subscripts.iter().zip(strides).collect();
+            port_access.subscripted = true;
+
+            port_access.highest = terms.iter().try_fold(0i128, |total, (s, stride)| {
+                let (lo, hi) = match s {
+                    Subscript::Const(c) => (*c as i128, *c as i128),
+                    Subscript::Var(op) => indexing.range(op, b, 0)?,
+                    Subscript::Unknown => return None,
+                };
+                let stride = *stride as i128;
+                Some(total + (lo * stride).max(hi * stride))
+            });
+            if let Some(l) = inner {
+                // Without a counter only constant subscripts are linear, and those don't move
+                let step = indexing.counters[l].as_ref().map_or(0, |c| c.step);
+                port_access.index = terms
+                    .iter()
+                    .try_fold((0i64, 0i64), |(coef, offset), (s, stride)| {
+                        let (c, o) = match s {
+                            Subscript::Const(c) => (0, *c),
+                            Subscript::Var(op) => indexing.linear(op, l, 0)?,
+                            Subscript::Unknown => return None,
+                        };
+                        Some((coef + c * stride, offset + o * stride))
+                    })
+                    .map(|(coef, offset)| (coef * step, offset));
+            }
+            result.push(port_access);
+        }
+    }
+    result
+}
+
+/// True if accesses `step` elements apart per iteration leave no gaps between them together,
+/// like the r, g, b writes to pixel[col * 3 + c].
+pub fn fills_gaps(step: i64, offsets: &[i64]) -> bool {
+    let n = step.unsigned_abs() as i64;
+    let mut covered: Vec<i64> = offsets.iter().map(|o| o.rem_euclid(n)).collect();
+    covered.sort();
+    covered.dedup();
+    covered.len() as i64 == n
+}
+
+/// Classifies the accesses of every pointer argument of a function.
+fn ports(module: &Module, func: &Function) -> Vec<Port> {
+    let loop_info = LoopInfo::new(func);
+    let mem = MemoryInfo::new(func);
+    let accesses = port_accesses(module, func, &loop_info);
+    let mut result = Vec::new();
+    for param in func.parameters.iter().filter(|p| matches!(p.ty.as_ref(), Type::PointerType { .. })) {
+        let mut port = Port {
+            name: block_label(&param.name),
+            dims: mem.dims(MemBase::Param(&param.name)),
+            reads: 0,
+            writes: 0,
+            element_bits: 0,
+            pattern: Pattern::Single,
+            depth: Some(0),
+        };
+        // Offsets of the accesses that move through the argument, by loop, direction and step
+        let mut moving: BTreeMap<(usize, bool, i64), Vec<i64>> = BTreeMap::new();
+        for acc in accesses.iter().filter(|a| a.param == &param.name) {
+            if acc.is_write {
+                port.writes += 1;
+            } else {
+                port.reads += 1;
+            }
+            port.element_bits = port.element_bits.max(acc.bits);
+            port.depth = match (port.depth, acc.highest) {
+                (Some(d), Some(h)) if h >= 0 => Some(d.max(h as u64 + 1)),
+                _ => None,
+            };
+            let pattern = match (acc.inner, acc.index) {
+                _ if !acc.subscripted => Pattern::Random,
+                (None, _) => Pattern::Single,
+                (Some(_), None) => Pattern::Random,
+                (Some(_), Some((0, _))) => Pattern::Repeated,
+                (Some(l), Some((step, offset))) => {
+                    moving.entry((l, acc.is_write, step)).or_default().push(offset);
+                    Pattern::Sequential
+                }
+            };
+            port.pattern = port.pattern.max(pattern);
+        }
+        if moving.iter().any(|((_, _, step), offsets)| !fills_gaps(*step, offsets)) {
+            port.pattern = port.pattern.max(Pattern::Strided);
+        }
+        if port.reads + port.writes == 0 {
+            port.depth = None;
+        }
+        result.push(port);
+    }
+    result
+}
+
+/// The mode of an INTERFACE pragma, given as `mode=m_axi` or as a bare `m_axi`.
+pub fn pragma_mode(pragma: &Pragma) -> Option<&str> {
+    pragma.option("mode").or_else(|| pragma.options.iter().find(|(_, v)| v.is_empty()).map(|(k, _)| k.as_str()))
+}
+
+/// `--interfaces [function] [--source-dir <dir>]...`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --interfaces [function] [--source-dir <dir>]...";
+    let mut func_name = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--source-dir" => {
+                search_dirs.push(PathBuf::from(rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?))
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+            name => func_name = Some(name),
+        }
+    }
+    let mut sources = Sources::new(search_dirs);
+    let metadata = LoopMetadata::load(module);
+
+    // Without a name, the functions no other function calls are the top-level ones
+    let functions: Vec<&Function> = match func_name {
+        Some(name) => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        None => {
+            let called: Vec<&str> =
+                module.functions.iter().flat_map(|f| &f.basic_blocks).flat_map(|bb| &bb.instrs).filter_map(callee_name).collect();
+            module.functions.iter().filter(|f| !called.contains(&f.name.as_str())).collect()
+        }
+    };
+
+    for func in functions {
+        let loop_info = LoopInfo::new(func);
+        let pragmas = collect(func, &loop_info, &metadata, &mut sources);
+        let ports = ports(module, func);
+        println!("Top function: {}", func.name);
+        println!(
+            "  {:<18} {:<14} {:<11} {:<11} {:>10} {:>6}  Interface",
+            "Argument", "Dims", "Access", "Pattern", "Depth", "Width"
+        );
+        for param in func.parameters.iter().filter(|p| !matches!(p.ty.as_ref(), Type::PointerType { .. })) {
+            let width = type_bits(&param.ty);
+            println!("  {:<18} {:<14} {:<11} {:<11} {:>10} {:>6}  s_axilite", block_label(&param.name), "-", "scalar", "-", "-", width);
+        }
+        for port in &ports {
+            let dims: String = port.dims.iter().map(|d| d.map_or("[?]".to_string(), |n| format!("[{}]", n))).collect();
+            let depth = port.depth.map_or("?".to_string(), |d| d.to_string());
+            println!(
+                "  {:<18} {:<14} {:<11} {:<11} {:>10} {:>6}  {}",
+                port.name,
+                dims,
+                port.direction(),
+                port.pattern.describe(),
+                depth,
+                port.element_bits,
+                port.mode()
+            );
+        }
+
+        println!("\n  Suggested pragmas:");
+        for param in func.parameters.iter().filter(|p| !matches!(p.ty.as_ref(), Type::PointerType { .. })) {
+            println!("    #pragma HLS INTERFACE mode=s_axilite port={} bundle=control", block_label(&param.name));
+        }
+        // One m_axi bundle per array, so they don't share an AXI port
+        let mut bundles = 0;
+        for port in ports.iter().filter(|p| p.reads + p.writes > 0) {
+            let depth = port.depth.map_or("<elements>".to_string(), |d| d.to_string());
+            let m_axi = format!("#pragma HLS INTERFACE mode=m_axi port={} depth={} offset=slave bundle=gmem{}", port.name, depth, bundles);
+            match port.mode() {
+                "m_axi" => println!("    {}", m_axi),
+                "axis" => {
+                    println!("    #pragma HLS INTERFACE mode=axis port={}", port.name);
+                    println!("    // or, to keep it in memory: {}", m_axi);
+                }
+                mode => {
+                    println!("    #pragma HLS INTERFACE mode={} port={} bundle=control", mode, port.name);
+                    continue;
+                }
+            }
+            bundles += 1;
+        }
+        println!("    #pragma HLS INTERFACE mode=s_axilite port=return bundle=control");
+
+        // What the source asks for, where it differs
+        for port in &ports {
+            let Some(pragma) = pragmas.arrays.get(&port.name).and_then(|ps| ps.iter().find(|p| p.name == "INTERFACE")) else { continue };
+            let mode = pragma_mode(pragma).unwrap_or("");
+            if !mode.eq_ignore_ascii_case(port.mode()) {
+                let note = match (mode.to_ascii_lowercase().as_str(), port.depth) {
+                    ("s_axilite", Some(d)) if d > 1 => format!(
+                        "; the whole array ({} bytes) is mapped into the AXI-Lite register space",
+                        d * port.element_bits.div_ceil(8)
+                    ),
+                    _ => String::new(),
+                };
+                println!("  Note: the source has {} for {}, suggested {}{}", pragma, port.name, port.mode(), note);
+            }
+            if let (Some(given), Some(depth)) = (pragma.option("depth").and_then(|d| d.parse::<u64>().ok()), port.depth)
+                && given != depth
+            {
+                println!("  Note: depth={} in the source for {}, but {} elements are accessed", given, port.name, depth);
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A copy loop writing r, g, b triples, and a polling loop without a counter that rereads
+    // one coefficient
+    const PORTS: &str = "
+define void @top(ptr %in, ptr %out, ptr %coef, ptr %flag) {
+entry:
+  br label %copy.cond
+copy.cond:
+  %i = phi i32 [ 0, %entry ], [ %i.next, %copy.body ]
+  %cmp = icmp slt i32 %i, 8
+  br i1 %cmp, label %copy.body, label %wait.cond
+copy.body:
+  %idx = sext i32 %i to i64
+  %src = getelementptr inbounds i32, ptr %in, i64 %idx
+  %v = load i32, ptr %src
+  %r = mul nsw i64 %idx, 3
+  %r.p = getelementptr inbounds i32, ptr %out, i64 %r
+  store i32 %v, ptr %r.p
+  %g = add nsw i64 %r, 1
+  %g.p = getelementptr inbounds i32, ptr %out, i64 %g
+  store i32 %v, ptr %g.p
+  %b = add nsw i64 %r, 2
+  %b.p = getelementptr inbounds i32, ptr %out, i64 %b
+  store i32 %v, ptr %b.p
+  %i.next = add nsw i32 %i, 1
+  br label %copy.cond
+wait.cond:
+  %f = load volatile i32, ptr %flag
+  %busy = icmp ne i32 %f, 0
+  br i1 %busy, label %wait.body, label %exit
+wait.body:
+  %k.p = getelementptr inbounds i32, ptr %coef, i64 2
+  %k = load i32, ptr %k.p
+  br label %wait.cond
+exit:
+  ret void
+}
+";
+
+    #[test]
+    fn classifies_port_patterns() {
+        let module = Module::from_ir_str(PORTS).unwrap();
+        let ports = ports(&module, &module.functions[0]);
+        let summary: Vec<(&str, &str, Option<u64>, &str)> =
+            ports.iter().map(|p| (p.name.as_str(), p.pattern.describe(), p.depth, p.mode())).collect();
+        assert_eq!(
+            summary,
+            [
+                ("in", "sequential", Some(8), "axis"),
+                ("out", "sequential", Some(24), "axis"),
+                // Constant indices repeat even in a loop whose counter isn't known
+                ("coef", "repeated", Some(3), "m_axi"),
+                ("flag", "repeated", Some(1), "m_axi"),
+            ]
+        );
+    }
+
+    #[test]
+    fn fills_gaps_between_interleaved_accesses() {
+        assert!(fills_gaps(3, &[0, 1, 2]));
+        assert!(fills_gaps(-3, &[5, 3, 4]));
+        assert!(!fills_gaps(3, &[0, 2]));
+        assert!(fills_gaps(1, &[0]));
+    }
+}
@@ -0,0 +1,1030 @@
+use crate::ir_util::is_annotation_call;
+use crate::layout::{field_offsets, int_store_bytes, size_of, struct_elements};
+use crate::printer::GepTypes;
+use llvm_ir::constant::Float;
+use llvm_ir::instruction::Call;
+use llvm_ir::types::FPType;
+use llvm_ir::{
+    BasicBlock, Constant, FPPredicate, Function, Instruction, IntPredicate, Module, Name, Operand, Terminator, Type,
+    TypeRef,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+// Pointers are encoded as (allocation id << 32) + offset, so they survive ptrtoint/inttoptr round trips
+const ADDR_SHIFT: u32 = 32;
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// A runtime value held in a virtual register.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int { bits: u32, value: u64 },
+    Float(f64),
+    Ptr(u64),
+    Aggregate(Vec<Value>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Result<(u32, u64), String> {
+        match self {
+            Value::Int { bits, value } => Ok((*bits, *value)),
+            Value::Ptr(addr) => Ok((64, *addr)),
+            other => Err(format!("expected an integer, got {}", other)),
+        }
+    }
+
+    pub fn as_signed(&self) -> Result<i64, String> {
+        let (bits, value) = self.as_int()?;
+        Ok(sext(value, bits))
+    }
+
+    pub fn as_ptr(&self) -> Result<u64, String> {
+        match self {
+            Value::Ptr(addr) => Ok(*addr),
+            Value::Int { value, .. } => Ok(*value),
+            other => Err(format!("expected a pointer, got {}", other)),
+        }
+    }
+
+    pub fn as_float(&self) -> Result<f64, String> {
+        match self {
+            Value::Float(f) => Ok(*f),
+            other => Err(format!("expected a floating-point value, got {}", other)),
+        }
+    }
+
+    fn as_bool(&self) -> Result<bool, String> {
+        Ok(self.as_int()?.1 & 1 == 1)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int { bits, value } => write!(f, "i{} {}", bits, sext(*value, *bits)),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Ptr(addr) => write!(f, "ptr {:#x}", addr),
+            Value::Aggregate(values) => {
+                let parts: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "{{ {} }}", parts.join(", "))
+            }
+        }
+    }
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 }
+}
+
+fn sext(value: u64, bits: u32) -> i64 {
+    if bits >= 64 || bits == 0 {
+        value as i64
+    } else {
+        let shift = 64 - bits;
+        ((value << shift) as i64) >> shift
+    }
+}
+
+pub fn int_value(bits: u32, value: u64) -> Value {
+    Value::Int { bits, value: value & mask(bits) }
+}
+
+/// Execution counts collected for one function while interpreting.
+#[derive(Default)]
+pub struct FunctionProfile<'m> {
+    pub calls: u64,
+    pub block_counts: HashMap<&'m Name, u64>,
+    pub edge_counts: HashMap<(&'m Name, &'m Name), u64>,
+}
+
+// Byte-addressed memory made of separate allocations
+#[derive(Default)]
+struct Memory {
+    allocs: Vec<Vec<u8>>,
+}
+
+impl Memory {
+    fn alloc(&mut self, size: u64) -> u64 {
+        self.allocs.push(vec![0; size as usize]);
+        (self.allocs.len() as u64) << ADDR_SHIFT
+    }
+
+    fn free(&mut self, addr: u64) {
+        if let Some(alloc) = self.allocs.get_mut((addr >> ADDR_SHIFT) as usize - 1) {
+            *alloc = Vec::new();
+        }
+    }
+
+    fn locate(&self, addr: u64, len: u64) -> Result<(usize, usize), String> {
+        let id = (addr >> ADDR_SHIFT) as usize;
+        let offset = (addr & mask(ADDR_SHIFT)) as usize;
+        match self.allocs.get(id.wrapping_sub(1)) {
+            Some(alloc) if offset + len as usize <= alloc.len() => Ok((id - 1, offset)),
+            _ => Err(format!("invalid memory access of {} bytes at address {:#x}", len, addr)),
+        }
+    }
+
+    fn read(&self, addr: u64, len: u64) -> Result<&[u8], String> {
+        let (id, offset) = self.locate(addr, len)?;
+        Ok(&self.allocs[id][offset..offset + len as usize])
+    }
+
+    fn write(&mut self, addr: u64, bytes: &[u8]) -> Result<(), String> {
+        let (id, offset) = self.locate(addr, bytes.len() as u64)?;
+        self.allocs[id][offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// A load or store recorded while tracing.
+pub struct MemEvent {
+    // Index of the call made by the traced function that the access happens under, None for its own accesses
+    pub process: Option<usize>,
+    pub addr: u64,
+    pub bytes: u64,
+    pub is_write: bool,
+    // Instructions executed before the access
+    pub step: u64,
+}
+
+/// Memory accesses of one traced function, attributed to the calls it makes.
+#[derive(Default)]
+pub struct Trace<'m> {
+    pub events: Vec<MemEvent>,
+    // Callee of each call made by the traced function and the step it started at
+    pub calls: Vec<(&'m str, u64)>,
+    // Allocation of each alloca of the traced function
+    pub allocas: HashMap<&'m Name, u64>,
+}
+
+/// Allocation a pointer points into, and its byte offset in it.
+pub fn allocation_of(addr: u64) -> (u64, u64) {
+    (addr >> ADDR_SHIFT, addr & mask(ADDR_SHIFT))
+}
+
+#[derive(Default)]
+struct Frame<'m> {
+    regs: HashMap<&'m Name, Value>,
+    allocs: Vec<u64>,
+}
+
+/// A simple interpreter for LLVM IR functions that records block and edge execution counts.
+pub struct Interpreter<'m> {
+    module: &'m Module,
+    memory: Memory,
+    globals: HashMap<&'m Name, u64>,
+    functions: HashMap<&'m str, &'m Function>,
+    fn_addrs: HashMap<u64, &'m str>,
+    // The other way round, for calls and references by name
+    fn_by_name: HashMap<&'m str, u64>,
+    blocks: HashMap<&'m str, HashMap<&'m Name, &'m BasicBlock>>,
+    gep_types: GepTypes<'m>,
+    pub profiles: HashMap<&'m str, FunctionProfile<'m>>,
+    // Set to Some to record the memory accesses of the function called with call_function
+    pub trace: Option<Trace<'m>>,
+    pub steps: u64,
+    pub max_steps: u64,
+    depth: usize,
+}
+
+impl<'m> Interpreter<'m> {
+    pub fn new(module: &'m Module) -> Result<Self, String> {
+        let mut interp = Interpreter {
+            module,
+            memory: Memory::default(),
+            globals: HashMap::new(),
+            functions: module.functions.iter().map(|f| (f.name.as_str(), f)).collect(),
+            fn_addrs: HashMap::new(),
+            fn_by_name: HashMap::new(),
+            blocks: HashMap::new(),
+            gep_types: GepTypes::new(module),
+            profiles: HashMap::new(),
+            trace: None,
+            steps: 0,
+            max_steps: 1_000_000_000,
+            depth: 0,
+        };
+        // Functions get a one-byte allocation so they can be used as function pointers
+        let names = module.functions.iter().map(|f| f.name.as_str()).chain(module.func_declarations.iter().map(|d| d.name.as_str()));
+        for name in names {
+            let addr = interp.memory.alloc(1);
+            interp.fn_addrs.insert(addr, name);
+            interp.fn_by_name.insert(name, addr);
+        }
+        // Allocate every global first so initializers can refer to each other
+        for global in &module.global_vars {
+            let size = match &global.initializer {
+                Some(init) => size_of(&module.types, &module.type_of(init)),
+                None => 0,
+            };
+            let addr = interp.memory.alloc(size);
+            interp.globals.insert(&global.name, addr);
+        }
+        for global in &module.global_vars {
+            if let Some(init) = &global.initializer {
+                let value = interp.const_value(init)?;
+                let addr = interp.globals[&global.name];
+                interp.write_value(addr, &module.type_of(init), &value)?;
+            }
+        }
+        Ok(interp)
+    }
+
+    /// Allocates a buffer filled with the given bytes and returns a pointer to it.
+    pub fn alloc_buffer(&mut self, bytes: &[u8]) -> Result<Value, String> {
+        let addr = self.memory.alloc(bytes.len() as u64);
+        self.memory.write(addr, bytes)?;
+        Ok(Value::Ptr(addr))
+    }
+
+    /// Runs a defined function with the given arguments and returns its result.
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Option<Value>, String> {
+        let func = *self.functions.get(name).ok_or_else(|| format!("Function '{}' is not defined in the module", name))?;
+        if func.parameters.len() != args.len() {
+            return Err(format!("Function '{}' expects {} arguments, got {}", name, func.parameters.len(), args.len()));
+        }
+        self.run_function(func, args)
+    }
+
+    fn block(&mut self, func: &'m Function, name: &Name) -> Result<&'m BasicBlock, String> {
+        let blocks = self
+            .blocks
+            .entry(func.name.as_str())
+            .or_insert_with(|| func.basic_blocks.iter().map(|bb| (&bb.name, bb)).collect());
+        blocks.get(name).copied().ok_or_else(|| format!("Block {} not found in '{}'", name, func.name))
+    }
+
+    fn run_function(&mut self, func: &'m Function, args: Vec<Value>) -> Result<Option<Value>, String> {
+        self.depth += 1;
+        if self.depth > MAX_CALL_DEPTH {
+            return Err(format!("Call depth limit exceeded in '{}'", func.name));
+        }
+        if self.depth == 2
+            && let Some(trace) = &mut self.trace
+        {
+            trace.calls.push((func.name.as_str(), self.steps));
+        }
+        let mut frame = Frame::default();
+        for (param, arg) in func.parameters.iter().zip(args) {
+            frame.regs.insert(&param.name, arg);
+        }
+        self.profiles.entry(func.name.as_str()).or_default().calls += 1;
+
+        let mut prev: Option<&'m Name> = None;
+        let mut bb = func.basic_blocks.first().ok_or_else(|| format!("Function '{}' has no body", func.name))?;
+        let result = loop {
+            if let Some(profile) = self.profiles.get_mut(func.name.as_str()) {
+                *profile.block_counts.entry(&bb.name).or_insert(0) += 1;
+                if let Some(from) = prev {
+                    *profile.edge_counts.entry((from, &bb.name)).or_insert(0) += 1;
+                }
+            }
+
+            // PHI nodes at the top of the block are evaluated together using the incoming edge
+            let mut phi_values = Vec::new();
+            for instr in &bb.instrs {
+                let Instruction::Phi(phi) = instr else { break };
+                let (value, _) = phi
+                    .incoming_values
+                    .iter()
+                    .find(|(_, from)| prev == Some(from))
+                    .ok_or_else(|| format!("No incoming value for {} in block {}", phi.dest, bb.name))?;
+                phi_values.push((&phi.dest, self.eval(value, &frame)?));
+            }
+            let num_phis = phi_values.len();
+            self.step(num_phis as u64)?;
+            for (dest, value) in phi_values {
+                frame.regs.insert(dest, value);
+            }
+
+            for instr in &bb.instrs[num_phis..] {
+                self.step(1)?;
+                self.execute(instr, &mut frame)?;
+            }
+
+            // Terminators count too, or a block that only branches to itself would never stop
+            self.step(1)?;
+            let next = match &bb.term {
+                Terminator::Ret(ret) => {
+                    break match &ret.return_operand {
+                        Some(op) => Some(self.eval(op, &frame)?),
+                        None => None,
+                    };
+                }
+                Terminator::Br(br) => &br.dest,
+                Terminator::CondBr(br) => {
+                    if self.eval(&br.condition, &frame)?.as_bool()? { &br.true_dest } else { &br.false_dest }
+                }
+                Terminator::Switch(sw) => {
+                    let (_, value) = self.eval(&sw.operand, &frame)?.as_int()?;
+                    let mut dest = &sw.default_dest;
+                    for (case, case_dest) in &sw.dests {
+                        if self.const_value(case)?.as_int()?.1 == value {
+                            dest = case_dest;
+                            break;
+                        }
+                    }
+                    dest
+                }
+                Terminator::Unreachable(_) => return Err(format!("Reached 'unreachable' in '{}'", func.name)),
+                other => return Err(format!("Unsupported terminator in '{}': {}", func.name, other)),
+            };
+            prev = Some(&bb.name);
+            bb = self.block(func, next)?;
+        };
+
+        for addr in frame.allocs {
+            self.memory.free(addr);
+        }
+        self.depth -= 1;
+        Ok(result)
+    }
+
+    fn eval(&self, op: &Operand, frame: &Frame) -> Result<Value, String> {
+        match op {
+            Operand::LocalOperand { name, .. } => {
+                frame.regs.get(name).cloned().ok_or_else(|| format!("Use of undefined value {}", name))
+            }
+            Operand::ConstantOperand(c) => self.const_value(c),
+            Operand::MetadataOperand => Err("Cannot evaluate a metadata operand".to_string()),
+        }
+    }
+
+    fn const_value(&self, c: &Constant) -> Result<Value, String> {
+        match c {
+            Constant::Int { bits, value } => Ok(int_value(*bits, *value)),
+            Constant::Float(Float::Single(f)) => Ok(Value::Float(*f as f64)),
+            Constant::Float(Float::Double(f)) => Ok(Value::Float(*f)),
+            Constant::Null(_) => Ok(Value::Ptr(0)),
+            Constant::AggregateZero(ty) | Constant::Undef(ty) | Constant::Poison(ty) => self.zero_value(ty),
+            Constant::Struct { values, .. } => {
+                Ok(Value::Aggregate(values.iter().map(|v| self.const_value(v)).collect::<Result<_, _>>()?))
+            }
+            Constant::Array { elements, .. } | Constant::Vector(elements) => {
+                Ok(Value::Aggregate(elements.iter().map(|v| self.const_value(v)).collect::<Result<_, _>>()?))
+            }
+            Constant::GlobalReference { name, .. } => {
+                if let Some(addr) = self.globals.get(name) {
+                    return Ok(Value::Ptr(*addr));
+                }
+                let Name::Name(func_name) = name else {
+                    return Err(format!("Unknown global {}", name));
+                };
+                self.fn_by_name
+                    .get(func_name.as_str())
+                    .map(|&addr| Value::Ptr(addr))
+                    .ok_or_else(|| format!("Unknown global {}", name))
+            }
+            Constant::GetElementPtr(gep) => {
+                let base = self.const_value(&gep.address)?.as_ptr()?;
+                let ty = self.gep_types.resolve(gep).ok_or_else(|| format!("Source element type of constant {} is unknown", c))?;
+                let indices = gep.indices.iter().map(|i| self.const_value(i)?.as_signed()).collect::<Result<Vec<_>, _>>()?;
+                Ok(Value::Ptr(base.wrapping_add(self.gep_offset(&ty, &indices)? as u64)))
+            }
+            Constant::BitCast(cast) => self.const_value(&cast.operand),
+            Constant::AddrSpaceCast(cast) => self.const_value(&cast.operand),
+            Constant::PtrToInt(cast) => Ok(int_value(self.int_bits(&cast.to_type)?, self.const_value(&cast.operand)?.as_ptr()?)),
+            Constant::IntToPtr(cast) => Ok(Value::Ptr(self.const_value(&cast.operand)?.as_int()?.1)),
+            Constant::Trunc(cast) => Ok(int_value(self.int_bits(&cast.to_type)?, self.const_value(&cast.operand)?.as_int()?.1)),
+            Constant::Add(a) => self.const_int_op(&a.operand0, &a.operand1, u64::wrapping_add),
+            Constant::Sub(s) => self.const_int_op(&s.operand0, &s.operand1, u64::wrapping_sub),
+            Constant::Mul(m) => self.const_int_op(&m.operand0, &m.operand1, u64::wrapping_mul),
+            other => Err(format!("Unsupported constant: {}", other)),
+        }
+    }
+
+    fn const_int_op(&self, a: &Constant, b: &Constant, f: fn(u64, u64) -> u64) -> Result<Value, String> {
+        let (bits, x) = self.const_value(a)?.as_int()?;
+        let (_, y) = self.const_value(b)?.as_int()?;
+        Ok(int_value(bits, f(x, y)))
+    }
+
+    fn zero_value(&self, ty: &Type) -> Result<Value, String> {
+        let types = &self.module.types;
+        match ty {
+            Type::IntegerType { bits } => Ok(int_value(*bits, 0)),
+            Type::PointerType { .. } => Ok(Value::Ptr(0)),
+            Type::FPType(_) => Ok(Value::Float(0.0)),
+            Type::ArrayType { element_type, num_elements } | Type::VectorType { element_type, num_elements, .. } => {
+                Ok(Value::Aggregate(vec![self.zero_value(element_type)?; *num_elements]))
+            }
+            Type::StructType { .. } | Type::NamedStructType { .. } => {
+                let (elements, _) = struct_elements(types, ty).ok_or_else(|| format!("Opaque struct type {}", ty))?;
+                Ok(Value::Aggregate(elements.iter().map(|e| self.zero_value(e)).collect::<Result<_, _>>()?))
+            }
+            other => Err(format!("Cannot create a value of type {}", other)),
+        }
+    }
+
+    fn int_bits(&self, ty: &Type) -> Result<u32, String> {
+        match ty {
+            Type::IntegerType { bits } if *bits <= 64 => Ok(*bits),
+            Type::IntegerType { bits } => Err(format!("Integers wider than 64 bits are not supported (i{})", bits)),
+            Type::PointerType { .. } => Ok(64),
+            other => Err(format!("Expected an integer type, got {}", other)),
+        }
+    }
+
+    fn is_single(&self, ty: &Type) -> bool {
+        matches!(ty, Type::FPType(FPType::Single))
+    }
+
+    /// Byte offset of a getelementptr with the given source element type and indices.
+    fn gep_offset(&self, source_ty: &TypeRef, indices: &[i64]) -> Result<i64, String> {
+        let types = &self.module.types;
+        let mut ty = source_ty.clone();
+        let mut offset: i64 = 0;
+        for (i, &idx) in indices.iter().enumerate() {
+            if i == 0 {
+                offset = offset.wrapping_add(idx.wrapping_mul(size_of(types, &ty) as i64));
+                continue;
+            }
+            let next = match ty.as_ref() {
+                Type::ArrayType { element_type, .. } | Type::VectorType { element_type, .. } => {
+                    offset = offset.wrapping_add(idx.wrapping_mul(size_of(types, element_type) as i64));
+                    element_type.clone()
+                }
+                Type::StructType { .. } | Type::NamedStructType { .. } => {
+                    let (elements, packed) =
+                        struct_elements(types, &ty).ok_or_else(|| format!("Opaque struct type {}", ty))?;
+                    let field = elements.get(idx as usize).ok_or_else(|| format!("Bad struct index {}", idx))?;
+                    offset += field_offsets(types, &elements, packed)[idx as usize] as i64;
+                    field.clone()
+                }
+                other => return Err(format!("Cannot index into type {}", other)),
+            };
+            ty = next;
+        }
+        Ok(offset)
+    }
+
+    fn read_value(&self, addr: u64, ty: &Type) -> Result<Value, String> {
+        let types = &self.module.types;
+        match ty {
+            Type::IntegerType { bits } => {
+                let bits = self.int_bits(ty).map(|_| *bits)?;
+                let bytes = self.memory.read(addr, int_store_bytes(bits))?;
+                let mut buf = [0u8; 8];
+                buf[..bytes.len()].copy_from_slice(bytes);
+                Ok(int_value(bits, u64::from_le_bytes(buf)))
+            }
+            Type::PointerType { .. } => {
+                let bytes = self.memory.read(addr, 8)?;
+                Ok(Value::Ptr(u64::from_le_bytes(bytes.try_into().unwrap_or_default())))
+            }
+            Type::FPType(FPType::Single) => {
+                let bytes = self.memory.read(addr, 4)?;
+                Ok(Value::Float(f32::from_le_bytes(bytes.try_into().unwrap_or_default()) as f64))
+            }
+            Type::FPType(FPType::Double) => {
+                let bytes = self.memory.read(addr, 8)?;
+                Ok(Value::Float(f64::from_le_bytes(bytes.try_into().unwrap_or_default())))
+            }
+            Type::ArrayType { element_type, num_elements } | Type::VectorType { element_type, num_elements, .. } => {
+                let elem_size = size_of(types, element_type);
+                let values = (0..*num_elements as u64)
+                    .map(|i| self.read_value(addr + i * elem_size, element_type))
+                    .collect::<Result<_, _>>()?;
+                Ok(Value::Aggregate(values))
+            }
+            Type::StructType { .. } | Type::NamedStructType { .. } => {
+                let (elements, packed) = struct_elements(types, ty).ok_or_else(|| format!("Opaque struct type {}", ty))?;
+                let offsets = field_offsets(types, &elements, packed);
+                let values = elements
+                    .iter()
+                    .zip(offsets)
+                    .map(|(e, off)| self.read_value(addr + off, e))
+                    .collect::<Result<_, _>>()?;
+                Ok(Value::Aggregate(values))
+            }
+            other => Err(format!("Cannot load a value of type {}", other)),
+        }
+    }
+
+    fn write_value(&mut self, addr: u64, ty: &Type, value: &Value) -> Result<(), String> {
+        let types = &self.module.types;
+        match (ty, value) {
+            (Type::IntegerType { bits }, _) => {
+                let (_, v) = value.as_int()?;
+                let len = int_store_bytes(self.int_bits(ty).map(|_| *bits)?) as usize;
+                self.memory.write(addr, &v.to_le_bytes()[..len])
+            }
+            (Type::PointerType { .. }, _) => self.memory.write(addr, &value.as_ptr()?.to_le_bytes()),
+            (Type::FPType(FPType::Single), Value::Float(f)) => self.memory.write(addr, &(*f as f32).to_le_bytes()),
+            (Type::FPType(FPType::Double), Value::Float(f)) => self.memory.write(addr, &f.to_le_bytes()),
+            (Type::ArrayType { element_type, .. } | Type::VectorType { element_type, .. }, Value::Aggregate(values)) => {
+                let elem_size = size_of(types, element_type);
+                for (i, v) in values.iter().enumerate() {
+                    self.write_value(addr + i as u64 * elem_size, element_type, v)?;
+                }
+                Ok(())
+            }
+            (Type::StructType { .. } | Type::NamedStructType { .. }, Value::Aggregate(values)) => {
+                let (elements, packed) = struct_elements(types, ty).ok_or_else(|| format!("Opaque struct type {}", ty))?;
+                let offsets = field_offsets(types, &elements, packed);
+                for ((e, off), v) in elements.iter().zip(offsets).zip(values) {
+                    self.write_value(addr + off, e, v)?;
+                }
+                Ok(())
+            }
+            _ => Err(format!("Cannot store {} as type {}", value, ty)),
+        }
+    }
+
+    fn int_binop(
+        &self,
+        frame: &mut Frame<'m>,
+        dest: &'m Name,
+        op0: &Operand,
+        op1: &Operand,
+        f: impl Fn(u64, u64, u32) -> Result<u64, String>,
+    ) -> Result<(), String> {
+        let (bits, a) = self.eval(op0, frame)?.as_int()?;
+        let (_, b) = self.eval(op1, frame)?.as_int()?;
+        frame.regs.insert(dest, int_value(bits, f(a & mask(bits), b & mask(bits), bits)?));
+        Ok(())
+    }
+
+    fn float_binop(
+        &self,
+        frame: &mut Frame<'m>,
+        dest: &'m Name,
+        op0: &Operand,
+        op1: &Operand,
+        f: impl Fn(f64, f64) -> f64,
+    ) -> Result<(), String> {
+        let a = self.eval(op0, frame)?.as_float()?;
+        let b = self.eval(op1, frame)?.as_float()?;
+        let mut result = f(a, b);
+        if self.is_single(&self.module.type_of(op0)) {
+            result = result as f32 as f64;
+        }
+        frame.regs.insert(dest, Value::Float(result));
+        Ok(())
+    }
+
+    fn execute(&mut self, instr: &'m Instruction, frame: &mut Frame<'m>) -> Result<(), String> {
+        match instr {
+            Instruction::Add(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| Ok(a.wrapping_add(b))),
+            Instruction::Sub(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| Ok(a.wrapping_sub(b))),
+            Instruction::Mul(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| Ok(a.wrapping_mul(b))),
+            Instruction::UDiv(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| {
+                a.checked_div(b).ok_or_else(|| "Division by zero".to_string())
+            }),
+            Instruction::SDiv(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, bits| {
+                sext(a, bits).checked_div(sext(b, bits)).map(|v| v as u64).ok_or_else(|| "Division by zero".to_string())
+            }),
+            Instruction::URem(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| {
+                a.checked_rem(b).ok_or_else(|| "Division by zero".to_string())
+            }),
+            Instruction::SRem(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, bits| {
+                sext(a, bits).checked_rem(sext(b, bits)).map(|v| v as u64).ok_or_else(|| "Division by zero".to_string())
+            }),
+            Instruction::And(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| Ok(a & b)),
+            Instruction::Or(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| Ok(a | b)),
+            Instruction::Xor(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, _| Ok(a ^ b)),
+            Instruction::Shl(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, bits| {
+                Ok(if b >= bits as u64 { 0 } else { a << b })
+            }),
+            Instruction::LShr(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, bits| {
+                Ok(if b >= bits as u64 { 0 } else { a >> b })
+            }),
+            Instruction::AShr(i) => self.int_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b, bits| {
+                Ok((sext(a, bits) >> b.min(63)) as u64)
+            }),
+            Instruction::FAdd(i) => self.float_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b| a + b),
+            Instruction::FSub(i) => self.float_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b| a - b),
+            Instruction::FMul(i) => self.float_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b| a * b),
+            Instruction::FDiv(i) => self.float_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b| a / b),
+            Instruction::FRem(i) => self.float_binop(frame, &i.dest, &i.operand0, &i.operand1, |a, b| a % b),
+            Instruction::FNeg(i) => {
+                let v = self.eval(&i.operand, frame)?.as_float()?;
+                frame.regs.insert(&i.dest, Value::Float(-v));
+                Ok(())
+            }
+            Instruction::ExtractValue(i) => {
+                let mut value = self.eval(&i.aggregate, frame)?;
+                for &idx in &i.indices {
+                    value = match value {
+                        Value::Aggregate(mut values) if (idx as usize) < values.len() => values.swap_remove(idx as usize),
+                        other => return Err(format!("Cannot extract index {} from {}", idx, other)),
+                    };
+                }
+                frame.regs.insert(&i.dest, value);
+                Ok(())
+            }
+            Instruction::InsertValue(i) => {
+                let mut aggregate = self.eval(&i.aggregate, frame)?;
+                let element = self.eval(&i.element, frame)?;
+                let mut slot = &mut aggregate;
+                for &idx in &i.indices {
+                    let Value::Aggregate(values) = slot else {
+                        return Err(format!("Cannot insert at index {} into a non-aggregate value", idx));
+                    };
+                    slot = values.get_mut(idx as usize).ok_or_else(|| format!("Bad aggregate index {}", idx))?;
+                }
+                *slot = element;
+                frame.regs.insert(&i.dest, aggregate);
+                Ok(())
+            }
+            Instruction::Alloca(i) => {
+                let (_, count) = self.eval(&i.num_elements, frame)?.as_int()?;
+                let addr = self.memory.alloc(size_of(&self.module.types, &i.allocated_type) * count);
+                frame.allocs.push(addr);
+                if self.depth == 1
+                    && let Some(trace) = &mut self.trace
+                {
+                    trace.allocas.insert(&i.dest, allocation_of(addr).0);
+                }
+                frame.regs.insert(&i.dest, Value::Ptr(addr));
+                Ok(())
+            }
+            Instruction::Load(i) => {
+                let addr = self.eval(&i.address, frame)?.as_ptr()?;
+                self.record(addr, &i.loaded_ty, false);
+                let value = self.read_value(addr, &i.loaded_ty)?;
+                frame.regs.insert(&i.dest, value);
+                Ok(())
+            }
+            Instruction::Store(i) => {
+                let addr = self.eval(&i.address, frame)?.as_ptr()?;
+                let value = self.eval(&i.value, frame)?;
+                let ty = self.module.type_of(&i.value);
+                self.record(addr, &ty, true);
+                self.write_value(addr, &ty, &value)
+            }
+            Instruction::GetElementPtr(i) => {
+                let base = self.eval(&i.address, frame)?.as_ptr()?;
+                let indices = i.indices.iter().map(|idx| self.eval(idx, frame)?.as_signed()).collect::<Result<Vec<_>, _>>()?;
+                let offset = self.gep_offset(&i.source_element_type, &indices)?;
+                frame.regs.insert(&i.dest, Value::Ptr(base.wrapping_add(offset as u64)));
+                Ok(())
+            }
+            Instruction::Trunc(i) => {
+                let (_, v) = self.eval(&i.operand, frame)?.as_int()?;
+                frame.regs.insert(&i.dest, int_value(self.int_bits(&i.to_type)?, v));
+                Ok(())
+            }
+            Instruction::ZExt(i) => {
+                let (_, v) = self.eval(&i.operand, frame)?.as_int()?;
+                frame.regs.insert(&i.dest, int_value(self.int_bits(&i.to_type)?, v));
+                Ok(())
+            }
+            Instruction::SExt(i) => {
+                let v = self.eval(&i.operand, frame)?.as_signed()?;
+                frame.regs.insert(&i.dest, int_value(self.int_bits(&i.to_type)?, v as u64));
+                Ok(())
+            }
+            Instruction::FPTrunc(i) => {
+                let v = self.eval(&i.operand, frame)?.as_float()?;
+                let v = if self.is_single(&i.to_type) { v as f32 as f64 } else { v };
+                frame.regs.insert(&i.dest, Value::Float(v));
+                Ok(())
+            }
+            Instruction::FPExt(i) => {
+                let v = self.eval(&i.operand, frame)?;
+                frame.regs.insert(&i.dest, v);
+                Ok(())
+            }
+            Instruction::FPToUI(i) => {
+                let v = self.eval(&i.operand, frame)?.as_float()?;
+                frame.regs.insert(&i.dest, int_value(self.int_bits(&i.to_type)?, v as u64));
+                Ok(())
+            }
+            Instruction::FPToSI(i) => {
+                let v = self.eval(&i.operand, frame)?.as_float()?;
+                frame.regs.insert(&i.dest, int_value(self.int_bits(&i.to_type)?, v as i64 as u64));
+                Ok(())
+            }
+            Instruction::UIToFP(i) => {
+                let (_, v) = self.eval(&i.operand, frame)?.as_int()?;
+                let f = if self.is_single(&i.to_type) { v as f32 as f64 } else { v as f64 };
+                frame.regs.insert(&i.dest, Value::Float(f));
+                Ok(())
+            }
+            Instruction::SIToFP(i) => {
+                let v = self.eval(&i.operand, frame)?.as_signed()?;
+                let f = if self.is_single(&i.to_type) { v as f32 as f64 } else { v as f64 };
+                frame.regs.insert(&i.dest, Value::Float(f));
+                Ok(())
+            }
+            Instruction::PtrToInt(i) => {
+                let addr = self.eval(&i.operand, frame)?.as_ptr()?;
+                frame.regs.insert(&i.dest, int_value(self.int_bits(&i.to_type)?, addr));
+                Ok(())
+            }
+            Instruction::IntToPtr(i) => {
+                let (_, v) = self.eval(&i.operand, frame)?.as_int()?;
+                frame.regs.insert(&i.dest, Value::Ptr(v));
+                Ok(())
+            }
+            Instruction::BitCast(i) => {
+                let v = self.eval(&i.operand, frame)?;
+                let v = match (v, i.to_type.as_ref()) {
+                    (Value::Float(f), Type::IntegerType { bits: 32 }) => int_value(32, (f as f32).to_bits() as u64),
+                    (Value::Float(f), Type::IntegerType { bits: 64 }) => int_value(64, f.to_bits()),
+                    (Value::Int { value, .. }, Type::FPType(FPType::Single)) => Value::Float(f32::from_bits(value as u32) as f64),
+                    (Value::Int { value, .. }, Type::FPType(FPType::Double)) => Value::Float(f64::from_bits(value)),
+                    (v, _) => v,
+                };
+                frame.regs.insert(&i.dest, v);
+                Ok(())
+            }
+            Instruction::AddrSpaceCast(i) => {
+                let v = self.eval(&i.operand, frame)?;
+                frame.regs.insert(&i.dest, v);
+                Ok(())
+            }
+            Instruction::ICmp(i) => {
+                let (bits, a) = self.eval(&i.operand0, frame)?.as_int()?;
+                let (_, b) = self.eval(&i.operand1, frame)?.as_int()?;
+                let (sa, sb) = (sext(a, bits), sext(b, bits));
+                let result = match i.predicate {
+                    IntPredicate::EQ => a == b,
+                    IntPredicate::NE => a != b,
+                    IntPredicate::UGT => a > b,
+                    IntPredicate::UGE => a >= b,
+                    IntPredicate::ULT => a < b,
+                    IntPredicate::ULE => a <= b,
+                    IntPredicate::SGT => sa > sb,
+                    IntPredicate::SGE => sa >= sb,
+                    IntPredicate::SLT => sa < sb,
+                    IntPredicate::SLE => sa <= sb,
+                };
+                frame.regs.insert(&i.dest, int_value(1, result as u64));
+                Ok(())
+            }
+            Instruction::FCmp(i) => {
+                let a = self.eval(&i.operand0, frame)?.as_float()?;
+                let b = self.eval(&i.operand1, frame)?.as_float()?;
+                let unordered = a.is_nan() || b.is_nan();
+                let result = match i.predicate {
+                    FPPredicate::False => false,
+                    FPPredicate::True => true,
+                    FPPredicate::ORD => !unordered,
+                    FPPredicate::UNO => unordered,
+                    FPPredicate::OEQ => !unordered && a == b,
+                    FPPredicate::OGT => !unordered && a > b,
+                    FPPredicate::OGE => !unordered && a >= b,
+                    FPPredicate::OLT => !unordered && a < b,
+                    FPPredicate::OLE => !unordered && a <= b,
+                    FPPredicate::ONE => !unordered && a != b,
+                    FPPredicate::UEQ => unordered || a == b,
+                    FPPredicate::UGT => unordered || a > b,
+                    FPPredicate::UGE => unordered || a >= b,
+                    FPPredicate::ULT => unordered || a < b,
+                    FPPredicate::ULE => unordered || a <= b,
+                    FPPredicate::UNE => unordered || a != b,
+                };
+                frame.regs.insert(&i.dest, int_value(1, result as u64));
+                Ok(())
+            }
+            Instruction::Select(i) => {
+                let cond = self.eval(&i.condition, frame)?.as_bool()?;
+                let v = self.eval(if cond { &i.true_value } else { &i.false_value }, frame)?;
+                frame.regs.insert(&i.dest, v);
+                Ok(())
+            }
+            Instruction::Freeze(i) => {
+                let v = self.eval(&i.operand, frame)?;
+                frame.regs.insert(&i.dest, v);
+                Ok(())
+            }
+            // Debug info and lifetime markers have no effect on execution
+            Instruction::Call(_) if is_annotation_call(instr) => Ok(()),
+            Instruction::Call(call) => self.call(call, frame),
+            Instruction::Phi(phi) => Err(format!("PHI node {} is not at the start of its block", phi.dest)),
+            other => Err(format!("Unsupported instruction: {}", other)),
+        }
+    }
+
+    // Counts executed instructions against the step limit
+    fn step(&mut self, count: u64) -> Result<(), String> {
+        self.steps += count;
+        if self.steps > self.max_steps {
+            return Err(format!("Step limit of {} instructions exceeded", self.max_steps));
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, addr: u64, ty: &Type, is_write: bool) {
+        let Some(trace) = &mut self.trace else { return };
+        let process = if self.depth >= 2 { trace.calls.len().checked_sub(1) } else { None };
+        let bytes = size_of(&self.module.types, ty);
+        trace.events.push(MemEvent { process, addr, bytes, is_write, step: self.steps });
+    }
+
+    fn call(&mut self, call: &'m Call, frame: &mut Frame<'m>) -> Result<(), String> {
+        let callee: &'m str = match call.function.as_ref().right() {
+            Some(Operand::ConstantOperand(c)) => match c.as_ref() {
+                Constant::GlobalReference { name: Name::Name(name), .. } => {
+                    if !self.fn_by_name.contains_key(name.as_str()) {
+                        return Err(format!("Call to unknown function @{}", name));
+                    }
+                    name.as_str()
+                }
+                other => return Err(format!("Unsupported callee: {}", other)),
+            },
+            Some(op) => {
+                let addr = self.eval(op, frame)?.as_ptr()?;
+                self.fn_addrs.get(&addr).copied().ok_or_else(|| format!("Indirect call to invalid address {:#x}", addr))?
+            }
+            None => return Err("Inline assembly is not supported".to_string()),
+        };
+
+        let args = call
+            .arguments
+            .iter()
+            .map(|(op, _)| self.eval(op, frame))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let result = if let Some(func) = self.functions.get(callee).copied() {
+            self.run_function(func, args)?
+        } else {
+            self.call_intrinsic(callee, &args)?
+        };
+        if let (Some(dest), Some(value)) = (&call.dest, result) {
+            frame.regs.insert(dest, value);
+        }
+        Ok(())
+    }
+
+    // Intrinsics commonly emitted by clang for HLS-style kernels
+    fn call_intrinsic(&mut self, callee: &str, args: &[Value]) -> Result<Option<Value>, String> {
+        let arg = |i: usize| args.get(i).ok_or_else(|| format!("Missing argument {} to {}", i, callee));
+        let base = callee.split('.').take(2).collect::<Vec<_>>().join(".");
+        match base.as_str() {
+            "llvm.memcpy" | "llvm.memmove" => {
+                let (dst, src, len) = (arg(0)?.as_ptr()?, arg(1)?.as_ptr()?, arg(2)?.as_int()?.1);
+                let bytes = self.memory.read(src, len)?.to_vec();
+                self.memory.write(dst, &bytes)?;
+                Ok(None)
+            }
+            "llvm.memset" => {
+                let (dst, byte, len) = (arg(0)?.as_ptr()?, arg(1)?.as_int()?.1 as u8, arg(2)?.as_int()?.1);
+                self.memory.write(dst, &vec![byte; len as usize])?;
+                Ok(None)
+            }
+            "llvm.smax" | "llvm.smin" | "llvm.umax" | "llvm.umin" => {
+                let (bits, a) = arg(0)?.as_int()?;
+                let (_, b) = arg(1)?.as_int()?;
+                let (sa, sb) = (sext(a, bits), sext(b, bits));
+                let v = match base.as_str() {
+                    "llvm.smax" => if sa >= sb { a } else { b },
+                    "llvm.smin" => if sa <= sb { a } else { b },
+                    "llvm.umax" => a.max(b),
+                    _ => a.min(b),
+                };
+                Ok(Some(int_value(bits, v)))
+            }
+            "llvm.abs" => {
+                let (bits, a) = arg(0)?.as_int()?;
+                Ok(Some(int_value(bits, sext(a, bits).wrapping_abs() as u64)))
+            }
+            "llvm.fmuladd" | "llvm.fma" => {
+                let (a, b, c) = (arg(0)?.as_float()?, arg(1)?.as_float()?, arg(2)?.as_float()?);
+                Ok(Some(Value::Float(a.mul_add(b, c))))
+            }
+            "llvm.fabs" => Ok(Some(Value::Float(arg(0)?.as_float()?.abs()))),
+            "llvm.sqrt" => Ok(Some(Value::Float(arg(0)?.as_float()?.sqrt()))),
+            _ => Err(format!("Call to external function '{}' is not supported by the interpreter", callee)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // -O0 style: a local counter, a helper called directly and through a pointer, and annotation calls
+    const SUM: &str = "
+@scale = global ptr @twice
+
+define i32 @twice(i32 %x) {
+entry:
+  %r = mul i32 %x, 2
+  ret i32 %r
+}
+
+define i32 @sum(i32 %n) {
+entry:
+  %i = alloca i32
+  %s = alloca i32
+  call void @llvm.lifetime.start.p0(i64 4, ptr %i)
+  store i32 0, ptr %i
+  store i32 0, ptr %s
+  br label %for.cond
+for.cond:
+  %0 = load i32, ptr %i
+  %cmp = icmp slt i32 %0, %n
+  br i1 %cmp, label %for.body, label %for.end
+for.body:
+  %1 = load i32, ptr %s
+  %2 = call i32 @twice(i32 %0)
+  %f = load ptr, ptr @scale
+  %3 = call i32 %f(i32 %2)
+  %4 = add i32 %1, %3
+  store i32 %4, ptr %s
+  %5 = add i32 %0, 1
+  store i32 %5, ptr %i
+  br label %for.cond
+for.end:
+  %6 = load i32, ptr %s
+  ret i32 %6
+}
+
+declare void @llvm.lifetime.start.p0(i64, ptr)
+";
+
+    // Two blocks that only hold phis and jump to each other
+    const PHI_CYCLE: &str = "
+define void @spin() {
+entry:
+  br label %a
+a:
+  %x = phi i32 [ 0, %entry ], [ %y, %b ]
+  br label %b
+b:
+  %y = phi i32 [ %x, %a ]
+  br label %a
+}
+";
+
+    // `for (;;) {}`: a block without instructions that branches to itself
+    const EMPTY_LOOP: &str = "
+define void @forever() {
+entry:
+  br label %loop
+loop:
+  br label %loop
+}
+";
+
+    // Constant GEPs as LLVM 18 writes them, in bytes, and clang's walk of the global's type
+    const TABLE: &str = "
+@table = global [4 x i32] [i32 1, i32 2, i32 3, i32 4]
+
+define i32 @third() {
+entry:
+  %v = load i32, ptr getelementptr inbounds (i8, ptr @table, i64 8)
+  ret i32 %v
+}
+
+define i32 @last() {
+entry:
+  %v = load i32, ptr getelementptr inbounds ([4 x i32], ptr @table, i64 0, i64 3)
+  ret i32 %v
+}
+";
+
+    #[test]
+    fn runs_loops_and_calls() {
+        let module = Module::from_ir_str(SUM).unwrap();
+        let mut interp = Interpreter::new(&module).unwrap();
+        let result = interp.call_function("sum", vec![int_value(32, 5)]).unwrap();
+        // 4 * (0 + 1 + 2 + 3 + 4)
+        assert_eq!(result, Some(int_value(32, 40)));
+        let profile = &interp.profiles["sum"];
+        assert_eq!(profile.block_counts[&Name::from("for.cond")], 6);
+        assert_eq!(profile.block_counts[&Name::from("for.body")], 5);
+        assert_eq!(interp.profiles["twice"].calls, 10);
+    }
+
+    #[test]
+    fn phis_count_against_the_step_limit() {
+        let module = Module::from_ir_str(PHI_CYCLE).unwrap();
+        let mut interp = Interpreter::new(&module).unwrap();
+        interp.max_steps = 1000;
+        let err = interp.call_function("spin", Vec::new()).unwrap_err();
+        assert!(err.contains("Step limit"), "{}", err);
+    }
+
+    #[test]
+    fn terminators_count_against_the_step_limit() {
+        let module = Module::from_ir_str(EMPTY_LOOP).unwrap();
+        let mut interp = Interpreter::new(&module).unwrap();
+        interp.max_steps = 1000;
+        let err = interp.call_function("forever", Vec::new()).unwrap_err();
+        assert!(err.contains("Step limit"), "{}", err);
+    }
+
+    #[test]
+    fn reads_through_constant_geps() {
+        // The byte offset's i8 is only in the text, which is read from the module's file when first needed
+        let path = std::env::temp_dir().join(format!("llvm_ir_tool_interp_{}.ll", std::process::id()));
+        std::fs::write(&path, TABLE).unwrap();
+        let module = Module::from_ir_path(&path).unwrap();
+        let mut interp = Interpreter::new(&module).unwrap();
+        let third = interp.call_function("third", Vec::new());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(third.unwrap(), Some(int_value(32, 3)));
+        assert_eq!(interp.call_function("last", Vec::new()).unwrap(), Some(int_value(32, 4)));
+
+        // Without the text, a GEP off the global's type is an error, not a guess
+        let module = Module::from_ir_str(TABLE).unwrap();
+        let mut interp = Interpreter::new(&module).unwrap();
+        let err = interp.call_function("third", Vec::new()).unwrap_err();
+        assert!(err.contains("unknown"), "{}", err);
+        assert_eq!(interp.call_function("last", Vec::new()).unwrap(), Some(int_value(32, 4)));
+    }
+}
@@ -0,0 +1,336 @@
+use llvm_ir::{Constant, DebugLoc, Function, Instruction, Name, Operand, Terminator};
+use llvm_ir::HasDebugLoc;
+use std::collections::{BTreeMap, HashMap};
+
+/// Short opcode name of an instruction (e.g. "Load", "Store").
+/// The Debug output of an instruction starts with the variant name, so we cut it at the first '('.
+pub fn opcode_name(instr: &Instruction) -> String {
+    let debug = format!("{:?}", instr);
+    debug.split('(').next().unwrap_or("Unknown").to_string()
+}
+
+/// Number of instructions of each opcode in a function, sorted by opcode name.
+pub fn opcode_counts(func: &Function) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        *counts.entry(opcode_name(instr)).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Names of the blocks a terminator can jump to.
+pub fn successors(term: &Terminator) -> Vec<&Name> {
+    match term {
+        Terminator::Br(br) => vec![&br.dest],
+        Terminator::CondBr(br) => vec![&br.true_dest, &br.false_dest],
+        Terminator::Switch(sw) => {
+            let mut dests: Vec<&Name> = sw.dests.iter().map(|(_, dest)| dest).collect();
+            dests.push(&sw.default_dest);
+            dests
+        }
+        Terminator::IndirectBr(br) => br.possible_dests.iter().collect(),
+        Terminator::Invoke(inv) => vec![&inv.return_label, &inv.exception_label],
+        Terminator::CatchRet(ret) => vec![&ret.successor],
+        _ => vec![],
+    }
+}
+
+/// First source line referenced by a basic block, looking at instructions and then the terminator.
+pub fn block_line(func: &Function, block: &Name) -> Option<u32> {
+    let bb = func.get_bb_by_name(block)?;
+    bb.instrs
+        .iter()
+        .filter_map(|instr| instr.get_debug_loc().as_ref())
+        .chain(bb.term.get_debug_loc().as_ref())
+        .map(|loc| loc.line)
+        .find(|&line| line != 0)
+}
+
+/// Prints a block name the way it appears in the .ll file (without the leading '%').
+pub fn block_label(name: &Name) -> String {
+    match name {
+        Name::Name(s) => s.to_string(),
+        Name::Number(n) => n.to_string(),
+    }
+}
+
+/// Instruction text as printed by llvm-ir, without its debug location marker.
+pub fn instr_text(instr: &Instruction) -> String {
+    instr.to_string().replace(" (with debugloc)", "")
+}
+
+/// All operands read by an instruction, in order.
+pub fn operands(instr: &Instruction) -> Vec<&Operand> {
+    match instr {
+        Instruction::Add(i) => vec![&i.operand0, &i.operand1],
+        Instruction::Sub(i) => vec![&i.operand0, &i.operand1],
+        Instruction::Mul(i) => vec![&i.operand0, &i.operand1],
+        Instruction::UDiv(i) => vec![&i.operand0, &i.operand1],
+        Instruction::SDiv(i) => vec![&i.operand0, &i.operand1],
+        Instruction::URem(i) => vec![&i.operand0, &i.operand1],
+        Instruction::SRem(i) => vec![&i.operand0, &i.operand1],
+        Instruction::And(i) => vec![&i.operand0, &i.operand1],
+        Instruction::Or(i) => vec![&i.operand0, &i.operand1],
+        Instruction::Xor(i) => vec![&i.operand0, &i.operand1],
+        Instruction::Shl(i) => vec![&i.operand0, &i.operand1],
+        Instruction::LShr(i) => vec![&i.operand0, &i.operand1],
+        Instruction::AShr(i) => vec![&i.operand0, &i.operand1],
+        Instruction::FAdd(i) => vec![&i.operand0, &i.operand1],
+        Instruction::FSub(i) => vec![&i.operand0, &i.operand1],
+        Instruction::FMul(i) => vec![&i.operand0, &i.operand1],
+        Instruction::FDiv(i) => vec![&i.operand0, &i.operand1],
+        Instruction::FRem(i) => vec![&i.operand0, &i.operand1],
+        Instruction::FNeg(i) => vec![&i.operand],
+        Instruction::ExtractElement(i) => vec![&i.vector, &i.index],
+        Instruction::InsertElement(i) => vec![&i.vector, &i.element, &i.index],
+        Instruction::ShuffleVector(i) => vec![&i.operand0, &i.operand1],
+        Instruction::ExtractValue(i) => vec![&i.aggregate],
+        Instruction::InsertValue(i) => vec![&i.aggregate, &i.element],
+        Instruction::Alloca(i) => vec![&i.num_elements],
+        Instruction::Load(i) => vec![&i.address],
+        Instruction::Store(i) => vec![&i.value, &i.address],
+        Instruction::Fence(_) => vec![],
+        Instruction::CmpXchg(i) => vec![&i.address, &i.expected, &i.replacement],
+        Instruction::AtomicRMW(i) => vec![&i.address, &i.value],
+        Instruction::GetElementPtr(i) => std::iter::once(&i.address).chain(i.indices.iter()).collect(),
+        Instruction::Trunc(i) => vec![&i.operand],
+        Instruction::ZExt(i) => vec![&i.operand],
+        Instruction::SExt(i) => vec![&i.operand],
+        Instruction::FPTrunc(i) => vec![&i.operand],
+        Instruction::FPExt(i) => vec![&i.operand],
+        Instruction::FPToUI(i) => vec![&i.operand],
+        Instruction::FPToSI(i) => vec![&i.operand],
+        Instruction::UIToFP(i) => vec![&i.operand],
+        Instruction::SIToFP(i) => vec![&i.operand],
+        Instruction::PtrToInt(i) => vec![&i.operand],
+        Instruction::IntToPtr(i) => vec![&i.operand],
+        Instruction::BitCast(i) => vec![&i.operand],
+        Instruction::AddrSpaceCast(i) => vec![&i.operand],
+        Instruction::ICmp(i) => vec![&i.operand0, &i.operand1],
+        Instruction::FCmp(i) => vec![&i.operand0, &i.operand1],
+        Instruction::Phi(i) => i.incoming_values.iter().map(|(op, _)| op).collect(),
+        Instruction::Select(i) => vec![&i.condition, &i.true_value, &i.false_value],
+        Instruction::Freeze(i) => vec![&i.operand],
+        Instruction::Call(i) => i.function.as_ref().right().into_iter().chain(i.arguments.iter().map(|(op, _)| op)).collect(),
+        Instruction::VAArg(i) => vec![&i.arg_list],
+        Instruction::LandingPad(_) => vec![],
+        Instruction::CatchPad(i) => std::iter::once(&i.catch_switch).chain(i.args.iter()).collect(),
+        Instruction::CleanupPad(i) => std::iter::once(&i.parent_pad).chain(i.args.iter()).collect(),
+    }
+}
+
+/// Mutable version of `operands`, used when rewriting instructions.
+pub fn operands_mut(instr: &mut Instruction) -> Vec<&mut Operand> {
+    match instr {
+        Instruction::Add(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::Sub(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::Mul(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::UDiv(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::SDiv(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::URem(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::SRem(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::And(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::Or(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::Xor(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::Shl(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::LShr(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::AShr(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::FAdd(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::FSub(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::FMul(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::FDiv(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::FRem(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::FNeg(i) => vec![&mut i.operand],
+        Instruction::ExtractElement(i) => vec![&mut i.vector, &mut i.index],
+        Instruction::InsertElement(i) => vec![&mut i.vector, &mut i.element, &mut i.index],
+        Instruction::ShuffleVector(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::ExtractValue(i) => vec![&mut i.aggregate],
+        Instruction::InsertValue(i) => vec![&mut i.aggregate, &mut i.element],
+        Instruction::Alloca(i) => vec![&mut i.num_elements],
+        Instruction::Load(i) => vec![&mut i.address],
+        Instruction::Store(i) => vec![&mut i.value, &mut i.address],
+        Instruction::Fence(_) => vec![],
+        Instruction::CmpXchg(i) => vec![&mut i.address, &mut i.expected, &mut i.replacement],
+        Instruction::AtomicRMW(i) => vec![&mut i.address, &mut i.value],
+        Instruction::GetElementPtr(i) => std::iter::once(&mut i.address).chain(i.indices.iter_mut()).collect(),
+        Instruction::Trunc(i) => vec![&mut i.operand],
+        Instruction::ZExt(i) => vec![&mut i.operand],
+        Instruction::SExt(i) => vec![&mut i.operand],
+        Instruction::FPTrunc(i) => vec![&mut i.operand],
+        Instruction::FPExt(i) => vec![&mut i.operand],
+        Instruction::FPToUI(i) => vec![&mut i.operand],
+        Instruction::FPToSI(i) => vec![&mut i.operand],
+        Instruction::UIToFP(i) => vec![&mut i.operand],
+        Instruction::SIToFP(i) => vec![&mut i.operand],
+        Instruction::PtrToInt(i) => vec![&mut i.operand],
+        Instruction::IntToPtr(i) => vec![&mut i.operand],
+        Instruction::BitCast(i) => vec![&mut i.operand],
+        Instruction::AddrSpaceCast(i) => vec![&mut i.operand],
+        Instruction::ICmp(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::FCmp(i) => vec![&mut i.operand0, &mut i.operand1],
+        Instruction::Phi(i) => i.incoming_values.iter_mut().map(|(op, _)| op).collect(),
+        Instruction::Select(i) => vec![&mut i.condition, &mut i.true_value, &mut i.false_value],
+        Instruction::Freeze(i) => vec![&mut i.operand],
+        Instruction::Call(i) => i.function.as_mut().right().into_iter().chain(i.arguments.iter_mut().map(|(op, _)| op)).collect(),
+        Instruction::VAArg(i) => vec![&mut i.arg_list],
+        Instruction::LandingPad(_) => vec![],
+        Instruction::CatchPad(i) => std::iter::once(&mut i.catch_switch).chain(i.args.iter_mut()).collect(),
+        Instruction::CleanupPad(i) => std::iter::once(&mut i.parent_pad).chain(i.args.iter_mut()).collect(),
+    }
+}
+
+/// Name defined by an instruction, for renaming.
+pub fn dest_mut(instr: &mut Instruction) -> Option<&mut Name> {
+    macro_rules! dest {
+        ($($variant:ident),*) => {
+            match instr {
+                $(Instruction::$variant(i) => Some(&mut i.dest),)*
+                Instruction::Call(i) => i.dest.as_mut(),
+                _ => None,
+            }
+        };
+    }
+    dest!(
+        Add, Sub, Mul, UDiv, SDiv, URem, SRem, And, Or, Xor, Shl, LShr, AShr, FAdd, FSub, FMul, FDiv, FRem, FNeg,
+        ExtractElement, InsertElement, ShuffleVector, ExtractValue, InsertValue, Alloca, Load, CmpXchg, AtomicRMW,
+        GetElementPtr, Trunc, ZExt, SExt, FPTrunc, FPExt, FPToUI, FPToSI, UIToFP, SIToFP, PtrToInt, IntToPtr, BitCast,
+        AddrSpaceCast, ICmp, FCmp, Phi, Select, Freeze, VAArg, LandingPad, CatchPad, CleanupPad
+    )
+}
+
+/// Mutable version of `successors`, used when redirecting branches.
+pub fn successors_mut(term: &mut Terminator) -> Vec<&mut Name> {
+    match term {
+        Terminator::Br(br) => vec![&mut br.dest],
+        Terminator::CondBr(br) => vec![&mut br.true_dest, &mut br.false_dest],
+        Terminator::Switch(sw) => {
+            let mut dests: Vec<&mut Name> = sw.dests.iter_mut().map(|(_, dest)| dest).collect();
+            dests.push(&mut sw.default_dest);
+            dests
+        }
+        Terminator::IndirectBr(br) => br.possible_dests.iter_mut().collect(),
+        Terminator::Invoke(inv) => vec![&mut inv.return_label, &mut inv.exception_label],
+        Terminator::CatchRet(ret) => vec![&mut ret.successor],
+        _ => vec![],
+    }
+}
+
+/// Operands read by a terminator.
+pub fn term_operands(term: &Terminator) -> Vec<&Operand> {
+    match term {
+        Terminator::Ret(ret) => ret.return_operand.iter().collect(),
+        Terminator::CondBr(br) => vec![&br.condition],
+        Terminator::Switch(sw) => vec![&sw.operand],
+        Terminator::IndirectBr(br) => vec![&br.operand],
+        Terminator::Resume(r) => vec![&r.operand],
+        _ => vec![],
+    }
+}
+
+/// Operands read by a terminator, mutable so they can be renamed.
+pub fn term_operands_mut(term: &mut Terminator) -> Vec<&mut Operand> {
+    match term {
+        Terminator::Ret(ret) => ret.return_operand.iter_mut().collect(),
+        Terminator::CondBr(br) => vec![&mut br.condition],
+        Terminator::Switch(sw) => vec![&mut sw.operand],
+        Terminator::IndirectBr(br) => vec![&mut br.operand],
+        Terminator::Resume(r) => vec![&mut r.operand],
+        _ => vec![],
+    }
+}
+
+/// Value of an integer constant operand, sign-extended to 64 bits.
+pub fn const_int(op: &Operand) -> Option<i64> {
+    match op {
+        Operand::ConstantOperand(c) => match c.as_ref() {
+            Constant::Int { bits, value } => {
+                let shift = 64 - (*bits).clamp(1, 64);
+                Some(((*value << shift) as i64) >> shift)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Name of the function called by a direct call instruction, if any.
+pub fn callee_name(instr: &Instruction) -> Option<&str> {
+    let Instruction::Call(call) = instr else { return None };
+    match call.function.as_ref().right()? {
+        Operand::ConstantOperand(c) => match c.as_ref() {
+            Constant::GlobalReference { name: Name::Name(name), .. } => Some(name.as_str()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// True for calls that only carry debug or optimizer hints (llvm.dbg.*, llvm.lifetime.*, ...).
+pub fn is_annotation_call(instr: &Instruction) -> bool {
+    callee_name(instr).is_some_and(|name| {
+        name.starts_with("llvm.dbg.")
+            || name.starts_with("llvm.lifetime.")
+            || name.starts_with("llvm.experimental.noalias")
+            || name == "llvm.assume"
+    })
+}
+
+/// A double-quoted string for DOT and JSON, which escape the same characters.
+pub fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// One line of a function's IR text, with the block and debug location of what it holds.
+pub struct IrLine {
+    pub index: usize,
+    pub block: Option<usize>,
+    // Set on block label lines
+    pub label: Option<usize>,
+    // Position of the instruction in its block, the terminator being last
+    pub instr: Option<usize>,
+    pub loc: Option<DebugLoc>,
+}
+
+/// Pairs the text lines of a function with its parsed blocks and instructions, in order.
+pub fn ir_lines(func: &Function, lines: &[String], (start, end): (usize, usize)) -> Vec<IrLine> {
+    let labels: HashMap<String, usize> =
+        func.basic_blocks.iter().enumerate().map(|(b, bb)| (format!("{}:", block_label(&bb.name)), b)).collect();
+    let mut rows = Vec::new();
+    let mut block = None;
+    let mut position = 0;
+    for (index, text) in lines.iter().enumerate().take(end + 1).skip(start) {
+        let label = text.split_whitespace().next().and_then(|word| labels.get(word)).copied();
+        let mut loc = None;
+        let mut instr = None;
+        if label.is_some() {
+            block = label;
+            position = 0;
+        } else if index == start {
+            // Instructions before the first label belong to the entry block
+            block = Some(0);
+        } else if text.starts_with(char::is_whitespace) && !text.trim().is_empty() && !text.trim_start().starts_with(';') {
+            if let Some(bb) = block.map(|b| &func.basic_blocks[b]) {
+                // The terminator comes after the instructions (a switch spans several lines)
+                loc = match bb.instrs.get(position) {
+                    Some(instr) => instr.get_debug_loc().clone(),
+                    None => bb.term.get_debug_loc().clone(),
+                };
+            }
+            instr = block.map(|b| position.min(func.basic_blocks[b].instrs.len()));
+            position += 1;
+        }
+        rows.push(IrLine { index, block: if text.trim() == "}" { None } else { block }, label, instr, loc });
+    }
+    rows
+}
@@ -0,0 +1,90 @@
+use llvm_ir::types::{FPType, NamedStructDef, Types};
+use llvm_ir::{Type, TypeRef};
+
+// Type sizes and alignments follow the usual x86-64 data layout that clang emits by default.
+
+/// Element types of a (possibly named) struct type, and whether it is packed.
+pub fn struct_elements(types: &Types, ty: &Type) -> Option<(Vec<TypeRef>, bool)> {
+    match ty {
+        Type::StructType { element_types, is_packed } => Some((element_types.clone(), *is_packed)),
+        Type::NamedStructType { name } => match types.named_struct_def(name) {
+            Some(NamedStructDef::Defined(def)) => struct_elements(types, def),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Number of bytes actually written when storing a scalar of this many bits.
+pub fn int_store_bytes(bits: u32) -> u64 {
+    bits.div_ceil(8) as u64
+}
+
+/// ABI alignment of a type in bytes.
+pub fn align_of(types: &Types, ty: &Type) -> u64 {
+    match ty {
+        Type::IntegerType { bits } => int_store_bytes(*bits).next_power_of_two().min(16),
+        Type::PointerType { .. } => 8,
+        Type::FPType(fpt) => fp_size(*fpt).min(16),
+        Type::VectorType { .. } => size_of(types, ty).next_power_of_two().min(16),
+        Type::ArrayType { element_type, .. } => align_of(types, element_type),
+        Type::StructType { .. } | Type::NamedStructType { .. } => match struct_elements(types, ty) {
+            Some((_, true)) => 1,
+            Some((elements, false)) => elements.iter().map(|e| align_of(types, e)).max().unwrap_or(1),
+            None => 1,
+        },
+        _ => 1,
+    }
+}
+
+/// Allocation size of a type in bytes, including tail padding.
+pub fn size_of(types: &Types, ty: &Type) -> u64 {
+    match ty {
+        Type::IntegerType { bits } => int_store_bytes(*bits).next_power_of_two(),
+        Type::PointerType { .. } => 8,
+        Type::FPType(fpt) => fp_size(*fpt),
+        Type::VectorType { element_type, num_elements, .. } => {
+            (size_of(types, element_type) * *num_elements as u64).next_power_of_two()
+        }
+        Type::ArrayType { element_type, num_elements } => size_of(types, element_type) * *num_elements as u64,
+        Type::StructType { .. } | Type::NamedStructType { .. } => {
+            let Some((elements, packed)) = struct_elements(types, ty) else {
+                return 0;
+            };
+            let offsets = field_offsets(types, &elements, packed);
+            let end = match (offsets.last(), elements.last()) {
+                (Some(off), Some(last)) => off + size_of(types, last),
+                _ => 0,
+            };
+            align_to(end, align_of(types, ty))
+        }
+        _ => 0,
+    }
+}
+
+/// Byte offset of every field in a struct with the given element types.
+pub fn field_offsets(types: &Types, elements: &[TypeRef], packed: bool) -> Vec<u64> {
+    let mut offsets = Vec::with_capacity(elements.len());
+    let mut offset = 0;
+    for element in elements {
+        if !packed {
+            offset = align_to(offset, align_of(types, element));
+        }
+        offsets.push(offset);
+        offset += size_of(types, element);
+    }
+    offsets
+}
+
+fn align_to(value: u64, align: u64) -> u64 {
+    if align <= 1 { value } else { value.div_ceil(align) * align }
+}
+
+fn fp_size(fpt: FPType) -> u64 {
+    match fpt {
+        FPType::Half | FPType::BFloat => 2,
+        FPType::Single => 4,
+        FPType::Double => 8,
+        FPType::FP128 | FPType::X86_FP80 | FPType::PPC_FP128 => 16,
+    }
+}
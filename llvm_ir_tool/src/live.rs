@@ -0,0 +1,330 @@
+use crate::ir_util::{block_label, operands};
+use crate::loops::LoopInfo;
+use crate::memory::{access, MemoryInfo};
+use crate::pragmas::{collect, LoopMetadata};
+use crate::resources::type_bits;
+use crate::schedule::{schedule_block, ScheduleOptions};
+use crate::source::{hls_loop_names, source_name, Sources};
+use crate::unroll::unroll;
+use llvm_ir::{Function, Instruction, Module, Name, Operand, Terminator};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// Values listed per stage before the rest is summarized as "...".
+const MAX_LISTED: usize = 6;
+
+/// Lifetime of one SSA value in the pipeline, in cycles from the start of the iteration.
+struct LiveRange {
+    name: String,
+    bits: u64,
+    // Cycle the value is available in, and the last cycle that reads it
+    ready: u32,
+    last_use: u32,
+}
+
+/// Registers a pipelined loop needs besides its operators.
+struct Pressure {
+    depth: u32,
+    ranges: Vec<LiveRange>,
+    // Local variables the body reads or writes, kept in one register each
+    variable_bits: u64,
+    // Values computed before the loop and read inside it
+    input_bits: u64,
+    notes: Vec<String>,
+}
+
+impl Pressure {
+    /// Bits held across the boundary after `cycle`.
+    fn live_bits(&self, cycle: u32) -> u64 {
+        self.live_at(cycle).map(|r| r.bits).sum()
+    }
+
+    fn live_at(&self, cycle: u32) -> impl Iterator<Item = &LiveRange> {
+        self.ranges.iter().filter(move |r| r.ready <= cycle && cycle < r.last_use)
+    }
+
+    fn pipeline_bits(&self) -> u64 {
+        (0..self.depth).map(|c| self.live_bits(c)).sum()
+    }
+}
+
+/// Schedules the body of loop `l` as one pipeline iteration, with its inner loops fully unrolled as
+/// HLS does for a pipelined loop, and computes the lifetime of each value it defines. Blocks of
+/// the body are laid out one after another, so both sides of a branch take stages.
+fn pressure(module: &Module, func: &Function, loop_info: &LoopInfo, l: usize, options: &ScheduleOptions) -> Pressure {
+    let mut notes = Vec::new();
+    let mut work = func.clone();
+    let mut inner: Vec<usize> = (0..loop_info.loops.len()).filter(|&c| is_inside(loop_info, c, l)).collect();
+    inner.sort_by_key(|&c| std::cmp::Reverse(loop_info.loops[c].depth));
+    for c in inner {
+        let header = block_label(loop_info.cfg.names[loop_info.loops[c].header]);
+        let current = LoopInfo::new(&work);
+        let Some(found) = current.find_by_header(&header) else { continue };
+        let unrolled = current.constant_trip_count(found).ok_or("no constant trip count".to_string()).and_then(|t| unroll(&work, found, t));
+        match unrolled {
+            Ok(f) => work = f,
+            Err(e) => notes.push(format!("{} is not unrolled ({}); its blocks take stages one after another", loop_info.describe(c), e)),
+        }
+    }
+
+    let work_info = LoopInfo::new(&work);
+    let header = block_label(loop_info.cfg.names[loop_info.loops[l].header]);
+    let Some(wl) = work_info.find_by_header(&header) else {
+        return Pressure { depth: 0, ranges: Vec::new(), variable_bits: 0, input_bits: 0, notes };
+    };
+    let body = &work_info.loops[wl].blocks;
+    let mem = MemoryInfo::new(&work);
+
+    // Cycle each value is ready in and the cycle of each instruction, blocks in execution order
+    let mut ready: HashMap<&Name, u32> = HashMap::new();
+    let mut uses: Vec<(&Operand, u32)> = Vec::new();
+    let mut offset = 0;
+    for b in work_info.cfg.reverse_post_order().into_iter().filter(|b| body.contains(b)) {
+        let bb = &work.basic_blocks[b];
+        let sched = schedule_block(module, &mem, bb, &options.model, options.clock);
+        let cycles: HashMap<*const Instruction, (u32, u32)> =
+            sched.ops.iter().map(|op| (op.instr as *const Instruction, (op.asap, op.timing.latency))).collect();
+        for instr in &bb.instrs {
+            let (asap, latency) = cycles.get(&(instr as *const Instruction)).copied().unwrap_or((0, 0));
+            if let Some(dest) = instr.try_get_result() {
+                ready.insert(dest, offset + asap + latency);
+            }
+            for op in operands(instr) {
+                uses.push((op, offset + asap));
+            }
+        }
+        if let Terminator::CondBr(br) = &bb.term {
+            uses.push((&br.condition, offset + sched.latency - 1));
+        }
+        offset += sched.latency;
+    }
+    let depth = offset;
+
+    let mut last_use: HashMap<&Name, u32> = HashMap::new();
+    let mut input_bits = 0;
+    let mut inputs_seen: Vec<&Name> = Vec::new();
+    for (op, cycle) in uses {
+        let Operand::LocalOperand { name, ty } = op else { continue };
+        if ready.contains_key(name) {
+            let last = last_use.entry(name).or_insert(cycle);
+            *last = (*last).max(cycle);
+        } else if !inputs_seen.contains(&name) && !mem.is_scalar_alloca(name) {
+            inputs_seen.push(name);
+            input_bits += type_bits(ty);
+        }
+    }
+    // Phis carry values into the next iteration, which starts after the whole body at the latest
+    for instr in work.basic_blocks.iter().enumerate().filter(|(b, _)| body.contains(b)).flat_map(|(_, bb)| &bb.instrs) {
+        if let Instruction::Phi(phi) = instr {
+            for (value, _) in &phi.incoming_values {
+                if let Operand::LocalOperand { name, .. } = value
+                    && ready.contains_key(name)
+                {
+                    last_use.insert(name, depth);
+                }
+            }
+        }
+    }
+
+    let mut ranges = Vec::new();
+    for (name, &last) in &last_use {
+        let Some(instr) = work.basic_blocks.iter().flat_map(|bb| &bb.instrs).find(|i| i.try_get_result() == Some(*name)) else {
+            continue;
+        };
+        let bits = type_bits(&module.type_of(instr));
+        if bits > 0 && last > ready[name] {
+            ranges.push(LiveRange { name: format!("%{}", block_label(name)), bits, ready: ready[name], last_use: last });
+        }
+    }
+    ranges.sort_by(|a, b| (a.ready, &a.name).cmp(&(b.ready, &b.name)));
+
+    // -O0 locals the body touches
+    let mut variables: BTreeMap<&Name, u64> = BTreeMap::new();
+    for &b in body {
+        for instr in &work.basic_blocks[b].instrs {
+            if let Some(acc) = access(instr)
+                && let Operand::LocalOperand { name, .. } = acc.address
+                && mem.is_scalar_alloca(name)
+            {
+                let bits = match instr {
+                    Instruction::Load(_) => type_bits(&module.type_of(instr)),
+                    Instruction::Store(store) => type_bits(&module.type_of(&store.value)),
+                    _ => 0,
+                };
+                variables.insert(name, bits);
+            }
+        }
+    }
+    Pressure { depth, ranges, variable_bits: variables.values().sum(), input_bits, notes }
+}
+
+fn is_inside(loop_info: &LoopInfo, inner: usize, outer: usize) -> bool {
+    let mut current = loop_info.loops[inner].parent;
+    while let Some(p) = current {
+        if p == outer {
+            return true;
+        }
+        current = loop_info.loops[p].parent;
+    }
+    false
+}
+
+/// `--live [function] [--loop <header|line>] [--source-dir <dir>]... [schedule options]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = format!(
+        "Usage: cargo run <filename.ll> --live [function] [--loop <header|line>] [--source-dir <dir>]... {}",
+        ScheduleOptions::USAGE
+    );
+    let mut options = ScheduleOptions::new();
+    let mut func_name = None;
+    let mut loop_spec = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        if !arg.starts_with("--") {
+            func_name = Some(arg);
+            continue;
+        }
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?;
+        match arg.as_str() {
+            "--loop" => loop_spec = Some(value),
+            "--source-dir" => search_dirs.push(PathBuf::from(value)),
+            _ if options.apply(arg, value)? => {}
+            _ => return Err(format!("Unknown option '{}'\n{}", arg, usage)),
+        }
+    }
+    let mut sources = Sources::new(search_dirs);
+    let metadata = LoopMetadata::load(module);
+
+    let functions: Vec<&Function> = match func_name {
+        Some(name) => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == *name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        None => module.functions.iter().collect(),
+    };
+    for func in functions {
+        let loop_info = LoopInfo::new(func);
+        if loop_info.loops.is_empty() {
+            continue;
+        }
+        let names = hls_loop_names(&loop_info, &mut sources);
+        // The loop asked for, else loops with a PIPELINE pragma, else innermost loops with a constant
+        // trip count, which Vitis pipelines on its own
+        let pipelined: Vec<usize> = match loop_spec {
+            Some(spec) => vec![loop_info.find(spec).ok_or_else(|| format!("Loop '{}' not found in {}", spec, func.name))?],
+            None => {
+                let pragmas = collect(func, &loop_info, &metadata, &mut sources);
+                let marked: Vec<usize> = (0..loop_info.loops.len())
+                    .filter(|&l| pragmas.loops[l].iter().any(|p| p.name == "PIPELINE" && p.option("off").is_none()))
+                    .collect();
+                if marked.is_empty() {
+                    (0..loop_info.loops.len())
+                        .filter(|&l| !loop_info.loops.iter().any(|lp| lp.parent == Some(l)))
+                        .filter(|&l| loop_info.constant_trip_count(l).is_some())
+                        .collect()
+                } else {
+                    marked
+                }
+            }
+        };
+        println!("Function: {}", func.name);
+        for l in pipelined {
+            let p = pressure(module, func, &loop_info, l, &options);
+            println!("  Loop {} ({}): {} stage(s) at {:.2} ns", loop_info.describe(l), names[l], p.depth, options.clock.effective());
+            for note in &p.notes {
+                println!("    Note: {}", note);
+            }
+            if p.depth > 0 {
+                println!("    {:<7} {:>6} {:>5}  Values held into the next stage", "Stage", "Values", "Bits");
+                for c in 0..p.depth {
+                    let live: Vec<&LiveRange> = p.live_at(c).collect();
+                    let mut listed: Vec<String> =
+                        live.iter().take(MAX_LISTED).map(|r| format!("{} ({}b)", r.name, r.bits)).collect();
+                    if live.len() > MAX_LISTED {
+                        listed.push(format!("... {} more", live.len() - MAX_LISTED));
+                    }
+                    println!("    {:<7} {:>6} {:>5}  {}", c, live.len(), p.live_bits(c), listed.join(", "));
+                }
+            }
+            let busiest = (0..p.depth).max_by_key(|&c| p.live_bits(c));
+            println!(
+                "    FF estimate: {} (pipeline registers {}, variables {}, loop inputs {}){}",
+                p.pipeline_bits() + p.variable_bits + p.input_bits,
+                p.pipeline_bits(),
+                p.variable_bits,
+                p.input_bits,
+                busiest.filter(|&c| p.live_bits(c) > 0).map_or(String::new(), |c| format!(", most after stage {}", c))
+            );
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A pipelined loop carrying %i and %acc in phis, with a two-trip inner loop on -O0 locals
+    // that is unrolled into it
+    const PIPELINED: &str = "
+define void @scale(ptr %in, ptr %out, i32 %k) {
+entry:
+  %j.addr = alloca i32
+  %s.addr = alloca i32
+  br label %loop
+loop:
+  %i = phi i32 [ 0, %entry ], [ %i.next, %latch ]
+  %acc = phi i32 [ 0, %entry ], [ %acc.next, %latch ]
+  %p = getelementptr inbounds i32, ptr %in, i32 %i
+  %x = load i32, ptr %p
+  %m = mul i32 %x, %k
+  store i32 0, ptr %j.addr
+  store i32 %m, ptr %s.addr
+  br label %inner
+inner:
+  %j = load i32, ptr %j.addr
+  %inner.cmp = icmp slt i32 %j, 2
+  br i1 %inner.cmp, label %inner.body, label %latch
+inner.body:
+  %s = load i32, ptr %s.addr
+  %s.next = add i32 %s, %j
+  store i32 %s.next, ptr %s.addr
+  %j.next = add i32 %j, 1
+  store i32 %j.next, ptr %j.addr
+  br label %inner
+latch:
+  %sum = load i32, ptr %s.addr
+  %acc.next = add i32 %acc, %sum
+  %q = getelementptr inbounds i32, ptr %out, i32 %i
+  store i32 %acc.next, ptr %q
+  %i.next = add i32 %i, 1
+  %cmp = icmp slt i32 %i.next, 16
+  br i1 %cmp, label %loop, label %exit
+exit:
+  ret void
+}
+";
+
+    #[test]
+    fn counts_registers_per_stage() {
+        let module = Module::from_ir_str(PIPELINED).unwrap();
+        let func = &module.functions[0];
+        let loop_info = LoopInfo::new(func);
+        let l = loop_info.find_by_header("loop").unwrap();
+        let p = pressure(&module, func, &loop_info, l, &ScheduleOptions::new());
+        // The inner loop is unrolled into the body, which takes 7 stages
+        assert!(p.notes.is_empty(), "{:?}", p.notes);
+        assert_eq!(p.depth, 7);
+
+        // %i and %acc are read in the last stage; the values the phis carry are held to the end
+        let ranges: Vec<(&str, u32, u32)> = p.ranges.iter().map(|r| (r.name.as_str(), r.ready, r.last_use)).collect();
+        assert_eq!(ranges, [("%acc", 0, 6), ("%i", 0, 6), ("%acc.next", 6, 7), ("%i.next", 6, 7)]);
+        assert!((0..p.depth).all(|c| p.live_bits(c) == 64));
+
+        // j and s are locals, %k comes from outside the loop
+        assert_eq!((p.variable_bits, p.input_bits), (64, 32));
+        assert_eq!(p.pipeline_bits() + p.variable_bits + p.input_bits, 544);
+    }
+}
@@ -0,0 +1,539 @@
+use crate::cfg::{dominates, Cfg};
+use crate::ir_util::{block_label, block_line, const_int};
+use crate::memory::MemoryInfo;
+use llvm_ir::instruction::ICmp;
+use llvm_ir::{Function, Instruction, IntPredicate, Name, Operand, Terminator, Type};
+use std::cell::OnceCell;
+use std::collections::BTreeSet;
+
+// Trip counts above this are reported as unknown
+const MAX_TRIP_COUNT: u64 = 1 << 24;
+
+/// A natural loop, identified by its header block and all blocks that can reach a back edge.
+pub struct Loop {
+    pub header: usize,
+    pub blocks: BTreeSet<usize>,
+    // Blocks that jump back to the header
+    pub latches: Vec<usize>,
+    // Index of the enclosing loop in `LoopInfo::loops`
+    pub parent: Option<usize>,
+    // 1 for outermost loops
+    pub depth: usize,
+}
+
+/// All natural loops of a function, ordered by the position of their header block.
+pub struct LoopInfo<'f> {
+    pub func: &'f Function,
+    pub cfg: Cfg<'f>,
+    pub loops: Vec<Loop>,
+    // Built on the first trip count query
+    mem: OnceCell<MemoryInfo<'f>>,
+    counters: Vec<OnceCell<Option<Counter<'f>>>>,
+}
+
+impl<'f> LoopInfo<'f> {
+    pub fn new(func: &'f Function) -> Self {
+        let cfg = Cfg::new(func);
+        let idom = cfg.immediate_dominators();
+        let mut loops: Vec<Loop> = Vec::new();
+
+        // A back edge goes from a block to one of its dominators
+        for (block, succs) in cfg.succs.iter().enumerate() {
+            for &header in succs {
+                if idom[block].is_none() && block != 0 {
+                    continue; // unreachable
+                }
+                if !dominates(&idom, header, block) {
+                    continue;
+                }
+                // Collect the loop body by walking predecessors back from the latch
+                let mut body = BTreeSet::from([header, block]);
+                let mut worklist = vec![block];
+                while let Some(b) = worklist.pop() {
+                    if b == header {
+                        continue;
+                    }
+                    for &p in &cfg.preds[b] {
+                        if body.insert(p) {
+                            worklist.push(p);
+                        }
+                    }
+                }
+                // Loops sharing a header are merged into one
+                if let Some(existing) = loops.iter_mut().find(|l| l.header == header) {
+                    existing.blocks.extend(body);
+                    existing.latches.push(block);
+                } else {
+                    loops.push(Loop { header, blocks: body, latches: vec![block], parent: None, depth: 1 });
+                }
+            }
+        }
+        loops.sort_by_key(|l| l.header);
+
+        // The parent of a loop is the smallest other loop that contains its header
+        for i in 0..loops.len() {
+            let parent = (0..loops.len())
+                .filter(|&j| j != i && loops[j].blocks.contains(&loops[i].header) && loops[j].blocks.len() > loops[i].blocks.len())
+                .min_by_key(|&j| loops[j].blocks.len());
+            loops[i].parent = parent;
+        }
+        for i in 0..loops.len() {
+            let mut depth = 1;
+            let mut current = loops[i].parent;
+            while let Some(p) = current {
+                depth += 1;
+                current = loops[p].parent;
+            }
+            loops[i].depth = depth;
+        }
+
+        let counters = loops.iter().map(|_| OnceCell::new()).collect();
+        LoopInfo { func, cfg, loops, mem: OnceCell::new(), counters }
+    }
+
+    /// Edges from outside the loop into its header.
+    pub fn entry_edges(&self, l: usize) -> Vec<(usize, usize)> {
+        let header = self.loops[l].header;
+        self.cfg.preds[header]
+            .iter()
+            .filter(|p| !self.loops[l].blocks.contains(p))
+            .map(|&p| (p, header))
+            .collect()
+    }
+
+    /// Edges from inside the loop to blocks outside of it.
+    pub fn exit_edges(&self, l: usize) -> Vec<(usize, usize)> {
+        let body = &self.loops[l].blocks;
+        body.iter()
+            .flat_map(|&b| self.cfg.succs[b].iter().map(move |&s| (b, s)))
+            .filter(|(_, s)| !body.contains(s))
+            .collect()
+    }
+
+    /// Source line of the loop, taken from the first debug location in its header.
+    pub fn line(&self, l: usize) -> Option<u32> {
+        block_line(self.func, self.cfg.names[self.loops[l].header])
+    }
+
+    /// Short description of a loop such as "for.cond (line 25)".
+    pub fn describe(&self, l: usize) -> String {
+        let header = block_label(self.cfg.names[self.loops[l].header]);
+        match self.line(l) {
+            Some(line) => format!("{} (line {})", header, line),
+            None => header,
+        }
+    }
+
+    /// Finds a loop by the label of its header block ("for.cond4", or "5" / "%5" for a numbered block).
+    pub fn find_by_header(&self, label: &str) -> Option<usize> {
+        let label = label.trim_start_matches('%');
+        (0..self.loops.len()).find(|&l| block_label(self.cfg.names[self.loops[l].header]) == label)
+    }
+
+    /// Finds a loop from a user's spec: a header block ("for.cond4", "%5") or a source line ("9").
+    /// A bare number is taken as a line first and as a numbered header if no loop is on that line.
+    pub fn find(&self, spec: &str) -> Option<usize> {
+        if spec.starts_with('%') {
+            return self.find_by_header(spec);
+        }
+        match spec.parse::<u32>() {
+            Ok(line) => (0..self.loops.len()).find(|&l| self.line(l) == Some(line)).or_else(|| self.find_by_header(spec)),
+            Err(_) => self.find_by_header(spec),
+        }
+    }
+
+    /// Innermost loop containing a block, if any.
+    pub fn innermost(&self, block: usize) -> Option<usize> {
+        (0..self.loops.len())
+            .filter(|&l| self.loops[l].blocks.contains(&block))
+            .max_by_key(|&l| self.loops[l].depth)
+    }
+
+    /// Trip count of a counted loop whose header compares the induction variable with a constant.
+    /// The induction variable is either a phi in the header or, at -O0, a local variable that is
+    /// initialized before the loop and incremented by a constant inside it.
+    pub fn constant_trip_count(&self, l: usize) -> Option<u64> {
+        self.counter(l).map(|c| c.trips)
+    }
+
+    /// The variable a counted loop steps and the values it takes in the body.
+    pub fn counter(&self, l: usize) -> Option<Counter<'f>> {
+        *self.counters[l].get_or_init(|| self.find_counter(l))
+    }
+
+    fn find_counter(&self, l: usize) -> Option<Counter<'f>> {
+        let mem = self.mem.get_or_init(|| MemoryInfo::new(self.func));
+        let HeaderTest { icmp, var, bound, var_first, stay_if_true } = self.header_test(mem, l)?;
+        let (init, step) = self.induction(mem, l, var)?;
+        let Operand::LocalOperand { name, ty } = var else { return None };
+        let Type::IntegerType { bits } = ty.as_ref() else { return None };
+        // Put the variable on the left, then turn the predicate into the one that keeps the loop going
+        let predicate = if var_first { icmp.predicate } else { swapped(icmp.predicate) };
+        let predicate = if stay_if_true { predicate } else { inverse(predicate) };
+        let trips = trip_count(predicate, *bits, init, step, bound).filter(|&t| t < MAX_TRIP_COUNT)?;
+        let stepped = self.func.basic_blocks.iter().flat_map(|bb| &bb.instrs).find_map(|instr| match instr {
+            Instruction::Phi(phi) if &phi.dest == name => Some(&phi.dest),
+            Instruction::Load(load) if &load.dest == name => match &load.address {
+                Operand::LocalOperand { name: slot, .. } => Some(slot),
+                _ => None,
+            },
+            _ => None,
+        })?;
+        Some(Counter { var: stepped, init, step, trips })
+    }
+
+    // The compare that decides whether the loop goes on, with the constant it tests against
+    fn header_test(&self, mem: &MemoryInfo, l: usize) -> Option<HeaderTest<'f>> {
+        let lp = &self.loops[l];
+        let header = &self.func.basic_blocks[lp.header];
+        let Terminator::CondBr(br) = &header.term else { return None };
+        let in_loop = |name: &Name| self.cfg.names.iter().position(|n| *n == name).is_some_and(|b| lp.blocks.contains(&b));
+        let stay_if_true = in_loop(&br.true_dest);
+        if stay_if_true == in_loop(&br.false_dest) {
+            return None;
+        }
+        let Operand::LocalOperand { name: cond, .. } = &br.condition else { return None };
+        let icmp = header.instrs.iter().find_map(|instr| match instr {
+            Instruction::ICmp(icmp) if &icmp.dest == cond => Some(icmp),
+            _ => None,
+        })?;
+        // The bound may be a local variable holding a constant (e.g. `k <= kernel_offset` at -O0)
+        let (var, bound, var_first) = match (mem.const_value(&icmp.operand0), mem.const_value(&icmp.operand1)) {
+            (None, Some(c)) => (&icmp.operand0, c, true),
+            (Some(c), None) => (&icmp.operand1, c, false),
+            _ => return None,
+        };
+        Some(HeaderTest { icmp, var, bound, var_first, stay_if_true })
+    }
+
+    // Initial value and step of the variable compared in the loop header
+    fn induction(&self, mem: &MemoryInfo, l: usize, var: &Operand) -> Option<(i64, i64)> {
+        let lp = &self.loops[l];
+        let Operand::LocalOperand { name, .. } = var else { return None };
+        let def = |name: &Name| {
+            self.func.basic_blocks.iter().flat_map(|bb| &bb.instrs).find(|instr| instr.try_get_result() == Some(name))
+        };
+        // Blocks entering the loop; only a single preheader is supported
+        let entries = self.entry_edges(l);
+        let [(preheader, _)] = entries.as_slice() else { return None };
+        match def(name)? {
+            Instruction::Phi(phi) => {
+                let mut init = None;
+                let mut step = None;
+                for (value, block) in &phi.incoming_values {
+                    if block == self.cfg.names[*preheader] {
+                        init = mem.const_value(value);
+                    } else if let Operand::LocalOperand { name: next, .. } = value {
+                        step = add_step(def(next)?, name);
+                    }
+                }
+                Some((init?, step?))
+            }
+            // -O0: `%0 = load i32, ptr %k` where %k is stored once before the loop and once inside it
+            Instruction::Load(load) => {
+                let Operand::LocalOperand { name: slot, .. } = &load.address else { return None };
+                let stores_to = |block: usize| {
+                    self.func.basic_blocks[block].instrs.iter().filter_map(move |instr| match instr {
+                        Instruction::Store(store) if matches!(&store.address, Operand::LocalOperand { name, .. } if name == slot) => Some(&store.value),
+                        _ => None,
+                    })
+                };
+                let init = mem.const_value(stores_to(*preheader).next_back()?)?;
+                let updates: Vec<&Operand> = lp.blocks.iter().flat_map(|&b| stores_to(b)).collect();
+                // Several increments (an unrolled body) add up, as long as the loop can only exit from the header
+                if updates.len() > 1 && self.exit_edges(l).iter().any(|&(from, _)| from != lp.header) {
+                    return None;
+                }
+                // Each increment reads the variable back from the same slot
+                let reloaded = |op: &Operand| match op {
+                    Operand::LocalOperand { name, .. } => matches!(def(name), Some(Instruction::Load(l)) if l.address == load.address),
+                    _ => false,
+                };
+                let mut step = 0;
+                for update in updates {
+                    let Operand::LocalOperand { name: next, .. } = update else { return None };
+                    let Instruction::Add(add) = def(next)? else { return None };
+                    step += match (const_int(&add.operand0), const_int(&add.operand1)) {
+                        (None, Some(c)) if reloaded(&add.operand0) => c,
+                        (Some(c), None) if reloaded(&add.operand1) => c,
+                        _ => return None,
+                    };
+                }
+                Some((init, step))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Induction variable of a counted loop: the header phi, or at -O0 the local it is reloaded from.
+#[derive(Clone, Copy)]
+pub struct Counter<'f> {
+    pub var: &'f Name,
+    pub init: i64,
+    pub step: i64,
+    pub trips: u64,
+}
+
+impl Counter<'_> {
+    /// Smallest and largest value the variable has inside the body, None if the body never runs.
+    pub fn bounds(&self) -> Option<(i64, i64)> {
+        let last = self.init.checked_add(self.step.checked_mul(self.trips.checked_sub(1)? as i64)?)?;
+        Some((self.init.min(last), self.init.max(last)))
+    }
+}
+
+struct HeaderTest<'f> {
+    icmp: &'f ICmp,
+    var: &'f Operand,
+    bound: i64,
+    // Whether the variable is the left operand, and whether a true result stays in the loop
+    var_first: bool,
+    stay_if_true: bool,
+}
+
+// Step of `next = var + C` or `next = var - C`
+fn add_step(next: &Instruction, var: &Name) -> Option<i64> {
+    let is_var = |op: &Operand| matches!(op, Operand::LocalOperand { name, .. } if name == var);
+    match next {
+        Instruction::Add(add) if is_var(&add.operand0) => const_int(&add.operand1),
+        Instruction::Add(add) if is_var(&add.operand1) => const_int(&add.operand0),
+        Instruction::Sub(sub) if is_var(&sub.operand0) => const_int(&sub.operand1).map(|c| -c),
+        _ => None,
+    }
+}
+
+// The predicate with its operands swapped: `a < b` is `b > a`
+fn swapped(predicate: IntPredicate) -> IntPredicate {
+    use IntPredicate::*;
+    match predicate {
+        SLT => SGT,
+        SLE => SGE,
+        SGT => SLT,
+        SGE => SLE,
+        ULT => UGT,
+        ULE => UGE,
+        UGT => ULT,
+        UGE => ULE,
+        p => p,
+    }
+}
+
+// The predicate that holds exactly when this one doesn't
+fn inverse(predicate: IntPredicate) -> IntPredicate {
+    use IntPredicate::*;
+    match predicate {
+        EQ => NE,
+        NE => EQ,
+        SLT => SGE,
+        SLE => SGT,
+        SGT => SLE,
+        SGE => SLT,
+        ULT => UGE,
+        ULE => UGT,
+        UGT => ULE,
+        UGE => ULT,
+    }
+}
+
+/// Number of times `var <predicate> bound` holds for var = init, init + step, ... in `bits`-bit
+/// arithmetic, or None if the loop never exits or only exits after wrapping around.
+fn trip_count(predicate: IntPredicate, bits: u32, init: i64, step: i64, bound: i64) -> Option<u64> {
+    use IntPredicate::*;
+    if !(1..=64).contains(&bits) {
+        return None;
+    }
+    let modulus = 1i128 << bits;
+    let wrap = |v: i128| v.rem_euclid(modulus);
+    // Step as a signed `bits`-bit value, so adding 255 to an i8 counts down by one
+    let step = match wrap(step as i128) {
+        s if s >= modulus / 2 => s - modulus,
+        s => s,
+    };
+    if step == 0 {
+        return None;
+    }
+    // Values as the compare sees them
+    let signed = matches!(predicate, SLT | SLE | SGT | SGE);
+    let (lo, hi) = if signed { (-modulus / 2, modulus / 2 - 1) } else { (0, modulus - 1) };
+    let value = |v: i64| match wrap(v as i128) {
+        v if v > hi => v - modulus,
+        v => v,
+    };
+    let (init, bound) = (value(init), value(bound));
+
+    if matches!(predicate, EQ | NE) {
+        if (init == bound) != (predicate == EQ) {
+            return Some(0);
+        }
+        if predicate == EQ {
+            return Some(1);
+        }
+        // Smallest k with k * step = bound - init (mod 2^bits)
+        let distance = wrap(bound - init);
+        let twos = step.trailing_zeros().min(bits);
+        if distance % (1 << twos) != 0 {
+            return None;
+        }
+        // Work modulo 2^(bits - twos), where the rest of the step is odd and has an inverse
+        let mask = ((modulus >> twos) - 1) as u128;
+        let odd = (step >> twos) as u128 & mask;
+        // Newton's iteration doubles the correct low bits of the inverse each round
+        let mut inv = odd;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u128.wrapping_sub(odd.wrapping_mul(inv))) & mask;
+        }
+        return Some(((distance >> twos) as u128).wrapping_mul(inv) as u64 & mask as u64);
+    }
+
+    // The loop goes on while the variable is at most `limit` (or at least, for > and >=)
+    let (up, limit) = match predicate {
+        SLT | ULT => (true, bound - 1),
+        SLE | ULE => (true, bound),
+        SGT | UGT => (false, bound + 1),
+        _ => (false, bound),
+    };
+    let holds = |v: i128| if up { v <= limit } else { v >= limit };
+    if !holds(init) {
+        return Some(0);
+    }
+    let toward_exit = (step > 0) == up;
+    let k = if toward_exit {
+        // Leaves the range on the far side of the limit, unless it wraps past the end first
+        let k = (limit - init).abs() / step.abs() + 1;
+        let next = init + k * step;
+        if next < lo || next > hi {
+            return None;
+        }
+        k
+    } else {
+        // Runs away from the limit until it wraps; it exits only if the wrapped value fails the test
+        let k = if step > 0 { (hi - init) / step + 1 } else { (init - lo) / -step + 1 };
+        let next = init + k * step;
+        let wrapped = if next > hi { next - modulus } else { next + modulus };
+        if holds(wrapped) {
+            return None;
+        }
+        k
+    };
+    u64::try_from(k).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llvm_ir::Module;
+
+    // An -O1 style loop where every block is numbered
+    const NUMBERED: &str = "
+define i32 @count(i32 %n) {
+  br label %1
+1:
+  %2 = phi i32 [ 0, %0 ], [ %5, %4 ]
+  %3 = icmp slt i32 %2, 10
+  br i1 %3, label %4, label %6
+4:
+  %5 = add nsw i32 %2, 1
+  br label %1
+6:
+  ret i32 %2
+}
+";
+
+    #[test]
+    fn finds_numbered_headers() {
+        let module = Module::from_ir_str(NUMBERED).unwrap();
+        let info = LoopInfo::new(&module.functions[0]);
+        assert_eq!(info.find_by_header("1"), Some(0));
+        assert_eq!(info.find_by_header("%1"), Some(0));
+        assert_eq!(info.find_by_header("4"), None);
+        assert_eq!(info.find("%1"), Some(0));
+        // Without debug info no loop is on line 1, so the number falls back to the header
+        assert_eq!(info.find("1"), Some(0));
+    }
+
+    // `for (uint8_t i = 0; i < 200; i++)`: 200 is -56 as an i8 constant
+    const UNSIGNED_I8: &str = "
+define void @bytes() {
+entry:
+  br label %loop
+loop:
+  %i = phi i8 [ 0, %entry ], [ %next, %body ]
+  %cmp = icmp ult i8 %i, -56
+  br i1 %cmp, label %body, label %exit
+body:
+  %next = add i8 %i, 1
+  br label %loop
+exit:
+  ret void
+}
+";
+
+    #[test]
+    fn counts_phi_loops() {
+        let module = Module::from_ir_str(NUMBERED).unwrap();
+        let info = LoopInfo::new(&module.functions[0]);
+        assert_eq!(info.constant_trip_count(0), Some(10));
+        let counter = info.counter(0).unwrap();
+        assert_eq!((counter.init, counter.step, counter.bounds()), (0, 1, Some((0, 9))));
+    }
+
+    #[test]
+    fn compares_at_the_icmp_width() {
+        let module = Module::from_ir_str(UNSIGNED_I8).unwrap();
+        let info = LoopInfo::new(&module.functions[0]);
+        assert_eq!(info.constant_trip_count(0), Some(200));
+    }
+
+    #[test]
+    fn closed_form_trip_counts() {
+        use IntPredicate::*;
+        assert_eq!(trip_count(SLT, 32, 0, 1, 10), Some(10));
+        assert_eq!(trip_count(SLE, 32, 0, 3, 10), Some(4));
+        assert_eq!(trip_count(SGT, 32, 10, -2, 0), Some(5));
+        assert_eq!(trip_count(SLT, 32, 10, 1, 0), Some(0));
+        // i8 250, 251, ... 255, 0, ... 3 before it equals 4
+        assert_eq!(trip_count(NE, 8, -6, 1, 4), Some(10));
+        assert_eq!(trip_count(NE, 8, 0, 2, 5), None);
+        // Counting down from 0 in i8 wraps to 127, which ends `i < 10`
+        assert_eq!(trip_count(SLT, 8, 0, -1, 10), Some(129));
+        // 0, 2, ... 126 and then -128, which still passes `i < 127`
+        assert_eq!(trip_count(SLT, 8, 0, 2, 127), None);
+        assert_eq!(trip_count(ULT, 16, 0, 1, 60000), Some(60000));
+        assert_eq!(trip_count(EQ, 32, 3, 1, 3), Some(1));
+        assert_eq!(trip_count(SLT, 32, 0, 0, 10), None);
+    }
+
+    // Any count the closed form gives matches stepping an i8 counter one iteration at a time
+    #[test]
+    fn closed_form_matches_stepping() {
+        use IntPredicate::*;
+        let holds = |predicate, a: i64, b: i64| {
+            let (ua, ub) = (a as u8, b as u8);
+            let (sa, sb) = (a as i8, b as i8);
+            match predicate {
+                EQ => ua == ub,
+                NE => ua != ub,
+                SLT => sa < sb,
+                SLE => sa <= sb,
+                SGT => sa > sb,
+                SGE => sa >= sb,
+                ULT => ua < ub,
+                ULE => ua <= ub,
+                UGT => ua > ub,
+                UGE => ua >= ub,
+            }
+        };
+        for predicate in [EQ, NE, SLT, SLE, SGT, SGE, ULT, ULE, UGT, UGE] {
+            for init in (-128..128).step_by(7) {
+                for bound in (-128..128).step_by(11) {
+                    for step in [-5, -1, 1, 2, 3, 64, 255] {
+                        let Some(trips) = trip_count(predicate, 8, init, step, bound) else { continue };
+                        let stepped = (0..600).find(|&k| !holds(predicate, init + k * step, bound));
+                        assert_eq!(stepped, Some(trips as i64), "{:?} {} {} {}", predicate, init, step, bound);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,650 @@
+use crate::ir_util::{block_label, ir_lines, quote};
+use crate::loops::LoopInfo;
+use crate::opt::lint;
+use crate::{explain_llvm_line, find_function_line_spans};
+use llvm_ir::{Instruction, Module};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+// LSP constants
+const SEVERITY_ERROR: u32 = 1;
+const SEVERITY_WARNING: u32 = 2;
+const SEVERITY_HINT: u32 = 4;
+const SYMBOL_FUNCTION: u32 = 12;
+const SYMBOL_VARIABLE: u32 = 13;
+const SYMBOL_KEY: u32 = 20;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// A parsed JSON value. Objects keep their keys in order.
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The value at a path of object keys.
+    fn at(&self, path: &[&str]) -> Option<&Json> {
+        path.iter().try_fold(self, |json, key| match json {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        })
+    }
+
+    fn str_at(&self, path: &[&str]) -> Option<&str> {
+        match self.at(path)? {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn usize_at(&self, path: &[&str]) -> Option<usize> {
+        match self.at(path)? {
+            Json::Number(n) if *n >= 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Json::Null => "null".to_string(),
+            Json::Bool(b) => b.to_string(),
+            Json::Number(n) => n.to_string(),
+            Json::String(s) => quote(s),
+            Json::Array(items) => format!("[{}]", items.iter().map(|i| i.to_text()).collect::<Vec<_>>().join(",")),
+            Json::Object(fields) => {
+                format!("{{{}}}", fields.iter().map(|(k, v)| format!("{}:{}", quote(k), v.to_text())).collect::<Vec<_>>().join(","))
+            }
+        }
+    }
+}
+
+/// Recursive descent over the characters of a JSON text.
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser { chars: text.chars().peekable() };
+        parser.value()
+    }
+
+    fn skip_space(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_space();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            other => Err(format!("Invalid JSON: expected '{}', found {:?}", expected, other)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_space();
+        match self.chars.peek().copied() {
+            Some('{') => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.skip_space();
+                if self.chars.next_if_eq(&'}').is_some() {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_space();
+                    match self.chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Json::Object(fields)),
+                        other => return Err(format!("Invalid JSON: expected ',' or '}}', found {:?}", other)),
+                    }
+                }
+            }
+            Some('[') => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.skip_space();
+                if self.chars.next_if_eq(&']').is_some() {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_space();
+                    match self.chars.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Json::Array(items)),
+                        other => return Err(format!("Invalid JSON: expected ',' or ']', found {:?}", other)),
+                    }
+                }
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_alphanumeric() || matches!(c, '-' | '+' | '.')) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    number => number.parse().map(Json::Number).map_err(|_| format!("Invalid JSON value '{}'", number)),
+                }
+            }
+            None => Err("Invalid JSON: unexpected end".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next().ok_or("Invalid JSON: unterminated string")? {
+                '"' => return Ok(out),
+                '\\' => match self.chars.next().ok_or("Invalid JSON: unterminated string")? {
+                    'n' => out.push('\n'),
+                    't' => out.push('\t'),
+                    'r' => out.push('\r'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4).filter_map(|_| self.chars.next()).collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("Invalid JSON escape \\u{}", hex))?;
+                        // Surrogate pairs come as two escapes
+                        let code = if (0xD800..0xDC00).contains(&code) && self.chars.next() == Some('\\') && self.chars.next() == Some('u') {
+                            let low: String = (0..4).filter_map(|_| self.chars.next()).collect();
+                            let low = u32::from_str_radix(&low, 16).unwrap_or(0xDC00);
+                            0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                        } else {
+                            code
+                        };
+                        out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+/// What the `character` of an LSP position counts, agreed on in `initialize`. UTF-16 code units
+/// unless the client offers "utf-32", which are the `char`s lines are indexed by here.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Encoding {
+    Utf16,
+    Utf32,
+}
+
+impl Encoding {
+    fn negotiate(initialize: &Json) -> Encoding {
+        match initialize.at(&["params", "capabilities", "general", "positionEncodings"]) {
+            Some(Json::Array(offered)) if offered.iter().any(|e| matches!(e, Json::String(s) if s == "utf-32")) => Encoding::Utf32,
+            _ => Encoding::Utf16,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf16 => "utf-16",
+            Encoding::Utf32 => "utf-32",
+        }
+    }
+}
+
+/// An open .ll document and, if it parses, its module.
+struct Document {
+    lines: Vec<String>,
+    module: Result<Module, String>,
+    encoding: Encoding,
+}
+
+impl Document {
+    fn new(text: &str, encoding: Encoding) -> Document {
+        // llvm-ir parses files, so the unsaved text goes through a temporary one
+        let path = std::env::temp_dir().join(format!("llvm_ir_tool_lsp_{}.ll", std::process::id()));
+        let module = fs::write(&path, text)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+            .and_then(|_| Module::from_ir_path(&path));
+        let _ = fs::remove_file(&path);
+        Document { lines: text.lines().map(|l| l.to_string()).collect(), module, encoding }
+    }
+
+    /// Position of the `char` at `index` of a line, in the negotiated encoding.
+    fn column(&self, line: usize, index: usize) -> usize {
+        match self.encoding {
+            Encoding::Utf32 => index,
+            Encoding::Utf16 => self.lines.get(line).map_or(index, |l| l.chars().take(index).map(char::len_utf16).sum()),
+        }
+    }
+
+    /// Index of the `char` a position in the negotiated encoding falls on.
+    fn char_index(&self, line: usize, column: usize) -> usize {
+        let Some(text) = self.lines.get(line).filter(|_| self.encoding == Encoding::Utf16) else { return column };
+        let mut units = 0;
+        text.chars().take_while(|c| {
+            units += c.len_utf16();
+            units <= column
+        })
+        .count()
+    }
+
+    /// An LSP range between `char` indices of lines.
+    fn range(&self, start_line: usize, start_char: usize, end_line: usize, end_char: usize) -> String {
+        range(start_line, self.column(start_line, start_char), end_line, self.column(end_line, end_char))
+    }
+
+    fn range_of_line(&self, line: usize) -> String {
+        self.range(line, 0, line, self.lines.get(line).map_or(0, |l| l.chars().count()))
+    }
+
+    /// Function containing a line, with its span of lines.
+    fn function_at(&self, line: usize) -> Option<(String, (usize, usize))> {
+        find_function_line_spans(&self.lines)
+            .into_iter()
+            .find(|(_, (start, end))| (*start..=*end).contains(&line))
+            .map(|(name, span)| (name.to_string(), span))
+    }
+
+    /// The %value, @global, !metadata or label at a character of a line, with its start column.
+    fn token_at(&self, line: usize, character: usize) -> Option<(String, usize)> {
+        let chars: Vec<char> = self.lines.get(line)?.chars().collect();
+        let is_name = |c: char| c.is_alphanumeric() || matches!(c, '.' | '_' | '$' | '-');
+        let mut start = character.min(chars.len());
+        while start > 0 && is_name(chars[start - 1]) {
+            start -= 1;
+        }
+        let mut end = character.min(chars.len());
+        while end < chars.len() && is_name(chars[end]) {
+            end += 1;
+        }
+        if start > 0 && matches!(chars[start - 1], '%' | '@' | '!') {
+            start -= 1;
+        }
+        (end > start).then(|| (chars[start..end].iter().collect(), start))
+    }
+
+    /// Line and `char` column where a token is defined.
+    fn definition(&self, line: usize, token: &str) -> Option<(usize, usize)> {
+        // `hit` gives byte offsets
+        let chars = |i: usize, col: usize| (i, self.lines[i][..col].chars().count());
+        let find = |range: std::ops::Range<usize>, hit: &dyn Fn(&str) -> Option<usize>| {
+            range.into_iter().find_map(|i| hit(&self.lines[i]).map(|col| chars(i, col)))
+        };
+        let all = 0..self.lines.len();
+        if let Some(name) = token.strip_prefix('@') {
+            let call = format!("@{}(", name);
+            let global = format!("@{} =", name);
+            return find(all, &|l| {
+                if (l.starts_with("define") || l.starts_with("declare")) && l.contains(&call) {
+                    l.find(&call)
+                } else {
+                    l.starts_with(&global).then_some(0)
+                }
+            });
+        }
+        if token.starts_with('!') {
+            let node = format!("{} =", token);
+            return find(all, &|l| l.starts_with(&node).then_some(0));
+        }
+        // Local values, labels and parameters live in the enclosing function
+        let (_, (start, end)) = self.function_at(line)?;
+        let name = token.trim_start_matches('%');
+        let assignment = format!("%{} =", name);
+        let label = format!("{}:", name);
+        find(start + 1..end, &|l| {
+            if l.trim_start().starts_with(&assignment) {
+                l.find('%')
+            } else {
+                l.starts_with(&label).then_some(0)
+            }
+        })
+        .or_else(|| {
+            // A parameter in the define line, not followed by more name characters
+            let define = &self.lines[start];
+            let param = format!("%{}", name);
+            define.match_indices(&param).map(|(i, _)| i).find(|&i| {
+                define[i + param.len()..].starts_with(|c: char| !(c.is_alphanumeric() || matches!(c, '.' | '_' | '$' | '-')))
+            })
+            .map(|col| chars(start, col))
+        })
+    }
+
+    /// Markdown shown when hovering over a line: the explanation, the source location and loop,
+    /// and where the value under the cursor comes from.
+    fn hover(&self, line: usize, character: usize) -> Option<String> {
+        let text = self.lines.get(line)?;
+        let trimmed = text.trim();
+        if trimmed.is_empty() || trimmed.starts_with(';') || trimmed == "}" {
+            return None;
+        }
+        let mut parts = Vec::new();
+        let (func_name, span) = self.function_at(line).unzip();
+        let func = self.module.as_ref().ok().zip(func_name.as_ref()).and_then(|(m, name)| m.get_func_by_name(name));
+        if let (Some(func), Some(span)) = (func, span) {
+            let loop_info = LoopInfo::new(func);
+            let rows = ir_lines(func, &self.lines, span);
+            if let Some(row) = rows.iter().find(|r| r.index == line) {
+                if let Some(b) = row.label {
+                    let names = |bs: &[usize]| bs.iter().map(|&b| block_label(loop_info.cfg.names[b])).collect::<Vec<_>>().join(", ");
+                    parts.push(format!(
+                        "Basic block **{}**: predecessors [{}], successors [{}]",
+                        block_label(&func.basic_blocks[b].name),
+                        names(&loop_info.cfg.preds[b]),
+                        names(&loop_info.cfg.succs[b])
+                    ));
+                } else if line == span.0 {
+                    let instrs: usize = func.basic_blocks.iter().map(|bb| bb.instrs.len()).sum();
+                    parts.push(format!("Function **{}**: {} blocks, {} instructions, {} loops", func.name, func.basic_blocks.len(), instrs, loop_info.loops.len()));
+                } else {
+                    parts.push(explain_llvm_line(text));
+                }
+                let mut place = Vec::new();
+                if let Some(loc) = &row.loc {
+                    place.push(format!("Source: {}:{}:{}", loc.filename, loc.line, loc.col.unwrap_or(0)));
+                }
+                if let Some(l) = row.block.and_then(|b| loop_info.innermost(b)) {
+                    place.push(format!("in loop {}, depth {}", loop_info.describe(l), loop_info.loops[l].depth));
+                }
+                if !place.is_empty() {
+                    parts.push(place.join(", "));
+                }
+            }
+        }
+        if parts.is_empty() {
+            parts.push(explain_llvm_line(text));
+        }
+        if let Some((token, _)) = self.token_at(line, character)
+            && token.starts_with(['%', '@'])
+            && let Some((def, _)) = self.definition(line, &token)
+            && def != line
+        {
+            parts.push(format!("`{}` is defined on line {}:\n```llvm\n{}\n```", token, def + 1, self.lines[def].trim()));
+        }
+        Some(parts.join("\n\n"))
+    }
+
+    /// Functions with their blocks, globals and declarations, as LSP DocumentSymbols.
+    fn symbols(&self) -> Vec<String> {
+        let symbol = |name: &str, kind: u32, (start, end): (usize, usize), children: Vec<String>| {
+            let range = self.range(start, 0, end, self.lines[end].chars().count());
+            let selection = self.range_of_line(start);
+            format!(
+                "{{\"name\":{},\"kind\":{},\"range\":{},\"selectionRange\":{},\"children\":[{}]}}",
+                quote(name),
+                kind,
+                range,
+                selection,
+                children.join(",")
+            )
+        };
+        let mut spans: Vec<(&str, (usize, usize))> = find_function_line_spans(&self.lines).into_iter().collect();
+        spans.sort_by_key(|(_, span)| *span);
+        let mut symbols = Vec::new();
+        for (i, text) in self.lines.iter().enumerate() {
+            if let Some(name) = text.strip_prefix('@').and_then(|t| t.split_once(" =")).map(|(n, _)| n) {
+                symbols.push((i, symbol(&format!("@{}", name), SYMBOL_VARIABLE, (i, i), Vec::new())));
+            } else if text.starts_with("declare")
+                && let Some(name) = text.split('@').nth(1).and_then(|t| t.split('(').next())
+            {
+                symbols.push((i, symbol(&format!("@{}", name), SYMBOL_FUNCTION, (i, i), Vec::new())));
+            }
+        }
+        for (name, (start, end)) in spans {
+            let blocks: Vec<String> = (start + 1..end)
+                .filter(|&i| !self.lines[i].starts_with(char::is_whitespace) && self.lines[i].contains(':') && !self.lines[i].starts_with(';'))
+                .map(|i| {
+                    // A block runs up to the line before the next label
+                    let next = (i + 1..end).find(|&j| !self.lines[j].starts_with(char::is_whitespace) && self.lines[j].contains(':'));
+                    let last = next.map_or(end - 1, |j| j - 1);
+                    let label = self.lines[i].split(':').next().unwrap_or_default();
+                    symbol(label, SYMBOL_KEY, (i, last.max(i)), Vec::new())
+                })
+                .collect();
+            symbols.push((start, symbol(name, SYMBOL_FUNCTION, (start, end), blocks)));
+        }
+        symbols.sort_by_key(|(line, _)| *line);
+        symbols.into_iter().map(|(_, s)| s).collect()
+    }
+
+    /// Parse errors, then the redundant loads, dead stores and foldable instructions --opt finds.
+    fn diagnostics(&self) -> Vec<String> {
+        let diagnostic = |line: usize, start: usize, end: usize, severity: u32, message: &str| {
+            format!(
+                "{{\"range\":{},\"severity\":{},\"source\":\"llvm_ir_tool\",\"message\":{}}}",
+                self.range(line, start, line, end),
+                severity,
+                quote(message)
+            )
+        };
+        let module = match &self.module {
+            Ok(module) => module,
+            Err(e) => {
+                // "<file>:<line>:<column>: error: <message>" from the LLVM parser
+                let location = Regex::new(r"\.ll:(\d+):(\d+): (?:error: )?([^\n]*)").unwrap();
+                return vec![match location.captures(e) {
+                    Some(caps) => {
+                        let line = caps[1].parse::<usize>().unwrap_or(1).saturating_sub(1);
+                        // The column counts bytes
+                        let col = caps[2].parse::<usize>().unwrap_or(1).saturating_sub(1);
+                        let col = self.lines.get(line).and_then(|l| l.get(..col)).map_or(col, |l| l.chars().count());
+                        let len = self.lines.get(line).map_or(col, |l| l.chars().count());
+                        diagnostic(line, col, len.max(col + 1), SEVERITY_ERROR, &caps[3])
+                    }
+                    None => diagnostic(0, 0, 1, SEVERITY_ERROR, e),
+                }];
+            }
+        };
+        let spans = find_function_line_spans(&self.lines);
+        let mut found = Vec::new();
+        for func in &module.functions {
+            let Some(&span) = spans.get(func.name.as_str()) else { continue };
+            let rows = ir_lines(func, &self.lines, span);
+            let lines: HashMap<*const Instruction, usize> = rows
+                .iter()
+                .filter_map(|r| {
+                    let instr = func.basic_blocks[r.block?].instrs.get(r.instr?)?;
+                    Some((instr as *const Instruction, r.index))
+                })
+                .collect();
+            for (instr, kind, detail) in lint(module, func) {
+                let Some(&line) = lines.get(&(instr as *const Instruction)) else { continue };
+                let severity = if kind == "Dead store" { SEVERITY_WARNING } else { SEVERITY_HINT };
+                let text = &self.lines[line];
+                let indent = text.chars().take_while(|c| c.is_whitespace()).count();
+                found.push(diagnostic(line, indent, text.chars().count(), severity, &format!("{}: {}", kind, detail)));
+            }
+        }
+        found
+    }
+}
+
+fn range(start_line: usize, start_char: usize, end_line: usize, end_char: usize) -> String {
+    format!(
+        "{{\"start\":{{\"line\":{},\"character\":{}}},\"end\":{{\"line\":{},\"character\":{}}}}}",
+        start_line, start_char, end_line, end_char
+    )
+}
+
+/// Reads one message: headers up to an empty line, then Content-Length bytes of JSON.
+fn read_message(input: &mut impl BufRead) -> Result<Option<String>, String> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).map_err(|e| format!("Failed to read message: {}", e))? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or("Message without Content-Length")?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| format!("Failed to read message: {}", e))?;
+    String::from_utf8(body).map(Some).map_err(|e| format!("Message is not UTF-8: {}", e))
+}
+
+fn send(body: &str) -> Result<(), String> {
+    let mut out = io::stdout().lock();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("Failed to write message: {}", e))
+}
+
+fn respond(id: &Json, result: &str) -> Result<(), String> {
+    send(&format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}", id.to_text(), result))
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: &[String]) -> Result<(), String> {
+    send(&format!(
+        "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{{\"uri\":{},\"diagnostics\":[{}]}}}}",
+        quote(uri),
+        diagnostics.join(",")
+    ))
+}
+
+/// `--lsp`: a Language Server Protocol server on stdin/stdout for .ll files. Hover explains the
+/// line, go-to-definition follows %values, @globals, !metadata and labels, document symbols list
+/// functions and blocks, and diagnostics show parse errors and --opt findings.
+pub fn run() -> Result<(), String> {
+    let mut documents: HashMap<String, Document> = HashMap::new();
+    let mut encoding = Encoding::Utf16;
+    let mut input = io::stdin().lock();
+    while let Some(body) = read_message(&mut input)? {
+        let message = match JsonParser::parse(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        let id = message.at(&["id"]);
+        let uri = message.str_at(&["params", "textDocument", "uri"]).unwrap_or_default().to_string();
+        let position = (message.usize_at(&["params", "position", "line"]), message.usize_at(&["params", "position", "character"]));
+        match message.str_at(&["method"]).unwrap_or_default() {
+            "initialize" => {
+                encoding = Encoding::negotiate(&message);
+                let capabilities = format!(
+                    "{{\"positionEncoding\":\"{}\",\"textDocumentSync\":1,\"hoverProvider\":true,\"definitionProvider\":true,\"documentSymbolProvider\":true}}",
+                    encoding.name()
+                );
+                let result = format!("{{\"capabilities\":{},\"serverInfo\":{{\"name\":\"llvm_ir_tool\"}}}}", capabilities);
+                respond(id.unwrap_or(&Json::Null), &result)?;
+            }
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                // Full sync: didOpen has the text, didChange the whole new text as its last change
+                let text = message.str_at(&["params", "textDocument", "text"]).or_else(|| match message.at(&["params", "contentChanges"]) {
+                    Some(Json::Array(changes)) => changes.last()?.str_at(&["text"]),
+                    _ => None,
+                });
+                if let Some(text) = text {
+                    let document = Document::new(text, encoding);
+                    publish_diagnostics(&uri, &document.diagnostics())?;
+                    documents.insert(uri, document);
+                }
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                publish_diagnostics(&uri, &[])?;
+            }
+            "textDocument/hover" => {
+                let hover = documents.get(&uri).zip(position.0.zip(position.1)).and_then(|(doc, (line, character))| doc.hover(line, doc.char_index(line, character)));
+                let result = hover.map_or("null".to_string(), |text| format!("{{\"contents\":{{\"kind\":\"markdown\",\"value\":{}}}}}", quote(&text)));
+                respond(id.unwrap_or(&Json::Null), &result)?;
+            }
+            "textDocument/definition" => {
+                let target = documents.get(&uri).zip(position.0.zip(position.1)).and_then(|(doc, (line, character))| {
+                    let (token, _) = doc.token_at(line, doc.char_index(line, character))?;
+                    let (def_line, col) = doc.definition(line, &token)?;
+                    let len = doc.token_at(def_line, col + 1).map_or(token.chars().count(), |(t, _)| t.chars().count());
+                    Some(format!("{{\"uri\":{},\"range\":{}}}", quote(&uri), doc.range(def_line, col, def_line, col + len)))
+                });
+                respond(id.unwrap_or(&Json::Null), &target.unwrap_or_else(|| "null".to_string()))?;
+            }
+            "textDocument/documentSymbol" => {
+                let symbols = documents.get(&uri).map_or(Vec::new(), |doc| doc.symbols());
+                respond(id.unwrap_or(&Json::Null), &format!("[{}]", symbols.join(",")))?;
+            }
+            "shutdown" => respond(id.unwrap_or(&Json::Null), "null")?,
+            "exit" => return Ok(()),
+            method => {
+                // Requests need an answer, notifications we don't handle are dropped
+                if let Some(id) = id {
+                    send(&format!(
+                        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":{},\"message\":{}}}}}",
+                        id.to_text(),
+                        METHOD_NOT_FOUND,
+                        quote(&format!("Unsupported method {}", method))
+                    ))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_json() {
+        let json = JsonParser::parse(r#" {"id": 3, "params": {"text": "a\"b\né𝄞", "ok": [true, null, -1.5e2]}} "#).unwrap();
+        assert_eq!(json.usize_at(&["id"]), Some(3));
+        assert_eq!(json.str_at(&["params", "text"]), Some("a\"b\né𝄞"));
+        assert_eq!(json.at(&["params", "ok"]).unwrap().to_text(), "[true,null,-150]");
+        assert_eq!(json.at(&["params", "text"]).unwrap().to_text(), r#""a\"b\né𝄞""#);
+        assert!(json.at(&["params", "missing"]).is_none());
+        assert!(JsonParser::parse(r#"{"id" 1}"#).is_err_and(|e| e.contains("expected ':'")));
+        assert!(JsonParser::parse(r#"["open"#).is_err());
+        assert!(JsonParser::parse("nope").is_err());
+    }
+
+    #[test]
+    fn reads_framed_messages() {
+        // Content-Length counts bytes, "é" is two
+        let stream = "content-length: 4\r\n\r\n\"é\"Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc\r\n\r\n{}";
+        let mut input = io::Cursor::new(stream.as_bytes());
+        assert_eq!(read_message(&mut input).unwrap().as_deref(), Some("\"é\""));
+        assert_eq!(read_message(&mut input).unwrap().as_deref(), Some("{}"));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        assert!(read_message(&mut io::Cursor::new(b"Content-Type: x\r\n\r\n{}")).is_err());
+    }
+
+    #[test]
+    fn counts_positions_in_the_negotiated_encoding() {
+        let offer = |encodings: &str| {
+            JsonParser::parse(&format!(r#"{{"method":"initialize","params":{{"capabilities":{{"general":{{"positionEncodings":{}}}}}}}}}"#, encodings)).unwrap()
+        };
+        assert_eq!(Encoding::negotiate(&offer(r#"["utf-16","utf-32"]"#)), Encoding::Utf32);
+        assert_eq!(Encoding::negotiate(&offer(r#"["utf-8","utf-16"]"#)), Encoding::Utf16);
+        assert_eq!(Encoding::negotiate(&JsonParser::parse(r#"{"params":{}}"#).unwrap()), Encoding::Utf16);
+
+        // "𝄞" is one char, two UTF-16 units and four bytes
+        let text = "define i32 @\"𝄞\"(i32 %a) {\nentry:\n  %sum = add i32 %a, 1\n  ret i32 %sum\n}\n";
+        let utf16 = Document::new(text, Encoding::Utf16);
+        assert_eq!(utf16.definition(2, "%a"), Some((0, 20)));
+        assert_eq!(utf16.definition(3, "%sum"), Some((2, 2)));
+        assert_eq!(utf16.range(0, 20, 0, 22), range(0, 21, 0, 23));
+        assert_eq!(utf16.char_index(0, 21), 20);
+        assert_eq!(utf16.token_at(0, utf16.char_index(0, 22)), Some(("%a".to_string(), 20)));
+        let utf32 = Document::new(text, Encoding::Utf32);
+        assert_eq!(utf32.range(0, 20, 0, 22), range(0, 20, 0, 22));
+        assert_eq!(utf32.char_index(0, 21), 21);
+    }
+}
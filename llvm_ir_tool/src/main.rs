@@ -1,4 +1,38 @@
-use llvm_ir::{Module}; //LLVM IR Library for handling .ll files
+mod bursts;
+mod cfg;
+mod compare;
+mod csynth;
+mod dataflow;
+mod ddg;
+mod directives;
+mod fifo;
+mod fixed;
+mod hls_log;
+mod interfaces;
+mod interp;
+mod ir_util;
+mod layout;
+mod live;
+mod loops;
+mod lsp;
+mod memory;
+mod opt;
+mod ports;
+mod pragmas;
+mod printer;
+mod profile;
+mod query;
+mod ranges;
+mod reduce;
+mod repl;
+mod resources;
+mod schedule;
+mod source;
+mod tui;
+mod unroll;
+mod watch;
+
+use llvm_ir::Module; //LLVM IR Library for handling .ll files
 use std::collections::HashMap;
 use std::env; // Command line arguments
 use std::fs::File;
@@ -10,9 +44,21 @@ fn main() -> Result<(), String> {
     let args: Vec<String> = env::args().collect();
     //Expect at least the executable and the filename
     if args.len() < 2 {
-        return Err("Usage: cargo run <filename.ll> [function_name(opt)] [line_number(opt)]".to_string());
+        return Err(USAGE.to_string());
+    }
+    // Modes that only read Vitis HLS output don't need an IR file
+    if args[1] == "--compare" {
+        return compare::run(&args[2..]);
+    }
+    // The language server gets its files from the editor
+    if args[1] == "--lsp" {
+        return lsp::run();
     }
     let filename = &args[1];
+    // Watch mode parses the file itself on every change, and it may only appear after the first compile
+    if args.get(2).map(|a| a.as_str()) == Some("--watch") {
+        return watch::run(filename, &args[3..]);
+    }
     let function_filter = args.get(2).map(|s| s.as_str());
     let explain_line_number: Option<usize> = args.get(3).and_then(|s| s.parse().ok());
 
@@ -20,6 +66,17 @@ fn main() -> Result<(), String> {
     let llvm_module = Module::from_ir_path(filename)
         .map_err(|e| format!("Failed to parse LLVM module: {}", e))?;
 
+    // The interactive views need the file text as well, to show and explain lines
+    match args.get(2).map(|a| a.as_str()) {
+        Some("--repl") => return repl::run(&llvm_module, filename),
+        Some("--tui") => return tui::run(&llvm_module, filename, &args[3..]),
+        _ => {}
+    }
+    // Analysis modes are selected with a flag right after the filename
+    if let Some(mode) = args.get(2).filter(|a| a.starts_with("--")) {
+        return run_mode(&llvm_module, mode, &args[3..]);
+    }
+
     // Read all lines of the input .ll file into a vector for line referencing
     let file = File::open(filename).map_err(|e| format!("Failed to open file: {}", e))?;
     let lines: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()
@@ -32,10 +89,9 @@ fn main() -> Result<(), String> {
     //Iterate through each function in the LLVM Module to find the matching one
     for func in &llvm_module.functions {
         let name = func.name.as_str();
-        if let Some(filter) = function_filter {
-            if filter != name {
-                continue;
-            }
+        if let Some(filter) = function_filter
+            && filter != name {
+            continue;
         }
         let instr_counts = func_instr_counts.entry(name).or_default();
         //Iterate through each basic blocks and extract the information
@@ -73,7 +129,7 @@ fn main() -> Result<(), String> {
 
             // If a line number to explain is passed and in range
             if let Some(explain_line) = explain_line_number {
-                if explain_line >= start_line + 1 && explain_line <= end_line + 1 {
+                if explain_line > start_line && explain_line <= end_line + 1 {
                     let code_line = &lines[explain_line - 1];
                     println!("{}",explain_llvm_line(code_line));
                 } else {
@@ -88,6 +144,60 @@ fn main() -> Result<(), String> {
     Ok(())
 }
 
+const USAGE: &str = "Usage: cargo run <filename.ll> [function_name(opt)] [line_number(opt)]
+       cargo run --compare <baseline.rpt> <report.rpt>... [--format text|markdown|csv] [--output <file>]
+       cargo run <filename.ll> --profile <function> [args...]
+       cargo run <filename.ll> --schedule <function> [--clock <ns>] [--uncertainty <percent>] [--delay <Opcode>=<ns>[:<cycles>]]...
+       cargo run <filename.ll> --ports <function> [--partition <array>:<spec>]... [--port-count <array>=<n>]... [--source-dir <dir>]...
+       cargo run <filename.ll> --unroll <function> <loop header|source line> <factor|full> [--show] [--output <file.ll>]
+       cargo run <filename.ll> --directives <function> [--output <file>] [--max-unroll <n>] [--source-dir <dir>]...
+       cargo run <filename.ll> --csynth <csynth.rpt> [--function <name>] [--source-dir <dir>]...
+       cargo run <filename.ll> --hls-log <vitis_hls.log> [--all] [--source-dir <dir>]...
+       cargo run <filename.ll> --dataflow [function] [--source-dir <dir>]...
+       cargo run <filename.ll> --pragmas [function] [--source-dir <dir>]...
+       cargo run <filename.ll> --ranges [function]
+       cargo run <filename.ll> --fixed [function] [--as <W>,<I>] [--source-dir <dir>]...
+       cargo run <filename.ll> --live [function] [--loop <header|line>] [--source-dir <dir>]...
+       cargo run <filename.ll> --fifo <function> [args...] [--output <file>]
+       cargo run <filename.ll> --interfaces [function] [--source-dir <dir>]...
+       cargo run <filename.ll> --bursts [function] [--max-burst <n>] [--report <csynth.rpt>] [--source-dir <dir>]...
+       cargo run <filename.ll> --ddg <function> [--uses <value>]... [--feeds <value>]... [--all] [--format text|dot|json] [--output <file>]
+       cargo run <filename.ll> --opt [function]
+       cargo run <filename.ll> --emit [--dce] [--output <file.ll>]
+       cargo run <filename.ll> --reduce --test <command> [--output <file.ll>]
+       cargo run <filename.ll> --query <query> [function]
+       cargo run <filename.ll> --repl
+       cargo run <filename.ll> --tui [function] [--source-dir <dir>]...
+       cargo run --lsp
+       cargo run <filename.ll> --watch [function] [--source <file.c>]... [--compile <command>] [--run \"--<mode> [args]\"]... [--interval <ms>]";
+
+/// Runs one of the analysis modes selected by a `--flag` argument.
+fn run_mode(module: &Module, mode: &str, args: &[String]) -> Result<(), String> {
+    match mode {
+        "--profile" => profile::run(module, args),
+        "--schedule" => schedule::run(module, args),
+        "--ports" => ports::run(module, args),
+        "--unroll" => unroll::run(module, args),
+        "--directives" => directives::run(module, args),
+        "--csynth" => csynth::run(module, args),
+        "--hls-log" => hls_log::run(module, args),
+        "--dataflow" => dataflow::run(module, args),
+        "--pragmas" => pragmas::run(module, args),
+        "--ranges" => ranges::run(module, args),
+        "--fixed" => fixed::run(module, args),
+        "--live" => live::run(module, args),
+        "--fifo" => fifo::run(module, args),
+        "--interfaces" => interfaces::run(module, args),
+        "--bursts" => bursts::run(module, args),
+        "--ddg" => ddg::run(module, args),
+        "--opt" => opt::run(module, args),
+        "--emit" => printer::run(module, args),
+        "--reduce" => reduce::run(module, args),
+        "--query" => query::run(module, args),
+        _ => Err(format!("Unknown option '{}'\n{}", mode, USAGE)),
+    }
+}
+
 /// Finds function start and end line numbers in the LLVM IR source lines.
 /// Returns a map from function name -> (start_line_index, end_line_index).
 /// Line numbers are zero-based indexes.
@@ -100,22 +210,20 @@ fn find_function_line_spans(lines: &[String]) -> HashMap<&str, (usize, usize)> {
         if line.trim_start().starts_with("define") {
             // Parse function name from line
             // This is naive: function name is after '@' and before '('
-            if let Some(at_pos) = line.find('@') {
-                if let Some(paren_pos) = line[at_pos..].find('(') {
-                    let name = &line[at_pos + 1..at_pos + paren_pos];
-                    // Close previous function span if any
-                    if let Some((prev_name, start)) = current_func.take() {
-                        spans.insert(prev_name, (start, i - 1));
-                    }
-                    current_func = Some((name, i));
+            if let Some(at_pos) = line.find('@')
+                && let Some(paren_pos) = line[at_pos..].find('(') {
+                let name = &line[at_pos + 1..at_pos + paren_pos];
+                // Close previous function span if any
+                if let Some((prev_name, start)) = current_func.take() {
+                    spans.insert(prev_name, (start, i - 1));
                 }
+                current_func = Some((name, i));
             }
         }
         // Detect function end line: a line with only `}` (possibly spaces)
-        if line.trim() == "}" {
-            if let Some((name, start)) = current_func.take() {
-                spans.insert(name, (start, i));
-            }
+        if line.trim() == "}"
+            && let Some((name, start)) = current_func.take() {
+            spans.insert(name, (start, i));
         }
     }
     // If file ended while inside function (no trailing '}'), close it at last line
@@ -0,0 +1,357 @@
+use crate::cfg::Cfg;
+use crate::ir_util::{const_int, is_annotation_call};
+use llvm_ir::instruction::GetElementPtr;
+use llvm_ir::{Constant, Function, Instruction, Name, Operand, Type, TypeRef};
+use std::collections::HashMap;
+use std::fmt;
+
+/// The object a pointer points into: a local array, a pointer parameter or a global.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub enum MemBase<'f> {
+    Alloca(&'f Name),
+    Param(&'f Name),
+    Global(&'f Name),
+}
+
+impl fmt::Display for MemBase<'_> {
+    // Prints the variable name without the '%' / '@' sigil, as it appears in the C source
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MemBase::Alloca(n) | MemBase::Param(n) | MemBase::Global(n) => n,
+        };
+        match name {
+            Name::Name(s) => write!(f, "{}", s),
+            Name::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// One array subscript of an access.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Subscript<'f> {
+    Const(i64),
+    Var(&'f Operand),
+    Unknown,
+}
+
+/// An access decomposed into its base object and one subscript per C array dimension.
+/// Pointer parameters get an extra leading dimension for the pointer index
+/// (`int A[][4]` is `ptr %A` indexed as `A[i][j]`). Missing trailing subscripts are 0.
+pub struct ArrayRef<'f> {
+    pub base: MemBase<'f>,
+    pub subscripts: Vec<Subscript<'f>>,
+}
+
+/// A load or store, with the pointer it accesses.
+pub struct Access<'f> {
+    pub address: &'f Operand,
+    pub is_write: bool,
+}
+
+/// Returns the memory access performed by an instruction, if any.
+pub fn access(instr: &Instruction) -> Option<Access<'_>> {
+    match instr {
+        Instruction::Load(load) => Some(Access { address: &load.address, is_write: false }),
+        Instruction::Store(store) => Some(Access { address: &store.address, is_write: true }),
+        _ => None,
+    }
+}
+
+/// True for calls that may touch memory we can't see (anything but debug/lifetime markers).
+pub fn is_opaque_call(instr: &Instruction) -> bool {
+    matches!(instr, Instruction::Call(_)) && !is_annotation_call(instr)
+}
+
+/// Per-function pointer bookkeeping used to map loads and stores back to the arrays they touch.
+pub struct MemoryInfo<'f> {
+    pub func: &'f Function,
+    // Instruction defining each local value
+    defs: HashMap<&'f Name, &'f Instruction>,
+    // For locals stored exactly once (e.g. `%A.addr` or a `const int` at -O0), the stored value
+    spilled: HashMap<&'f Name, &'f Operand>,
+    // Block and position of each instruction result
+    locations: HashMap<&'f Name, (usize, usize)>,
+    // The only predecessor of each block, if it has exactly one
+    single_pred: Vec<Option<usize>>,
+}
+
+impl<'f> MemoryInfo<'f> {
+    pub fn new(func: &'f Function) -> Self {
+        let mut defs = HashMap::new();
+        let mut locations = HashMap::new();
+        let mut stores: HashMap<&'f Name, Vec<&'f Operand>> = HashMap::new();
+        for (b, bb) in func.basic_blocks.iter().enumerate() {
+            for (i, instr) in bb.instrs.iter().enumerate() {
+                if let Some(dest) = instr.try_get_result() {
+                    defs.insert(dest, instr);
+                    locations.insert(dest, (b, i));
+                }
+                if let Instruction::Store(store) = instr
+                    && let Operand::LocalOperand { name, .. } = &store.address
+                {
+                    stores.entry(name).or_default().push(&store.value);
+                }
+            }
+        }
+        let spilled = stores
+            .into_iter()
+            .filter(|(_, values)| values.len() == 1)
+            .map(|(name, values)| (name, values[0]))
+            .collect();
+        let cfg = Cfg::new(func);
+        let single_pred = cfg.preds.iter().map(|p| if p.len() == 1 { Some(p[0]) } else { None }).collect();
+        MemoryInfo { func, defs, spilled, locations, single_pred }
+    }
+
+    /// Follows GEPs, casts and -O0 pointer spills back to the object a pointer points into.
+    pub fn base_of(&self, ptr: &'f Operand) -> Option<MemBase<'f>> {
+        self.base_of_depth(ptr, 0)
+    }
+
+    fn base_of_depth(&self, ptr: &'f Operand, depth: usize) -> Option<MemBase<'f>> {
+        if depth > 32 {
+            return None;
+        }
+        match ptr {
+            Operand::LocalOperand { name, .. } => match self.defs.get(name) {
+                None => self.func.parameters.iter().find(|p| &p.name == name).map(|p| MemBase::Param(&p.name)),
+                Some(Instruction::Alloca(_)) => Some(MemBase::Alloca(name)),
+                Some(Instruction::GetElementPtr(gep)) => self.base_of_depth(&gep.address, depth + 1),
+                Some(Instruction::BitCast(cast)) => self.base_of_depth(&cast.operand, depth + 1),
+                Some(Instruction::AddrSpaceCast(cast)) => self.base_of_depth(&cast.operand, depth + 1),
+                // `%0 = load ptr, ptr %A.addr`: the pointer argument reloaded from its stack slot
+                Some(Instruction::Load(load)) => match &load.address {
+                    Operand::LocalOperand { name: slot, .. } => {
+                        let stored = self.spilled.get(slot)?;
+                        self.base_of_depth(stored, depth + 1)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            Operand::ConstantOperand(c) => constant_base(c),
+            Operand::MetadataOperand => None,
+        }
+    }
+
+    /// Value of an integer operand if it is a compile-time constant. Follows simple arithmetic and
+    /// -O0 loads of local variables back to the store that last wrote them, within a block and its
+    /// chain of single predecessors, or to the only store of a variable written once.
+    pub fn const_value(&self, op: &Operand) -> Option<i64> {
+        self.eval(op, 0)
+    }
+
+    fn eval(&self, op: &Operand, depth: usize) -> Option<i64> {
+        if depth > 64 {
+            return None;
+        }
+        let Operand::LocalOperand { name, .. } = op else { return const_int(op) };
+        let eval = |op: &Operand| self.eval(op, depth + 1);
+        let bits = |op: &Operand| match op {
+            Operand::LocalOperand { ty, .. } => match ty.as_ref() {
+                Type::IntegerType { bits } => Some(*bits),
+                _ => None,
+            },
+            _ => None,
+        };
+        let value = match self.defs.get(name)? {
+            Instruction::Add(i) => eval(&i.operand0)?.wrapping_add(eval(&i.operand1)?),
+            Instruction::Sub(i) => eval(&i.operand0)?.wrapping_sub(eval(&i.operand1)?),
+            Instruction::Mul(i) => eval(&i.operand0)?.wrapping_mul(eval(&i.operand1)?),
+            Instruction::SDiv(i) => eval(&i.operand0)?.checked_div(eval(&i.operand1)?)?,
+            Instruction::SRem(i) => eval(&i.operand0)?.checked_rem(eval(&i.operand1)?)?,
+            Instruction::Shl(i) => eval(&i.operand0)?.wrapping_shl(eval(&i.operand1)? as u32),
+            Instruction::AShr(i) => eval(&i.operand0)?.wrapping_shr(eval(&i.operand1)? as u32),
+            Instruction::And(i) => eval(&i.operand0)? & eval(&i.operand1)?,
+            Instruction::Or(i) => eval(&i.operand0)? | eval(&i.operand1)?,
+            Instruction::Xor(i) => eval(&i.operand0)? ^ eval(&i.operand1)?,
+            Instruction::SExt(i) => eval(&i.operand)?,
+            Instruction::ZExt(i) => {
+                let v = eval(&i.operand)?;
+                match bits(&i.operand)? {
+                    64.. => v,
+                    b => v & ((1i64 << b) - 1),
+                }
+            }
+            Instruction::Trunc(i) => {
+                let shift = 64 - bits(op)?.clamp(1, 64);
+                (eval(&i.operand)? << shift) >> shift
+            }
+            Instruction::Select(i) => {
+                if eval(&i.condition)? != 0 { eval(&i.true_value)? } else { eval(&i.false_value)? }
+            }
+            Instruction::Load(load) => {
+                let Operand::LocalOperand { name: slot, .. } = &load.address else { return None };
+                if !self.is_scalar_alloca(slot) {
+                    return None;
+                }
+                let (block, index) = self.locations[name];
+                match self.last_store(slot, block, index) {
+                    Some(value) => eval(value)?,
+                    None => eval(self.spilled.get(slot)?)?,
+                }
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // The value most recently stored to a local before position `index` of `block`,
+    // walking back through blocks with a single predecessor
+    fn last_store(&self, slot: &Name, mut block: usize, mut index: usize) -> Option<&'f Operand> {
+        for _ in 0..self.single_pred.len() {
+            let instrs = &self.func.basic_blocks[block].instrs;
+            for instr in instrs[..index].iter().rev() {
+                match instr {
+                    Instruction::Store(store) if matches!(&store.address, Operand::LocalOperand { name, .. } if name == slot) => {
+                        return Some(&store.value);
+                    }
+                    _ if is_opaque_call(instr) => return None,
+                    _ => {}
+                }
+            }
+            block = self.single_pred[block]?;
+            index = self.func.basic_blocks[block].instrs.len();
+        }
+        None
+    }
+
+    /// Splits an address into its base object and array subscripts by walking the GEP chain.
+    /// Returns None when the GEPs don't follow the array type (e.g. byte offsets after optimization).
+    pub fn array_ref(&self, ptr: &'f Operand) -> Option<ArrayRef<'f>> {
+        // GEPs between the address and its base object, outermost first
+        let mut geps: Vec<&'f GetElementPtr> = Vec::new();
+        let mut current = ptr;
+        for _ in 0..32 {
+            let Operand::LocalOperand { name, .. } = current else { break };
+            current = match self.defs.get(name) {
+                Some(Instruction::GetElementPtr(gep)) => {
+                    geps.push(gep);
+                    &gep.address
+                }
+                Some(Instruction::BitCast(cast)) => &cast.operand,
+                Some(Instruction::Load(load)) => match &load.address {
+                    Operand::LocalOperand { name: slot, .. } => self.spilled.get(slot)?,
+                    _ => return None,
+                },
+                _ => break,
+            };
+        }
+        let base = self.base_of(current)?;
+        geps.reverse();
+
+        // subscripts[0] is the pointer index, which only counts as a dimension for parameters
+        let mut subscripts = vec![Subscript::Const(0)];
+        let mut ty: Option<TypeRef> = geps.first().map(|gep| gep.source_element_type.clone());
+        for gep in &geps {
+            if ty.as_ref() != Some(&gep.source_element_type) {
+                return None;
+            }
+            let mut indices = gep.indices.iter();
+            // The first index steps over whole objects of the source type, i.e. it adds to the last subscript
+            if let Some(first) = indices.next() {
+                let last = subscripts.last_mut()?;
+                *last = add_subscripts(*last, self.subscript(first));
+            }
+            for index in indices {
+                ty = match ty.as_deref() {
+                    Some(Type::ArrayType { element_type, .. }) | Some(Type::VectorType { element_type, .. }) => {
+                        Some(element_type.clone())
+                    }
+                    _ => return None,
+                };
+                subscripts.push(self.subscript(index));
+            }
+        }
+        if !matches!(base, MemBase::Param(_)) {
+            subscripts.remove(0);
+        }
+        Some(ArrayRef { base, subscripts })
+    }
+
+    fn subscript(&self, index: &'f Operand) -> Subscript<'f> {
+        match (index, self.const_value(index)) {
+            (_, Some(value)) => Subscript::Const(value),
+            (Operand::LocalOperand { .. }, None) => Subscript::Var(index),
+            _ => Subscript::Unknown,
+        }
+    }
+
+    /// Dimensions of an array object, outermost first; None where the size is unknown
+    /// (the pointer dimension of a parameter). Parameters and globals take their
+    /// shape from the GEPs that index them.
+    pub fn dims(&self, base: MemBase<'f>) -> Vec<Option<u64>> {
+        if let MemBase::Alloca(name) = base
+            && let Some(Instruction::Alloca(alloca)) = self.defs.get(name)
+        {
+            return array_dims(&alloca.allocated_type);
+        }
+        let source_type = self.func.basic_blocks.iter().flat_map(|bb| &bb.instrs).find_map(|instr| match instr {
+            Instruction::GetElementPtr(gep) if self.base_of(&gep.address) == Some(base) && !self.is_derived(&gep.address) => {
+                Some(&gep.source_element_type)
+            }
+            _ => None,
+        });
+        let inner = source_type.map(array_dims).unwrap_or_default();
+        match base {
+            MemBase::Param(_) => std::iter::once(None).chain(inner).collect(),
+            _ => inner,
+        }
+    }
+
+    // True if the pointer is itself the result of a GEP (an interior pointer)
+    fn is_derived(&self, ptr: &Operand) -> bool {
+        match ptr {
+            Operand::LocalOperand { name, .. } => matches!(self.defs.get(name), Some(Instruction::GetElementPtr(_))),
+            _ => false,
+        }
+    }
+
+    /// True if the alloca holds a single scalar (an `int` or a pointer), which HLS keeps in a register.
+    pub fn is_scalar_alloca(&self, name: &Name) -> bool {
+        match self.defs.get(name) {
+            Some(Instruction::Alloca(alloca)) => matches!(
+                alloca.allocated_type.as_ref(),
+                Type::IntegerType { .. } | Type::FPType(_) | Type::PointerType { .. }
+            ),
+            _ => false,
+        }
+    }
+
+    /// True if the access goes to an array or buffer rather than a scalar local variable.
+    pub fn is_array_access(&self, access: &Access<'f>) -> bool {
+        match self.base_of(access.address) {
+            Some(MemBase::Alloca(name)) => !self.is_scalar_alloca(name),
+            _ => true,
+        }
+    }
+}
+
+fn constant_base(c: &Constant) -> Option<MemBase<'_>> {
+    match c {
+        Constant::GlobalReference { name, .. } => Some(MemBase::Global(name)),
+        Constant::GetElementPtr(gep) => constant_base(&gep.address),
+        Constant::BitCast(cast) => constant_base(&cast.operand),
+        Constant::AddrSpaceCast(cast) => constant_base(&cast.operand),
+        _ => None,
+    }
+}
+
+
+fn add_subscripts<'f>(a: Subscript<'f>, b: Subscript<'f>) -> Subscript<'f> {
+    match (a, b) {
+        (Subscript::Const(x), Subscript::Const(y)) => Subscript::Const(x + y),
+        (Subscript::Const(0), other) | (other, Subscript::Const(0)) => other,
+        _ => Subscript::Unknown,
+    }
+}
+
+fn array_dims(ty: &TypeRef) -> Vec<Option<u64>> {
+    let mut dims = Vec::new();
+    let mut ty = ty;
+    while let Type::ArrayType { element_type, num_elements } = ty.as_ref() {
+        dims.push(Some(*num_elements as u64));
+        ty = element_type;
+    }
+    dims
+}
@@ -0,0 +1,460 @@
+use crate::cfg::Cfg;
+use crate::ir_util::{block_label, instr_text, is_annotation_call, opcode_counts, opcode_name, operands, term_operands};
+use crate::memory::{is_opaque_call, MemBase, MemoryInfo};
+use crate::source::source_name;
+use llvm_ir::instruction::ICmp;
+use llvm_ir::{Function, HasDebugLoc, Instruction, IntPredicate, Module, Name, Operand, Type};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// Instructions listed per finding before the rest is summarized.
+const MAX_LISTED: usize = 10;
+
+/// A scalar local whose address is only loaded from and stored to, so mem2reg turns it into SSA values.
+struct Promotable<'f> {
+    name: &'f Name,
+    alloca: &'f Instruction,
+    ty: String,
+    loads: Vec<&'f Instruction>,
+    stores: Vec<&'f Instruction>,
+    // Blocks that need a phi for it once promoted
+    phis: usize,
+}
+
+/// An instruction a cleanup pass removes, and why.
+struct Finding<'f> {
+    instr: &'f Instruction,
+    detail: String,
+}
+
+fn line(instr: &Instruction) -> String {
+    instr.get_debug_loc().as_ref().map_or("-".to_string(), |loc| loc.line.to_string())
+}
+
+/// True if `op` is the local `name` itself.
+fn is_local(op: &Operand, name: &Name) -> bool {
+    matches!(op, Operand::LocalOperand { name: n, .. } if n == name)
+}
+
+/// Scalar allocas that mem2reg promotes: every use is a load from it or a store to it (debug and
+/// lifetime markers aside), so its address never escapes. Phis go in the blocks of the iterated
+/// dominance frontier of the stores where the variable is live on entry.
+fn promotable<'f>(func: &'f Function, mem: &MemoryInfo<'f>, cfg: &Cfg) -> Vec<Promotable<'f>> {
+    let idom = cfg.immediate_dominators();
+    let frontiers = cfg.dominance_frontiers(&idom);
+    let mut result = Vec::new();
+    for alloca in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        let Instruction::Alloca(a) = alloca else { continue };
+        if !mem.is_scalar_alloca(&a.dest) {
+            continue;
+        }
+        let name = &a.dest;
+        let mut loads = Vec::new();
+        let mut stores = Vec::new();
+        let mut escapes = func.basic_blocks.iter().any(|bb| term_operands(&bb.term).iter().any(|op| is_local(op, name)));
+        for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+            match instr {
+                Instruction::Load(load) if is_local(&load.address, name) => loads.push(instr),
+                Instruction::Store(store) if is_local(&store.address, name) && !is_local(&store.value, name) => stores.push(instr),
+                _ if is_annotation_call(instr) => {}
+                _ => escapes |= operands(instr).iter().any(|op| is_local(op, name)),
+            }
+        }
+        if escapes {
+            continue;
+        }
+
+        // Live on entry to a block: read there before any store, or live after a block that doesn't store
+        let mut upward = vec![false; cfg.num_blocks()];
+        let mut kills = vec![false; cfg.num_blocks()];
+        let mut def_blocks = BTreeSet::new();
+        for (b, bb) in func.basic_blocks.iter().enumerate() {
+            for instr in &bb.instrs {
+                match instr {
+                    Instruction::Load(load) if is_local(&load.address, name) && !kills[b] => upward[b] = true,
+                    Instruction::Store(store) if is_local(&store.address, name) => {
+                        kills[b] = true;
+                        def_blocks.insert(b);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut live_in = upward.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..cfg.num_blocks()).rev() {
+                if !live_in[b] && !kills[b] && cfg.succs[b].iter().any(|&s| live_in[s]) {
+                    live_in[b] = true;
+                    changed = true;
+                }
+            }
+        }
+        let mut phi_blocks = BTreeSet::new();
+        let mut work: Vec<usize> = def_blocks.into_iter().collect();
+        while let Some(b) = work.pop() {
+            for &f in &frontiers[b] {
+                if live_in[f] && phi_blocks.insert(f) {
+                    work.push(f);
+                }
+            }
+        }
+        result.push(Promotable {
+            name,
+            alloca,
+            ty: a.allocated_type.to_string(),
+            loads,
+            stores,
+            phis: phi_blocks.len(),
+        });
+    }
+    result
+}
+
+/// Whether two accesses may touch the same object. Distinct locals and globals never overlap, while
+/// pointer arguments may point anywhere but into this function's locals.
+fn may_alias(a: Option<MemBase>, b: Option<MemBase>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) if a == b => true,
+        (Some(MemBase::Alloca(_)), Some(_)) | (Some(_), Some(MemBase::Alloca(_))) => false,
+        (Some(MemBase::Global(_)), Some(MemBase::Global(_))) => false,
+        _ => true,
+    }
+}
+
+/// Local value numbering within each block: a load is redundant when an earlier load of the same
+/// address, or a store to it, already produced its value and nothing that may write the object
+/// came in between. Stores overwritten before anything could read them are dead. Volatile and
+/// atomic accesses are never removed.
+fn redundant_accesses<'f>(func: &'f Function, mem: &MemoryInfo<'f>) -> (Vec<Finding<'f>>, Vec<Finding<'f>>) {
+    let mut loads = Vec::new();
+    let mut stores = Vec::new();
+    for bb in &func.basic_blocks {
+        let mut keys: HashMap<&Name, String> = HashMap::new();
+        // Value available at each address (its key and how to show it), and the store to it nothing has read yet
+        let mut available: HashMap<String, (Option<MemBase>, String, String)> = HashMap::new();
+        let mut pending: HashMap<String, (Option<MemBase>, &Instruction)> = HashMap::new();
+        for instr in &bb.instrs {
+            // Volatile and atomic accesses are observable, and order the accesses around them
+            let ordered = match instr {
+                Instruction::Load(load) => load.volatile || load.atomicity.is_some(),
+                Instruction::Store(store) => store.volatile || store.atomicity.is_some(),
+                _ => false,
+            };
+            if ordered || is_opaque_call(instr) {
+                available.clear();
+                pending.clear();
+                continue;
+            }
+            let key = |op: &Operand, keys: &HashMap<&Name, String>| match op {
+                Operand::LocalOperand { name, .. } => keys.get(name).cloned().unwrap_or_else(|| format!("%{}", block_label(name))),
+                other => other.to_string(),
+            };
+            match instr {
+                Instruction::Load(load) => {
+                    let address = key(&load.address, &keys);
+                    let base = mem.base_of(&load.address);
+                    pending.retain(|_, (b, _)| !may_alias(*b, base));
+                    let value = match available.get(&address) {
+                        Some((_, value, shown)) => {
+                            loads.push(Finding { instr, detail: format!("same value as {}", shown) });
+                            value.clone()
+                        }
+                        None => {
+                            let value = format!("%{}", block_label(&load.dest));
+                            available.insert(address, (base, value.clone(), value.clone()));
+                            value
+                        }
+                    };
+                    keys.insert(&load.dest, value);
+                }
+                Instruction::Store(store) => {
+                    let address = key(&store.address, &keys);
+                    let base = mem.base_of(&store.address);
+                    if let Some((_, earlier)) = pending.remove(&address) {
+                        let by = instr.get_debug_loc().as_ref().map_or("a later store".to_string(), |loc| format!("the store on line {}", loc.line));
+                        stores.push(Finding { instr: earlier, detail: format!("overwritten by {} before it is read", by) });
+                    }
+                    available.retain(|_, (b, _, _)| !may_alias(*b, base));
+                    pending.retain(|_, (b, _)| !may_alias(*b, base));
+                    let shown = format!("the value stored on line {}", line(instr));
+                    available.insert(address.clone(), (base, key(&store.value, &keys), shown));
+                    pending.insert(address, (base, instr));
+                }
+                Instruction::Alloca(_) | Instruction::Phi(_) | Instruction::Call(_) => {}
+                _ => {
+                    // Pure expressions are keyed by opcode and operands, so recomputed addresses match
+                    if let Some(dest) = instr.try_get_result() {
+                        let ops: Vec<String> = operands(instr).into_iter().map(|op| key(op, &keys)).collect();
+                        let opcode = match instr {
+                            Instruction::ICmp(icmp) => format!("icmp {}", icmp.predicate),
+                            _ => opcode_name(instr),
+                        };
+                        keys.insert(dest, format!("{}({})", opcode, ops.join(", ")));
+                    }
+                }
+            }
+        }
+    }
+    (loads, stores)
+}
+
+/// Local arrays that are written but never read, and whose address doesn't leave the function:
+/// all their stores are dead.
+fn unread_arrays<'f>(func: &'f Function, mem: &MemoryInfo<'f>) -> Vec<Finding<'f>> {
+    let mut read = HashSet::new();
+    let mut escaped = HashSet::new();
+    for bb in &func.basic_blocks {
+        for instr in &bb.instrs {
+            match instr {
+                Instruction::Load(load) => {
+                    read.insert(mem.base_of(&load.address));
+                }
+                Instruction::Store(store) => {
+                    escaped.insert(mem.base_of(&store.value));
+                }
+                _ if is_opaque_call(instr) => escaped.extend(operands(instr).into_iter().map(|op| mem.base_of(op))),
+                _ => {}
+            }
+        }
+        escaped.extend(term_operands(&bb.term).into_iter().map(|op| mem.base_of(op)));
+    }
+    let mut dead = Vec::new();
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        if let Instruction::Store(store) = instr
+            && let Some(MemBase::Alloca(name)) = mem.base_of(&store.address)
+            && !mem.is_scalar_alloca(name)
+        {
+            let base = Some(MemBase::Alloca(name));
+            if !read.contains(&base) && !escaped.contains(&base) {
+                dead.push(Finding { instr, detail: format!("{} is never read", name) });
+            }
+        }
+    }
+    dead
+}
+
+fn compare(predicate: IntPredicate, a: i64, b: i64, bits: u32) -> bool {
+    let unsigned = |v: i64| if bits >= 64 { v as u64 } else { (v as u64) & ((1u64 << bits) - 1) };
+    match predicate {
+        IntPredicate::EQ => a == b,
+        IntPredicate::NE => a != b,
+        IntPredicate::SGT => a > b,
+        IntPredicate::SGE => a >= b,
+        IntPredicate::SLT => a < b,
+        IntPredicate::SLE => a <= b,
+        IntPredicate::UGT => unsigned(a) > unsigned(b),
+        IntPredicate::UGE => unsigned(a) >= unsigned(b),
+        IntPredicate::ULT => unsigned(a) < unsigned(b),
+        IntPredicate::ULE => unsigned(a) <= unsigned(b),
+    }
+}
+
+/// Integer arithmetic, casts and comparisons whose result is known at compile time once -O0 locals
+/// written with a constant are forwarded (e.g. `int kernel_offset = BLUR_SIZE / 2;`).
+fn foldable<'f>(module: &Module, func: &'f Function, mem: &MemoryInfo<'f>) -> Vec<Finding<'f>> {
+    let mut found = Vec::new();
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        let value = match instr {
+            Instruction::Load(_) | Instruction::Alloca(_) | Instruction::Call(_) | Instruction::Phi(_) => None,
+            Instruction::ICmp(ICmp { predicate, operand0, operand1, .. }) => {
+                let bits = match module.type_of(operand0).as_ref() {
+                    Type::IntegerType { bits } => *bits,
+                    _ => 64,
+                };
+                match (mem.const_value(operand0), mem.const_value(operand1)) {
+                    (Some(a), Some(b)) => Some(i64::from(compare(*predicate, a, b, bits))),
+                    _ => None,
+                }
+            }
+            _ => instr
+                .try_get_result()
+                .and_then(|dest| mem.const_value(&Operand::LocalOperand { name: dest.clone(), ty: module.type_of(instr) })),
+        };
+        if let Some(v) = value {
+            found.push(Finding { instr, detail: format!("= {}", v) });
+        }
+    }
+    found
+}
+
+/// The findings whose instruction no earlier cleanup removes already, which are marked removed now.
+fn not_removed<'f>(removed: &mut HashSet<*const Instruction>, findings: Vec<Finding<'f>>) -> Vec<Finding<'f>> {
+    findings.into_iter().filter(|f| removed.insert(f.instr as *const Instruction)).collect()
+}
+
+/// Redundant loads, dead stores and constant-foldable instructions of a function with what they
+/// are, for editors to show as diagnostics. Promotable locals are left alone, they are what -O0
+/// code looks like.
+pub fn lint<'f>(module: &Module, func: &'f Function) -> Vec<(&'f Instruction, &'static str, String)> {
+    let mem = MemoryInfo::new(func);
+    let (redundant, overwritten) = redundant_accesses(func, &mem);
+    let dead: Vec<Finding> = overwritten.into_iter().chain(unread_arrays(func, &mem)).collect();
+    let mut removed = HashSet::new();
+    let mut found = Vec::new();
+    for (kind, findings) in [("Redundant load", redundant), ("Dead store", dead), ("Constant-foldable", foldable(module, func, &mem))] {
+        found.extend(not_removed(&mut removed, findings).into_iter().map(|f| (f.instr, kind, f.detail)));
+    }
+    found
+}
+
+fn print_findings(title: &str, findings: &[Finding]) {
+    println!("  {}: {}", title, findings.len());
+    for f in findings.iter().take(MAX_LISTED) {
+        println!("    line {:<5} {}  ({})", line(f.instr), instr_text(f.instr), f.detail);
+    }
+    if findings.len() > MAX_LISTED {
+        println!("    ... {} more", findings.len() - MAX_LISTED);
+    }
+}
+
+/// `--opt [function]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --opt [function]";
+    let mut func_name = None;
+    for arg in args {
+        if arg.starts_with("--") {
+            return Err(format!("Unknown option '{}'\n{}", arg, usage));
+        }
+        func_name = Some(arg);
+    }
+    let functions: Vec<&Function> = match func_name {
+        Some(name) => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == *name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        None => module.functions.iter().collect(),
+    };
+
+    for func in functions {
+        let mem = MemoryInfo::new(func);
+        let cfg = Cfg::new(func);
+        let promoted = promotable(func, &mem, &cfg);
+        let (redundant, overwritten) = redundant_accesses(func, &mem);
+        let unread = unread_arrays(func, &mem);
+        let folded = foldable(module, func, &mem);
+
+        // Each instruction is counted once, under the first cleanup that removes it
+        let mut removed: HashSet<*const Instruction> = HashSet::new();
+        for p in &promoted {
+            removed.insert(p.alloca as *const Instruction);
+            removed.extend(p.loads.iter().chain(&p.stores).map(|&i| i as *const Instruction));
+        }
+        let redundant = not_removed(&mut removed, redundant);
+        let dead = not_removed(&mut removed, overwritten.into_iter().chain(unread).collect());
+        let folded = not_removed(&mut removed, folded);
+
+        println!("Function: {}", func.name);
+        let scalars = func
+            .basic_blocks
+            .iter()
+            .flat_map(|bb| &bb.instrs)
+            .filter(|i| matches!(i, Instruction::Alloca(a) if mem.is_scalar_alloca(&a.dest)))
+            .count();
+        println!("  Promotable allocas (mem2reg): {} of {} scalar locals", promoted.len(), scalars);
+        if !promoted.is_empty() {
+            println!("    {:<20} {:<8} {:>6} {:>7} {:>5}", "Local", "Type", "Loads", "Stores", "Phis");
+            for p in &promoted {
+                println!(
+                    "    {:<20} {:<8} {:>6} {:>7} {:>5}",
+                    format!("%{}", block_label(p.name)),
+                    p.ty,
+                    p.loads.len(),
+                    p.stores.len(),
+                    p.phis
+                );
+            }
+        }
+        print_findings("Redundant loads", &redundant);
+        print_findings("Dead stores", &dead);
+        print_findings("Constant-foldable", &folded);
+
+        let before = opcode_counts(func);
+        let mut after = before.clone();
+        for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+            if removed.contains(&(instr as *const Instruction))
+                && let Some(count) = after.get_mut(&opcode_name(instr))
+            {
+                *count -= 1;
+            }
+        }
+        let phis: usize = promoted.iter().map(|p| p.phis).sum();
+        *after.entry("Phi".to_string()).or_insert(0) += phis;
+        let changed: BTreeMap<&String, (usize, usize)> = before
+            .keys()
+            .chain(after.keys())
+            .map(|op| (op, (before.get(op).copied().unwrap_or(0), after.get(op).copied().unwrap_or(0))))
+            .filter(|(_, (b, a))| b != a)
+            .collect();
+        if !changed.is_empty() {
+            println!("  {:<15} {:>8} {:>8} {:>8}", "Opcode", "Before", "After", "Delta");
+            for (op, (b, a)) in &changed {
+                println!("  {:<15} {:>8} {:>8} {:>+8}", op, b, a, *a as i64 - *b as i64);
+            }
+        }
+        let (b, a): (usize, usize) = (before.values().sum(), after.values().sum());
+        if b > 0 {
+            println!(
+                "  Total: {} -> {} instructions ({:.0}% is -O0 stack traffic or foldable)\n",
+                b,
+                a,
+                100.0 * (b - a.min(b)) as f64 / b as f64
+            );
+        } else {
+            println!();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same reads and writes of a global, plain and volatile
+    const ACCESSES: &str = r#"
+@g = global i32 0
+
+define i32 @plain(i32 %x) {
+entry:
+  store i32 %x, ptr @g
+  store i32 1, ptr @g
+  %a = load i32, ptr @g
+  %b = load i32, ptr @g
+  %s = add i32 %a, %b
+  ret i32 %s
+}
+
+define i32 @volatile(i32 %x) {
+entry:
+  store volatile i32 %x, ptr @g
+  store volatile i32 1, ptr @g
+  %a = load volatile i32, ptr @g
+  %b = load volatile i32, ptr @g
+  %s = add i32 %a, %b
+  ret i32 %s
+}
+"#;
+
+    // Positions in the entry block of the redundant loads and the dead stores
+    fn findings(func: &Function) -> (Vec<usize>, Vec<usize>) {
+        let mem = MemoryInfo::new(func);
+        let (loads, stores) = redundant_accesses(func, &mem);
+        let instrs = &func.basic_blocks[0].instrs;
+        let positions = |found: Vec<Finding>| found.iter().filter_map(|f| instrs.iter().position(|i| std::ptr::eq(i, f.instr))).collect();
+        (positions(loads), positions(stores))
+    }
+
+    #[test]
+    fn finds_redundant_loads_and_dead_stores() {
+        let module = Module::from_ir_str(ACCESSES).unwrap();
+        // Both loads read the stored 1, which overwrites the store of %x
+        assert_eq!(findings(&module.functions[0]), (vec![2, 3], vec![0]));
+    }
+
+    #[test]
+    fn keeps_volatile_accesses() {
+        let module = Module::from_ir_str(ACCESSES).unwrap();
+        assert_eq!(findings(&module.functions[1]), (vec![], vec![]));
+    }
+}
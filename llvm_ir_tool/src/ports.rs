@@ -0,0 +1,394 @@
+use crate::ir_util::{block_label, instr_text};
+use crate::loops::LoopInfo;
+use crate::memory::{access, MemBase, MemoryInfo, Subscript};
+use crate::pragmas::{self, LoopMetadata, Pragma};
+use crate::schedule::{schedule_block, BlockSchedule, ScheduleOptions};
+use crate::source::Sources;
+use llvm_ir::{HasDebugLoc, Instruction, Module};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+
+/// How an array dimension is split into banks, as in `#pragma HLS ARRAY_PARTITION`.
+#[derive(Clone, Copy, Debug)]
+pub enum PartitionKind {
+    Complete,
+    Cyclic(u64),
+    Block(u64),
+}
+
+/// One ARRAY_PARTITION directive. `dim` is 1-based; 0 means every dimension.
+#[derive(Clone, Copy, Debug)]
+pub struct Partition {
+    pub kind: PartitionKind,
+    pub dim: usize,
+}
+
+impl Partition {
+    /// Parses `complete[:dim]`, `cyclic:<factor>[:dim]` or `block:<factor>[:dim]`.
+    pub fn parse(spec: &str) -> Result<Partition, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let number = |s: &str| s.parse::<u64>().map_err(|_| format!("Invalid number '{}' in partition '{}'", s, spec));
+        let (kind, dim) = match parts.as_slice() {
+            ["complete"] => (PartitionKind::Complete, 1),
+            ["complete", dim] => (PartitionKind::Complete, number(dim)?),
+            ["cyclic", factor] => (PartitionKind::Cyclic(number(factor)?), 1),
+            ["cyclic", factor, dim] => (PartitionKind::Cyclic(number(factor)?), number(dim)?),
+            ["block", factor] => (PartitionKind::Block(number(factor)?), 1),
+            ["block", factor, dim] => (PartitionKind::Block(number(factor)?), number(dim)?),
+            _ => return Err(format!("Invalid partition '{}', expected complete[:dim], cyclic:<factor>[:dim] or block:<factor>[:dim]", spec)),
+        };
+        if matches!(kind, PartitionKind::Cyclic(0) | PartitionKind::Block(0)) {
+            return Err(format!("Partition factor must be positive in '{}'", spec));
+        }
+        Ok(Partition { kind, dim: dim as usize })
+    }
+
+    /// The partition of an `ARRAY_PARTITION` pragma (`type=complete|cyclic|block factor=<n> dim=<d>`).
+    pub fn from_pragma(pragma: &Pragma) -> Option<Partition> {
+        let factor = || pragma.option("factor").and_then(|f| f.parse().ok()).filter(|&f| f > 0);
+        let kind = match pragma.option("type").unwrap_or("complete").to_ascii_lowercase().as_str() {
+            "complete" => PartitionKind::Complete,
+            "cyclic" => PartitionKind::Cyclic(factor()?),
+            "block" => PartitionKind::Block(factor()?),
+            _ => return None,
+        };
+        let dim = pragma.option("dim").map_or(Some(1), |d| d.parse().ok())?;
+        Some(Partition { kind, dim })
+    }
+
+    fn covers(&self, dim: usize) -> bool {
+        self.dim == 0 || self.dim == dim + 1
+    }
+}
+
+impl fmt::Display for Partition {
+    /// In pragma form, e.g. `cyclic factor=2 dim=1`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            PartitionKind::Complete => write!(f, "complete dim={}", self.dim),
+            PartitionKind::Cyclic(n) => write!(f, "cyclic factor={} dim={}", n, self.dim),
+            PartitionKind::Block(n) => write!(f, "block factor={} dim={}", n, self.dim),
+        }
+    }
+}
+
+/// Memory organisation of one array: its shape, partitioning and ports per bank.
+pub struct ArrayConfig {
+    pub dims: Vec<Option<u64>>,
+    pub partitions: Vec<Partition>,
+    pub ports: usize,
+}
+
+impl ArrayConfig {
+    /// True if every dimension is completely partitioned, so the array becomes registers.
+    pub fn is_registers(&self) -> bool {
+        !self.dims.is_empty()
+            && (0..self.dims.len())
+                .all(|d| self.partitions.iter().any(|p| p.covers(d) && matches!(p.kind, PartitionKind::Complete)))
+    }
+
+    /// Number of banks, or None if a partitioned dimension has unknown size.
+    pub fn banks(&self) -> Option<u64> {
+        let mut banks = 1;
+        for (d, size) in self.dims.iter().enumerate() {
+            for p in self.partitions.iter().filter(|p| p.covers(d)) {
+                banks *= match p.kind {
+                    PartitionKind::Complete => (*size)?,
+                    PartitionKind::Cyclic(f) | PartitionKind::Block(f) => f,
+                };
+            }
+        }
+        Some(banks)
+    }
+
+    /// Bank selected by each partitioned dimension for the given subscripts (None = decided at run time).
+    pub fn bank_of(&self, subscripts: Option<&[Subscript]>) -> Vec<Option<i64>> {
+        let mut key = Vec::new();
+        for (d, size) in self.dims.iter().enumerate() {
+            for p in self.partitions.iter().filter(|p| p.covers(d)) {
+                let index = match subscripts {
+                    Some(subs) => match subs.get(d) {
+                        Some(Subscript::Const(i)) => Some(*i),
+                        None => Some(0),
+                        _ => None,
+                    },
+                    None => None,
+                };
+                key.push(index.map(|i| match p.kind {
+                    PartitionKind::Complete => i,
+                    PartitionKind::Cyclic(f) => i.rem_euclid(f as i64),
+                    PartitionKind::Block(f) => {
+                        let block = size.map_or(1, |s| s.div_ceil(f)).max(1);
+                        i.div_euclid(block as i64)
+                    }
+                }));
+            }
+        }
+        key
+    }
+
+    fn describe(&self) -> String {
+        if self.partitions.is_empty() {
+            return "none".to_string();
+        }
+        let parts: Vec<String> = self.partitions.iter().map(|p| p.to_string()).collect();
+        parts.join(", ")
+    }
+}
+
+/// Accesses to one array issued in one cycle of a block schedule.
+pub struct PortUsage<'f> {
+    pub cycle: u32,
+    pub array: MemBase<'f>,
+    // Accesses that may hit the busiest bank
+    pub accesses: Vec<&'f Instruction>,
+    pub reads: usize,
+    pub writes: usize,
+    pub ports: Option<usize>,
+    // Cycles added because the busiest bank has fewer ports than accesses
+    pub extra_cycles: u32,
+}
+
+/// Counts reads and writes per array, bank and cycle in a block schedule and compares
+/// them to the ports available. Two accesses can only share a bank when their subscripts
+/// on the partitioned dimensions are equal or not known statically.
+pub fn port_usage<'f>(
+    mem: &MemoryInfo<'f>,
+    sched: &BlockSchedule<'f>,
+    configs: &HashMap<MemBase<'f>, ArrayConfig>,
+) -> Vec<PortUsage<'f>> {
+    // (cycle, array) -> [(instruction, is_write, bank key)]
+    type Issued<'f> = Vec<(&'f Instruction, bool, Vec<Option<i64>>)>;
+    let mut groups: BTreeMap<(u32, MemBase<'f>), Issued<'f>> = BTreeMap::new();
+    for op in &sched.ops {
+        let Some(acc) = access(op.instr) else { continue };
+        if !mem.is_array_access(&acc) {
+            continue;
+        }
+        let array_ref = mem.array_ref(acc.address);
+        let Some(base) = array_ref.as_ref().map(|r| r.base).or_else(|| mem.base_of(acc.address)) else { continue };
+        let Some(config) = configs.get(&base) else { continue };
+        let key = config.bank_of(array_ref.as_ref().map(|r| r.subscripts.as_slice()));
+        groups.entry((op.asap, base)).or_default().push((op.instr, acc.is_write, key));
+    }
+
+    let mut usage = Vec::new();
+    for ((cycle, array), issued) in groups {
+        let config = &configs[&array];
+        let keys: Vec<_> = issued.iter().map(|(_, _, key)| key.clone()).collect();
+        let busiest: Vec<_> = busiest_bank(&keys).into_iter().map(|i| &issued[i]).collect();
+        let ports = (!config.is_registers()).then_some(config.ports);
+        let extra_cycles = match ports {
+            Some(p) if p > 0 => (busiest.len().div_ceil(p) as u32).saturating_sub(1),
+            _ => 0,
+        };
+        usage.push(PortUsage {
+            cycle,
+            array,
+            accesses: busiest.iter().map(|(instr, _, _)| *instr).collect(),
+            reads: busiest.iter().filter(|(_, w, _)| !w).count(),
+            writes: busiest.iter().filter(|(_, w, _)| *w).count(),
+            ports,
+            extra_cycles,
+        });
+    }
+    usage
+}
+
+/// Indices of the accesses that may hit the busiest bank, given their `bank_of` keys:
+/// the access compatible with the most others, together with those others.
+pub fn busiest_bank(keys: &[Vec<Option<i64>>]) -> Vec<usize> {
+    let compatible = |a: &[Option<i64>], b: &[Option<i64>]| {
+        a.iter().zip(b).all(|(x, y)| x.is_none() || y.is_none() || x == y)
+    };
+    keys.iter()
+        .map(|key| (0..keys.len()).filter(|&j| compatible(key, &keys[j])).collect::<Vec<_>>())
+        .max_by_key(|group| group.len())
+        .unwrap_or_default()
+}
+
+/// Every array accessed in a function with its configuration. Local arrays default to
+/// dual-port BRAM, array arguments to a single-port memory interface.
+pub fn array_configs<'f>(
+    mem: &MemoryInfo<'f>,
+    partitions: &HashMap<String, Vec<Partition>>,
+    port_counts: &HashMap<String, usize>,
+) -> HashMap<MemBase<'f>, ArrayConfig> {
+    let mut configs = HashMap::new();
+    for instr in mem.func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        let Some(acc) = access(instr) else { continue };
+        if !mem.is_array_access(&acc) {
+            continue;
+        }
+        let Some(base) = mem.base_of(acc.address) else { continue };
+        configs.entry(base).or_insert_with(|| {
+            let name = base.to_string();
+            let default_ports = if matches!(base, MemBase::Param(_)) { 1 } else { 2 };
+            ArrayConfig {
+                dims: mem.dims(base),
+                partitions: partitions.get(&name).cloned().unwrap_or_default(),
+                ports: port_counts.get(&name).copied().unwrap_or(default_ports),
+            }
+        });
+    }
+    configs
+}
+
+/// Suggests a partition for conflicting accesses: the first dimension whose subscripts are
+/// distinct constants gives every access its own bank.
+pub fn suggest_partition(mem: &MemoryInfo, accesses: &[&Instruction], dims: &[Option<u64>]) -> Option<Partition> {
+    let subscripts: Vec<Vec<Subscript>> = accesses
+        .iter()
+        .filter_map(|instr| access(instr).and_then(|acc| mem.array_ref(acc.address)).map(|r| r.subscripts))
+        .collect();
+    if subscripts.len() != accesses.len() {
+        return None;
+    }
+    (0..dims.len()).find_map(|d| {
+        let mut values: Vec<i64> = Vec::new();
+        for subs in &subscripts {
+            match subs.get(d).copied().unwrap_or(Subscript::Const(0)) {
+                Subscript::Const(v) if !values.contains(&v) => values.push(v),
+                _ => return None,
+            }
+        }
+        let kind = match dims[d] {
+            Some(size) if size <= 64 => PartitionKind::Complete,
+            _ => PartitionKind::Cyclic(values.len() as u64),
+        };
+        Some(Partition { kind, dim: d + 1 })
+    })
+}
+
+/// `--ports <function> [--partition <array>:<spec>]... [--port-count <array>=<n>] [--source-dir <dir>]... [schedule options]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = format!(
+        "Usage: cargo run <filename.ll> --ports <function> [--partition <array>:complete|cyclic:<factor>|block:<factor>[:dim]]... [--port-count <array>=<n>]... [--source-dir <dir>]... {}",
+        ScheduleOptions::USAGE
+    );
+    let func_name = args.first().filter(|a| !a.starts_with("--")).ok_or(usage.as_str())?;
+    let func = module
+        .get_func_by_name(func_name)
+        .ok_or_else(|| format!("Function '{}' not found in module.", func_name))?;
+
+    let mut options = ScheduleOptions::new();
+    let mut partitions: HashMap<String, Vec<Partition>> = HashMap::new();
+    let mut port_counts: HashMap<String, usize> = HashMap::new();
+    let mut search_dirs = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", flag, usage))?;
+        match flag.as_str() {
+            "--partition" => {
+                let (array, spec) = value.split_once(':').ok_or_else(|| format!("Invalid partition '{}'\n{}", value, usage))?;
+                partitions.entry(array.to_string()).or_default().push(Partition::parse(spec)?);
+            }
+            "--port-count" => {
+                let (array, n) = value.split_once('=').ok_or_else(|| format!("Invalid port count '{}'\n{}", value, usage))?;
+                let n = n.parse().map_err(|_| format!("Invalid port count '{}'", n))?;
+                port_counts.insert(array.to_string(), n);
+            }
+            "--source-dir" => search_dirs.push(PathBuf::from(value)),
+            _ if options.apply(flag, value)? => {}
+            _ => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+        }
+    }
+
+    // ARRAY_PARTITION pragmas in the source apply unless --partition overrides them for that array
+    let loop_info = LoopInfo::new(func);
+    let mut sources = Sources::new(search_dirs);
+    let in_effect = pragmas::collect(func, &loop_info, &LoopMetadata::load(module), &mut sources);
+    let mut from_pragmas: HashMap<String, Vec<Partition>> = HashMap::new();
+    for (array, pragma) in in_effect.partitions() {
+        if !partitions.contains_key(array)
+            && let Some(p) = Partition::from_pragma(pragma)
+        {
+            from_pragmas.entry(array.to_string()).or_default().push(p);
+        }
+    }
+
+    let pragma_arrays: Vec<String> = from_pragmas.keys().cloned().collect();
+    from_pragmas.extend(partitions.iter().map(|(a, ps)| (a.clone(), ps.clone())));
+
+    let mem = MemoryInfo::new(func);
+    let configs = array_configs(&mem, &from_pragmas, &port_counts);
+    for name in partitions.keys().chain(port_counts.keys()) {
+        if !configs.keys().any(|base| &base.to_string() == name) {
+            return Err(format!("Array '{}' is not accessed in '{}'", name, func.name));
+        }
+    }
+
+    println!("Memory ports for '{}' ({:.2} ns per cycle after uncertainty)\n", func.name, options.clock.effective());
+    let mut arrays: Vec<_> = configs.iter().collect();
+    arrays.sort_by_key(|(base, _)| **base);
+    println!("  {:<16} {:<16} {:<10} {:>6} {:>6}  Partition", "Array", "Dims", "Kind", "Banks", "Ports");
+    for (base, config) in &arrays {
+        let dims: String = config.dims.iter().map(|d| d.map_or("[?]".to_string(), |n| format!("[{}]", n))).collect();
+        let kind = match base {
+            MemBase::Alloca(_) => "local",
+            MemBase::Param(_) => "argument",
+            MemBase::Global(_) => "global",
+        };
+        let banks = config.banks().map_or("?".to_string(), |b| b.to_string());
+        let ports = if config.is_registers() { "regs".to_string() } else { config.ports.to_string() };
+        let origin = if pragma_arrays.contains(&base.to_string()) { " (#pragma)" } else { "" };
+        println!("  {:<16} {:<16} {:<10} {:>6} {:>6}  {}{}", base.to_string(), dims, kind, banks, ports, config.describe(), origin);
+    }
+    println!();
+
+    let mut totals = Vec::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        let sched = schedule_block(module, &mem, bb, &options.model, options.clock);
+        let usage = port_usage(&mem, &sched, &configs);
+        if usage.is_empty() {
+            continue;
+        }
+        // Blocks are reported with their innermost loop, since that is what the pragmas refer to
+        let location = loop_info.innermost(b).map_or(String::new(), |l| format!(" in loop {}", loop_info.describe(l)));
+        println!("Block: {}{} ({} cycles)", block_label(&bb.name), location, sched.latency);
+        println!("  {:>5}  {:<16} {:>5} {:>6} {:>5} {:>6}", "Cycle", "Array", "Reads", "Writes", "Ports", "Extra");
+
+        // Conflicts in different arrays during the same cycle overlap, so only the worst counts
+        let mut extra_per_cycle: BTreeMap<u32, u32> = BTreeMap::new();
+        for u in &usage {
+            let ports = u.ports.map_or("regs".to_string(), |p| p.to_string());
+            let marker = if u.extra_cycles > 0 { "  CONFLICT" } else { "" };
+            println!(
+                "  {:>5}  {:<16} {:>5} {:>6} {:>5} {:>6}{}",
+                u.cycle,
+                u.array.to_string(),
+                u.reads,
+                u.writes,
+                ports,
+                u.extra_cycles,
+                marker
+            );
+            let worst = extra_per_cycle.entry(u.cycle).or_insert(0);
+            *worst = (*worst).max(u.extra_cycles);
+        }
+        for u in usage.iter().filter(|u| u.extra_cycles > 0) {
+            println!("\n  Cycle {}: {} accesses to {} may hit the same bank:", u.cycle, u.accesses.len(), u.array);
+            for instr in &u.accesses {
+                let line = instr.get_debug_loc().as_ref().map_or(String::new(), |loc| format!("  (line {})", loc.line));
+                println!("    {}{}", instr_text(instr), line);
+            }
+            match suggest_partition(&mem, &u.accesses, &configs[&u.array].dims) {
+                Some(spec) => println!("  Suggestion: #pragma HLS ARRAY_PARTITION variable={} {}", u.array, spec),
+                None => println!(
+                    "  The bank of these accesses depends on run-time subscripts; unrolling the loop may make them constant."
+                ),
+            }
+        }
+        let extra: u32 = extra_per_cycle.values().sum();
+        println!();
+        totals.push((block_label(&bb.name), sched.latency, extra));
+    }
+
+    println!("Summary:");
+    println!("  {:<20} {:>8} {:>8} {:>8}", "Block", "Cycles", "Forced", "Total");
+    for (name, latency, extra) in &totals {
+        println!("  {:<20} {:>8} {:>8} {:>8}", name, latency, extra, latency + extra);
+    }
+    Ok(())
+}
@@ -0,0 +1,360 @@
+use crate::ir_util::block_label;
+use crate::loops::LoopInfo;
+use crate::source::{function_pragmas, hls_loop_names, source_name, Sources};
+use llvm_ir::{Function, HasDebugLoc, Module};
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+// `#pragma HLS <name> <options>`
+static PRAGMA: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)^\s*#\s*pragma\s+HLS\s+(\w+)\s*(.*)$").unwrap());
+// A loop hint node's body, e.g. `!"llvm.loop.unroll.count", i32 4`
+static LOOP_HINT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^\s*!"([\w.]+)"\s*(?:,\s*i\d+\s+(-?\d+))?(?:,\s*i\d+\s+(-?\d+))?"#).unwrap());
+
+/// An HLS directive in effect, from a `#pragma HLS` line or from `!llvm.loop` metadata.
+#[derive(Clone, Debug)]
+pub struct Pragma {
+    // Upper case, e.g. "PIPELINE"
+    pub name: String,
+    // `key=value` options in order; flags like `off` have an empty value
+    pub options: Vec<(String, String)>,
+    // Source line of a #pragma, None for metadata
+    pub line: Option<u32>,
+}
+
+impl Pragma {
+    /// Parses `#pragma HLS pipeline II=1`.
+    pub fn parse(text: &str, line: Option<u32>) -> Option<Pragma> {
+        let caps = PRAGMA.captures(text)?;
+        let rest = caps[2].split("//").next().unwrap_or("");
+        let options = rest
+            .split_whitespace()
+            .map(|opt| match opt.split_once('=') {
+                Some((k, v)) => (k.to_ascii_lowercase(), v.to_string()),
+                None => (opt.to_ascii_lowercase(), String::new()),
+            })
+            .collect();
+        Some(Pragma { name: caps[1].to_ascii_uppercase(), options, line })
+    }
+
+    /// Value of an option such as "II" or "variable", ignoring case.
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+}
+
+impl fmt::Display for Pragma {
+    /// In pragma form, e.g. `PIPELINE II=1`, followed by where it came from.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (k, v) in &self.options {
+            let key = if k == "ii" { "II" } else { k.as_str() };
+            if v.is_empty() { write!(f, " {}", key)? } else { write!(f, " {}={}", key, v)? }
+        }
+        match self.line {
+            Some(line) => write!(f, " (line {})", line),
+            None => write!(f, " (!llvm.loop)"),
+        }
+    }
+}
+
+/// The pragmas of one function, attached to what they apply to.
+pub struct FunctionPragmas {
+    pub function: Vec<Pragma>,
+    // Indexed like LoopInfo::loops
+    pub loops: Vec<Vec<Pragma>>,
+    // By the array named in variable= or port=
+    pub arrays: BTreeMap<String, Vec<Pragma>>,
+}
+
+impl FunctionPragmas {
+    /// ARRAY_PARTITION pragmas, by array.
+    pub fn partitions(&self) -> impl Iterator<Item = (&str, &Pragma)> {
+        self.arrays.iter().flat_map(|(array, ps)| ps.iter().filter(|p| p.name == "ARRAY_PARTITION").map(move |p| (array.as_str(), p)))
+    }
+}
+
+/// Loop hints in the `!llvm.loop` metadata of a .ll file, by function and latch block.
+pub struct LoopMetadata {
+    hints: HashMap<(String, String), Vec<Pragma>>,
+}
+
+impl LoopMetadata {
+    /// Reads the .ll file the module was parsed from; the llvm-ir crate doesn't keep metadata.
+    pub fn load(module: &Module) -> LoopMetadata {
+        let text = fs::read_to_string(&module.name).unwrap_or_default();
+        LoopMetadata::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> LoopMetadata {
+        let define_re = Regex::new(r#"^define\s.*@([\w.$]+|"[^"]+")\("#).unwrap();
+        let label_re = Regex::new(r"^([\w.$-]+):").unwrap();
+        let loop_ref_re = Regex::new(r"!llvm\.loop !(\d+)").unwrap();
+        let node_re = Regex::new(r"^!(\d+) = (?:distinct )?!\{(.*)\}\s*$").unwrap();
+        let ref_re = Regex::new(r"!(\d+)").unwrap();
+
+        let mut nodes: HashMap<&str, &str> = HashMap::new();
+        let mut latches: Vec<(String, String, String)> = Vec::new();
+        let (mut func, mut block) = (String::new(), String::new());
+        for line in text.lines() {
+            if let Some(caps) = define_re.captures(line) {
+                func = caps[1].trim_matches('"').to_string();
+                // Until a label is seen, instructions are in the unnamed entry block
+                block = "0".to_string();
+            } else if let Some(caps) = label_re.captures(line) {
+                block = caps[1].to_string();
+            } else if let Some(caps) = loop_ref_re.captures(line) {
+                latches.push((func.clone(), block.clone(), caps[1].to_string()));
+            } else if let Some(caps) = node_re.captures(line) {
+                let (id, body) = (caps.get(1).unwrap().as_str(), caps.get(2).unwrap().as_str());
+                nodes.insert(id, body);
+            }
+        }
+
+        let mut hints: HashMap<(String, String), Vec<Pragma>> = HashMap::new();
+        for (func, block, id) in latches {
+            let Some(body) = nodes.get(id.as_str()) else { continue };
+            let pragmas: Vec<Pragma> = ref_re
+                .captures_iter(body)
+                .filter(|c| c[1] != id)
+                .filter_map(|c| nodes.get(&c[1]).and_then(|hint| loop_hint(hint)))
+                .collect();
+            if !pragmas.is_empty() {
+                hints.entry((func, block)).or_default().extend(pragmas);
+            }
+        }
+        LoopMetadata { hints }
+    }
+}
+
+/// Translates one loop hint node, e.g. `!"llvm.loop.unroll.count", i32 4`, into its pragma.
+fn loop_hint(node: &str) -> Option<Pragma> {
+    let caps = LOOP_HINT.captures(node)?;
+    let value = |i: usize| caps.get(i).map_or(String::new(), |m| m.as_str().to_string());
+    let pragma = |name: &str, options: Vec<(&str, String)>| {
+        Some(Pragma { name: name.to_string(), options: options.into_iter().map(|(k, v)| (k.to_string(), v)).collect(), line: None })
+    };
+    match &caps[1] {
+        "llvm.loop.unroll.full" | "llvm.loop.unroll.enable" => pragma("UNROLL", vec![]),
+        "llvm.loop.unroll.count" => pragma("UNROLL", vec![("factor", value(2))]),
+        "llvm.loop.unroll.disable" => pragma("UNROLL", vec![("off", String::new())]),
+        "llvm.loop.pipeline.initiationinterval" => pragma("PIPELINE", vec![("ii", value(2))]),
+        "llvm.loop.pipeline.disable" => pragma("PIPELINE", vec![("off", String::new())]),
+        "llvm.loop.flatten.enable" => pragma("LOOP_FLATTEN", vec![]),
+        "llvm.loop.dataflow.enable" => pragma("DATAFLOW", vec![]),
+        "llvm.loop.tripcount" => pragma("LOOP_TRIPCOUNT", vec![("min", value(2)), ("max", value(3))]),
+        _ => None,
+    }
+}
+
+/// Source lines spanned by each loop: from its header line to the last line of code in its body.
+fn loop_spans(loop_info: &LoopInfo) -> Vec<Option<(u32, u32)>> {
+    (0..loop_info.loops.len())
+        .map(|l| {
+            let start = loop_info.line(l)?;
+            let end = loop_info.loops[l]
+                .blocks
+                .iter()
+                .flat_map(|&b| &loop_info.func.basic_blocks[b].instrs)
+                .filter_map(|i| i.get_debug_loc().as_ref().map(|loc| loc.line))
+                .max()?;
+            Some((start, end.max(start)))
+        })
+        .collect()
+}
+
+/// Collects the pragmas in effect for a function. A source pragma goes to the array it names,
+/// else to the innermost loop whose lines contain it, else to the function; metadata hints go
+/// to the loop whose latch carries them.
+pub fn collect(func: &Function, loop_info: &LoopInfo, metadata: &LoopMetadata, sources: &mut Sources) -> FunctionPragmas {
+    let mut result = FunctionPragmas { function: Vec::new(), loops: vec![Vec::new(); loop_info.loops.len()], arrays: BTreeMap::new() };
+    let spans = loop_spans(loop_info);
+    for (line, text) in function_pragmas(func, sources) {
+        let Some(pragma) = Pragma::parse(&text, Some(line)) else { continue };
+        if let Some(array) = pragma.option("variable").or(pragma.option("port")) {
+            result.arrays.entry(array.to_string()).or_default().push(pragma);
+            continue;
+        }
+        let owner = (0..loop_info.loops.len())
+            .filter(|&l| spans[l].is_some_and(|(start, end)| start < line && line <= end))
+            .max_by_key(|&l| loop_info.loops[l].depth);
+        match owner {
+            Some(l) => result.loops[l].push(pragma),
+            None => result.function.push(pragma),
+        }
+    }
+    for (l, lp) in loop_info.loops.iter().enumerate() {
+        for &latch in &lp.latches {
+            let key = (func.name.clone(), block_label(loop_info.cfg.names[latch]));
+            if let Some(hints) = metadata.hints.get(&key) {
+                result.loops[l].extend(hints.iter().cloned());
+            }
+        }
+    }
+    result
+}
+
+/// `--pragmas [function] [--source-dir <dir>]...`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --pragmas [function] [--source-dir <dir>]...";
+    let mut func_name = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--source-dir" => {
+                search_dirs.push(PathBuf::from(rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?))
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'\n{}", flag, usage)),
+            name => func_name = Some(name),
+        }
+    }
+    let mut sources = Sources::new(search_dirs);
+    let metadata = LoopMetadata::load(module);
+
+    let functions: Vec<&Function> = match func_name {
+        Some(name) => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        None => module.functions.iter().collect(),
+    };
+    for func in functions {
+        let loop_info = LoopInfo::new(func);
+        let names = hls_loop_names(&loop_info, &mut sources);
+        let pragmas = collect(func, &loop_info, &metadata, &mut sources);
+        println!("Function: {}", func.name);
+        for p in &pragmas.function {
+            println!("  {}", p);
+        }
+        if !loop_info.loops.is_empty() {
+            println!("  {:<30} {:<20} Pragmas", "Loop", "HLS name");
+            let mut order: Vec<usize> = (0..loop_info.loops.len()).collect();
+            order.sort_by_key(|&l| (loop_info.line(l), loop_info.loops[l].depth));
+            for l in order {
+                let indent = "  ".repeat(loop_info.loops[l].depth.saturating_sub(1));
+                let list: Vec<String> = pragmas.loops[l].iter().map(|p| p.to_string()).collect();
+                let list = if list.is_empty() { "-".to_string() } else { list.join(", ") };
+                println!("  {:<30} {:<20} {}", format!("{}{}", indent, loop_info.describe(l)), names[l], list);
+            }
+        }
+        for (array, ps) in &pragmas.arrays {
+            let list: Vec<String> = ps.iter().map(|p| p.to_string()).collect();
+            println!("  Array {}: {}", array, list.join(", "));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATRIX_SOURCE: &str = include_str!("../../matrix_multiplication_project/matrix_mult.cpp");
+
+    // matrix_mult's three loops (lines 18, 20 and 25 of matrix_mult.cpp) with an -O0 style
+    // unroll hint on the product loop's latch
+    const MATRIX_IR: &str = r#"
+define void @matrix_mult(ptr %A, ptr %B, ptr %C) !dbg !5 {
+entry:
+  br label %row.cond, !dbg !6
+row.cond:
+  %i = phi i32 [ 0, %entry ], [ %i.next, %row.inc ]
+  %row.cmp = icmp slt i32 %i, 4, !dbg !7
+  br i1 %row.cmp, label %col.cond, label %row.end, !dbg !7
+col.cond:
+  %j = phi i32 [ 0, %row.cond ], [ %j.next, %col.inc ]
+  %col.cmp = icmp slt i32 %j, 4, !dbg !8
+  br i1 %col.cmp, label %product.cond, label %row.inc, !dbg !8
+product.cond:
+  %k = phi i32 [ 0, %col.cond ], [ %k.next, %product.body ]
+  %product.cmp = icmp slt i32 %k, 4, !dbg !9
+  br i1 %product.cmp, label %product.body, label %col.inc, !dbg !9
+product.body:
+  %a = getelementptr i32, ptr %A, i32 %k, !dbg !10
+  %x = load i32, ptr %a, !dbg !10
+  %k.next = add i32 %k, 1, !dbg !9
+  br label %product.cond, !dbg !9, !llvm.loop !12
+col.inc:
+  %c = getelementptr i32, ptr %C, i32 %j, !dbg !11
+  store i32 0, ptr %c, !dbg !11
+  %j.next = add i32 %j, 1, !dbg !8
+  br label %col.cond, !dbg !8
+row.inc:
+  %i.next = add i32 %i, 1, !dbg !7
+  br label %row.cond, !dbg !7
+row.end:
+  ret void, !dbg !11
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2, !3}
+!0 = distinct !DICompileUnit(language: DW_LANG_C_plus_plus_14, file: !1, producer: "clang", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug)
+!1 = !DIFile(filename: "matrix_mult.cpp", directory: "/nonexistent")
+!2 = !{i32 7, !"Dwarf Version", i32 5}
+!3 = !{i32 2, !"Debug Info Version", i32 3}
+!4 = !DISubroutineType(types: !{null})
+!5 = distinct !DISubprogram(name: "matrix_mult", scope: !1, file: !1, line: 6, type: !4, scopeLine: 6, spFlags: DISPFlagDefinition, unit: !0)
+!6 = !DILocation(line: 6, column: 1, scope: !5)
+!7 = !DILocation(line: 18, column: 12, scope: !5)
+!8 = !DILocation(line: 20, column: 13, scope: !5)
+!9 = !DILocation(line: 25, column: 18, scope: !5)
+!10 = !DILocation(line: 27, column: 12, scope: !5)
+!11 = !DILocation(line: 29, column: 12, scope: !5)
+!12 = distinct !{!12, !13}
+!13 = !{!"llvm.loop.unroll.count", i32 2}
+"#;
+
+    #[test]
+    fn parses_source_pragmas() {
+        let pragmas: Vec<Pragma> = MATRIX_SOURCE.lines().filter_map(|l| Pragma::parse(l, None)).collect();
+        assert_eq!(pragmas.len(), 9);
+        assert_eq!(pragmas[0].name, "DATAFLOW");
+        // Trailing comments are not options
+        assert!(pragmas[0].options.is_empty());
+        assert_eq!(pragmas[1].name, "ARRAY_PARTITION");
+        assert_eq!((pragmas[1].option("variable"), pragmas[1].option("DIM")), (Some("A"), Some("1")));
+        assert_eq!(pragmas[1].options[1], ("complete".to_string(), String::new()));
+        let pipeline = &pragmas[7];
+        assert_eq!((pipeline.name.as_str(), pipeline.option("ii")), ("PIPELINE", Some("1")));
+        assert_eq!(Pragma { line: Some(21), ..pipeline.clone() }.to_string(), "PIPELINE II=1 (line 21)");
+        assert!(Pragma::parse("//#pragma HLS UNROLL", None).is_none());
+    }
+
+    #[test]
+    fn reads_loop_metadata() {
+        let metadata = LoopMetadata::parse(MATRIX_IR);
+        let hints = &metadata.hints[&("matrix_mult".to_string(), "product.body".to_string())];
+        assert_eq!(hints.len(), 1);
+        assert_eq!(hints[0].to_string(), "UNROLL factor=2 (!llvm.loop)");
+        assert_eq!(loop_hint(r#"!"llvm.loop.tripcount", i32 1, i32 8"#).unwrap().to_string(), "LOOP_TRIPCOUNT min=1 max=8 (!llvm.loop)");
+        assert!(loop_hint(r#"!"llvm.loop.mustprogress""#).is_none());
+    }
+
+    #[test]
+    fn attaches_pragmas_to_loops_and_arrays() {
+        let module = Module::from_ir_str(MATRIX_IR).unwrap();
+        let func = &module.functions[0];
+        let loop_info = LoopInfo::new(func);
+        let search = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/../matrix_multiplication_project"));
+        let mut sources = Sources::new(vec![search]);
+        let pragmas = collect(func, &loop_info, &LoopMetadata::parse(MATRIX_IR), &mut sources);
+        let shown = |ps: &[Pragma]| ps.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(shown(&pragmas.function), ["DATAFLOW (line 8)"]);
+        let row = loop_info.find_by_header("row.cond").unwrap();
+        let col = loop_info.find_by_header("col.cond").unwrap();
+        let product = loop_info.find_by_header("product.cond").unwrap();
+        assert!(pragmas.loops[row].is_empty());
+        assert_eq!(shown(&pragmas.loops[col]), ["PIPELINE II=1 (line 21)"]);
+        assert_eq!(shown(&pragmas.loops[product]), ["UNROLL (line 26)", "UNROLL factor=2 (!llvm.loop)"]);
+        assert_eq!(pragmas.arrays.keys().collect::<Vec<_>>(), ["A", "B", "C"]);
+        assert_eq!(pragmas.partitions().count(), 6);
+        assert_eq!(hls_loop_names(&loop_info, &mut sources)[product], "product_loop");
+    }
+}
@@ -0,0 +1,992 @@
+use crate::ir_util::{is_annotation_call, operands, term_operands};
+use llvm_ir::constant::{Float, GetElementPtr};
+use llvm_ir::function::{CallingConvention, FunctionAttribute, ParameterAttribute};
+use llvm_ir::module::{Linkage, ThreadLocalMode, UnnamedAddr, Visibility};
+use llvm_ir::types::NamedStructDef;
+use llvm_ir::{
+    BasicBlock, Constant, ConstantRef, DebugLoc, Function, HasDebugLoc, Instruction, Module, Name, Operand, Terminator, Type,
+    TypeRef,
+};
+use regex::Regex;
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+/// Writes a module back to textual LLVM IR that `clang`, `opt` and Vitis can read. Debug locations
+/// are kept as a line table, so line-based modes work on the output too; the rest of the metadata
+/// (loop pragmas, variable info) is not kept by llvm-ir and is dropped along with the llvm.dbg.*
+/// calls that referred to it. Unnamed values are renumbered in order, as the parser requires.
+pub fn module_to_ll(module: &Module) -> Result<String, String> {
+    let mut printer = Printer::new(module);
+    let mut out = format!("; ModuleID = '{}'\n", module.name);
+    out.push_str(&format!("source_filename = \"{}\"\n", escape(&module.source_file_name)));
+    if !module.data_layout.layout_str.is_empty() {
+        out.push_str(&format!("target datalayout = \"{}\"\n", escape(&module.data_layout.layout_str)));
+    }
+    if let Some(triple) = module.target_triple.as_ref().filter(|t| !t.is_empty()) {
+        out.push_str(&format!("target triple = \"{}\"\n", escape(triple)));
+    }
+    for line in module.inline_assembly.lines() {
+        out.push_str(&format!("module asm \"{}\"\n", escape(line)));
+    }
+
+    let mut struct_names: Vec<&String> = module.types.all_struct_names().collect();
+    struct_names.sort();
+    if !struct_names.is_empty() {
+        out.push('\n');
+    }
+    for name in struct_names {
+        let def = match module.types.named_struct_def(name) {
+            Some(NamedStructDef::Defined(ty)) => ty_text(ty),
+            _ => "opaque".to_string(),
+        };
+        out.push_str(&format!("%{} = type {}\n", identifier(name), def));
+    }
+
+    if !module.global_vars.is_empty() {
+        out.push('\n');
+    }
+    for global in &module.global_vars {
+        let mut line = format!("{} =", global_name(&global.name));
+        let linkage = linkage_text(global.linkage);
+        if global.initializer.is_none() && linkage.is_empty() {
+            line.push_str(" external");
+        }
+        line.push_str(&linkage);
+        line.push_str(visibility_text(global.visibility));
+        if global.thread_local_mode != ThreadLocalMode::NotThreadLocal {
+            line.push_str(" thread_local");
+        }
+        match global.unnamed_addr {
+            Some(UnnamedAddr::Global) => line.push_str(" unnamed_addr"),
+            Some(UnnamedAddr::Local) => line.push_str(" local_unnamed_addr"),
+            None => {}
+        }
+        if global.addr_space != 0 {
+            line.push_str(&format!(" addrspace({})", global.addr_space));
+        }
+        line.push_str(if global.is_constant { " constant " } else { " global " });
+        line.push_str(&ty_text(&printer.value_type(&global.name, global.initializer.as_ref())));
+        if let Some(init) = &global.initializer {
+            line.push_str(&format!(" {}", printer.constant_value(init)?));
+        }
+        if let Some(section) = &global.section {
+            line.push_str(&format!(", section \"{}\"", escape(section)));
+        }
+        if global.alignment > 0 {
+            line.push_str(&format!(", align {}", global.alignment));
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    for alias in &module.global_aliases {
+        out.push_str(&format!(
+            "{} ={}{} alias {}, {}\n",
+            global_name(&alias.name),
+            linkage_text(alias.linkage),
+            visibility_text(alias.visibility),
+            ty_text(&alias.ty),
+            printer.constant(&alias.aliasee)?
+        ));
+    }
+    if !module.global_ifuncs.is_empty() {
+        return Err("Cannot print ifuncs".to_string());
+    }
+
+    for func in &module.functions {
+        out.push('\n');
+        out.push_str(&printer.function(func)?);
+    }
+    let mut declared = false;
+    for decl in module.func_declarations.iter().filter(|d| !d.name.starts_with("llvm.dbg.")) {
+        if !declared {
+            out.push('\n');
+            declared = true;
+        }
+        let params: Vec<String> =
+            decl.parameters.iter().map(|p| format!("{}{}", ty_text(&p.ty), param_attributes(&p.attributes))).collect();
+        let params = if decl.is_var_arg { [params, vec!["...".to_string()]].concat() } else { params };
+        out.push_str(&format!(
+            "declare{}{}{}{} {} @{}({})\n",
+            linkage_text(decl.linkage),
+            visibility_text(decl.visibility),
+            calling_convention_text(decl.calling_convention),
+            param_attributes(&decl.return_attributes),
+            ty_text(&decl.return_type),
+            identifier(&decl.name),
+            params.join(", ")
+        ));
+    }
+    out.push_str(&printer.metadata_text());
+    Ok(out)
+}
+
+/// Writes the module to `path`.
+pub fn write_module(module: &Module, path: &str) -> Result<(), String> {
+    let text = module_to_ll(module)?;
+    fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+struct Printer<'m> {
+    module: &'m Module,
+    // Metadata nodes, numbered by position: the compile unit and its flags come first
+    nodes: Vec<String>,
+    files: HashMap<(String, String), usize>,
+    locations: HashMap<(u32, u32, usize), usize>,
+    // New number of each unnamed value in the function being printed
+    numbers: HashMap<usize, usize>,
+    // Type of the value each referenced global holds; `GlobalVariable::ty` is only `ptr`
+    global_types: HashMap<&'m Name, TypeRef>,
+    gep_types: GepTypes<'m>,
+}
+
+impl<'m> Printer<'m> {
+    fn new(module: &'m Module) -> Self {
+        let mut global_types = HashMap::new();
+        let constants = module
+            .functions
+            .iter()
+            .flat_map(|f| &f.basic_blocks)
+            .flat_map(|bb| bb.instrs.iter().flat_map(operands).chain(term_operands(&bb.term)))
+            .filter_map(|op| op.as_constant())
+            .chain(module.global_vars.iter().filter_map(|g| g.initializer.as_deref()));
+        for c in constants {
+            referenced_globals(c, &mut global_types);
+        }
+        Printer {
+            module,
+            nodes: Vec::new(),
+            files: HashMap::new(),
+            locations: HashMap::new(),
+            numbers: HashMap::new(),
+            global_types,
+            gep_types: GepTypes::new(module),
+        }
+    }
+
+    /// The type a global holds: its initializer's, else the type it is referenced with. An
+    /// external global nothing refers to is declared as a byte.
+    fn value_type(&self, name: &Name, initializer: Option<&ConstantRef>) -> TypeRef {
+        match initializer {
+            Some(init) => self.module.type_of(init),
+            None => self.global_types.get(name).cloned().unwrap_or_else(|| self.module.types.i8()),
+        }
+    }
+
+    fn function(&mut self, func: &Function) -> Result<String, String> {
+        // Renumber unnamed values in the order the parser expects: parameters, then block labels
+        // and results in program order
+        self.numbers.clear();
+        let params = func.parameters.iter().map(|p| &p.name);
+        let defined = func.basic_blocks.iter().flat_map(|bb| std::iter::once(&bb.name).chain(bb.instrs.iter().filter_map(|i| i.try_get_result())));
+        for name in params.chain(defined) {
+            if let Name::Number(n) = name {
+                let next = self.numbers.len();
+                self.numbers.insert(*n, next);
+            }
+        }
+
+        let locs = func.basic_blocks.iter().flat_map(|bb| bb.instrs.iter().map(|i| i.get_debug_loc()).chain(std::iter::once(bb.term.get_debug_loc())));
+        let first_loc = func.debugloc.as_ref().or_else(|| locs.flatten().next());
+        let scope = first_loc.map(|loc| self.subprogram(func, loc));
+
+        let params: Vec<String> = func
+            .parameters
+            .iter()
+            .map(|p| format!("{}{} {}", ty_text(&p.ty), param_attributes(&p.attributes), self.local(&p.name)))
+            .collect();
+        let params = if func.is_var_arg { [params, vec!["...".to_string()]].concat() } else { params };
+        let attributes: Vec<String> = func.function_attributes.iter().filter_map(function_attribute).collect();
+        let mut out = format!(
+            "define{}{}{}{} {} @{}({})",
+            linkage_text(func.linkage),
+            visibility_text(func.visibility),
+            calling_convention_text(func.calling_convention),
+            param_attributes(&func.return_attributes),
+            ty_text(&func.return_type),
+            identifier(&func.name),
+            params.join(", ")
+        );
+        if !attributes.is_empty() {
+            out.push_str(&format!(" {}", attributes.join(" ")));
+        }
+        if let Some(section) = &func.section {
+            out.push_str(&format!(" section \"{}\"", escape(section)));
+        }
+        if func.alignment > 0 {
+            out.push_str(&format!(" align {}", func.alignment));
+        }
+        if let Some(personality) = &func.personality_function {
+            out.push_str(&format!(" personality {}", self.constant(personality)?));
+        }
+        if let Some(sp) = scope {
+            out.push_str(&format!(" !dbg !{}", sp));
+        }
+        out.push_str(" {\n");
+        for (i, bb) in func.basic_blocks.iter().enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            out.push_str(&self.block(bb, scope)?);
+        }
+        out.push_str("}\n");
+        Ok(out)
+    }
+
+    fn block(&mut self, bb: &BasicBlock, scope: Option<usize>) -> Result<String, String> {
+        let label = self.local(&bb.name);
+        let mut out = format!("{}:\n", &label[1..]);
+        for instr in &bb.instrs {
+            // llvm-ir doesn't keep the metadata the debug intrinsics take
+            if is_annotation_call(instr) && operands(instr).iter().any(|op| matches!(op, Operand::MetadataOperand)) {
+                continue;
+            }
+            let text = self.instruction(instr)?;
+            out.push_str(&format!("  {}{}\n", text, self.dbg(instr.get_debug_loc(), scope, matches!(instr, Instruction::Call(_)))));
+        }
+        let text = self.terminator(&bb.term)?;
+        out.push_str(&format!("  {}{}\n", text, self.dbg(bb.term.get_debug_loc(), scope, false)));
+        Ok(out)
+    }
+
+    // The `, !dbg` attachment of an instruction. Calls in a function with debug info must have one,
+    // so calls without a location get line 0.
+    fn dbg(&mut self, loc: &Option<DebugLoc>, scope: Option<usize>, is_call: bool) -> String {
+        let Some(scope) = scope else { return String::new() };
+        let (line, col) = match loc {
+            Some(loc) => (loc.line, loc.col.unwrap_or(0)),
+            None if is_call => (0, 0),
+            None => return String::new(),
+        };
+        let next = self.nodes.len();
+        let id = *self.locations.entry((line, col, scope)).or_insert(next);
+        if id == next {
+            self.nodes.push(format!("!DILocation(line: {}, column: {}, scope: !{})", line, col, scope));
+        }
+        format!(", !dbg !{}", id)
+    }
+
+    fn compile_unit(&mut self, file: usize) {
+        if self.nodes[0].is_empty() {
+            self.nodes[0] = format!(
+                "distinct !DICompileUnit(language: DW_LANG_C99, file: !{}, producer: \"llvm_ir_tool\", isOptimized: false, runtimeVersion: 0, emissionKind: LineTablesOnly)",
+                file
+            );
+        }
+    }
+
+    fn file(&mut self, filename: &str, directory: &str) -> usize {
+        if self.nodes.is_empty() {
+            // Compile unit, module flag and subroutine type, filled in by compile_unit
+            self.nodes.push(String::new());
+            self.nodes.push("!{i32 2, !\"Debug Info Version\", i32 3}".to_string());
+            self.nodes.push("!DISubroutineType(types: !{})".to_string());
+        }
+        let key = (filename.to_string(), directory.to_string());
+        if let Some(&id) = self.files.get(&key) {
+            return id;
+        }
+        let id = self.nodes.len();
+        self.nodes.push(format!("!DIFile(filename: \"{}\", directory: \"{}\")", escape(filename), escape(directory)));
+        self.files.insert(key, id);
+        id
+    }
+
+    fn subprogram(&mut self, func: &Function, loc: &DebugLoc) -> usize {
+        let file = self.file(&loc.filename, loc.directory.as_deref().unwrap_or(""));
+        self.compile_unit(file);
+        let id = self.nodes.len();
+        self.nodes.push(format!(
+            "distinct !DISubprogram(name: \"{}\", scope: !{}, file: !{}, line: {}, type: !2, scopeLine: {}, spFlags: DISPFlagDefinition, unit: !0)",
+            escape(&func.name),
+            file,
+            file,
+            loc.line,
+            loc.line
+        ));
+        id
+    }
+
+    fn metadata_text(&self) -> String {
+        if self.nodes.is_empty() {
+            return String::new();
+        }
+        let mut out = String::from("\n!llvm.dbg.cu = !{!0}\n!llvm.module.flags = !{!1}\n\n");
+        for (i, node) in self.nodes.iter().enumerate() {
+            out.push_str(&format!("!{} = {}\n", i, node));
+        }
+        out
+    }
+
+    /// `%name`, with unnamed values renumbered.
+    fn local(&self, name: &Name) -> String {
+        match name {
+            Name::Name(s) => format!("%{}", identifier(s)),
+            Name::Number(n) => format!("%{}", self.numbers.get(n).copied().unwrap_or(*n)),
+        }
+    }
+
+    /// An operand without its type.
+    fn value(&self, op: &Operand) -> Result<String, String> {
+        match op {
+            Operand::LocalOperand { name, .. } => Ok(self.local(name)),
+            Operand::ConstantOperand(c) => self.constant_value(c),
+            Operand::MetadataOperand => Err("Cannot print metadata operands".to_string()),
+        }
+    }
+
+    /// An operand with its type, e.g. `i32 %x`.
+    fn operand(&self, op: &Operand) -> Result<String, String> {
+        Ok(format!("{} {}", ty_text(&self.module.type_of(op)), self.value(op)?))
+    }
+
+    fn constant(&self, c: &ConstantRef) -> Result<String, String> {
+        Ok(format!("{} {}", ty_text(&self.module.type_of(c)), self.constant_value(c)?))
+    }
+
+    fn constant_value(&self, c: &Constant) -> Result<String, String> {
+        let list = |items: &[ConstantRef]| -> Result<String, String> {
+            Ok(items.iter().map(|c| self.constant(c)).collect::<Result<Vec<_>, _>>()?.join(", "))
+        };
+        let binary = |op: &str, a: &ConstantRef, b: &ConstantRef| -> Result<String, String> {
+            Ok(format!("{} ({}, {})", op, self.constant(a)?, self.constant(b)?))
+        };
+        let cast = |op: &str, operand: &ConstantRef, to: &TypeRef| -> Result<String, String> {
+            Ok(format!("{} ({} to {})", op, self.constant(operand)?, ty_text(to)))
+        };
+        Ok(match c {
+            Constant::Int { bits: 1, value } => (if *value == 0 { "false" } else { "true" }).to_string(),
+            Constant::Int { bits, value } if *bits < 64 => {
+                let shift = 64 - bits;
+                (((*value << shift) as i64) >> shift).to_string()
+            }
+            Constant::Int { value, .. } => (*value as i64).to_string(),
+            // Hexadecimal is exact; floats are written as the double of the same value
+            Constant::Float(Float::Single(f)) => format!("0x{:016X}", (*f as f64).to_bits()),
+            Constant::Float(Float::Double(d)) => format!("0x{:016X}", d.to_bits()),
+            Constant::Float(f) => return Err(format!("Cannot print {:?} constants", f)),
+            Constant::Null(_) => "null".to_string(),
+            Constant::AggregateZero(_) => "zeroinitializer".to_string(),
+            Constant::Undef(_) => "undef".to_string(),
+            Constant::Poison(_) => "poison".to_string(),
+            Constant::TokenNone => "none".to_string(),
+            Constant::Struct { values, is_packed, .. } => {
+                let body = format!("{{ {} }}", list(values)?);
+                if *is_packed { format!("<{}>", body) } else { body }
+            }
+            Constant::Array { element_type, elements } => match (element_type.as_ref(), byte_string(elements)) {
+                (Type::IntegerType { bits: 8 }, Some(bytes)) => format!("c\"{}\"", escape_bytes(&bytes)),
+                _ => format!("[{}]", list(elements)?),
+            },
+            Constant::Vector(elements) => format!("<{}>", list(elements)?),
+            Constant::GlobalReference { name, .. } => global_name(name),
+            Constant::Add(e) => binary("add", &e.operand0, &e.operand1)?,
+            Constant::Sub(e) => binary("sub", &e.operand0, &e.operand1)?,
+            Constant::Mul(e) => binary("mul", &e.operand0, &e.operand1)?,
+            Constant::Xor(e) => binary("xor", &e.operand0, &e.operand1)?,
+            Constant::Shl(e) => binary("shl", &e.operand0, &e.operand1)?,
+            Constant::ICmp(e) => binary(&format!("icmp {}", e.predicate), &e.operand0, &e.operand1)?,
+            Constant::FCmp(e) => binary(&format!("fcmp {}", e.predicate), &e.operand0, &e.operand1)?,
+            Constant::Trunc(e) => cast("trunc", &e.operand, &e.to_type)?,
+            Constant::PtrToInt(e) => cast("ptrtoint", &e.operand, &e.to_type)?,
+            Constant::IntToPtr(e) => cast("inttoptr", &e.operand, &e.to_type)?,
+            Constant::BitCast(e) => cast("bitcast", &e.operand, &e.to_type)?,
+            Constant::AddrSpaceCast(e) => cast("addrspacecast", &e.operand, &e.to_type)?,
+            Constant::GetElementPtr(gep) => {
+                let operands = format!("{}, {}", self.constant(&gep.address)?, list(&gep.indices)?);
+                let inbounds = if gep.in_bounds { " inbounds" } else { "" };
+                let ty = self.gep_types.text(gep).ok_or_else(|| {
+                    format!("Cannot print constant getelementptr ({}): its source element type is unknown", operands)
+                })?;
+                format!("getelementptr{} ({}, {})", inbounds, ty, operands)
+            }
+            other => return Err(format!("Cannot print constant {}", other)),
+        })
+    }
+
+    fn instruction(&self, instr: &Instruction) -> Result<String, String> {
+        let binary = |op: &str, flags: &[(bool, &str)], a: &Operand, b: &Operand, dest: &Name| -> Result<String, String> {
+            let flags: String = flags.iter().filter(|(on, _)| *on).map(|(_, f)| format!(" {}", f)).collect();
+            Ok(format!("{} = {}{} {}, {}", self.local(dest), op, flags, self.operand(a)?, self.value(b)?))
+        };
+        let cast = |op: &str, operand: &Operand, to: &TypeRef, dest: &Name| -> Result<String, String> {
+            Ok(format!("{} = {} {} to {}", self.local(dest), op, self.operand(operand)?, ty_text(to)))
+        };
+        let align = |a: u32| if a > 0 { format!(", align {}", a) } else { String::new() };
+        Ok(match instr {
+            Instruction::Add(i) => binary("add", &[(i.nuw, "nuw"), (i.nsw, "nsw")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::Sub(i) => binary("sub", &[(i.nuw, "nuw"), (i.nsw, "nsw")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::Mul(i) => binary("mul", &[(i.nuw, "nuw"), (i.nsw, "nsw")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::Shl(i) => binary("shl", &[(i.nuw, "nuw"), (i.nsw, "nsw")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::UDiv(i) => binary("udiv", &[(i.exact, "exact")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::SDiv(i) => binary("sdiv", &[(i.exact, "exact")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::LShr(i) => binary("lshr", &[(i.exact, "exact")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::AShr(i) => binary("ashr", &[(i.exact, "exact")], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::URem(i) => binary("urem", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::SRem(i) => binary("srem", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::And(i) => binary("and", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::Or(i) => binary("or", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::Xor(i) => binary("xor", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::FAdd(i) => binary("fadd", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::FSub(i) => binary("fsub", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::FMul(i) => binary("fmul", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::FDiv(i) => binary("fdiv", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::FRem(i) => binary("frem", &[], &i.operand0, &i.operand1, &i.dest)?,
+            Instruction::FNeg(i) => format!("{} = fneg {}", self.local(&i.dest), self.operand(&i.operand)?),
+            Instruction::Freeze(i) => format!("{} = freeze {}", self.local(&i.dest), self.operand(&i.operand)?),
+            Instruction::ExtractElement(i) => {
+                format!("{} = extractelement {}, {}", self.local(&i.dest), self.operand(&i.vector)?, self.operand(&i.index)?)
+            }
+            Instruction::InsertElement(i) => format!(
+                "{} = insertelement {}, {}, {}",
+                self.local(&i.dest),
+                self.operand(&i.vector)?,
+                self.operand(&i.element)?,
+                self.operand(&i.index)?
+            ),
+            Instruction::ShuffleVector(i) => format!(
+                "{} = shufflevector {}, {}, {}",
+                self.local(&i.dest),
+                self.operand(&i.operand0)?,
+                self.operand(&i.operand1)?,
+                self.constant(&i.mask)?
+            ),
+            Instruction::ExtractValue(i) => {
+                let indices: Vec<String> = i.indices.iter().map(|n| n.to_string()).collect();
+                format!("{} = extractvalue {}, {}", self.local(&i.dest), self.operand(&i.aggregate)?, indices.join(", "))
+            }
+            Instruction::InsertValue(i) => {
+                let indices: Vec<String> = i.indices.iter().map(|n| n.to_string()).collect();
+                format!(
+                    "{} = insertvalue {}, {}, {}",
+                    self.local(&i.dest),
+                    self.operand(&i.aggregate)?,
+                    self.operand(&i.element)?,
+                    indices.join(", ")
+                )
+            }
+            Instruction::Alloca(i) => {
+                let count = match i.num_elements.as_constant() {
+                    Some(Constant::Int { value: 1, .. }) => String::new(),
+                    _ => format!(", {}", self.operand(&i.num_elements)?),
+                };
+                format!("{} = alloca {}{}{}", self.local(&i.dest), ty_text(&i.allocated_type), count, align(i.alignment))
+            }
+            Instruction::Load(i) if i.atomicity.is_none() => format!(
+                "{} = load {}{}, {}{}",
+                self.local(&i.dest),
+                if i.volatile { "volatile " } else { "" },
+                ty_text(&i.loaded_ty),
+                self.operand(&i.address)?,
+                align(i.alignment)
+            ),
+            Instruction::Store(i) if i.atomicity.is_none() => format!(
+                "store {}{}, {}{}",
+                if i.volatile { "volatile " } else { "" },
+                self.operand(&i.value)?,
+                self.operand(&i.address)?,
+                align(i.alignment)
+            ),
+            Instruction::GetElementPtr(i) => {
+                let mut text = format!(
+                    "{} = getelementptr{} {}, {}",
+                    self.local(&i.dest),
+                    if i.in_bounds { " inbounds" } else { "" },
+                    ty_text(&i.source_element_type),
+                    self.operand(&i.address)?
+                );
+                for index in &i.indices {
+                    text.push_str(&format!(", {}", self.operand(index)?));
+                }
+                text
+            }
+            Instruction::Trunc(i) => cast("trunc", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::ZExt(i) => cast("zext", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::SExt(i) => cast("sext", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::FPTrunc(i) => cast("fptrunc", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::FPExt(i) => cast("fpext", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::FPToUI(i) => cast("fptoui", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::FPToSI(i) => cast("fptosi", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::UIToFP(i) => cast("uitofp", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::SIToFP(i) => cast("sitofp", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::PtrToInt(i) => cast("ptrtoint", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::IntToPtr(i) => cast("inttoptr", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::BitCast(i) => cast("bitcast", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::AddrSpaceCast(i) => cast("addrspacecast", &i.operand, &i.to_type, &i.dest)?,
+            Instruction::ICmp(i) => {
+                format!("{} = icmp {} {}, {}", self.local(&i.dest), i.predicate, self.operand(&i.operand0)?, self.value(&i.operand1)?)
+            }
+            Instruction::FCmp(i) => {
+                format!("{} = fcmp {} {}, {}", self.local(&i.dest), i.predicate, self.operand(&i.operand0)?, self.value(&i.operand1)?)
+            }
+            Instruction::Phi(i) => {
+                let incoming: Vec<String> = i
+                    .incoming_values
+                    .iter()
+                    .map(|(value, block)| Ok(format!("[ {}, {} ]", self.value(value)?, self.local(block))))
+                    .collect::<Result<_, String>>()?;
+                format!("{} = phi {} {}", self.local(&i.dest), ty_text(&i.to_type), incoming.join(", "))
+            }
+            Instruction::Select(i) => format!(
+                "{} = select {}, {}, {}",
+                self.local(&i.dest),
+                self.operand(&i.condition)?,
+                self.operand(&i.true_value)?,
+                self.operand(&i.false_value)?
+            ),
+            Instruction::VAArg(i) => {
+                format!("{} = va_arg {}, {}", self.local(&i.dest), self.operand(&i.arg_list)?, ty_text(&i.cur_type))
+            }
+            Instruction::Call(call) => {
+                let Some(callee) = call.function.as_ref().right() else {
+                    return Err("Cannot print inline assembly calls".to_string());
+                };
+                // Variadic callees need their whole type, others just the return type
+                let ty = match call.function_ty.as_ref() {
+                    Type::FuncType { is_var_arg: true, .. } => ty_text(&call.function_ty),
+                    Type::FuncType { result_type, .. } => ty_text(result_type),
+                    _ => return Err("Call through a value that is not a function".to_string()),
+                };
+                let args: Vec<String> = call
+                    .arguments
+                    .iter()
+                    .map(|(arg, attrs)| {
+                        let ty = ty_text(&self.module.type_of(arg));
+                        Ok(format!("{}{} {}", ty, param_attributes(attrs), self.value(arg)?))
+                    })
+                    .collect::<Result<_, String>>()?;
+                let dest = call.dest.as_ref().map_or(String::new(), |d| format!("{} = ", self.local(d)));
+                format!(
+                    "{}{}call{}{} {} {}({})",
+                    dest,
+                    if call.is_tail_call { "tail " } else { "" },
+                    calling_convention_text(call.calling_convention),
+                    param_attributes(&call.return_attributes),
+                    ty,
+                    self.value(callee)?,
+                    args.join(", ")
+                )
+            }
+            other => return Err(format!("Cannot print instruction {}", other)),
+        })
+    }
+
+    fn terminator(&self, term: &Terminator) -> Result<String, String> {
+        Ok(match term {
+            Terminator::Ret(ret) => match &ret.return_operand {
+                Some(op) => format!("ret {}", self.operand(op)?),
+                None => "ret void".to_string(),
+            },
+            Terminator::Br(br) => format!("br label {}", self.local(&br.dest)),
+            Terminator::CondBr(br) => format!(
+                "br {}, label {}, label {}",
+                self.operand(&br.condition)?,
+                self.local(&br.true_dest),
+                self.local(&br.false_dest)
+            ),
+            Terminator::Switch(switch) => {
+                let mut text = format!("switch {}, label {} [", self.operand(&switch.operand)?, self.local(&switch.default_dest));
+                for (value, dest) in &switch.dests {
+                    text.push_str(&format!("\n    {}, label {}", self.constant(value)?, self.local(dest)));
+                }
+                text.push_str("\n  ]");
+                text
+            }
+            Terminator::Unreachable(_) => "unreachable".to_string(),
+            other => return Err(format!("Cannot print terminator {}", other)),
+        })
+    }
+}
+
+/// Records the value type of every global a constant refers to.
+pub fn referenced_globals<'m>(c: &'m Constant, types: &mut HashMap<&'m Name, TypeRef>) {
+    match c {
+        Constant::GlobalReference { name, ty } => {
+            types.insert(name, ty.clone());
+        }
+        Constant::GetElementPtr(gep) => referenced_globals(&gep.address, types),
+        Constant::BitCast(cast) => referenced_globals(&cast.operand, types),
+        Constant::PtrToInt(cast) => referenced_globals(&cast.operand, types),
+        Constant::Struct { values: items, .. } | Constant::Array { elements: items, .. } | Constant::Vector(items) => {
+            for item in items {
+                referenced_globals(item, types);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Source element types of constant GEPs. llvm-ir doesn't keep them, so they are taken from the
+/// same GEP in the .ll file the module was parsed from, else from the global when the indices
+/// start at 0 and walk the global's type. Anything else would move the address, so it is unknown.
+pub struct GepTypes<'m> {
+    module: &'m Module,
+    // Types of the constant GEPs in the module's .ll file, read when first needed
+    from_text: OnceCell<HashMap<String, Option<String>>>,
+}
+
+impl<'m> GepTypes<'m> {
+    pub fn new(module: &'m Module) -> Self {
+        GepTypes { module, from_text: OnceCell::new() }
+    }
+
+    /// The source element type of a GEP as written in .ll text.
+    pub fn text(&self, gep: &GetElementPtr) -> Option<String> {
+        let from_text = self.from_text.get_or_init(|| gep_types(&fs::read_to_string(&self.module.name).unwrap_or_default()));
+        if let Some(Some(ty)) = self.operands(gep).and_then(|operands| from_text.get(&operands)) {
+            return Some(ty.clone());
+        }
+        let Constant::GlobalReference { ty, .. } = gep.address.as_ref() else { return None };
+        let (first, rest) = gep.indices.split_first()?;
+        (matches!(first.as_ref(), Constant::Int { value: 0, .. }) && self.walks(ty, rest)).then(|| ty_text(ty))
+    }
+
+    /// The source element type of a GEP, if it is an integer or a type within the global's.
+    pub fn resolve(&self, gep: &GetElementPtr) -> Option<TypeRef> {
+        let text = self.text(gep)?;
+        if let Some(bits) = text.strip_prefix('i').and_then(|b| b.parse().ok()) {
+            return Some(self.module.types.int(bits));
+        }
+        let Constant::GlobalReference { ty, .. } = gep.address.as_ref() else { return None };
+        let mut pending = vec![ty.clone()];
+        while let Some(ty) = pending.pop() {
+            if ty_text(&ty) == text {
+                return Some(ty);
+            }
+            match ty.as_ref() {
+                Type::ArrayType { element_type, .. } | Type::VectorType { element_type, .. } => pending.push(element_type.clone()),
+                Type::StructType { element_types, .. } => pending.extend(element_types.iter().cloned()),
+                Type::NamedStructType { name } => {
+                    if let Some(NamedStructDef::Defined(def)) = self.module.types.named_struct_def(name)
+                        && let Type::StructType { element_types, .. } = def.as_ref()
+                    {
+                        pending.extend(element_types.iter().cloned());
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // The operands of a GEP on a global with integer indices as printed, e.g. `ptr @g, i64 6`
+    fn operands(&self, gep: &GetElementPtr) -> Option<String> {
+        let Constant::GlobalReference { name, .. } = gep.address.as_ref() else { return None };
+        let mut operands = format!("{} {}", ty_text(&self.module.type_of(&gep.address)), global_name(name));
+        for index in &gep.indices {
+            let Constant::Int { bits, value } = index.as_ref() else { return None };
+            let shift = 64 - (*bits).clamp(1, 64);
+            operands.push_str(&format!(", i{} {}", bits, ((*value << shift) as i64) >> shift));
+        }
+        Some(operands)
+    }
+
+    // True if constant indices select in-range elements and fields all the way down `ty`
+    fn walks(&self, ty: &Type, indices: &[ConstantRef]) -> bool {
+        let Some((index, rest)) = indices.split_first() else { return true };
+        let Constant::Int { value, .. } = index.as_ref() else { return false };
+        let i = *value as usize;
+        match ty {
+            Type::ArrayType { element_type, num_elements } | Type::VectorType { element_type, num_elements, .. } => {
+                i < *num_elements && self.walks(element_type, rest)
+            }
+            Type::StructType { element_types, .. } => element_types.get(i).is_some_and(|t| self.walks(t, rest)),
+            Type::NamedStructType { name } => match self.module.types.named_struct_def(name) {
+                Some(NamedStructDef::Defined(def)) => self.walks(def, indices),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Source element types of the constant GEPs on globals in .ll text, by their operands as printed
+/// (`ptr @g, i64 0, i64 2`). Operands used with two different types map to None.
+fn gep_types(text: &str) -> HashMap<String, Option<String>> {
+    let re = Regex::new(r#"getelementptr (?:inbounds )?\(([^()]+?), (ptr @(?:[-\w.$]+|"[^"]*")(?:, i\d+ -?\d+)*)\)"#).unwrap();
+    let mut types: HashMap<String, Option<String>> = HashMap::new();
+    for caps in re.captures_iter(text) {
+        let ty = caps[1].to_string();
+        types.entry(caps[2].to_string()).and_modify(|known| if known.as_ref() != Some(&ty) { *known = None }).or_insert(Some(ty));
+    }
+    types
+}
+
+fn ty_text(ty: &Type) -> String {
+    let list = |types: &[TypeRef]| types.iter().map(|t| ty_text(t)).collect::<Vec<_>>().join(", ");
+    match ty {
+        Type::NamedStructType { name } => format!("%{}", identifier(name)),
+        Type::ArrayType { element_type, num_elements } => format!("[{} x {}]", num_elements, ty_text(element_type)),
+        Type::VectorType { element_type, num_elements, scalable: false } => format!("<{} x {}>", num_elements, ty_text(element_type)),
+        Type::StructType { element_types, is_packed } => {
+            let body = if element_types.is_empty() { "{}".to_string() } else { format!("{{ {} }}", list(element_types)) };
+            if *is_packed { format!("<{}>", body) } else { body }
+        }
+        Type::FuncType { result_type, param_types, is_var_arg } => {
+            let mut params = list(param_types);
+            if *is_var_arg {
+                params = if params.is_empty() { "...".to_string() } else { format!("{}, ...", params) };
+            }
+            format!("{} ({})", ty_text(result_type), params)
+        }
+        other => other.to_string(),
+    }
+}
+
+/// A name as an LLVM identifier, quoted unless it only has the characters an unquoted one may have.
+fn identifier(name: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-$._".contains(c);
+    if !name.is_empty() && name.chars().all(plain) && !name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.to_string()
+    } else {
+        format!("\"{}\"", escape(name))
+    }
+}
+
+fn global_name(name: &Name) -> String {
+    match name {
+        Name::Name(s) => format!("@{}", identifier(s)),
+        Name::Number(n) => format!("@{}", n),
+    }
+}
+
+/// The bytes of an i8 array, if all its elements are constant integers.
+fn byte_string(elements: &[ConstantRef]) -> Option<Vec<u8>> {
+    elements
+        .iter()
+        .map(|e| match e.as_ref() {
+            Constant::Int { value, .. } => Some(*value as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| if (b' '..=b'~').contains(&b) && b != b'"' && b != b'\\' { (b as char).to_string() } else { format!("\\{:02X}", b) })
+        .collect()
+}
+
+fn escape(s: &str) -> String {
+    escape_bytes(s.as_bytes())
+}
+
+fn linkage_text(linkage: Linkage) -> String {
+    let text = match linkage {
+        Linkage::Private => "private",
+        Linkage::Internal => "internal",
+        Linkage::ExternalWeak => "extern_weak",
+        Linkage::AvailableExternally => "available_externally",
+        Linkage::LinkOnceAny => "linkonce",
+        Linkage::LinkOnceODR => "linkonce_odr",
+        Linkage::WeakAny => "weak",
+        Linkage::WeakODR => "weak_odr",
+        Linkage::Common => "common",
+        Linkage::Appending => "appending",
+        _ => return String::new(),
+    };
+    format!(" {}", text)
+}
+
+fn visibility_text(visibility: Visibility) -> &'static str {
+    match visibility {
+        Visibility::Default => "",
+        Visibility::Hidden => " hidden",
+        Visibility::Protected => " protected",
+    }
+}
+
+fn calling_convention_text(cc: CallingConvention) -> &'static str {
+    match cc {
+        CallingConvention::Fast => " fastcc",
+        CallingConvention::Cold => " coldcc",
+        _ => "",
+    }
+}
+
+fn param_attributes(attrs: &[ParameterAttribute]) -> String {
+    attrs
+        .iter()
+        .filter_map(|a| {
+            let text = match a {
+                ParameterAttribute::ZeroExt => "zeroext".to_string(),
+                ParameterAttribute::SignExt => "signext".to_string(),
+                ParameterAttribute::InReg => "inreg".to_string(),
+                ParameterAttribute::ByVal(ty) => format!("byval({})", ty_text(ty)),
+                ParameterAttribute::SRet(ty) => format!("sret({})", ty_text(ty)),
+                ParameterAttribute::InAlloca(ty) => format!("inalloca({})", ty_text(ty)),
+                ParameterAttribute::Preallocated(ty) => format!("preallocated({})", ty_text(ty)),
+                ParameterAttribute::Alignment(n) => format!("align {}", n),
+                ParameterAttribute::NoAlias => "noalias".to_string(),
+                ParameterAttribute::NoCapture => "nocapture".to_string(),
+                ParameterAttribute::NoFree => "nofree".to_string(),
+                ParameterAttribute::Nest => "nest".to_string(),
+                ParameterAttribute::Returned => "returned".to_string(),
+                ParameterAttribute::NonNull => "nonnull".to_string(),
+                ParameterAttribute::Dereferenceable(n) => format!("dereferenceable({})", n),
+                ParameterAttribute::DereferenceableOrNull(n) => format!("dereferenceable_or_null({})", n),
+                ParameterAttribute::SwiftSelf => "swiftself".to_string(),
+                ParameterAttribute::SwiftError => "swifterror".to_string(),
+                ParameterAttribute::ImmArg => "immarg".to_string(),
+                ParameterAttribute::NoUndef => "noundef".to_string(),
+                ParameterAttribute::StringAttribute { kind, value } => string_attribute(kind, value),
+                _ => return None,
+            };
+            Some(format!(" {}", text))
+        })
+        .collect()
+}
+
+/// Function attributes that don't depend on metadata or on the target; leaving the others out is
+/// always valid, it only gives the optimizer less to go on.
+fn function_attribute(attr: &FunctionAttribute) -> Option<String> {
+    let text = match attr {
+        FunctionAttribute::AlwaysInline => "alwaysinline",
+        FunctionAttribute::Cold => "cold",
+        FunctionAttribute::InlineHint => "inlinehint",
+        FunctionAttribute::MinimizeSize => "minsize",
+        FunctionAttribute::NoFree => "nofree",
+        FunctionAttribute::NoInline => "noinline",
+        FunctionAttribute::NoRecurse => "norecurse",
+        FunctionAttribute::NoReturn => "noreturn",
+        FunctionAttribute::NoSync => "nosync",
+        FunctionAttribute::NoUnwind => "nounwind",
+        FunctionAttribute::OptNone => "optnone",
+        FunctionAttribute::OptSize => "optsize",
+        FunctionAttribute::WillReturn => "willreturn",
+        FunctionAttribute::StringAttribute { kind, value } => return Some(string_attribute(kind, value)),
+        _ => return None,
+    };
+    Some(text.to_string())
+}
+
+fn string_attribute(kind: &str, value: &str) -> String {
+    if value.is_empty() { format!("\"{}\"", escape(kind)) } else { format!("\"{}\"=\"{}\"", escape(kind), escape(value)) }
+}
+
+/// Removes instructions without side effects whose result nothing uses, until none are left.
+/// Returns how many were removed.
+pub fn remove_dead_code(func: &mut Function) -> usize {
+    let mut removed = 0;
+    loop {
+        let used: HashSet<Name> = func
+            .basic_blocks
+            .iter()
+            .flat_map(|bb| bb.instrs.iter().flat_map(operands).chain(term_operands(&bb.term)))
+            .filter_map(|op| match op {
+                Operand::LocalOperand { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        let before: usize = func.basic_blocks.iter().map(|bb| bb.instrs.len()).sum();
+        for bb in &mut func.basic_blocks {
+            bb.instrs.retain(|instr| {
+                let pure = match instr {
+                    Instruction::Load(load) => !load.volatile && load.atomicity.is_none(),
+                    Instruction::Store(_)
+                    | Instruction::Call(_)
+                    | Instruction::Fence(_)
+                    | Instruction::CmpXchg(_)
+                    | Instruction::AtomicRMW(_)
+                    | Instruction::VAArg(_)
+                    | Instruction::LandingPad(_)
+                    | Instruction::CatchPad(_)
+                    | Instruction::CleanupPad(_) => false,
+                    _ => true,
+                };
+                !pure || instr.try_get_result().is_some_and(|dest| used.contains(dest))
+            });
+        }
+        let after: usize = func.basic_blocks.iter().map(|bb| bb.instrs.len()).sum();
+        if after == before {
+            return removed;
+        }
+        removed += before - after;
+    }
+}
+
+/// `--emit [--dce] [--output <file.ll>]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --emit [--dce] [--output <file.ll>]";
+    let mut dce = false;
+    let mut output = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--dce" => dce = true,
+            "--output" => output = Some(rest.next().ok_or_else(|| format!("Missing value for --output\n{}", usage))?),
+            _ => return Err(format!("Unknown option '{}'\n{}", arg, usage)),
+        }
+    }
+    let mut module = module.clone();
+    if dce {
+        for func in &mut module.functions {
+            let removed = remove_dead_code(func);
+            if removed > 0 {
+                eprintln!("Removed {} dead instruction(s) from {}", removed, func.name);
+            }
+        }
+    }
+    match output {
+        Some(path) => {
+            write_module(&module, path)?;
+            println!("Wrote {}", path);
+        }
+        None => print!("{}", module_to_ll(&module)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LLVM 18 folds constant GEPs into byte offsets from the global
+    const BYTE_OFFSETS: &str = r#"
+@table = global [4 x i32] [i32 1, i32 2, i32 3, i32 4]
+@pair = global { i16, [2 x i16] } zeroinitializer
+
+define i32 @read() {
+entry:
+  %a = load i16, ptr getelementptr inbounds (i8, ptr @table, i64 6)
+  %b = load i32, ptr getelementptr inbounds ([4 x i32], ptr @table, i64 0, i64 2)
+  %c = load i16, ptr getelementptr inbounds ({ i16, [2 x i16] }, ptr @pair, i64 0, i32 1, i64 1)
+  %a.ext = sext i16 %a to i32
+  %c.ext = sext i16 %c to i32
+  %sum = add i32 %a.ext, %b
+  %total = add i32 %sum, %c.ext
+  ret i32 %total
+}
+"#;
+
+    #[test]
+    fn round_trips_byte_offset_geps() {
+        let path = std::env::temp_dir().join(format!("llvm_ir_tool_printer_{}.ll", std::process::id()));
+        fs::write(&path, BYTE_OFFSETS).unwrap();
+        let module = Module::from_ir_path(&path).unwrap();
+        let text = module_to_ll(&module).unwrap();
+        assert!(text.contains("getelementptr inbounds (i8, ptr @table, i64 6)"), "{}", text);
+
+        // The printed file reads back to the same code, and prints the same again
+        fs::write(&path, &text).unwrap();
+        let reparsed = Module::from_ir_path(&path).unwrap();
+        let reprinted = module_to_ll(&reparsed);
+        let _ = fs::remove_file(&path);
+        assert_eq!(reparsed.functions, module.functions);
+        assert_eq!(reprinted.unwrap(), text);
+    }
+
+    #[test]
+    fn needs_the_text_for_geps_off_the_globals_type() {
+        // Without the file, only GEPs that walk the global's own type can be printed
+        let module = Module::from_ir_str(BYTE_OFFSETS).unwrap();
+        let err = module_to_ll(&module).unwrap_err();
+        assert!(err.contains("ptr @table, i64 6"), "{}", err);
+
+        let walking = BYTE_OFFSETS.replace("(i8, ptr @table, i64 6)", "([4 x i32], ptr @table, i64 0, i64 1)");
+        let module = Module::from_ir_str(&walking).unwrap();
+        let text = module_to_ll(&module).unwrap();
+        assert!(text.contains("getelementptr inbounds ({ i16, [2 x i16] }, ptr @pair, i64 0, i32 1, i64 1)"), "{}", text);
+    }
+}
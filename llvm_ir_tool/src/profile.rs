@@ -0,0 +1,141 @@
+use crate::interp::{int_value, FunctionProfile, Interpreter, Value};
+use crate::ir_util::{block_label, opcode_name, successors};
+use crate::loops::LoopInfo;
+use llvm_ir::types::FPType;
+use llvm_ir::{Function, Module, Type};
+use std::collections::BTreeMap;
+
+// Pointer arguments without an explicit size get a zero-filled buffer of this many bytes
+const DEFAULT_BUFFER_BYTES: usize = 1 << 20;
+
+/// `--profile <function> [args...]`: runs the function in the interpreter and prints
+/// block, edge and loop execution counts next to the static opcode counts.
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let func_name = args.first().ok_or("Usage: cargo run <filename.ll> --profile <function> [args...]")?;
+    let func = module
+        .get_func_by_name(func_name)
+        .ok_or_else(|| format!("Function '{}' not found in module.", func_name))?;
+
+    let mut interp = Interpreter::new(module)?;
+    let call_args = parse_arguments(&mut interp, func, &args[1..])?;
+    let result = interp.call_function(func_name, call_args)?;
+
+    println!("Profiled '{}' ({} instructions executed)", func_name, interp.steps);
+    if let Some(value) = result {
+        println!("Return value: {}", value);
+    }
+    println!();
+
+    // Report callees too, in module order
+    for func in &module.functions {
+        if let Some(profile) = interp.profiles.get(func.name.as_str()) {
+            print_function_profile(func, profile);
+        }
+    }
+    Ok(())
+}
+
+/// Converts command line values to interpreter values according to the parameter types.
+/// Integers and floats are parsed directly; pointer parameters take either a byte count
+/// for a zero-filled buffer or `file:<path>` to load the buffer contents from a file.
+pub fn parse_arguments(interp: &mut Interpreter, func: &Function, args: &[String]) -> Result<Vec<Value>, String> {
+    let mut values = Vec::new();
+    for (i, param) in func.parameters.iter().enumerate() {
+        let arg = args.get(i).map(|s| s.as_str());
+        let value = match (param.ty.as_ref(), arg) {
+            (Type::PointerType { .. }, Some(spec)) if spec.starts_with("file:") => {
+                let bytes = std::fs::read(&spec[5..]).map_err(|e| format!("Failed to read '{}': {}", &spec[5..], e))?;
+                interp.alloc_buffer(&bytes)?
+            }
+            (Type::PointerType { .. }, spec) => {
+                let size = match spec {
+                    Some(s) => s.parse().map_err(|_| format!("Invalid buffer size '{}' for {}", s, param.name))?,
+                    None => DEFAULT_BUFFER_BYTES,
+                };
+                interp.alloc_buffer(&vec![0; size])?
+            }
+            (Type::IntegerType { bits }, Some(s)) => {
+                let v: i64 = s.parse().map_err(|_| format!("Invalid integer '{}' for {}", s, param.name))?;
+                int_value(*bits, v as u64)
+            }
+            (Type::FPType(fpt), Some(s)) => {
+                let v: f64 = s.parse().map_err(|_| format!("Invalid number '{}' for {}", s, param.name))?;
+                Value::Float(if *fpt == FPType::Single { v as f32 as f64 } else { v })
+            }
+            (_, None) => return Err(format!("Missing argument for parameter {} of '{}'", param.name, func.name)),
+            (ty, Some(_)) => return Err(format!("Unsupported parameter type {} for {}", ty, param.name)),
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+fn print_function_profile(func: &Function, profile: &FunctionProfile) {
+    println!("Function: {} (called {} times)", func.name, profile.calls);
+
+    println!("  {:<20} {:>12}", "Block", "Count");
+    for bb in &func.basic_blocks {
+        let count = profile.block_counts.get(&bb.name).copied().unwrap_or(0);
+        println!("  {:<20} {:>12}", block_label(&bb.name), count);
+    }
+
+    println!("\n  {:<36} {:>12}", "Edge", "Count");
+    for bb in &func.basic_blocks {
+        for succ in successors(&bb.term) {
+            let count = profile.edge_counts.get(&(&bb.name, succ)).copied().unwrap_or(0);
+            let edge = format!("{} -> {}", block_label(&bb.name), block_label(succ));
+            println!("  {:<36} {:>12}", edge, count);
+        }
+    }
+
+    let loop_info = LoopInfo::new(func);
+    if !loop_info.loops.is_empty() {
+        println!("\n  Loops:");
+    }
+    for (l, lp) in loop_info.loops.iter().enumerate() {
+        let cfg = &loop_info.cfg;
+        let edge_count = |(from, to): (usize, usize)| {
+            profile.edge_counts.get(&(cfg.names[from], cfg.names[to])).copied().unwrap_or(0)
+        };
+        let entries: u64 = loop_info.entry_edges(l).into_iter().map(edge_count).sum();
+        let header_execs = profile.block_counts.get(cfg.names[lp.header]).copied().unwrap_or(0);
+        // Iterations = header executions minus the ones that left the loop straight from the header.
+        // This gives the body trip count both for -O0 "for.cond" headers and for rotated loops.
+        let header_exits: u64 = loop_info
+            .exit_edges(l)
+            .into_iter()
+            .filter(|(from, _)| *from == lp.header)
+            .map(edge_count)
+            .sum();
+        let iterations = header_execs.saturating_sub(header_exits);
+        let avg = if entries > 0 { iterations as f64 / entries as f64 } else { 0.0 };
+        println!(
+            "  {}{} (depth {}): entered {} times, {} iterations, avg trip count {:.1}",
+            "  ".repeat(lp.depth - 1),
+            loop_info.describe(l),
+            lp.depth,
+            entries,
+            iterations,
+            avg
+        );
+    }
+
+    // Static opcode counts next to the counts weighted by block executions
+    let mut mix: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+    for bb in &func.basic_blocks {
+        let count = profile.block_counts.get(&bb.name).copied().unwrap_or(0);
+        for instr in &bb.instrs {
+            let entry = mix.entry(opcode_name(instr)).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += count;
+        }
+    }
+    let static_total: usize = mix.values().map(|(s, _)| s).sum();
+    let dynamic_total: u64 = mix.values().map(|(_, d)| d).sum();
+    println!("\n  {:<15} {:>8} {:>14} {:>8}", "Opcode", "Static", "Dynamic", "Share");
+    for (op_name, (static_count, dynamic_count)) in &mix {
+        let share = if dynamic_total > 0 { *dynamic_count as f64 * 100.0 / dynamic_total as f64 } else { 0.0 };
+        println!("  {:<15} {:>8} {:>14} {:>7.1}%", op_name, static_count, dynamic_count, share);
+    }
+    println!("  {:<15} {:>8} {:>14}\n", "Total", static_total, dynamic_total);
+}
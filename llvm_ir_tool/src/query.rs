@@ -0,0 +1,449 @@
+use crate::ir_util::{block_label, callee_name, instr_text, opcode_name, operands, term_operands};
+use crate::loops::LoopInfo;
+use crate::memory::{access, MemBase, MemoryInfo};
+use crate::source::source_name;
+use llvm_ir::{Constant, Function, HasDebugLoc, Instruction, Module, Name, Operand};
+use std::collections::{HashMap, HashSet};
+
+pub const QUERY_SYNTAX: &str = "Query: <opcode|any> [type] [clause]...
+  from|to|on <base>|<name>             loads/stores through a pointer to <base>
+  in loop [depth <op> <n>|<header|line>], outside loop, in block <name>
+  at line <n>[-<m>]                    source lines from the debug info
+  calling <function>                   direct calls
+  where operand is [not] <kind>        some operand (all of them with 'operands are')
+  where result is unused|used
+  <base> is arg, local or global, optionally with :<name>; <kind> is constant, a <base> or an opcode
+  e.g. 'load from arg:input_pixels in loop depth>=2', 'mul i32 where operand is constant'";
+
+/// Opcodes a query can name, as `opcode_name` prints them in lower case.
+const OPCODES: &[&str] = &[
+    "add", "sub", "mul", "udiv", "sdiv", "urem", "srem", "and", "or", "xor", "shl", "lshr", "ashr", "fadd", "fsub",
+    "fmul", "fdiv", "frem", "fneg", "extractelement", "insertelement", "shufflevector", "extractvalue", "insertvalue",
+    "alloca", "load", "store", "fence", "cmpxchg", "atomicrmw", "getelementptr", "trunc", "zext", "sext", "fptrunc",
+    "fpext", "fptoui", "fptosi", "uitofp", "sitofp", "ptrtoint", "inttoptr", "bitcast", "addrspacecast", "icmp", "fcmp",
+    "phi", "select", "freeze", "call", "vaarg", "landingpad", "catchpad", "cleanuppad",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Compare {
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Ge,
+    Gt,
+}
+
+impl Compare {
+    fn holds(self, a: usize, b: usize) -> bool {
+        match self {
+            Compare::Lt => a < b,
+            Compare::Le => a <= b,
+            Compare::Eq => a == b,
+            Compare::Ne => a != b,
+            Compare::Ge => a >= b,
+            Compare::Gt => a > b,
+        }
+    }
+}
+
+/// An argument, local variable or global, optionally by name; None matches any of the three.
+struct Base {
+    kind: Option<&'static str>,
+    name: Option<String>,
+}
+
+impl Base {
+    fn matches(&self, base: MemBase) -> bool {
+        let kind = match base {
+            MemBase::Param(_) => "arg",
+            MemBase::Alloca(_) => "local",
+            MemBase::Global(_) => "global",
+        };
+        self.kind.is_none_or(|k| k == kind) && self.name.as_ref().is_none_or(|n| *n == base.to_string())
+    }
+}
+
+/// What an operand must be.
+enum Kind {
+    Constant,
+    // Derived from an argument, local or global (also through a load of it)
+    Value(Base),
+    // Result of an instruction with this opcode
+    Opcode(String),
+}
+
+enum Clause {
+    Access(Base),
+    InLoop(Option<(Compare, usize)>, Option<String>),
+    OutsideLoop,
+    InBlock(String),
+    Lines(u32, u32),
+    Calling(String),
+    Operand { all: bool, negate: bool, kind: Kind },
+    Used(bool),
+}
+
+/// A parsed structural query: an opcode, an optional type and clauses that must all hold.
+pub struct Query {
+    opcode: Option<String>,
+    ty: Option<String>,
+    clauses: Vec<Clause>,
+}
+
+/// An instruction a query matched.
+pub struct Match<'f> {
+    pub func: &'f Function,
+    pub block: usize,
+    pub index: usize,
+    pub instr: &'f Instruction,
+}
+
+impl Match<'_> {
+    /// Source location if the instruction has one, then the block and position (as --ddg takes it).
+    pub fn location(&self) -> String {
+        let at = format!("{}:{}", block_label(&self.func.basic_blocks[self.block].name), self.index);
+        match self.instr.get_debug_loc() {
+            Some(loc) => format!("{}:{}:{} {}", loc.filename, loc.line, loc.col.unwrap_or(0), at),
+            None => at,
+        }
+    }
+}
+
+// Splits comparisons off their neighbours, so `depth>=2` reads as `depth >= 2`
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for word in text.split_whitespace() {
+        let mut current = String::new();
+        let mut in_op = false;
+        for c in word.chars() {
+            let is_op = matches!(c, '<' | '>' | '=' | '!');
+            if is_op != in_op && !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            in_op = is_op;
+            current.push(c);
+        }
+        if !current.is_empty() {
+            tokens.push(current);
+        }
+    }
+    tokens
+}
+
+fn is_type(token: &str) -> bool {
+    matches!(token, "half" | "float" | "double" | "fp128" | "ptr" | "void")
+        || token.strip_prefix('i').is_some_and(|bits| !bits.is_empty() && bits.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn parse_opcode(token: &str) -> Option<String> {
+    let token = token.to_lowercase();
+    let token = if token == "gep" { "getelementptr".to_string() } else { token };
+    OPCODES.contains(&token.as_str()).then_some(token)
+}
+
+fn base(token: &str) -> Option<Base> {
+    let (kind, name) = token.split_once(':').map_or((token, None), |(k, n)| (k, Some(n.trim_start_matches(['%', '@']).to_string())));
+    let kind = match kind {
+        "arg" => Some("arg"),
+        "local" => Some("local"),
+        "global" => Some("global"),
+        "any" => None,
+        _ => return None,
+    };
+    Some(Base { kind, name })
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Query, String> {
+        let tokens = tokenize(text);
+        let mut rest = tokens.iter().map(|t| t.as_str()).peekable();
+        let unexpected = |token: Option<&str>, expected: &str| match token {
+            Some(t) => format!("Unexpected '{}' in query, expected {}", t, expected),
+            None => format!("Query ends early, expected {}", expected),
+        };
+        let opcode = match rest.next() {
+            Some("any" | "*") => None,
+            Some(t) => Some(parse_opcode(t).ok_or_else(|| format!("Unknown opcode '{}' in query", t))?),
+            None => return Err("Empty query".to_string()),
+        };
+        let ty = rest.next_if(|t| is_type(t)).map(|t| t.to_string());
+        let mut clauses = Vec::new();
+        while let Some(word) = rest.next() {
+            let clause = match word {
+                "and" => continue,
+                "from" | "to" | "on" => {
+                    // A plain name matches an argument, local or global of that name
+                    let token = rest.next().ok_or_else(|| unexpected(None, "arg, local or global[:<name>]"))?;
+                    let name = Some(token.trim_start_matches(['%', '@']).to_string());
+                    Clause::Access(base(token).unwrap_or(Base { kind: None, name }))
+                }
+                "outside" => match rest.next() {
+                    Some("loop" | "loops") => Clause::OutsideLoop,
+                    other => return Err(unexpected(other, "'loop'")),
+                },
+                "in" => match rest.next() {
+                    Some("block") => Clause::InBlock(rest.next().ok_or_else(|| unexpected(None, "a block name"))?.trim_start_matches('%').to_string()),
+                    Some("loop") if rest.next_if_eq(&"depth").is_some() => {
+                        let op = match rest.next() {
+                            Some("<") => Compare::Lt,
+                            Some("<=") => Compare::Le,
+                            Some("=" | "==") => Compare::Eq,
+                            Some("!=") => Compare::Ne,
+                            Some(">=") => Compare::Ge,
+                            Some(">") => Compare::Gt,
+                            other => return Err(unexpected(other, "a comparison (<, <=, =, !=, >=, >)")),
+                        };
+                        let token = rest.next();
+                        let depth = token.and_then(|t| t.parse().ok()).ok_or_else(|| unexpected(token, "a loop depth"))?;
+                        Clause::InLoop(Some((op, depth)), None)
+                    }
+                    Some("loop") => {
+                        // A loop header or source line may follow, anything else starts the next clause
+                        let spec = rest.next_if(|t| t.parse::<u32>().is_ok() || t.starts_with('%') || t.contains('.'));
+                        Clause::InLoop(None, spec.map(|s| s.to_string()))
+                    }
+                    other => return Err(unexpected(other, "'loop' or 'block'")),
+                },
+                "at" => {
+                    rest.next_if_eq(&"line");
+                    let token = rest.next();
+                    let range = token.and_then(|t| match t.split_once('-') {
+                        Some((a, b)) => Some((a.parse().ok()?, b.parse().ok()?)),
+                        None => t.parse().ok().map(|l| (l, l)),
+                    });
+                    let (first, last) = range.ok_or_else(|| unexpected(token, "a line or <first>-<last>"))?;
+                    Clause::Lines(first, last)
+                }
+                "calling" => Clause::Calling(rest.next().ok_or_else(|| unexpected(None, "a function name"))?.trim_start_matches('@').to_string()),
+                "where" => match (rest.next(), rest.next()) {
+                    (Some("result"), Some("is")) => match rest.next() {
+                        Some("unused") => Clause::Used(false),
+                        Some("used") => Clause::Used(true),
+                        other => return Err(unexpected(other, "'used' or 'unused'")),
+                    },
+                    (Some(subject @ ("operand" | "operands")), Some("is" | "are")) => {
+                        let negate = rest.next_if_eq(&"not").is_some();
+                        let token = rest.next();
+                        let kind = match token {
+                            Some("constant" | "const") => Kind::Constant,
+                            Some(t) => match (base(t), parse_opcode(t)) {
+                                (Some(b), _) => Kind::Value(b),
+                                (None, Some(op)) => Kind::Opcode(op),
+                                (None, None) => return Err(unexpected(token, "constant, arg, local, global or an opcode")),
+                            },
+                            None => return Err(unexpected(None, "constant, arg, local, global or an opcode")),
+                        };
+                        Clause::Operand { all: subject == "operands", negate, kind }
+                    }
+                    (other, _) => return Err(unexpected(other, "'operand is', 'operands are' or 'result is'")),
+                },
+                other => return Err(unexpected(Some(other), "from, to, on, in, outside, at, calling or where")),
+            };
+            clauses.push(clause);
+        }
+        Ok(Query { opcode, ty, clauses })
+    }
+
+    /// Instructions of the function that match, in block order.
+    pub fn find<'f>(&self, module: &Module, func: &'f Function) -> Vec<Match<'f>> {
+        let loop_info = LoopInfo::new(func);
+        let mem = MemoryInfo::new(func);
+        let defs: HashMap<&Name, &Instruction> =
+            func.basic_blocks.iter().flat_map(|bb| &bb.instrs).filter_map(|i| Some((i.try_get_result()?, i))).collect();
+        let used: HashSet<&Name> = func
+            .basic_blocks
+            .iter()
+            .flat_map(|bb| bb.instrs.iter().flat_map(operands).chain(term_operands(&bb.term)))
+            .filter_map(|op| match op {
+                Operand::LocalOperand { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect();
+
+        // Arguments, locals or globals a value comes from, looking through one load of it
+        let bases = |op: &'f Operand| {
+            let mut bases: Vec<MemBase> = mem.base_of(op).into_iter().collect();
+            if let Operand::LocalOperand { name, .. } = op
+                && let Some(Instruction::Load(load)) = defs.get(name)
+            {
+                bases.extend(mem.base_of(&load.address));
+            }
+            bases
+        };
+        let is_kind = |op: &'f Operand, kind: &Kind| match kind {
+            Kind::Constant => match op {
+                Operand::ConstantOperand(c) => !matches!(c.as_ref(), Constant::GlobalReference { .. }),
+                _ => mem.const_value(op).is_some(),
+            },
+            Kind::Value(base) => bases(op).into_iter().any(|b| base.matches(b)),
+            Kind::Opcode(opcode) => match op {
+                Operand::LocalOperand { name, .. } => defs.get(name).is_some_and(|d| opcode_name(d).to_lowercase() == *opcode),
+                _ => false,
+            },
+        };
+
+        let mut matches = Vec::new();
+        for (b, bb) in func.basic_blocks.iter().enumerate() {
+            let depth = loop_info.innermost(b).map_or(0, |l| loop_info.loops[l].depth);
+            for (index, instr) in bb.instrs.iter().enumerate() {
+                if self.opcode.as_ref().is_some_and(|op| opcode_name(instr).to_lowercase() != *op) {
+                    continue;
+                }
+                if let Some(ty) = &self.ty {
+                    let actual = match instr {
+                        Instruction::Store(store) => module.type_of(&store.value),
+                        _ => module.type_of(instr),
+                    };
+                    if actual.to_string() != *ty {
+                        continue;
+                    }
+                }
+                // Call arguments only, the callee is matched with 'calling'
+                let ops: Vec<&Operand> = match instr {
+                    Instruction::Call(call) => call.arguments.iter().map(|(op, _)| op).collect(),
+                    _ => operands(instr),
+                };
+                let ops: Vec<&Operand> = ops.into_iter().filter(|op| !matches!(op, Operand::MetadataOperand)).collect();
+                let line = instr.get_debug_loc().as_ref().map(|loc| loc.line);
+                let holds = |clause: &Clause| match clause {
+                    Clause::Access(base) => {
+                        access(instr).and_then(|a| mem.base_of(a.address)).is_some_and(|b| base.matches(b))
+                    }
+                    Clause::InLoop(Some((op, n)), _) => depth > 0 && op.holds(depth, *n),
+                    Clause::InLoop(None, Some(spec)) => {
+                        loop_info.find(spec).is_some_and(|l| loop_info.loops[l].blocks.contains(&b))
+                    }
+                    Clause::InLoop(None, None) => depth > 0,
+                    Clause::OutsideLoop => depth == 0,
+                    Clause::InBlock(name) => block_label(&bb.name) == *name,
+                    Clause::Lines(first, last) => line.is_some_and(|l| (*first..=*last).contains(&l)),
+                    Clause::Calling(name) => callee_name(instr).is_some_and(|c| c == name || source_name(c) == *name),
+                    Clause::Operand { all: false, negate, kind } => ops.iter().any(|op| is_kind(op, kind) != *negate),
+                    Clause::Operand { all: true, negate, kind } => {
+                        !ops.is_empty() && ops.iter().all(|op| is_kind(op, kind) != *negate)
+                    }
+                    Clause::Used(used_at_all) => {
+                        instr.try_get_result().is_some_and(|dest| used.contains(dest) == *used_at_all)
+                    }
+                };
+                if self.clauses.iter().all(holds) {
+                    matches.push(Match { func, block: b, index, instr });
+                }
+            }
+        }
+        matches
+    }
+}
+
+/// `--query <query> [function]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = format!("Usage: cargo run <filename.ll> --query \"<query>\" [function]\n{}", QUERY_SYNTAX);
+    let (text, func_name) = match args {
+        [text] => (text, None),
+        [text, func] => (text, Some(func)),
+        _ => return Err(usage),
+    };
+    let query = Query::parse(text).map_err(|e| format!("{}\n{}", e, usage))?;
+    let functions: Vec<&Function> = match func_name {
+        Some(name) => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == *name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        None => module.functions.iter().collect(),
+    };
+
+    let mut total = 0;
+    for func in functions {
+        let matches = query.find(module, func);
+        if matches.is_empty() {
+            continue;
+        }
+        println!("Function: {} ({} match(es))", func.name, matches.len());
+        let locations: Vec<String> = matches.iter().map(|m| m.location()).collect();
+        let width = locations.iter().map(|l| l.len()).max().unwrap_or(0);
+        for (m, location) in matches.iter().zip(&locations) {
+            println!("  {:<width$}  {}", location, instr_text(m.instr), width = width);
+        }
+        println!();
+        total += matches.len();
+    }
+    println!("{} instruction(s) match '{}'", total, text);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // box_blur's window sum at -O0 with the pixels read through an argument
+    const SUM9: &str = "
+define i32 @sum9(ptr %input_pixels) {
+entry:
+  %p.addr = alloca ptr
+  %sum_r = alloca i32
+  %k = alloca i32
+  store ptr %input_pixels, ptr %p.addr
+  store i32 0, ptr %sum_r
+  store i32 0, ptr %k
+  br label %for.cond
+for.cond:
+  %0 = load i32, ptr %k
+  %cmp = icmp slt i32 %0, 9
+  br i1 %cmp, label %for.body, label %for.end
+for.body:
+  %1 = load ptr, ptr %p.addr
+  %2 = load i32, ptr %k
+  %idx = sext i32 %2 to i64
+  %a = getelementptr inbounds i8, ptr %1, i64 %idx
+  %3 = load i8, ptr %a
+  %conv = zext i8 %3 to i32
+  %4 = load i32, ptr %sum_r
+  %add = add nsw i32 %4, %conv
+  store i32 %add, ptr %sum_r
+  %5 = load i32, ptr %k
+  %inc = add nsw i32 %5, 1
+  store i32 %inc, ptr %k
+  br label %for.cond
+for.end:
+  %6 = load i32, ptr %sum_r
+  %div = sdiv i32 %6, 9
+  ret i32 %div
+}
+";
+
+    // Where each match is, as block:index
+    fn found(query: &str) -> Vec<String> {
+        let module = Module::from_ir_str(SUM9).unwrap();
+        let query = Query::parse(query).unwrap();
+        query.find(&module, &module.functions[0]).iter().map(|m| m.location()).collect()
+    }
+
+    #[test]
+    fn tokenizes_comparisons() {
+        assert_eq!(tokenize("in loop depth>=2"), ["in", "loop", "depth", ">=", "2"]);
+        assert_eq!(tokenize("depth != 1"), ["depth", "!=", "1"]);
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let error = |q: &str| Query::parse(q).err().unwrap();
+        assert_eq!(error(""), "Empty query");
+        assert_eq!(error("frobnicate"), "Unknown opcode 'frobnicate' in query");
+        assert_eq!(error("load in loop depth ~ 2"), "Unexpected '~' in query, expected a comparison (<, <=, =, !=, >=, >)");
+        assert_eq!(error("load from"), "Query ends early, expected arg, local or global[:<name>]");
+        assert_eq!(error("mul where result is maybe"), "Unexpected 'maybe' in query, expected 'used' or 'unused'");
+        assert!(Query::parse("GEP i8 in loop for.cond and where operand is not constant").is_ok());
+    }
+
+    #[test]
+    fn matches_instructions() {
+        assert_eq!(found("load from arg:input_pixels in loop depth>=1"), ["for.body:4"]);
+        assert_eq!(found("store to local:sum_r"), ["entry:4", "for.body:8"]);
+        assert_eq!(found("add i32 where operand is zext"), ["for.body:7"]);
+        assert_eq!(found("any outside loop where operand is local:sum_r"), ["entry:4", "for.end:0", "for.end:1"]);
+        assert_eq!(found("icmp in loop %for.cond where operands are not arg"), ["for.cond:1"]);
+        assert_eq!(found("load from local:k in block for.body"), ["for.body:1", "for.body:9"]);
+        assert!(found("sdiv in loop").is_empty());
+    }
+}
@@ -0,0 +1,663 @@
+use crate::cfg::dominates;
+use crate::ir_util::{block_label, const_int, instr_text, operands};
+use crate::loops::LoopInfo;
+use crate::resources::{op_resources_at, op_width, Resources};
+use crate::source::source_name;
+use llvm_ir::{Function, HasDebugLoc, Instruction, Module, Name, Operand, Type};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Rounds of plain iteration before ranges that keep growing are widened to their whole type.
+const MAX_ROUNDS: usize = 16;
+
+/// A conservative range of integer values, read as signed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Range {
+    pub lo: i128,
+    pub hi: i128,
+}
+
+impl Range {
+    fn point(v: i128) -> Range {
+        Range { lo: v, hi: v }
+    }
+
+    /// Every value of an `iN`; `i1` holds 0 and 1.
+    fn of_bits(bits: u64) -> Range {
+        match bits {
+            0 => Range::point(0),
+            1 => Range { lo: 0, hi: 1 },
+            _ => {
+                let half = 1i128 << (bits.min(127) - 1);
+                Range { lo: -half, hi: half - 1 }
+            }
+        }
+    }
+
+    fn union(self, other: Range) -> Range {
+        Range { lo: self.lo.min(other.lo), hi: self.hi.max(other.hi) }
+    }
+
+    fn from_corners(values: [i128; 4]) -> Range {
+        Range { lo: *values.iter().min().unwrap(), hi: *values.iter().max().unwrap() }
+    }
+
+    /// Bits needed to hold every value: unsigned if none is negative, else two's complement.
+    pub fn bits_needed(&self) -> u64 {
+        let magnitude = |v: i128| 128 - v.leading_zeros() as u64;
+        if self.lo >= 0 {
+            magnitude(self.hi).max(1)
+        } else {
+            1 + magnitude(-(self.lo + 1)).max(magnitude(self.hi.max(0)))
+        }
+    }
+
+    fn is_signed(&self) -> bool {
+        self.lo < 0
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}, {}]", self.lo, self.hi)
+    }
+}
+
+/// How a store changes an -O0 local variable.
+enum Update<'f> {
+    // `x = value`
+    Assign(&'f Operand),
+    // `x += delta` or `x -= delta`, read back from the same variable
+    Step { delta: &'f Operand, negate: bool },
+}
+
+/// Value ranges of one function.
+pub struct ValueRanges<'f> {
+    // Integer SSA values
+    pub values: HashMap<&'f Name, Range>,
+    // -O0 local integer variables, over everything stored to them
+    pub variables: HashMap<&'f Name, Range>,
+}
+
+fn int_bits(ty: &Type) -> Option<u64> {
+    match ty {
+        Type::IntegerType { bits } => Some(*bits as u64),
+        _ => None,
+    }
+}
+
+/// Computes ranges by iterating to a fixed point. Loads of array elements and parameters may hold
+/// anything their type allows; masks, shifts, `zext` and `trunc` narrow that down, and a variable
+/// or phi that is stepped inside counted loops is bounded by its initial value plus trip count
+/// times step (so a sum of 9 `unsigned char` pixels stays within [0, 2295]).
+pub fn analyze<'f>(module: &Module, func: &'f Function) -> ValueRanges<'f> {
+    let loop_info = LoopInfo::new(func);
+    let idom = loop_info.cfg.immediate_dominators();
+    let mut defs: HashMap<&'f Name, (usize, &'f Instruction)> = HashMap::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        for instr in &bb.instrs {
+            if let Some(dest) = instr.try_get_result() {
+                defs.insert(dest, (b, instr));
+            }
+        }
+    }
+    let variable_bits: HashMap<&'f Name, u64> = defs
+        .iter()
+        .filter_map(|(name, (_, instr))| match instr {
+            Instruction::Alloca(a) => int_bits(&a.allocated_type).map(|bits| (*name, bits)),
+            _ => None,
+        })
+        .collect();
+
+    // Variables whose address is passed on can change behind our back
+    let mut escaped: HashSet<&'f Name> = HashSet::new();
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        let address = match instr {
+            Instruction::Load(load) => Some(&load.address),
+            Instruction::Store(store) => Some(&store.address),
+            _ => None,
+        };
+        for op in operands(instr) {
+            if let Operand::LocalOperand { name, .. } = op
+                && variable_bits.contains_key(name)
+                && address != Some(op)
+            {
+                escaped.insert(name);
+            }
+        }
+    }
+
+    // Stores to each variable, split into assignments and steps
+    let mut updates: HashMap<&'f Name, Vec<(usize, Update<'f>)>> = HashMap::new();
+    for (b, bb) in func.basic_blocks.iter().enumerate() {
+        for instr in &bb.instrs {
+            let Instruction::Store(store) = instr else { continue };
+            let Operand::LocalOperand { name: slot, .. } = &store.address else { continue };
+            if !variable_bits.contains_key(slot) || escaped.contains(slot) {
+                continue;
+            }
+            let reads_slot = |op: &Operand| match op {
+                Operand::LocalOperand { name, .. } => matches!(
+                    defs.get(name),
+                    Some((_, Instruction::Load(load))) if matches!(&load.address, Operand::LocalOperand { name, .. } if name == slot)
+                ),
+                _ => false,
+            };
+            let def = match &store.value {
+                Operand::LocalOperand { name, .. } => defs.get(name).map(|(_, instr)| *instr),
+                _ => None,
+            };
+            let update = match def {
+                Some(Instruction::Add(i)) if reads_slot(&i.operand0) => Update::Step { delta: &i.operand1, negate: false },
+                Some(Instruction::Add(i)) if reads_slot(&i.operand1) => Update::Step { delta: &i.operand0, negate: false },
+                Some(Instruction::Sub(i)) if reads_slot(&i.operand0) => Update::Step { delta: &i.operand1, negate: true },
+                _ => Update::Assign(&store.value),
+            };
+            updates.entry(slot).or_default().push((b, update));
+        }
+    }
+
+    // Times a step at `block` can run between resets: the product of the trip counts of the loops
+    // around it that contain no assignment dominating it
+    let step_count = |block: usize, assigned_in: &[usize]| -> Option<i128> {
+        let mut count: i128 = 1;
+        for (l, lp) in loop_info.loops.iter().enumerate() {
+            if !lp.blocks.contains(&block) || assigned_in.iter().any(|&a| lp.blocks.contains(&a) && dominates(&idom, a, block)) {
+                continue;
+            }
+            count = count.checked_mul(loop_info.constant_trip_count(l)? as i128)?;
+        }
+        Some(count)
+    };
+
+    // Variables that escape or are never assigned may hold anything
+    let mut ranges = ValueRanges { values: HashMap::new(), variables: HashMap::new() };
+    for (slot, bits) in &variable_bits {
+        let assigned = updates.get(slot).is_some_and(|list| list.iter().any(|(_, u)| matches!(u, Update::Assign(_))));
+        if !assigned {
+            ranges.variables.insert(slot, Range::of_bits(*bits));
+        }
+    }
+    let order = loop_info.cfg.reverse_post_order();
+    for round in 0.. {
+        let widen = round >= MAX_ROUNDS;
+        let mut changed = false;
+        for &b in &order {
+            for instr in &func.basic_blocks[b].instrs {
+                let Some(dest) = instr.try_get_result() else { continue };
+                let Some(bits) = int_bits(&module.type_of(instr)) else { continue };
+                let Some(range) = eval(module, func, &loop_info, &defs, &ranges, instr, b) else { continue };
+                changed |= join(&mut ranges.values, dest, clamp(range, bits), bits, widen);
+            }
+        }
+        for (slot, list) in &updates {
+            let bits = variable_bits[slot];
+            let assigned_in: Vec<usize> =
+                list.iter().filter(|(_, u)| matches!(u, Update::Assign(_))).map(|(b, _)| *b).collect();
+            let mut init: Option<Range> = None;
+            for (_, update) in list {
+                if let Update::Assign(value) = update
+                    && let Some(r) = operand_range(module, func, &ranges, value)
+                {
+                    init = Some(init.map_or(r, |i| i.union(r)));
+                }
+            }
+            let Some(mut range) = init else { continue };
+            let mut known = true;
+            for (b, update) in list {
+                let Update::Step { delta, negate } = update else { continue };
+                let Some(mut d) = operand_range(module, func, &ranges, delta) else {
+                    known = false;
+                    continue;
+                };
+                if *negate {
+                    d = Range { lo: -d.hi, hi: -d.lo };
+                }
+                match step_count(*b, &assigned_in) {
+                    Some(n) => {
+                        range.lo = range.lo.saturating_add(n.saturating_mul(d.lo.min(0)));
+                        range.hi = range.hi.saturating_add(n.saturating_mul(d.hi.max(0)));
+                    }
+                    None if d == Range::point(0) => {}
+                    None => range = Range::of_bits(bits),
+                }
+            }
+            if known {
+                changed |= join(&mut ranges.variables, slot, clamp(range, bits), bits, widen);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    ranges
+}
+
+// Merges a new range into the table, or gives up on the value once widening has started
+fn join<'f>(table: &mut HashMap<&'f Name, Range>, name: &'f Name, range: Range, bits: u64, widen: bool) -> bool {
+    let merged = table.get(name).map_or(range, |old| old.union(range));
+    if table.get(name) == Some(&merged) {
+        return false;
+    }
+    table.insert(name, if widen { Range::of_bits(bits) } else { merged });
+    true
+}
+
+// A range that doesn't fit the type may wrap, so it could be anything
+fn clamp(range: Range, bits: u64) -> Range {
+    let full = Range::of_bits(bits);
+    if range.lo < full.lo || range.hi > full.hi { full } else { range }
+}
+
+fn operand_range(module: &Module, func: &Function, ranges: &ValueRanges, op: &Operand) -> Option<Range> {
+    match op {
+        Operand::ConstantOperand(_) => match const_int(op) {
+            Some(v) => Some(Range::point(v as i128)),
+            None => int_bits(&module.type_of(op)).map(Range::of_bits),
+        },
+        Operand::LocalOperand { name, ty } => match ranges.values.get(name) {
+            Some(r) => Some(*r),
+            // Parameters can be anything; other values haven't been reached yet
+            None if func.parameters.iter().any(|p| &p.name == name) => int_bits(ty).map(Range::of_bits),
+            None => None,
+        },
+        Operand::MetadataOperand => None,
+    }
+}
+
+// Range of an instruction's result from its operands' ranges, None while they are unknown
+fn eval(
+    module: &Module,
+    func: &Function,
+    loop_info: &LoopInfo,
+    defs: &HashMap<&Name, (usize, &Instruction)>,
+    ranges: &ValueRanges,
+    instr: &Instruction,
+    block: usize,
+) -> Option<Range> {
+    let bits = int_bits(&module.type_of(instr))?;
+    let full = Range::of_bits(bits);
+    let range = |op: &Operand| operand_range(module, func, ranges, op);
+    let operand_bits = |op: &Operand| int_bits(&module.type_of(op)).unwrap_or(bits);
+    let result = match instr {
+        Instruction::Add(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            Range { lo: a.lo.saturating_add(b.lo), hi: a.hi.saturating_add(b.hi) }
+        }
+        Instruction::Sub(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            Range { lo: a.lo.saturating_sub(b.hi), hi: a.hi.saturating_sub(b.lo) }
+        }
+        Instruction::Mul(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            let products = [a.lo.checked_mul(b.lo), a.lo.checked_mul(b.hi), a.hi.checked_mul(b.lo), a.hi.checked_mul(b.hi)];
+            match products {
+                [Some(p), Some(q), Some(r), Some(s)] => Range::from_corners([p, q, r, s]),
+                _ => full,
+            }
+        }
+        Instruction::SDiv(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            if b.lo > 0 || b.hi < 0 {
+                Range::from_corners([a.lo / b.lo, a.lo / b.hi, a.hi / b.lo, a.hi / b.hi])
+            } else {
+                let m = a.lo.abs().max(a.hi.abs());
+                Range { lo: -m, hi: m }
+            }
+        }
+        Instruction::UDiv(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            if a.lo >= 0 && b.lo > 0 { Range { lo: a.lo / b.hi, hi: a.hi / b.lo } } else { full }
+        }
+        Instruction::SRem(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            let m = (b.lo.abs().max(b.hi.abs()) - 1).max(0);
+            Range { lo: if a.lo < 0 { -m.min(-a.lo) } else { 0 }, hi: if a.hi > 0 { m.min(a.hi) } else { 0 } }
+        }
+        Instruction::URem(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            if a.lo >= 0 && b.lo > 0 { Range { lo: 0, hi: a.hi.min(b.hi - 1) } } else { full }
+        }
+        Instruction::And(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            // A non-negative operand works as a mask
+            match (a.lo >= 0, b.lo >= 0) {
+                (true, true) => Range { lo: 0, hi: a.hi.min(b.hi) },
+                (true, false) => Range { lo: 0, hi: a.hi },
+                (false, true) => Range { lo: 0, hi: b.hi },
+                (false, false) => full,
+            }
+        }
+        Instruction::Or(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            bitwise_or(a, b, full)
+        }
+        Instruction::Xor(i) => {
+            let (a, b) = (range(&i.operand0)?, range(&i.operand1)?);
+            bitwise_or(a, b, full)
+        }
+        Instruction::Shl(i) => {
+            let (a, s) = (range(&i.operand0)?, range(&i.operand1)?);
+            if s.lo < 0 || s.hi >= bits as i128 {
+                full
+            } else {
+                let (lo, hi) = (1i128 << s.lo, 1i128 << s.hi);
+                Range::from_corners([a.lo.saturating_mul(lo), a.lo.saturating_mul(hi), a.hi.saturating_mul(lo), a.hi.saturating_mul(hi)])
+            }
+        }
+        Instruction::AShr(i) => {
+            let (a, s) = (range(&i.operand0)?, range(&i.operand1)?);
+            if s.lo < 0 || s.hi >= bits as i128 {
+                full
+            } else {
+                Range::from_corners([a.lo >> s.lo, a.lo >> s.hi, a.hi >> s.lo, a.hi >> s.hi])
+            }
+        }
+        Instruction::LShr(i) => {
+            let (a, s) = (range(&i.operand0)?, range(&i.operand1)?);
+            if s.lo < 0 || s.hi >= bits as i128 {
+                full
+            } else if a.lo >= 0 {
+                Range { lo: a.lo >> s.hi, hi: a.hi >> s.lo }
+            } else {
+                // Negative values shift in as large unsigned ones
+                Range { lo: 0, hi: unsigned_max(bits) >> s.lo }
+            }
+        }
+        Instruction::ZExt(i) => {
+            let a = range(&i.operand)?;
+            if a.lo >= 0 { a } else { Range { lo: 0, hi: unsigned_max(operand_bits(&i.operand)) } }
+        }
+        Instruction::SExt(i) => range(&i.operand)?,
+        Instruction::Trunc(i) => {
+            let a = range(&i.operand)?;
+            if a.lo >= full.lo && a.hi <= full.hi { a } else { full }
+        }
+        Instruction::Select(i) => range(&i.true_value)?.union(range(&i.false_value)?),
+        Instruction::Phi(phi) => phi_range(module, func, loop_info, defs, ranges, phi, block)?,
+        Instruction::ICmp(_) => Range { lo: 0, hi: 1 },
+        // Loads of variables are covered by what is stored to them, once that is known
+        Instruction::Load(load) => match &load.address {
+            Operand::LocalOperand { name, .. } if ranges.variables.contains_key(name) => ranges.variables[name],
+            Operand::LocalOperand { name, .. } if matches!(defs.get(name), Some((_, Instruction::Alloca(_)))) => return None,
+            _ => full,
+        },
+        _ => full,
+    };
+    Some(result)
+}
+
+// Or and xor of non-negative values stay below the next power of two
+fn bitwise_or(a: Range, b: Range, full: Range) -> Range {
+    if a.lo < 0 || b.lo < 0 {
+        return full;
+    }
+    Range { lo: 0, hi: unsigned_max(Range { lo: 0, hi: a.hi.max(b.hi) }.bits_needed()) }
+}
+
+fn unsigned_max(bits: u64) -> i128 {
+    if bits >= 127 { i128::MAX } else { (1i128 << bits) - 1 }
+}
+
+// A header phi stepped by a constant-range delta each iteration of a counted loop is bounded like an
+// -O0 loop variable; other phis are the union of their incoming values
+fn phi_range(
+    module: &Module,
+    func: &Function,
+    loop_info: &LoopInfo,
+    defs: &HashMap<&Name, (usize, &Instruction)>,
+    ranges: &ValueRanges,
+    phi: &llvm_ir::instruction::Phi,
+    block: usize,
+) -> Option<Range> {
+    let range = |op: &Operand| operand_range(module, func, ranges, op);
+    let header_of = loop_info.loops.iter().position(|lp| lp.header == block);
+    if let Some(l) = header_of
+        && let Some(trips) = loop_info.constant_trip_count(l)
+    {
+        let outside = |b: &Name| {
+            loop_info.cfg.names.iter().position(|n| *n == b).is_some_and(|b| !loop_info.loops[l].blocks.contains(&b))
+        };
+        let mut init: Option<Range> = None;
+        let mut step: Option<Range> = None;
+        for (value, from) in &phi.incoming_values {
+            if outside(from) {
+                let r = range(value)?;
+                init = Some(init.map_or(r, |i| i.union(r)));
+                continue;
+            }
+            let Operand::LocalOperand { name, .. } = value else { return None };
+            let d = match defs.get(name) {
+                Some((_, Instruction::Add(i))) if is_local(&i.operand0, &phi.dest) => range(&i.operand1)?,
+                Some((_, Instruction::Add(i))) if is_local(&i.operand1, &phi.dest) => range(&i.operand0)?,
+                Some((_, Instruction::Sub(i))) if is_local(&i.operand0, &phi.dest) => {
+                    let d = range(&i.operand1)?;
+                    Range { lo: -d.hi, hi: -d.lo }
+                }
+                _ => {
+                    step = None;
+                    init = None;
+                    break;
+                }
+            };
+            step = Some(step.map_or(d, |s| s.union(d)));
+        }
+        if let (Some(init), Some(d)) = (init, step) {
+            let n = trips as i128;
+            return Some(Range {
+                lo: init.lo.saturating_add(n.saturating_mul(d.lo.min(0))),
+                hi: init.hi.saturating_add(n.saturating_mul(d.hi.max(0))),
+            });
+        }
+    }
+    let known: Vec<Range> = phi.incoming_values.iter().filter_map(|(value, _)| range(value)).collect();
+    known.into_iter().reduce(Range::union)
+}
+
+fn is_local(op: &Operand, name: &Name) -> bool {
+    matches!(op, Operand::LocalOperand { name: n, .. } if n == name)
+}
+
+/// Width of an operator that computes the same results on the needed bits: the low bits of a sum,
+/// difference, product, shift left or bitwise op only depend on the low bits of the operands, but
+/// divisions, right shifts and comparisons need their operands in full.
+fn needed_width(module: &Module, func: &Function, ranges: &ValueRanges, instr: &Instruction, result: Option<Range>) -> Option<u64> {
+    let operand_bits = |op: &Operand| operand_range(module, func, ranges, op).map(|r| r.bits_needed());
+    let both = |a: &Operand, b: &Operand| Some(operand_bits(a)?.max(operand_bits(b)?));
+    match instr {
+        Instruction::ICmp(i) => {
+            // Mixing signed and unsigned operands takes a sign bit on top
+            let (a, b) = (operand_range(module, func, ranges, &i.operand0)?, operand_range(module, func, ranges, &i.operand1)?);
+            let sign = u64::from(a.is_signed() != b.is_signed());
+            Some(a.bits_needed().max(b.bits_needed()) + sign)
+        }
+        Instruction::Add(_) | Instruction::Sub(_) | Instruction::Mul(_) | Instruction::Shl(_) => Some(result?.bits_needed()),
+        Instruction::And(_) | Instruction::Or(_) | Instruction::Xor(_) | Instruction::Select(_) => Some(result?.bits_needed()),
+        Instruction::SDiv(i) => Some(both(&i.operand0, &i.operand1)?.max(result?.bits_needed())),
+        Instruction::UDiv(i) => Some(both(&i.operand0, &i.operand1)?.max(result?.bits_needed())),
+        Instruction::SRem(i) => Some(both(&i.operand0, &i.operand1)?.max(result?.bits_needed())),
+        Instruction::URem(i) => Some(both(&i.operand0, &i.operand1)?.max(result?.bits_needed())),
+        Instruction::AShr(i) => Some(operand_bits(&i.operand0)?.max(result?.bits_needed())),
+        Instruction::LShr(i) => Some(operand_bits(&i.operand0)?.max(result?.bits_needed())),
+        _ => None,
+    }
+}
+
+// What narrowing from `from` to `to` bits saves
+fn saving(from: Resources, to: Resources) -> Resources {
+    Resources {
+        bram: from.bram.saturating_sub(to.bram),
+        dsp: from.dsp.saturating_sub(to.dsp),
+        ff: from.ff.saturating_sub(to.ff),
+        lut: from.lut.saturating_sub(to.lut),
+    }
+}
+
+// "FF 880, LUT 880", leaving out what doesn't change
+fn saving_text(saved: Resources) -> String {
+    let parts: Vec<String> = [("DSP", saved.dsp), ("FF", saved.ff), ("LUT", saved.lut)]
+        .iter()
+        .filter(|(_, n)| *n > 0)
+        .map(|(unit, n)| format!("{} {}", unit, n))
+        .collect();
+    parts.join(", ")
+}
+
+fn needed_text(range: Range) -> String {
+    format!("{} ({})", range.bits_needed(), if range.is_signed() { "signed" } else { "unsigned" })
+}
+
+/// `--ranges [function]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --ranges [function]";
+    let functions: Vec<&Function> = match args {
+        [] => module.functions.iter().collect(),
+        [name] if !name.starts_with("--") => vec![module
+            .get_func_by_name(name)
+            .or_else(|| module.functions.iter().find(|f| source_name(&f.name) == *name))
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?],
+        _ => return Err(usage.to_string()),
+    };
+    for func in functions {
+        let ranges = analyze(module, func);
+        let mut total = Resources::default();
+        println!("Function: {}", func.name);
+
+        // Variables, at the line of their first store
+        let mut variables = Vec::new();
+        for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+            let Instruction::Alloca(alloca) = instr else { continue };
+            let (Some(bits), Some(range)) = (int_bits(&alloca.allocated_type), ranges.variables.get(&alloca.dest)) else { continue };
+            if range.bits_needed() >= bits {
+                continue;
+            }
+            let line = func.basic_blocks.iter().flat_map(|bb| &bb.instrs).find_map(|i| match i {
+                Instruction::Store(s) if matches!(&s.address, Operand::LocalOperand { name, .. } if *name == alloca.dest) => {
+                    s.get_debug_loc().as_ref().map(|loc| loc.line)
+                }
+                _ => None,
+            });
+            total.ff += bits - range.bits_needed();
+            variables.push((block_label(&alloca.dest), bits, *range, line));
+        }
+        if !variables.is_empty() {
+            println!("  {:<20} {:<8} {:<24} {:<16} {:>8} Line", "Variable", "Declared", "Range", "Needed bits", "FF saved");
+            for (name, bits, range, line) in &variables {
+                let line = line.map_or("-".to_string(), |l| l.to_string());
+                let saved = bits - range.bits_needed();
+                println!("  {:<20} {:<8} {:<24} {:<16} {:>8} {}", name, format!("i{}", bits), range.to_string(), needed_text(*range), saved, line);
+            }
+        }
+
+        // Operators that could be narrower, with what that saves
+        let mut ops = Vec::new();
+        for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+            let width = op_width(module, instr);
+            let result = instr.try_get_result().and_then(|dest| ranges.values.get(dest)).copied();
+            let Some(needed) = needed_width(module, func, &ranges, instr, result) else { continue };
+            if needed >= width {
+                continue;
+            }
+            let saved = saving(op_resources_at(instr, width), op_resources_at(instr, needed));
+            if saved == Resources::default() {
+                continue;
+            }
+            total += saved;
+            let line = instr.get_debug_loc().as_ref().map_or("-".to_string(), |loc| loc.line.to_string());
+            ops.push((line, instr_text(instr), width, needed, result, saved));
+        }
+        if !ops.is_empty() {
+            println!("  Operators that can be narrower:");
+            for (line, text, width, needed, result, saved) in &ops {
+                let range = result.map_or(String::new(), |r| format!(", result {}", r));
+                println!("    line {:<5} {}", line, text);
+                println!("               {} -> {} bits{}; saves {}", width, needed, range, saving_text(*saved));
+            }
+        }
+        if variables.is_empty() && ops.is_empty() {
+            println!("  No integer wider than its values need.");
+        } else {
+            println!(
+                "  Estimated savings: {} FF, {} LUT, {} DSP ({} variable(s), {} operator(s))",
+                total.ff,
+                total.lut,
+                total.dsp,
+                variables.len(),
+                ops.len()
+            );
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // box_blur's 3x3 window sum at -O0: nine unsigned char pixels added into an int
+    const SUM9: &str = "
+define i32 @sum9(ptr %p) {
+entry:
+  %p.addr = alloca ptr
+  %sum_r = alloca i32
+  %k = alloca i32
+  store ptr %p, ptr %p.addr
+  store i32 0, ptr %sum_r
+  store i32 0, ptr %k
+  br label %for.cond
+for.cond:
+  %0 = load i32, ptr %k
+  %cmp = icmp slt i32 %0, 9
+  br i1 %cmp, label %for.body, label %for.end
+for.body:
+  %1 = load ptr, ptr %p.addr
+  %2 = load i32, ptr %k
+  %idx = sext i32 %2 to i64
+  %a = getelementptr inbounds i8, ptr %1, i64 %idx
+  %3 = load i8, ptr %a
+  %conv = zext i8 %3 to i32
+  %4 = load i32, ptr %sum_r
+  %add = add nsw i32 %4, %conv
+  store i32 %add, ptr %sum_r
+  br label %for.inc
+for.inc:
+  %5 = load i32, ptr %k
+  %inc = add nsw i32 %5, 1
+  store i32 %inc, ptr %k
+  br label %for.cond
+for.end:
+  %6 = load i32, ptr %sum_r
+  %div = sdiv i32 %6, 9
+  %low = and i32 %div, 15
+  ret i32 %low
+}
+";
+
+    #[test]
+    fn counts_bits_needed() {
+        assert_eq!(Range { lo: 0, hi: 0 }.bits_needed(), 1);
+        assert_eq!(Range { lo: 0, hi: 255 }.bits_needed(), 8);
+        assert_eq!(Range { lo: 0, hi: 2295 }.bits_needed(), 12);
+        assert_eq!(Range { lo: -128, hi: 127 }.bits_needed(), 8);
+        assert_eq!(Range { lo: -129, hi: 0 }.bits_needed(), 9);
+        assert_eq!(Range { lo: -1, hi: 128 }.bits_needed(), 9);
+        assert_eq!(Range::of_bits(8), Range { lo: -128, hi: 127 });
+        assert_eq!(Range::of_bits(1), Range { lo: 0, hi: 1 });
+    }
+
+    #[test]
+    fn bounds_a_stepped_sum_by_its_trip_count() {
+        let module = Module::from_ir_str(SUM9).unwrap();
+        let ranges = analyze(&module, &module.functions[0]);
+        let variable = |name: &str| ranges.variables[&Name::from(name)];
+        let value = |name: &str| ranges.values[&Name::from(name)];
+        assert_eq!(variable("sum_r"), Range { lo: 0, hi: 2295 });
+        assert_eq!(variable("k"), Range { lo: 0, hi: 9 });
+        assert_eq!(value("conv"), Range { lo: 0, hi: 255 });
+        assert_eq!(value("add"), Range { lo: 0, hi: 2550 });
+        assert_eq!(value("inc"), Range { lo: 1, hi: 10 });
+        assert_eq!(value("div"), Range { lo: 0, hi: 255 });
+        assert_eq!(value("low"), Range { lo: 0, hi: 15 });
+    }
+}
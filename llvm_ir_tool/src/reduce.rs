@@ -0,0 +1,492 @@
+use crate::cfg::Cfg;
+use crate::ir_util::{callee_name, operands, operands_mut, successors, successors_mut, term_operands, term_operands_mut};
+use crate::printer::{module_to_ll, referenced_globals, write_module};
+use llvm_ir::function::FunctionDeclaration;
+use llvm_ir::module::Linkage;
+use llvm_ir::terminator::{Br, Unreachable};
+use llvm_ir::{BasicBlock, Constant, ConstantRef, Function, HasDebugLoc, Instruction, Module, Name, Operand, Terminator};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// What the reducer removes, coarsest first.
+#[derive(Clone, Copy)]
+enum Level {
+    Functions,
+    Blocks,
+    Instructions,
+}
+
+/// A removable piece of the module: (function, block, instruction) indices, with the parts the
+/// level doesn't use left at 0.
+type Unit = (usize, usize, usize);
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Functions => "Functions",
+            Level::Blocks => "Blocks",
+            Level::Instructions => "Instructions",
+        }
+    }
+
+    /// How many of the level's pieces the module has.
+    fn count(self, module: &Module) -> usize {
+        let (functions, blocks, instrs) = size(module);
+        match self {
+            Level::Functions => functions,
+            Level::Blocks => blocks,
+            Level::Instructions => instrs,
+        }
+    }
+
+    fn units(self, module: &Module) -> Vec<Unit> {
+        let funcs = module.functions.iter().enumerate();
+        match self {
+            Level::Functions => funcs.map(|(f, _)| (f, 0, 0)).collect(),
+            // The entry block stays, a function needs one
+            Level::Blocks => funcs.flat_map(|(f, func)| (1..func.basic_blocks.len()).map(move |b| (f, b, 0))).collect(),
+            Level::Instructions => funcs
+                .flat_map(|(f, func)| func.basic_blocks.iter().enumerate().map(move |(b, bb)| (f, b, bb)))
+                .flat_map(|(f, b, bb)| bb.instrs.iter().enumerate().map(move |(i, instr)| (f, b, i, instr)))
+                // llvm.dbg.* calls are dropped by the printer anyway
+                .filter(|(_, _, _, instr)| !callee_name(instr).is_some_and(|c| c.starts_with("llvm.dbg.")))
+                .map(|(f, b, i, _)| (f, b, i))
+                .collect(),
+        }
+    }
+
+    fn remove(self, module: &Module, units: &[Unit]) -> Module {
+        let mut module = module.clone();
+        match self {
+            Level::Functions => {
+                let doomed: HashSet<usize> = units.iter().map(|&(f, _, _)| f).collect();
+                let mut kept = Vec::new();
+                for (f, func) in std::mem::take(&mut module.functions).into_iter().enumerate() {
+                    if doomed.contains(&f) {
+                        module.func_declarations.push(declaration(func));
+                    } else {
+                        kept.push(func);
+                    }
+                }
+                module.functions = kept;
+            }
+            Level::Blocks => {
+                let mut by_func: BTreeMap<usize, HashSet<Name>> = BTreeMap::new();
+                for &(f, b, _) in units {
+                    by_func.entry(f).or_default().insert(module.functions[f].basic_blocks[b].name.clone());
+                }
+                for (f, doomed) in by_func {
+                    remove_blocks(&mut module.functions[f], &doomed);
+                }
+            }
+            Level::Instructions => {
+                let mut by_block: BTreeMap<(usize, usize), HashSet<usize>> = BTreeMap::new();
+                for &(f, b, i) in units {
+                    by_block.entry((f, b)).or_default().insert(i);
+                }
+                let mut removed: HashMap<usize, HashSet<Name>> = HashMap::new();
+                for ((f, b), doomed) in by_block {
+                    let bb = &mut module.functions[f].basic_blocks[b];
+                    let results = removed.entry(f).or_default();
+                    let mut i = 0;
+                    bb.instrs.retain(|instr| {
+                        i += 1;
+                        if !doomed.contains(&(i - 1)) {
+                            return true;
+                        }
+                        results.extend(instr.try_get_result().cloned());
+                        false
+                    });
+                }
+                for (f, results) in removed {
+                    replace_with_undef(&mut module.functions[f], &results);
+                }
+            }
+        }
+        module
+    }
+}
+
+/// Turns a function definition into a declaration, so calls to it stay valid.
+fn declaration(func: Function) -> FunctionDeclaration {
+    FunctionDeclaration {
+        name: func.name,
+        parameters: func.parameters,
+        is_var_arg: func.is_var_arg,
+        return_type: func.return_type,
+        return_attributes: func.return_attributes,
+        // Declarations can't be internal
+        linkage: Linkage::External,
+        visibility: func.visibility,
+        dll_storage_class: func.dll_storage_class,
+        calling_convention: func.calling_convention,
+        alignment: func.alignment,
+        garbage_collector_name: func.garbage_collector_name,
+        debugloc: func.debugloc,
+    }
+}
+
+/// Removes the given blocks and everything that is no longer reachable without them. Branches to
+/// a removed block that only jumps on skip over it; otherwise they go to the other destination if
+/// there is one and become `unreachable` if not.
+fn remove_blocks(func: &mut Function, doomed: &HashSet<Name>) {
+    let jumps: HashMap<Name, Name> = func
+        .basic_blocks
+        .iter()
+        .filter(|bb| doomed.contains(&bb.name))
+        .filter_map(|bb| match &bb.term {
+            Terminator::Br(br) => Some((bb.name.clone(), br.dest.clone())),
+            _ => None,
+        })
+        .collect();
+    // (new predecessor, removed block it replaces, target)
+    let mut skips = Vec::new();
+    for bb in func.basic_blocks.iter_mut().filter(|bb| !doomed.contains(&bb.name)) {
+        for dest in successors_mut(&mut bb.term) {
+            let mut last = dest.clone();
+            let mut seen = HashSet::new();
+            while let Some(next) = jumps.get(&last).filter(|_| seen.insert(last.clone())) {
+                if !doomed.contains(next) {
+                    skips.push((bb.name.clone(), last.clone(), next.clone()));
+                    *dest = next.clone();
+                    break;
+                }
+                last = next.clone();
+            }
+        }
+    }
+    // The target's phis take the skipped block's value from the new predecessor
+    for (pred, skipped, target) in skips {
+        let Some(bb) = func.basic_blocks.iter_mut().find(|bb| bb.name == target) else { continue };
+        for instr in &mut bb.instrs {
+            if let Instruction::Phi(phi) = instr
+                && !phi.incoming_values.iter().any(|(_, b)| *b == pred)
+                && let Some((value, _)) = phi.incoming_values.iter().find(|(_, b)| *b == skipped)
+            {
+                let value = value.clone();
+                phi.incoming_values.push((value, pred.clone()));
+            }
+        }
+    }
+
+    let mut removed = HashSet::new();
+    for bb in func.basic_blocks.iter_mut().filter(|bb| !doomed.contains(&bb.name)) {
+        let unreachable = Terminator::Unreachable(Unreachable { debugloc: bb.term.get_debug_loc().clone() });
+        let new_term = match &bb.term {
+            Terminator::CondBr(br) => match (doomed.contains(&br.true_dest), doomed.contains(&br.false_dest)) {
+                (true, true) => Some(unreachable),
+                (true, false) => Some(Terminator::Br(Br { dest: br.false_dest.clone(), debugloc: br.debugloc.clone() })),
+                (false, true) => Some(Terminator::Br(Br { dest: br.true_dest.clone(), debugloc: br.debugloc.clone() })),
+                (false, false) => None,
+            },
+            Terminator::Switch(sw) if successors(&bb.term).iter().any(|d| doomed.contains(*d)) => {
+                let mut sw = sw.clone();
+                sw.dests.retain(|(_, dest)| !doomed.contains(dest));
+                // A removed default becomes one of the remaining cases
+                if doomed.contains(&sw.default_dest)
+                    && let Some((_, dest)) = sw.dests.first()
+                {
+                    sw.default_dest = dest.clone();
+                }
+                Some(if doomed.contains(&sw.default_dest) { unreachable } else { Terminator::Switch(sw) })
+            }
+            term if successors(term).iter().any(|d| doomed.contains(*d)) => {
+                removed.extend(term.try_get_result().cloned());
+                Some(unreachable)
+            }
+            _ => None,
+        };
+        if let Some(term) = new_term {
+            bb.term = term;
+        }
+    }
+    for bb in func.basic_blocks.iter().filter(|bb| doomed.contains(&bb.name)) {
+        removed.extend(results(bb));
+    }
+    func.basic_blocks.retain(|bb| !doomed.contains(&bb.name));
+
+    // Blocks only the removed ones led to go too
+    let cfg = Cfg::new(func);
+    let reachable: HashSet<Name> = cfg.reverse_post_order().into_iter().map(|b| cfg.names[b].clone()).collect();
+    for bb in func.basic_blocks.iter().filter(|bb| !reachable.contains(&bb.name)) {
+        removed.extend(results(bb));
+    }
+    func.basic_blocks.retain(|bb| reachable.contains(&bb.name));
+
+    // Phis only keep the incoming values of blocks that still branch to them
+    let cfg = Cfg::new(func);
+    let preds: HashMap<Name, HashSet<Name>> = (0..cfg.num_blocks())
+        .map(|b| (cfg.names[b].clone(), cfg.preds[b].iter().map(|&p| cfg.names[p].clone()).collect()))
+        .collect();
+    for bb in &mut func.basic_blocks {
+        for instr in &mut bb.instrs {
+            if let Instruction::Phi(phi) = instr {
+                phi.incoming_values.retain(|(_, pred)| preds[&bb.name].contains(pred));
+            }
+        }
+    }
+    replace_with_undef(func, &removed);
+}
+
+fn results(bb: &BasicBlock) -> Vec<Name> {
+    bb.instrs.iter().filter_map(|i| i.try_get_result().cloned()).chain(bb.term.try_get_result().cloned()).collect()
+}
+
+/// Replaces the uses of removed values with `undef` of the same type.
+fn replace_with_undef(func: &mut Function, removed: &HashSet<Name>) {
+    if removed.is_empty() {
+        return;
+    }
+    for bb in &mut func.basic_blocks {
+        let ops = bb.instrs.iter_mut().flat_map(operands_mut).chain(term_operands_mut(&mut bb.term));
+        for op in ops {
+            if let Operand::LocalOperand { name, ty } = op
+                && removed.contains(name)
+            {
+                *op = Operand::ConstantOperand(ConstantRef::new(Constant::Undef(ty.clone())));
+            }
+        }
+    }
+}
+
+/// Globals and declarations nothing refers to any more.
+fn without_unused_globals(module: &Module) -> Module {
+    let mut module = module.clone();
+    loop {
+        let mut used = HashMap::new();
+        let constants = module
+            .functions
+            .iter()
+            .flat_map(|f| &f.basic_blocks)
+            .flat_map(|bb| bb.instrs.iter().flat_map(operands).chain(term_operands(&bb.term)))
+            .filter_map(|op| op.as_constant())
+            .chain(module.global_vars.iter().filter_map(|g| g.initializer.as_deref()));
+        for c in constants {
+            referenced_globals(c, &mut used);
+        }
+        let used: HashSet<Name> = used.into_keys().cloned().collect();
+        let before = module.global_vars.len() + module.func_declarations.len();
+        module.global_vars.retain(|g| used.contains(&g.name));
+        module.func_declarations.retain(|d| used.contains(&Name::from(d.name.clone())));
+        if module.global_vars.len() + module.func_declarations.len() == before {
+            return module;
+        }
+    }
+}
+
+/// Number of functions, blocks and instructions in a module.
+fn size(module: &Module) -> (usize, usize, usize) {
+    let blocks = module.functions.iter().map(|f| f.basic_blocks.len()).sum();
+    let instrs = module.functions.iter().flat_map(|f| &f.basic_blocks).map(|bb| bb.instrs.len()).sum();
+    (module.functions.len(), blocks, instrs)
+}
+
+/// Runs the predicate command on candidate modules.
+struct Predicate {
+    command: String,
+    path: PathBuf,
+    runs: usize,
+}
+
+impl Predicate {
+    /// True if the command exits with status 0 on the module. Candidates that can't be written
+    /// as IR don't count.
+    fn holds(&mut self, module: &Module) -> Result<bool, String> {
+        let Ok(text) = module_to_ll(module) else { return Ok(false) };
+        fs::write(&self.path, text).map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        let path = self.path.display().to_string();
+        let command =
+            if self.command.contains("{}") { self.command.replace("{}", &path) } else { format!("{} {}", self.command, path) };
+        self.runs += 1;
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| format!("Failed to run '{}': {}", command, e))?;
+        Ok(status.success())
+    }
+}
+
+/// Delta debugging over one level: tries removing chunks of units, halving the chunk size
+/// whenever a whole pass removes nothing, until single units can't be removed either.
+fn reduce_level(module: Module, level: Level, predicate: &mut Predicate) -> Result<Module, String> {
+    let mut current = module;
+    let mut chunk = level.units(&current).len().div_ceil(2).max(1);
+    loop {
+        let mut progress = false;
+        let mut start = 0;
+        loop {
+            // Indices shift after every removal, so the units are listed again
+            let units = level.units(&current);
+            if start >= units.len() {
+                break;
+            }
+            let end = (start + chunk).min(units.len());
+            let candidate = level.remove(&current, &units[start..end]);
+            if predicate.holds(&candidate)? {
+                current = candidate;
+                progress = true;
+            } else {
+                start = end;
+            }
+        }
+        if chunk == 1 && !progress {
+            return Ok(current);
+        }
+        chunk = (chunk / 2).max(1);
+    }
+}
+
+/// `--reduce --test <command> [--output <file.ll>]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --reduce --test <command> [--output <file.ll>]\n\
+                 The command gets the candidate .ll file in place of {} (or as its last argument) and must exit with 0 while the problem is still there.";
+    let mut command = None;
+    let mut output = "reduced.ll".to_string();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?;
+        match arg.as_str() {
+            "--test" => command = Some(value.clone()),
+            "--output" => output = value.clone(),
+            _ => return Err(format!("Unknown option '{}'\n{}", arg, usage)),
+        }
+    }
+    let command = command.ok_or_else(|| usage.to_string())?;
+    let mut predicate = Predicate {
+        command,
+        path: std::env::temp_dir().join(format!("llvm_ir_tool_reduce_{}.ll", std::process::id())),
+        runs: 0,
+    };
+
+    // The reducer works on the module as the printer writes it, which has no loop or variable metadata
+    if !predicate.holds(module)? {
+        return Err(format!(
+            "The test '{}' fails on the input as written back by --emit (debug info other than line locations is dropped), nothing to reduce.",
+            predicate.command
+        ));
+    }
+    let before = size(module);
+    println!("Reducing with '{}'", predicate.command);
+    let mut current = module.clone();
+    // Removing instructions can make blocks or whole functions removable, so rounds repeat until one changes nothing
+    for round in 1.. {
+        let start = size(&current);
+        println!("Round {}:", round);
+        for level in [Level::Functions, Level::Blocks, Level::Instructions] {
+            let (count, runs) = (level.count(&current), predicate.runs);
+            current = reduce_level(current, level, &mut predicate)?;
+            println!("  {:<14} {:>6} -> {:<6} ({} tests)", level.name(), count, level.count(&current), predicate.runs - runs);
+        }
+        if size(&current) == start {
+            break;
+        }
+    }
+    let candidate = without_unused_globals(&current);
+    let globals = current.global_vars.len() + current.func_declarations.len();
+    if candidate.global_vars.len() + candidate.func_declarations.len() < globals && predicate.holds(&candidate)? {
+        current = candidate;
+    }
+    let _ = fs::remove_file(&predicate.path);
+
+    let after = size(&current);
+    write_module(&current, &output)?;
+    println!(
+        "\nWrote {}: functions {} -> {}, blocks {} -> {}, instructions {} -> {} after {} tests",
+        output, before.0, after.0, before.1, after.1, before.2, after.2, predicate.runs
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A helper with a loop, a function that divides, and a global only the helper uses
+    const TWO_FUNCTIONS: &str = "
+@scale = global i32 3
+
+define i32 @sum(i32 %n) {
+entry:
+  %k = load i32, ptr @scale
+  br label %loop
+loop:
+  %i = phi i32 [ 0, %entry ], [ %i.next, %body ]
+  %s = phi i32 [ 0, %entry ], [ %s.next, %body ]
+  %cmp = icmp slt i32 %i, %n
+  br i1 %cmp, label %body, label %exit
+body:
+  %m = mul i32 %i, %k
+  %s.next = add i32 %s, %m
+  %i.next = add i32 %i, 1
+  br label %loop
+exit:
+  ret i32 %s
+}
+
+define i32 @average(i32 %total) {
+entry:
+  %a = call i32 @sum(i32 %total)
+  %b = add i32 %a, 1
+  %d = sdiv i32 %b, 9
+  ret i32 %d
+}
+";
+
+    fn predicate(command: &str, name: &str) -> Predicate {
+        let path = std::env::temp_dir().join(format!("llvm_ir_tool_reduce_test_{}_{}.ll", name, std::process::id()));
+        Predicate { command: command.to_string(), path, runs: 0 }
+    }
+
+    #[test]
+    fn removed_blocks_leave_valid_ir() {
+        let module = Module::from_ir_str(TWO_FUNCTIONS).unwrap();
+        // The loop body only jumps back, so the header branches to itself instead, and its phis
+        // take the body's values, now undef, from there
+        let reduced = Level::Blocks.remove(&module, &[(0, 2, 0)]);
+        let text = module_to_ll(&reduced).unwrap();
+        let reparsed = Module::from_ir_str(&text).unwrap();
+        assert_eq!(reparsed.functions[0].basic_blocks.len(), 3);
+        assert!(text.contains("br i1 %cmp, label %loop, label %exit"), "{}", text);
+        assert!(text.contains("%i = phi i32 [ 0, %entry ], [ undef, %loop ]"), "{}", text);
+
+        // Without the exit, the header's branch only goes into the loop
+        let reduced = Level::Blocks.remove(&module, &[(0, 3, 0)]);
+        let text = module_to_ll(&reduced).unwrap();
+        Module::from_ir_str(&text).unwrap();
+        assert!(text.contains("br label %body"), "{}", text);
+
+        // Removing the helper keeps the call valid through a declaration
+        let reduced = Level::Functions.remove(&module, &[(0, 0, 0)]);
+        assert_eq!(reduced.func_declarations.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(), ["sum"]);
+        Module::from_ir_str(&module_to_ll(&reduced).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn removed_instructions_become_undef() {
+        let module = Module::from_ir_str(TWO_FUNCTIONS).unwrap();
+        let reduced = Level::Instructions.remove(&module, &[(1, 0, 1)]);
+        let text = module_to_ll(&reduced).unwrap();
+        assert!(text.contains("%d = sdiv i32 undef, 9"), "{}", text);
+    }
+
+    #[test]
+    fn reduces_to_what_the_test_needs() {
+        let module = Module::from_ir_str(TWO_FUNCTIONS).unwrap();
+        let mut keeps_sdiv = predicate("grep -q sdiv", "sdiv");
+        let mut current = module;
+        for level in [Level::Functions, Level::Blocks, Level::Instructions] {
+            current = reduce_level(current, level, &mut keeps_sdiv).unwrap();
+        }
+        let current = without_unused_globals(&current);
+        let _ = fs::remove_file(&keeps_sdiv.path);
+        assert_eq!(size(&current), (1, 1, 1));
+        assert!(current.global_vars.is_empty() && current.func_declarations.is_empty());
+        let text = module_to_ll(&current).unwrap();
+        assert!(text.contains("sdiv i32 undef, 9"), "{}", text);
+    }
+}
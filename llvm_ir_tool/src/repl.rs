@@ -0,0 +1,513 @@
+use crate::cfg::Cfg;
+use crate::ir_util::{block_label, instr_text, operands, successors, term_operands};
+use crate::loops::LoopInfo;
+use crate::source::source_name;
+use crate::{explain_llvm_line, find_function_line_spans, run_mode, USAGE};
+use llvm_ir::{Function, Module, Name, Operand, Terminator};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+
+const HELP: &str = "Commands:
+  functions                  list the functions with their size
+  function <name>            select a function (and its entry block)
+  lines                      print the IR lines of the selected function
+  blocks                     list the blocks of the selected function with their edges
+  block <name>               select a block and print its lines
+  explain [line]             explain an IR line, or every instruction of the selected block
+  follow [true|false|<n>|<block>]
+                             go to a successor of the selected block
+  back                       go back to the previously selected block
+  def <value>                jump to the definition of %value, @global or a block label
+  uses <value>               list the instructions that use %value
+  run --<mode> [args...]     run an analysis mode on the loaded module, e.g. run --schedule main
+  reload                     parse the file again after it changed
+  help, quit";
+
+const COMMANDS: &[&str] =
+    &["functions", "function", "lines", "blocks", "block", "explain", "follow", "back", "def", "uses", "run", "reload", "help", "quit"];
+
+/// A loaded module and the text it was parsed from, with the function and block being looked at.
+struct Session {
+    filename: String,
+    module: Module,
+    lines: Vec<String>,
+    // Function name -> (first line, last line), zero-based
+    spans: HashMap<String, (usize, usize)>,
+    func: Option<usize>,
+    block: usize,
+    // Previously selected (function, block) pairs, for `back`
+    trail: Vec<(usize, usize)>,
+}
+
+impl Session {
+    fn load(filename: &str, module: Module) -> Result<Session, String> {
+        let text = fs::read_to_string(filename).map_err(|e| format!("Failed to read {}: {}", filename, e))?;
+        let lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        let spans = find_function_line_spans(&lines).into_iter().map(|(name, span)| (name.to_string(), span)).collect();
+        Ok(Session { filename: filename.to_string(), module, lines, spans, func: None, block: 0, trail: Vec::new() })
+    }
+
+    fn prompt(&self) -> String {
+        match self.current() {
+            Some(func) => format!("{}:{}> ", func.name, block_label(&func.basic_blocks[self.block].name)),
+            None => "> ".to_string(),
+        }
+    }
+
+    fn current(&self) -> Option<&Function> {
+        self.func.map(|f| &self.module.functions[f])
+    }
+
+    fn selected(&self) -> Result<&Function, String> {
+        self.current().ok_or_else(|| "No function selected, use 'function <name>'".to_string())
+    }
+
+    fn select(&mut self, func: usize, block: usize) {
+        if let Some(f) = self.func
+            && (f, self.block) != (func, block)
+        {
+            self.trail.push((f, self.block));
+        }
+        self.func = Some(func);
+        self.block = block;
+    }
+
+    /// Line index of a block's label in the text, or of the `define` line for an unlabeled entry block.
+    fn block_start(&self, func: &Function, block: usize) -> Option<usize> {
+        let &(start, end) = self.spans.get(&func.name)?;
+        let label = format!("{}:", block_label(&func.basic_blocks[block].name));
+        let found = (start + 1..end).find(|&i| self.lines[i].starts_with(&label));
+        if block == 0 { Some(found.unwrap_or(start)) } else { found }
+    }
+
+    /// Line range of a block: its label up to the next label or the closing brace.
+    fn block_lines(&self, func: &Function, block: usize) -> Option<(usize, usize)> {
+        let first = self.block_start(func, block)?;
+        let end = self.spans.get(&func.name)?.1;
+        let last = (first + 1..end)
+            .find(|&i| func.basic_blocks.iter().any(|bb| self.lines[i].starts_with(&format!("{}:", block_label(&bb.name)))))
+            .unwrap_or(end);
+        Some((first, last))
+    }
+
+    fn print_lines(&self, first: usize, last: usize) {
+        for i in first..last {
+            println!("{:4} | {}", i + 1, self.lines[i]);
+        }
+    }
+
+    fn find_block(&self, func: &Function, name: &str) -> Option<usize> {
+        let name = name.trim_start_matches('%');
+        func.basic_blocks.iter().position(|bb| block_label(&bb.name) == name)
+    }
+
+    /// Names that complete the last word of `line`.
+    fn completions(&self, line: &str) -> Vec<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let starting = line.is_empty() || line.ends_with(' ');
+        let position = if starting { words.len() } else { words.len() - 1 };
+        let blocks = || self.current().map_or(Vec::new(), |f| f.basic_blocks.iter().map(|bb| block_label(&bb.name)).collect());
+        match (position, words.first().copied()) {
+            (0, _) => COMMANDS.iter().map(|c| c.to_string()).collect(),
+            (_, Some("function")) => self.module.functions.iter().map(|f| f.name.clone()).collect(),
+            (_, Some("block" | "follow")) => blocks(),
+            (_, Some("def" | "uses")) => match self.current() {
+                Some(func) => {
+                    let params = func.parameters.iter().map(|p| format!("%{}", block_label(&p.name)));
+                    let values = func.basic_blocks.iter().flat_map(|bb| &bb.instrs).filter_map(|i| i.try_get_result());
+                    params.chain(values.map(|n| format!("%{}", block_label(n)))).chain(blocks()).collect()
+                }
+                None => Vec::new(),
+            },
+            (1, Some("run")) => modes(),
+            (_, Some("run")) => self.module.functions.iter().map(|f| f.name.clone()).chain(blocks()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Runs one command line; returns false when the session should end.
+    fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let words = split_words(line);
+        let Some(command) = words.first() else { return Ok(true) };
+        let arg = words.get(1).map(|s| s.as_str());
+        match (command.as_str(), arg) {
+            ("quit" | "exit" | "q", _) => return Ok(false),
+            ("help" | "h" | "?", _) => println!("{}", HELP),
+            ("functions", _) => {
+                for func in &self.module.functions {
+                    let instrs: usize = func.basic_blocks.iter().map(|bb| bb.instrs.len()).sum();
+                    let lines = self.spans.get(&func.name).map_or(String::new(), |(s, e)| format!(", lines {}-{}", s + 1, e + 1));
+                    println!("  {} ({} blocks, {} instructions{})", func.name, func.basic_blocks.len(), instrs, lines);
+                }
+            }
+            ("function" | "f", Some(name)) => {
+                let f = self
+                    .module
+                    .functions
+                    .iter()
+                    .position(|f| f.name == name || source_name(&f.name) == name)
+                    .ok_or_else(|| format!("Function '{}' not found in module.", name))?;
+                self.select(f, 0);
+                let func = &self.module.functions[f];
+                println!("{}: {} parameter(s), {} block(s)", func.name, func.parameters.len(), func.basic_blocks.len());
+            }
+            ("lines", _) => {
+                let func = self.selected()?;
+                let &(start, end) = self.spans.get(&func.name).ok_or_else(|| format!("No lines found for '{}'", func.name))?;
+                self.print_lines(start, end + 1);
+            }
+            ("blocks", _) => {
+                let func = self.selected()?;
+                let cfg = Cfg::new(func);
+                let loop_info = LoopInfo::new(func);
+                let names = |bs: &[usize]| bs.iter().map(|&b| block_label(cfg.names[b])).collect::<Vec<_>>().join(", ");
+                for b in 0..cfg.num_blocks() {
+                    let marker = if b == self.block { "*" } else { " " };
+                    let depth = loop_info.innermost(b).map_or(String::new(), |l| format!(", loop depth {}", loop_info.loops[l].depth));
+                    let line = self.block_start(func, b).map_or(String::new(), |i| format!("line {}, ", i + 1));
+                    println!(
+                        "{} {} ({}{} instructions{}) preds: [{}] succs: [{}]",
+                        marker,
+                        block_label(cfg.names[b]),
+                        line,
+                        func.basic_blocks[b].instrs.len(),
+                        depth,
+                        names(&cfg.preds[b]),
+                        names(&cfg.succs[b])
+                    );
+                }
+            }
+            ("block" | "b", Some(name)) => {
+                let f = self.func.ok_or("No function selected, use 'function <name>'")?;
+                let b = self
+                    .find_block(&self.module.functions[f], name)
+                    .ok_or_else(|| format!("No block '{}' in {}", name, self.module.functions[f].name))?;
+                self.select(f, b);
+                self.show_block()?;
+            }
+            ("block" | "b", None) => self.show_block()?,
+            ("explain" | "e", Some(number)) => {
+                let n: usize = number.parse().map_err(|_| format!("Invalid line number '{}'", number))?;
+                let line = self.lines.get(n.wrapping_sub(1)).ok_or_else(|| format!("Line {} is out of range", n))?;
+                println!("{:4} | {}\n{}", n, line, explain_llvm_line(line));
+            }
+            ("explain" | "e", None) => {
+                let func = self.selected()?;
+                let (first, last) = self.block_lines(func, self.block).ok_or("The selected block has no lines in the file")?;
+                for i in first + 1..last {
+                    let line = &self.lines[i];
+                    if line.trim().is_empty() || line.trim_start().starts_with(';') {
+                        continue;
+                    }
+                    println!("{:4} | {}\n       {}", i + 1, line.trim(), explain_llvm_line(line));
+                }
+            }
+            ("follow", _) => {
+                let f = self.func.ok_or("No function selected, use 'function <name>'")?;
+                let func = &self.module.functions[f];
+                let term = &func.basic_blocks[self.block].term;
+                let succs = successors(term);
+                let dest = match (arg, term) {
+                    (Some("true"), Terminator::CondBr(br)) => &br.true_dest,
+                    (Some("false"), Terminator::CondBr(br)) => &br.false_dest,
+                    (None, _) if succs.len() == 1 => succs[0],
+                    (None, _) if succs.is_empty() => return Err("The selected block has no successors".to_string()),
+                    (None, _) => {
+                        let list: Vec<String> = succs.iter().enumerate().map(|(i, s)| format!("{}: {}", i, block_label(s))).collect();
+                        return Err(format!("{} successors, pick one: {}", succs.len(), list.join(", ")));
+                    }
+                    (Some(pick), _) => match pick.parse::<usize>() {
+                        Ok(i) => succs.get(i).copied().ok_or_else(|| format!("No successor {}", i))?,
+                        Err(_) => succs
+                            .iter()
+                            .copied()
+                            .find(|s| block_label(s) == pick.trim_start_matches('%'))
+                            .ok_or_else(|| format!("'{}' is not a successor of the selected block", pick))?,
+                    },
+                };
+                let b = func.basic_blocks.iter().position(|bb| bb.name == *dest).ok_or("Branch to a missing block")?;
+                self.select(f, b);
+                self.show_block()?;
+            }
+            ("back", _) => {
+                let (f, b) = self.trail.pop().ok_or("Nothing to go back to")?;
+                self.func = Some(f);
+                self.block = b;
+                self.show_block()?;
+            }
+            ("def" | "d", Some(value)) => self.definition(value)?,
+            ("uses" | "u", Some(value)) => {
+                let func = self.selected()?;
+                let name = value_name(value);
+                let mut count = 0;
+                for bb in &func.basic_blocks {
+                    let users = bb.instrs.iter().filter(|i| operands(i).iter().any(|op| is_local(op, &name))).map(instr_text);
+                    let term = term_operands(&bb.term).iter().any(|op| is_local(op, &name)).then(|| bb.term.to_string());
+                    for text in users.chain(term) {
+                        println!("  {}: {}", block_label(&bb.name), text.replace(" (with debugloc)", ""));
+                        count += 1;
+                    }
+                }
+                println!("{} use(s) of %{}", count, block_label(&name));
+            }
+            ("run" | "r", Some(mode)) => run_mode(&self.module, mode, &words[2..])?,
+            ("reload", _) => {
+                let module = Module::from_ir_path(&self.filename).map_err(|e| format!("Failed to parse LLVM module: {}", e))?;
+                let selected = self.current().map(|f| f.name.clone());
+                *self = Session::load(&self.filename, module)?;
+                if let Some(f) = selected.and_then(|name| self.module.functions.iter().position(|f| f.name == name)) {
+                    self.func = Some(f);
+                }
+                println!("Reloaded {} ({} functions)", self.filename, self.module.functions.len());
+            }
+            (other, _) => return Err(format!("Unknown command or missing argument: '{}' (try 'help')", other)),
+        }
+        Ok(true)
+    }
+
+    fn show_block(&self) -> Result<(), String> {
+        let func = self.selected()?;
+        let (first, last) = self.block_lines(func, self.block).ok_or("The selected block has no lines in the file")?;
+        self.print_lines(first, last);
+        let succs: Vec<String> = successors(&func.basic_blocks[self.block].term).iter().map(|s| block_label(s)).collect();
+        if !succs.is_empty() {
+            println!("  -> {}", succs.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Prints where a value, global or block label is defined and selects its block.
+    fn definition(&mut self, value: &str) -> Result<(), String> {
+        if let Some(global) = value.strip_prefix('@') {
+            if let Some(f) = self.module.functions.iter().position(|f| f.name == global) {
+                self.select(f, 0);
+                return self.show_block();
+            }
+            let prefix = format!("@{} ", global);
+            let i = self.lines.iter().position(|l| l.starts_with(&prefix)).ok_or_else(|| format!("No global @{}", global))?;
+            println!("{:4} | {}", i + 1, self.lines[i]);
+            return Ok(());
+        }
+        let f = self.func.ok_or("No function selected, use 'function <name>'")?;
+        let func = &self.module.functions[f];
+        let name = value_name(value);
+        if func.parameters.iter().any(|p| p.name == name) {
+            let start = self.spans.get(&func.name).map_or(0, |s| s.0);
+            println!("%{} is a parameter of {}\n{:4} | {}", block_label(&name), func.name, start + 1, self.lines[start]);
+            return Ok(());
+        }
+        let found = func
+            .basic_blocks
+            .iter()
+            .enumerate()
+            .find_map(|(b, bb)| bb.instrs.iter().position(|i| i.try_get_result() == Some(&name)).map(|i| (b, i)));
+        let Some((b, i)) = found else {
+            // A block label
+            let b = self.find_block(func, value).ok_or_else(|| format!("No value or block '{}' in {}", value, func.name))?;
+            self.select(f, b);
+            return self.show_block();
+        };
+        self.select(f, b);
+        let func = &self.module.functions[f];
+        let prefix = format!("%{} =", block_label(&name));
+        let line = self.block_lines(func, b).and_then(|(first, last)| (first..last).find(|&l| self.lines[l].trim_start().starts_with(&prefix)));
+        match line {
+            Some(l) => println!("{:4} | {}\n{}", l + 1, self.lines[l], explain_llvm_line(&self.lines[l])),
+            None => println!("{}", instr_text(&func.basic_blocks[b].instrs[i])),
+        }
+        println!("  in block {}", block_label(&func.basic_blocks[b].name));
+        Ok(())
+    }
+}
+
+fn value_name(value: &str) -> Name {
+    let value = value.trim_start_matches('%');
+    match value.parse::<usize>() {
+        Ok(n) => Name::Number(n),
+        Err(_) => Name::from(value),
+    }
+}
+
+fn is_local(op: &Operand, name: &Name) -> bool {
+    matches!(op, Operand::LocalOperand { name: n, .. } if n == name)
+}
+
+/// The analysis modes listed in the usage text.
+fn modes() -> Vec<String> {
+    USAGE
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(3))
+        .filter(|w| w.starts_with("--") && !matches!(*w, "--repl" | "--tui"))
+        .map(|w| w.to_string())
+        .collect()
+}
+
+/// Splits a command line into words, keeping quoted text together.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut in_word = false;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    words
+}
+
+/// Puts the terminal in character mode while a line is edited, restoring it when dropped.
+/// Uses `stty`, so without it (or without a terminal) input stays line-buffered.
+pub struct RawMode(Option<String>);
+
+impl RawMode {
+    pub fn enable() -> RawMode {
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+        if saved.is_some() {
+            let _ = Command::new("stty").args(["-icanon", "-echo", "-isig", "min", "1"]).stdin(Stdio::inherit()).status();
+        }
+        RawMode(saved)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.0 {
+            let _ = Command::new("stty").arg(saved).stdin(Stdio::inherit()).status();
+        }
+    }
+}
+
+/// Reads one line with history (up/down) and tab completion. None at end of input (Ctrl-D).
+fn edit_line(session: &Session, history: &[String]) -> Result<Option<String>, String> {
+    let prompt = session.prompt();
+    let redraw = |line: &str| {
+        print!("\r\x1b[K{}{}", prompt, line);
+        let _ = io::stdout().flush();
+    };
+    let _raw = RawMode::enable();
+    let mut line = String::new();
+    let mut position = history.len();
+    redraw(&line);
+    let mut bytes = io::stdin().lock().bytes();
+    let mut next = || bytes.next().transpose().map_err(|e| format!("Failed to read input: {}", e));
+    while let Some(byte) = next()? {
+        match byte {
+            b'\r' | b'\n' => {
+                println!();
+                return Ok(Some(line));
+            }
+            // Ctrl-C drops the line, Ctrl-D on an empty line ends the session
+            3 => {
+                println!("^C");
+                return Ok(Some(String::new()));
+            }
+            4 if line.is_empty() => {
+                println!();
+                return Ok(None);
+            }
+            127 | 8 => {
+                line.pop();
+                redraw(&line);
+            }
+            b'\t' => {
+                let start = line.rfind(' ').map_or(0, |i| i + 1);
+                let word = line[start..].to_string();
+                let matches: Vec<String> = session.completions(&line).into_iter().filter(|c| c.starts_with(&word)).collect();
+                match matches.as_slice() {
+                    [] => {}
+                    [only] => {
+                        line.replace_range(start.., only);
+                        line.push(' ');
+                    }
+                    many => {
+                        let common = many.iter().skip(1).fold(many[0].clone(), |common, m| {
+                            common.chars().zip(m.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a).collect()
+                        });
+                        if common.len() > word.len() {
+                            line.replace_range(start.., &common);
+                        } else {
+                            println!("\r\n{}", many.join("  "));
+                        }
+                    }
+                }
+                redraw(&line);
+            }
+            // Arrow keys arrive as ESC [ A..D
+            27 => {
+                if next()? != Some(b'[') {
+                    continue;
+                }
+                match next()? {
+                    Some(b'A') if position > 0 => position -= 1,
+                    Some(b'B') if position < history.len() => position += 1,
+                    _ => continue,
+                }
+                line = history.get(position).cloned().unwrap_or_default();
+                redraw(&line);
+            }
+            b if b.is_ascii() && !b.is_ascii_control() => {
+                line.push(b as char);
+                redraw(&line);
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// `--repl`: an interactive session on the module, which is parsed only once.
+pub fn run(module: &Module, filename: &str) -> Result<(), String> {
+    let mut session = Session::load(filename, module.clone())?;
+    let interactive = io::stdin().is_terminal();
+    if interactive {
+        println!("{}: {} function(s). Type 'help' for commands, Tab completes.", filename, module.functions.len());
+    }
+    let mut history: Vec<String> = Vec::new();
+    // Piped input is read a line at a time, without prompts
+    let mut input = (!interactive).then(|| io::stdin().lock().lines());
+    loop {
+        let line = match &mut input {
+            Some(lines) => lines.next().transpose().map_err(|e| format!("Failed to read input: {}", e))?,
+            None => edit_line(&session, &history)?,
+        };
+        let Some(line) = line else { return Ok(()) };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if history.last() != Some(&line) {
+            history.push(line.clone());
+        }
+        match session.execute(&line) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::layout::size_of;
+use crate::memory::{access, MemoryInfo};
+use crate::schedule::BlockSchedule;
+use llvm_ir::types::FPType;
+use llvm_ir::{Function, Instruction, Module, Name, Operand, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::AddAssign;
+
+// Arrays up to this many bits are mapped to LUTRAM instead of block RAM
+const LUTRAM_MAX_BITS: u64 = 1024;
+const BRAM_18K_BITS: u64 = 18 * 1024;
+// How many `sext`/`zext` to look through for the width of a multiplier's operand
+const MAX_EXTENSIONS: usize = 8;
+
+/// FPGA resources in the units of the csynth "Utilization Estimates" table.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Resources {
+    pub bram: u64,
+    pub dsp: u64,
+    pub ff: u64,
+    pub lut: u64,
+}
+
+impl AddAssign for Resources {
+    fn add_assign(&mut self, other: Resources) {
+        self.bram += other.bram;
+        self.dsp += other.dsp;
+        self.ff += other.ff;
+        self.lut += other.lut;
+    }
+}
+
+impl fmt::Display for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BRAM {} DSP {} FF {} LUT {}", self.bram, self.dsp, self.ff, self.lut)
+    }
+}
+
+/// Bit width of a scalar type (0 for anything that isn't an integer or float).
+pub fn type_bits(ty: &Type) -> u64 {
+    match ty {
+        Type::IntegerType { bits } => *bits as u64,
+        Type::FPType(FPType::Half) | Type::FPType(FPType::BFloat) => 16,
+        Type::FPType(FPType::Single) => 32,
+        Type::FPType(FPType::Double) => 64,
+        _ => 0,
+    }
+}
+
+/// DSP blocks for a multiplier whose wider operand has this many bits (DSP48 slices are 27x18).
+pub fn mul_dsps(operand_bits: u64) -> u64 {
+    match operand_bits {
+        0..=18 => 1,
+        19..=27 => 2,
+        28..=35 => 3,
+        36..=52 => 6,
+        _ => 10,
+    }
+}
+
+/// The instruction defining each value of a function.
+pub fn definitions(func: &Function) -> HashMap<&Name, &Instruction> {
+    func.basic_blocks.iter().flat_map(|bb| &bb.instrs).filter_map(|instr| Some((instr.try_get_result()?, instr))).collect()
+}
+
+/// Width of an operand before `sext`/`zext`, and whether it was sign-extended.
+pub fn source_width(defs: &HashMap<&Name, &Instruction>, module: &Module, op: &Operand) -> (u64, Option<bool>) {
+    let mut current = op;
+    let mut signed = None;
+    for _ in 0..MAX_EXTENSIONS {
+        let Operand::LocalOperand { name, .. } = current else { break };
+        current = match defs.get(name) {
+            Some(Instruction::SExt(ext)) => {
+                signed = Some(true);
+                &ext.operand
+            }
+            Some(Instruction::ZExt(ext)) => {
+                signed = signed.or(Some(false));
+                &ext.operand
+            }
+            _ => break,
+        };
+    }
+    (type_bits(&module.type_of(current)), signed)
+}
+
+/// Resources of the operator implementing one instruction, without sharing. A multiplier is
+/// sized by its operands before extension, `defs` being the function's `definitions`.
+pub fn op_resources(module: &Module, defs: &HashMap<&Name, &Instruction>, instr: &Instruction) -> Resources {
+    let bits = match instr {
+        Instruction::Mul(mul) => source_width(defs, module, &mul.operand0).0.max(source_width(defs, module, &mul.operand1).0),
+        _ => op_width(module, instr),
+    };
+    op_resources_at(instr, bits)
+}
+
+/// Width of the operator for an instruction: its operands' for comparisons, else its result's.
+pub fn op_width(module: &Module, instr: &Instruction) -> u64 {
+    match instr {
+        Instruction::ICmp(i) => type_bits(&module.type_of(&i.operand0)),
+        _ => type_bits(&module.type_of(instr)),
+    }
+}
+
+/// Resources of the operator for an instruction if it were `bits` wide.
+pub fn op_resources_at(instr: &Instruction, bits: u64) -> Resources {
+    let is_double = bits == 64;
+    let res = |dsp, ff, lut| Resources { bram: 0, dsp, ff, lut };
+    match instr {
+        Instruction::Add(_) | Instruction::Sub(_) => res(0, 0, bits),
+        Instruction::ICmp(_) => res(0, 0, (bits / 2).max(1)),
+        Instruction::And(_) | Instruction::Or(_) | Instruction::Xor(_) | Instruction::Select(_) => res(0, 0, bits),
+        Instruction::Shl(i) => shift_resources(&i.operand1, bits),
+        Instruction::LShr(i) => shift_resources(&i.operand1, bits),
+        Instruction::AShr(i) => shift_resources(&i.operand1, bits),
+        // `bits` is the width of the wider operand
+        Instruction::Mul(_) => res(mul_dsps(bits), bits, 0),
+        Instruction::UDiv(_) | Instruction::SDiv(_) | Instruction::URem(_) | Instruction::SRem(_) => {
+            res(0, bits * bits, bits * bits)
+        }
+        Instruction::FAdd(_) | Instruction::FSub(_) if is_double => res(3, 685, 635),
+        Instruction::FAdd(_) | Instruction::FSub(_) => res(2, 227, 214),
+        Instruction::FMul(_) if is_double => res(11, 299, 203),
+        Instruction::FMul(_) => res(3, 128, 135),
+        Instruction::FDiv(_) | Instruction::FRem(_) if is_double => res(0, 3211, 3658),
+        Instruction::FDiv(_) | Instruction::FRem(_) => res(0, 1426, 2102),
+        Instruction::FCmp(_) => res(0, 66, 239),
+        Instruction::FPToSI(_) | Instruction::FPToUI(_) | Instruction::SIToFP(_) | Instruction::UIToFP(_) => {
+            res(0, 200, 300)
+        }
+        // Non-constant array indices need an address adder
+        Instruction::GetElementPtr(gep) if gep.indices.iter().any(|i| matches!(i, Operand::LocalOperand { .. })) => {
+            res(0, 0, 16)
+        }
+        _ => Resources::default(),
+    }
+}
+
+fn shift_resources(amount: &Operand, bits: u64) -> Resources {
+    match amount {
+        Operand::ConstantOperand(_) => Resources::default(),
+        // Barrel shifter: one mux level per bit of the shift amount
+        _ => Resources { lut: bits * (64 - bits.leading_zeros() as u64), ..Resources::default() },
+    }
+}
+
+/// Estimates the resources of a scheduled function: one operator per instruction (no sharing),
+/// registers for scalar variables and multi-cycle results, memories for local arrays and a
+/// one-hot state machine with one state per scheduled cycle.
+pub fn estimate(module: &Module, func: &Function, schedules: &[BlockSchedule]) -> Resources {
+    let mem = MemoryInfo::new(func);
+    let defs = definitions(func);
+    let mut total = Resources::default();
+    let mut states = 0;
+    for sched in schedules {
+        states += sched.latency as u64;
+        for op in &sched.ops {
+            if let Some(acc) = access(op.instr)
+                && !mem.is_array_access(&acc)
+            {
+                continue;
+            }
+            total += op_resources(module, &defs, op.instr);
+            if op.timing.latency > 0 {
+                total.ff += type_bits(&module.type_of(op.instr));
+            }
+        }
+    }
+    for instr in func.basic_blocks.iter().flat_map(|bb| &bb.instrs) {
+        let Instruction::Alloca(alloca) = instr else { continue };
+        let ty = alloca.allocated_type.as_ref();
+        if mem.is_scalar_alloca(&alloca.dest) {
+            total.ff += type_bits(ty);
+        } else {
+            let bits = size_of(&module.types, ty) * 8;
+            if bits <= LUTRAM_MAX_BITS {
+                total.lut += bits.div_ceil(64);
+            } else {
+                total.bram += bits.div_ceil(BRAM_18K_BITS);
+            }
+        }
+    }
+    total.ff += states;
+    total.lut += states;
+    total
+}
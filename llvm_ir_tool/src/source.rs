@@ -0,0 +1,180 @@
+use crate::loops::LoopInfo;
+use llvm_ir::{DebugLoc, Function, HasDebugLoc};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+// Loop labels, `label: for (...)` and a label alone on its line
+static INLINE_LABEL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*([A-Za-z_]\w*)\s*:\s*(for|while|do)\b").unwrap());
+static LABEL_ALONE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*([A-Za-z_]\w*)\s*:\s*$").unwrap());
+
+/// C/C++ source files referenced by debug locations, read on first use.
+pub struct Sources {
+    search_dirs: Vec<PathBuf>,
+    files: HashMap<String, Option<Vec<String>>>,
+}
+
+impl Sources {
+    /// `search_dirs` are tried when the path recorded in the debug info doesn't exist here
+    /// (e.g. the IR was generated on another machine).
+    pub fn new(search_dirs: Vec<PathBuf>) -> Self {
+        Sources { search_dirs, files: HashMap::new() }
+    }
+
+    /// All lines of the file a debug location refers to.
+    pub fn file(&mut self, loc: &DebugLoc) -> Option<&[String]> {
+        let key = format!("{}/{}", loc.directory.as_deref().unwrap_or(""), loc.filename);
+        if !self.files.contains_key(&key) {
+            let lines = self.load(loc);
+            self.files.insert(key.clone(), lines);
+        }
+        self.files[&key].as_deref()
+    }
+
+    fn load(&self, loc: &DebugLoc) -> Option<Vec<String>> {
+        let filename = Path::new(&loc.filename);
+        let mut candidates = Vec::new();
+        if let Some(dir) = &loc.directory {
+            candidates.push(Path::new(dir).join(filename));
+        }
+        candidates.push(filename.to_path_buf());
+        for dir in &self.search_dirs {
+            candidates.push(dir.join(filename));
+            if let Some(base) = filename.file_name() {
+                candidates.push(dir.join(base));
+            }
+        }
+        candidates
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(|l| l.to_string()).collect())
+    }
+}
+
+/// Debug location of a function: its definition, or else its first instruction.
+pub fn function_loc(func: &Function) -> Option<&DebugLoc> {
+    func.debugloc.as_ref().or_else(|| {
+        func.basic_blocks
+            .iter()
+            .flat_map(|bb| &bb.instrs)
+            .find_map(|instr| instr.get_debug_loc().as_ref())
+    })
+}
+
+/// Name of a function as written in the source. Plain Itanium-mangled C++ names
+/// (`_Z11matrix_multPA4_...`, `_ZN2ns3fooEv`) are reduced to the last identifier.
+pub fn source_name(name: &str) -> String {
+    let Some(mangled) = name.strip_prefix("_Z") else { return name.to_string() };
+    let mut rest = mangled.strip_prefix('N').unwrap_or(mangled);
+    let mut last = None;
+    // Read <length><identifier> pairs until something else follows
+    loop {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let Ok(len) = rest[..digits].parse::<usize>() else { break };
+        let Some(ident) = rest.get(digits..digits + len) else { break };
+        last = Some(ident);
+        rest = &rest[digits + len..];
+    }
+    last.map_or(name.to_string(), |s| s.to_string())
+}
+
+/// Whether a mangled name is a member or operator of the Vitis arbitrary-precision types
+/// (`ap_int_base::operator*`, `ap_fixed` constructors, `af_range_ref`, ...), which HLS
+/// inlines as operations rather than keeping as calls.
+pub fn is_ap_operator(name: &str) -> bool {
+    let Some(mangled) = name.strip_prefix("_Z") else { return false };
+    let ap_type = |ident: &str| ident.starts_with("ap_") || ident.starts_with("af_");
+    if let Some(nested) = mangled.strip_prefix('N') {
+        // A member: the first name of the nested name is its class
+        let rest = nested.trim_start_matches(['r', 'V', 'K', 'R', 'O']);
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let Ok(len) = rest[..digits].parse::<usize>() else { return false };
+        return rest.get(digits..digits + len).is_some_and(ap_type);
+    }
+    // A free operator (`_Zpl...`, `_Zml...`) templated on one of the types
+    if !mangled.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return false;
+    }
+    let mut rest = mangled;
+    while let Some(i) = rest.find(|c: char| c.is_ascii_digit()) {
+        let digits = rest[i..].chars().take_while(|c| c.is_ascii_digit()).count();
+        rest = &rest[i + digits..];
+        if ap_type(rest) {
+            return true;
+        }
+    }
+    false
+}
+
+/// C label in front of a loop statement, e.g. `row_loop` in `row_loop: for (...)`,
+/// looking at the loop's line and a label alone on the line before.
+pub fn loop_label(lines: &[String], line: u32) -> Option<String> {
+    let index = (line as usize).checked_sub(1)?;
+    if let Some(caps) = lines.get(index).and_then(|l| INLINE_LABEL.captures(l)) {
+        return Some(caps[1].to_string());
+    }
+    let previous = lines.get(index.checked_sub(1)?)?;
+    LABEL_ALONE.captures(previous).map(|caps| caps[1].to_string())
+}
+
+/// Loop names as Vitis HLS reports them: the C label if there is one, otherwise
+/// VITIS_LOOP_<line>_<n>, where n numbers the function's loops in source order.
+pub fn hls_loop_names(loop_info: &LoopInfo, sources: &mut Sources) -> Vec<String> {
+    let mut by_line: Vec<(u32, usize)> = (0..loop_info.loops.len()).map(|l| (loop_info.line(l).unwrap_or(0), l)).collect();
+    by_line.sort();
+    let mut names = vec![String::new(); loop_info.loops.len()];
+    let loc = function_loc(loop_info.func).cloned();
+    for (n, (line, l)) in by_line.into_iter().enumerate() {
+        let label = loc.as_ref().and_then(|loc| sources.file(loc)).and_then(|lines| loop_label(lines, line));
+        names[l] = label.unwrap_or_else(|| format!("VITIS_LOOP_{}_{}", line, n + 1));
+    }
+    names
+}
+
+/// `#pragma HLS` lines in the body of a function, with their line numbers. The body is found by
+/// matching braces from the line of the function's definition.
+pub fn function_pragmas(func: &Function, sources: &mut Sources) -> Vec<(u32, String)> {
+    let Some(loc) = func.debugloc.as_ref().or_else(|| function_loc(func)) else { return Vec::new() };
+    let Some(lines) = sources.file(loc) else { return Vec::new() };
+    let pragma = Regex::new(r"(?i)^\s*#\s*pragma\s+HLS\b").unwrap();
+    let mut pragmas = Vec::new();
+    let mut depth = 0;
+    let mut opened = false;
+    for (i, line) in lines.iter().enumerate().skip((loc.line as usize).saturating_sub(1)) {
+        if pragma.is_match(line) && opened {
+            pragmas.push((i as u32 + 1, line.trim().to_string()));
+        }
+        let code = line.split("//").next().unwrap_or("");
+        for c in code.chars() {
+            match c {
+                '{' => {
+                    depth += 1;
+                    opened = true;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        if opened && depth <= 0 {
+            break;
+        }
+    }
+    pragmas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_ap_operators() {
+        assert!(is_ap_operator("_ZN11ap_int_baseILi32ELb1EEC2Ei"));
+        assert!(is_ap_operator("_ZNK13ap_fixed_baseILi16ELi8ELb1EL9ap_q_mode5EL9ap_o_mode3ELi0EEmlILi16ELi8EEEv"));
+        assert!(is_ap_operator("_ZplILi32ELb1EE11ap_int_baseIXT_ELb1EERKS0_IXT_ELb1EEi"));
+        assert!(!is_ap_operator("_Z7computeP8ap_fixedILi16ELi8EES_"));
+        assert!(!is_ap_operator("_ZN2ns3fooEv"));
+        assert!(!is_ap_operator("llvm.memcpy.p0.p0.i64"));
+    }
+}
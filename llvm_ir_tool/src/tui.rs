@@ -0,0 +1,309 @@
+use crate::ir_util::{block_label, ir_lines, IrLine};
+use crate::loops::LoopInfo;
+use crate::repl::RawMode;
+use crate::source::{hls_loop_names, source_name, Sources};
+use crate::{explain_llvm_line, find_function_line_spans};
+use llvm_ir::{Function, Module};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// Rows of the explanation pane, below the source and IR panes
+const EXPLANATION_ROWS: usize = 4;
+
+// How long a terminal size is used before `stty` is asked again, to notice resizes
+const SIZE_REFRESH: Duration = Duration::from_millis(500);
+
+const KEYS: &str = " j/k line  n/p block  ]/[ loop  f/F function  PgUp/PgDn  g/G top/bottom  q quit";
+
+/// Rows and columns of the terminal, 24x80 if `stty` can't tell.
+fn terminal_size() -> (usize, usize) {
+    let output = Command::new("stty").arg("size").stdin(Stdio::inherit()).output().ok();
+    let size = output.and_then(|o| {
+        let text = String::from_utf8_lossy(&o.stdout).to_string();
+        let mut parts = text.split_whitespace().map(|p| p.parse::<usize>().ok());
+        Some((parts.next()??, parts.next()??))
+    });
+    size.filter(|&(h, w)| h > 0 && w > 0).unwrap_or((24, 80))
+}
+
+/// Cuts or pads text to exactly `width` columns.
+fn fit(text: &str, width: usize) -> String {
+    let text = text.replace('\t', "    ");
+    let mut out: String = text.chars().take(width).collect();
+    let len = out.chars().count();
+    out.extend(std::iter::repeat_n(' ', width - len));
+    out
+}
+
+/// Splits text into lines of at most `width` columns at spaces.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    for word in text.split_whitespace() {
+        let last = lines.last_mut().unwrap();
+        if !last.is_empty() && last.chars().count() + 1 + word.chars().count() > width {
+            lines.push(word.to_string());
+        } else {
+            if !last.is_empty() {
+                last.push(' ');
+            }
+            last.push_str(word);
+        }
+    }
+    lines
+}
+
+struct View<'m> {
+    filename: String,
+    module: &'m Module,
+    lines: Vec<String>,
+    spans: HashMap<String, (usize, usize)>,
+    // Functions defined in the file, by index in `module.functions`
+    functions: Vec<usize>,
+    func: usize,
+    rows: Vec<IrLine>,
+    cursor: usize,
+    sources: Sources,
+    // Loops of the current function and their HLS names, rebuilt when it changes
+    loop_info: LoopInfo<'m>,
+    names: Vec<String>,
+    size: (usize, usize),
+    sized: Instant,
+}
+
+impl<'m> View<'m> {
+    fn current(&self) -> &'m Function {
+        &self.module.functions[self.functions[self.func]]
+    }
+
+    fn select_function(&mut self, func: usize) {
+        self.func = func;
+        let f = self.current();
+        self.rows = ir_lines(f, &self.lines, self.spans[&f.name]);
+        self.cursor = 0;
+        self.loop_info = LoopInfo::new(f);
+        self.names = hls_loop_names(&self.loop_info, &mut self.sources);
+    }
+
+    /// Terminal size, queried again once `SIZE_REFRESH` has passed.
+    fn size(&mut self) -> (usize, usize) {
+        if self.sized.elapsed() >= SIZE_REFRESH {
+            self.size = terminal_size();
+            self.sized = Instant::now();
+        }
+        self.size
+    }
+
+    /// Moves the cursor to the next (or previous) row that satisfies `target`.
+    fn jump(&mut self, forward: bool, target: impl Fn(&IrLine) -> bool) {
+        let found = if forward {
+            (self.cursor + 1..self.rows.len()).find(|&r| target(&self.rows[r]))
+        } else {
+            (0..self.cursor).rev().find(|&r| target(&self.rows[r]))
+        };
+        if let Some(r) = found {
+            self.cursor = r;
+        }
+    }
+
+    fn draw(&mut self) -> String {
+        let (height, width) = self.size();
+        let mut out = String::from("\x1b[H");
+        if height < EXPLANATION_ROWS + 6 || width < 40 {
+            return out + "\x1b[2JTerminal too small";
+        }
+        let func = self.current();
+        let (loop_info, names) = (&self.loop_info, &self.names);
+        let row = &self.rows[self.cursor];
+        let inner = row.block.and_then(|b| loop_info.innermost(b));
+
+        let block = row.block.map_or("-".to_string(), |b| block_label(&func.basic_blocks[b].name));
+        let place = match inner {
+            Some(l) => format!("loop {} {} depth {}", names[l], loop_info.describe(l), loop_info.loops[l].depth),
+            None => "not in a loop".to_string(),
+        };
+        let status = format!(
+            " {} | {} ({}/{}) | block {} | {}",
+            self.filename,
+            source_name(&func.name),
+            self.func + 1,
+            self.functions.len(),
+            block,
+            place
+        );
+        out.push_str(&format!("\x1b[7m{}\x1b[0m\r\n", fit(&status, width)));
+
+        // Source lines the selected block came from are marked, the selected line is highlighted
+        let body = height - EXPLANATION_ROWS - 2;
+        let left = width * 2 / 5;
+        let right = width - left - 1;
+        let block_lines: HashSet<u32> = self
+            .rows
+            .iter()
+            .filter(|r| r.block.is_some() && r.block == row.block)
+            .filter_map(|r| r.loc.as_ref().map(|loc| loc.line))
+            .collect();
+        let loc = row.loc.clone().or_else(|| {
+            // A label or the define line shows the first location after it
+            self.rows[self.cursor..].iter().find_map(|r| r.loc.clone())
+        });
+        let source: Vec<String> = match &loc {
+            Some(loc) => match self.sources.file(loc) {
+                Some(text) => {
+                    let top = (loc.line as usize).saturating_sub(body / 2 + 1);
+                    (top..top + body)
+                        .map(|i| match text.get(i) {
+                            Some(line) => {
+                                let n = i as u32 + 1;
+                                let mark = if block_lines.contains(&n) { '>' } else { ' ' };
+                                let cell = fit(&format!("{:4}{}{}", n, mark, line), left);
+                                if n == loc.line && row.loc.is_some() { format!("\x1b[7m{}\x1b[0m", cell) } else { cell }
+                            }
+                            None => fit("", left),
+                        })
+                        .collect()
+                }
+                None => vec![fit(&format!(" {} not found, add --source-dir", loc.filename), left)],
+            },
+            None => vec![fit(" (no debug location)", left)],
+        };
+        let top = self.cursor.saturating_sub(body / 2).min(self.rows.len().saturating_sub(body));
+        for r in 0..body {
+            let left_cell = source.get(r).cloned().unwrap_or_else(|| fit("", left));
+            let right_cell = match self.rows.get(top + r) {
+                Some(ir) => {
+                    let cell = fit(&format!("{:5} {}", ir.index + 1, self.lines[ir.index]), right);
+                    if top + r == self.cursor { format!("\x1b[7m{}\x1b[0m", cell) } else { cell }
+                }
+                None => fit("", right),
+            };
+            out.push_str(&format!("{}|{}\r\n", left_cell, right_cell));
+        }
+
+        let text = &self.lines[row.index];
+        let explanation = if let Some(b) = row.label {
+            let preds = loop_info.cfg.preds[b].iter().map(|&p| block_label(loop_info.cfg.names[p])).collect::<Vec<_>>();
+            format!("Start of basic block {} (predecessors: {}).", block, if preds.is_empty() { "none".to_string() } else { preds.join(", ") })
+        } else if text.trim_start().starts_with("define") {
+            format!("Definition of function {} with {} basic blocks.", func.name, func.basic_blocks.len())
+        } else if row.block.is_some() && !text.trim().is_empty() {
+            explain_llvm_line(text)
+        } else {
+            String::new()
+        };
+        let title = format!("-- Line {} ", row.index + 1);
+        out.push_str(&format!("\x1b[1m{}{}\x1b[0m\r\n", title, "-".repeat(width.saturating_sub(title.len()))));
+        let mut explained = wrap(&explanation, width);
+        if let Some(loc) = &row.loc {
+            explained.push(format!("Source: {}:{}:{}", loc.filename, loc.line, loc.col.unwrap_or(0)));
+        }
+        for r in 0..EXPLANATION_ROWS - 1 {
+            out.push_str(&format!("{}\r\n", fit(explained.get(r).map_or("", |l| l.as_str()), width)));
+        }
+        out.push_str(&format!("\x1b[7m{}\x1b[0m", fit(KEYS, width)));
+        out
+    }
+}
+
+/// `--tui [function] [--source-dir <dir>]...`
+pub fn run(module: &Module, filename: &str, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: cargo run <filename.ll> --tui [function] [--source-dir <dir>]...";
+    let mut func_name = None;
+    let mut search_dirs = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--source-dir" => search_dirs.push(PathBuf::from(rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?)),
+            a if !a.starts_with("--") => func_name = Some(arg),
+            _ => return Err(format!("Unknown option '{}'\n{}", arg, usage)),
+        }
+    }
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return Err("--tui needs a terminal, use --repl for piped input".to_string());
+    }
+
+    let text = fs::read_to_string(filename).map_err(|e| format!("Failed to read {}: {}", filename, e))?;
+    let lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+    let spans: HashMap<String, (usize, usize)> =
+        find_function_line_spans(&lines).into_iter().map(|(name, span)| (name.to_string(), span)).collect();
+    let functions: Vec<usize> = (0..module.functions.len()).filter(|&f| spans.contains_key(&module.functions[f].name)).collect();
+    if functions.is_empty() {
+        return Err(format!("No function definitions found in {}", filename));
+    }
+    let start = match func_name {
+        Some(name) => functions
+            .iter()
+            .position(|&f| module.functions[f].name == *name || source_name(&module.functions[f].name) == *name)
+            .ok_or_else(|| format!("Function '{}' not found in module.", name))?,
+        None => 0,
+    };
+    // Filled in by `select_function`
+    let loop_info = LoopInfo::new(&module.functions[functions[start]]);
+    let mut view = View {
+        filename: filename.to_string(),
+        module,
+        lines,
+        spans,
+        functions,
+        func: 0,
+        rows: Vec::new(),
+        cursor: 0,
+        sources: Sources::new(search_dirs),
+        loop_info,
+        names: Vec::new(),
+        size: terminal_size(),
+        sized: Instant::now(),
+    };
+    view.select_function(start);
+
+    let _raw = RawMode::enable();
+    // Alternate screen without a cursor, put back on the way out
+    print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+    let mut bytes = io::stdin().lock().bytes();
+    let mut next = || bytes.next().transpose().map_err(|e| format!("Failed to read input: {}", e));
+    let result = loop {
+        print!("{}", view.draw());
+        let _ = io::stdout().flush();
+        let page = view.size().0.saturating_sub(EXPLANATION_ROWS + 2).max(1);
+        let key = match next() {
+            Ok(Some(27)) => match (next(), next()) {
+                (Ok(Some(b'[')), Ok(Some(b'A'))) => b'k',
+                (Ok(Some(b'[')), Ok(Some(b'B'))) => b'j',
+                // Page up and down are ESC [ 5 ~ and ESC [ 6 ~
+                (Ok(Some(b'[')), Ok(Some(b @ (b'5' | b'6')))) => {
+                    let _ = next();
+                    if b == b'5' { b'u' } else { b'd' }
+                }
+                _ => continue,
+            },
+            Ok(Some(key)) => key,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        let last = view.rows.len() - 1;
+        match key {
+            b'q' | 3 | 4 => break Ok(()),
+            b'j' => view.cursor = (view.cursor + 1).min(last),
+            b'k' => view.cursor = view.cursor.saturating_sub(1),
+            b'd' => view.cursor = (view.cursor + page).min(last),
+            b'u' => view.cursor = view.cursor.saturating_sub(page),
+            b'g' => view.cursor = 0,
+            b'G' => view.cursor = last,
+            b'n' => view.jump(true, |r| r.label.is_some()),
+            b'p' => view.jump(false, |r| r.label.is_some()),
+            b']' | b'[' => {
+                let headers: HashSet<usize> = view.loop_info.loops.iter().map(|l| l.header).collect();
+                view.jump(key == b']', |r| r.label.is_some_and(|b| headers.contains(&b)));
+            }
+            b'f' => view.select_function((view.func + 1) % view.functions.len()),
+            b'F' => view.select_function((view.func + view.functions.len() - 1) % view.functions.len()),
+            _ => {}
+        }
+    };
+    print!("\x1b[?25h\x1b[?1049l");
+    let _ = io::stdout().flush();
+    result
+}
@@ -0,0 +1,472 @@
+use crate::ir_util::{
+    block_label, dest_mut, instr_text, opcode_counts, operands, operands_mut, successors, successors_mut, term_operands_mut,
+};
+use crate::loops::LoopInfo;
+use crate::printer::write_module;
+use crate::resources;
+use crate::schedule::{function_latency, loop_latency, schedule_function, BlockSchedule, ScheduleOptions};
+use llvm_ir::terminator::Br;
+use llvm_ir::{BasicBlock, Function, Instruction, Module, Name, Operand, Terminator};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Name of a value or block in the k-th copy of an unrolled loop body (copy 0 keeps the original names).
+fn copy_name(name: &Name, k: u64) -> Name {
+    if k == 0 { name.clone() } else { Name::from(format!("{}.u{}", block_label(name), k)) }
+}
+
+/// The loop-level facts the unroller needs, by name so they stay valid while blocks are rewritten.
+struct LoopShape {
+    header: Name,
+    latch: Name,
+    blocks: Vec<Name>,
+    values: HashSet<Name>,
+    trip_count: Option<u64>,
+}
+
+/// Unrolls loop `l` of `func` by `factor`, returning the rewritten function.
+/// Every copy of the body keeps its exit test unless the trip count is known and divisible by the
+/// factor; a full unroll also removes the back edge. Straight-line chains of the copies are then
+/// merged into single blocks so they are scheduled together, as HLS does after unrolling.
+pub fn unroll(func: &Function, l: usize, factor: u64) -> Result<Function, String> {
+    let loop_info = LoopInfo::new(func);
+    let lp = &loop_info.loops[l];
+    let names = &loop_info.cfg.names;
+    let [latch] = lp.latches.as_slice() else {
+        return Err(format!("Loop {} has {} latches, only single-latch loops can be unrolled", loop_info.describe(l), lp.latches.len()));
+    };
+    let shape = LoopShape {
+        header: names[lp.header].clone(),
+        latch: names[*latch].clone(),
+        blocks: lp.blocks.iter().map(|&b| names[b].clone()).collect(),
+        values: lp
+            .blocks
+            .iter()
+            .flat_map(|&b| &func.basic_blocks[b].instrs)
+            .filter_map(|instr| instr.try_get_result().cloned())
+            .collect(),
+        trip_count: loop_info.constant_trip_count(l),
+    };
+    match shape.trip_count {
+        Some(0) => return Err(format!("Loop {} never executes", loop_info.describe(l))),
+        Some(n) if factor > n => return Err(format!("Unroll factor {} is larger than the trip count {}", factor, n)),
+        _ => {}
+    }
+    if factor <= 1 {
+        return Ok(func.clone());
+    }
+    check_closed(func, &shape)?;
+
+    let in_loop = |name: &Name| shape.blocks.contains(name);
+    let remap = |op: &mut Operand, k: u64| {
+        if let Operand::LocalOperand { name, .. } = op
+            && shape.values.contains(name)
+        {
+            *name = copy_name(name, k);
+        }
+    };
+    // The back edge of copy k goes to the header of copy k+1, and the last copy loops back to copy 0
+    let next_header = |k: u64| copy_name(&shape.header, if k + 1 == factor { 0 } else { k + 1 });
+
+    let mut unrolled = func.clone();
+    let mut copies: Vec<BasicBlock> = Vec::new();
+    for k in 1..factor {
+        for bb in func.basic_blocks.iter().filter(|bb| in_loop(&bb.name)) {
+            let mut bb = bb.clone();
+            let is_header = bb.name == shape.header;
+            bb.name = copy_name(&bb.name, k);
+            for instr in &mut bb.instrs {
+                if let Some(dest) = dest_mut(instr) {
+                    *dest = copy_name(dest, k);
+                }
+                match instr {
+                    // Header phis of copy k only receive the value from the previous copy's latch
+                    Instruction::Phi(phi) if is_header => {
+                        phi.incoming_values.retain(|(_, block)| *block == shape.latch);
+                        for (value, block) in &mut phi.incoming_values {
+                            remap(value, k - 1);
+                            *block = copy_name(block, k - 1);
+                        }
+                    }
+                    Instruction::Phi(phi) => {
+                        for (value, block) in &mut phi.incoming_values {
+                            remap(value, k);
+                            *block = copy_name(block, k);
+                        }
+                    }
+                    _ => operands_mut(instr).into_iter().for_each(|op| remap(op, k)),
+                }
+            }
+            let is_latch = bb.name == copy_name(&shape.latch, k);
+            for dest in successors_mut(&mut bb.term) {
+                if is_latch && *dest == shape.header {
+                    *dest = next_header(k);
+                } else if in_loop(dest) {
+                    *dest = copy_name(dest, k);
+                }
+            }
+            term_operands_mut(&mut bb.term).into_iter().for_each(|op| remap(op, k));
+            copies.push(bb);
+        }
+    }
+
+    for bb in &mut unrolled.basic_blocks {
+        if bb.name == shape.header {
+            // The original header now receives its back edge from the last copy
+            for instr in &mut bb.instrs {
+                if let Instruction::Phi(phi) = instr {
+                    for (value, block) in &mut phi.incoming_values {
+                        if *block == shape.latch {
+                            remap(value, factor - 1);
+                            *block = copy_name(block, factor - 1);
+                        }
+                    }
+                }
+            }
+        }
+        if bb.name == shape.latch {
+            for dest in successors_mut(&mut bb.term) {
+                if *dest == shape.header {
+                    *dest = next_header(0);
+                }
+            }
+        }
+        if !in_loop(&bb.name) {
+            // Exit blocks get a phi entry for every copy of the exiting block
+            for instr in &mut bb.instrs {
+                if let Instruction::Phi(phi) = instr {
+                    let mut extra = Vec::new();
+                    for (value, block) in &phi.incoming_values {
+                        if in_loop(block) {
+                            for k in 1..factor {
+                                let mut value = value.clone();
+                                remap(&mut value, k);
+                                extra.push((value, copy_name(block, k)));
+                            }
+                        }
+                    }
+                    phi.incoming_values.extend(extra);
+                }
+            }
+        }
+    }
+    // Insert the copies right after the last block of the loop to keep the layout readable
+    let last = unrolled.basic_blocks.iter().rposition(|bb| in_loop(&bb.name)).unwrap_or(0);
+    unrolled.basic_blocks.splice(last + 1..last + 1, copies);
+
+    if let Some(n) = shape.trip_count
+        && n % factor == 0
+    {
+        drop_exit_tests(&mut unrolled, &shape, factor, n == factor);
+    }
+
+    let merge_candidates: HashSet<Name> = (0..factor).flat_map(|k| shape.blocks.iter().map(move |b| copy_name(b, k))).collect();
+    merge_chains(&mut unrolled, &merge_candidates);
+    Ok(unrolled)
+}
+
+/// Rejects loops whose values are used after the loop other than through exit-block phis,
+/// since the copies would need new phis to merge them.
+fn check_closed(func: &Function, shape: &LoopShape) -> Result<(), String> {
+    for bb in func.basic_blocks.iter().filter(|bb| !shape.blocks.contains(&bb.name)) {
+        for instr in &bb.instrs {
+            if matches!(instr, Instruction::Phi(_)) {
+                continue;
+            }
+            for op in operands(instr) {
+                if let Operand::LocalOperand { name, .. } = op
+                    && shape.values.contains(name)
+                {
+                    return Err(format!(
+                        "{} defined in the loop is used in {} after it; run `opt -passes=lcssa` first",
+                        name,
+                        block_label(&bb.name)
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// With a trip count divisible by the factor only the first copy needs its exit test.
+/// For a full unroll the first test always passes too, and the last copy leaves the loop directly,
+/// provided the header only computes its exit condition.
+fn drop_exit_tests(func: &mut Function, shape: &LoopShape, factor: u64, full: bool) {
+    let exits: Vec<Name> = func
+        .basic_blocks
+        .iter()
+        .filter(|bb| bb.name == shape.header)
+        .flat_map(|bb| successors(&bb.term))
+        .filter(|s| !shape.blocks.contains(s))
+        .cloned()
+        .collect();
+    let [exit] = exits.as_slice() else { return };
+    let header_is_pure = func.basic_blocks.iter().filter(|bb| bb.name == shape.header).all(|bb| {
+        bb.instrs.iter().all(|i| !matches!(i, Instruction::Phi(_) | Instruction::Store(_) | Instruction::Call(_)))
+    });
+    let exit_has_phis = func
+        .basic_blocks
+        .iter()
+        .any(|bb| bb.name == *exit && bb.instrs.iter().any(|i| matches!(i, Instruction::Phi(_))));
+    let remove_back_edge = full && header_is_pure && !exit_has_phis;
+
+    let first = if remove_back_edge { 0 } else { 1 };
+    let tests: Vec<Name> = (first..factor).map(|k| copy_name(&shape.header, k)).collect();
+    for bb in &mut func.basic_blocks {
+        if tests.contains(&bb.name)
+            && let Terminator::CondBr(br) = &bb.term
+        {
+            let stay = if br.true_dest == *exit { br.false_dest.clone() } else { br.true_dest.clone() };
+            bb.term = Terminator::Br(Br { dest: stay, debugloc: br.debugloc.clone() });
+        }
+        if remove_back_edge && bb.name == copy_name(&shape.latch, factor - 1) {
+            for dest in successors_mut(&mut bb.term) {
+                if *dest == shape.header {
+                    *dest = exit.clone();
+                }
+            }
+        }
+        // Exit phis no longer receive values from the removed tests
+        if bb.name == *exit {
+            for instr in &mut bb.instrs {
+                if let Instruction::Phi(phi) = instr {
+                    phi.incoming_values.retain(|(_, block)| !tests.contains(block));
+                }
+            }
+        }
+    }
+}
+
+/// Merges a block into its only predecessor when that predecessor falls through to it unconditionally.
+fn merge_chains(func: &mut Function, candidates: &HashSet<Name>) {
+    loop {
+        let mut preds: HashMap<Name, Vec<Name>> = HashMap::new();
+        for bb in &func.basic_blocks {
+            for s in successors(&bb.term) {
+                preds.entry(s.clone()).or_default().push(bb.name.clone());
+            }
+        }
+        let entry = func.basic_blocks[0].name.clone();
+        let pair = func.basic_blocks.iter().find_map(|bb| {
+            let Terminator::Br(br) = &bb.term else { return None };
+            let succ = func.basic_blocks.iter().find(|s| s.name == br.dest)?;
+            let mergeable = candidates.contains(&bb.name)
+                && candidates.contains(&succ.name)
+                && succ.name != bb.name
+                && succ.name != entry
+                && preds.get(&succ.name).is_some_and(|p| p.len() == 1)
+                && !succ.instrs.iter().any(|i| matches!(i, Instruction::Phi(_)));
+            mergeable.then(|| (bb.name.clone(), succ.name.clone()))
+        });
+        let Some((into, from)) = pair else { break };
+
+        let from_index = func.basic_blocks.iter().position(|bb| bb.name == from).unwrap();
+        let from_bb = func.basic_blocks.remove(from_index);
+        let into_bb = func.basic_blocks.iter_mut().find(|bb| bb.name == into).unwrap();
+        into_bb.instrs.extend(from_bb.instrs);
+        into_bb.term = from_bb.term;
+        // Phis in the successors now see the merged block as their predecessor
+        for bb in &mut func.basic_blocks {
+            for instr in &mut bb.instrs {
+                if let Instruction::Phi(phi) = instr {
+                    for (_, block) in &mut phi.incoming_values {
+                        if *block == from {
+                            *block = into.clone();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Latency of a loop after rewriting: the loop's own estimate if it still exists, otherwise
+/// the cycles of the blocks left over from its body.
+fn rewritten_loop_latency(loop_info: &LoopInfo, schedules: &[BlockSchedule], header: &Name, body: &HashSet<Name>) -> Option<u64> {
+    match loop_info.find_by_header(&block_label(header)) {
+        Some(l) => loop_latency(loop_info, schedules, l),
+        None => Some(
+            schedules
+                .iter()
+                .filter(|s| body.contains(&s.block.name))
+                .map(|s| s.latency as u64)
+                .sum(),
+        ),
+    }
+}
+
+/// `--unroll <function> <loop> <factor|full> [--show] [--output <file.ll>] [schedule options]`
+pub fn run(module: &Module, args: &[String]) -> Result<(), String> {
+    let usage = format!(
+        "Usage: cargo run <filename.ll> --unroll <function> <loop header|source line> <factor|full> [--show] [--output <file.ll>] {}",
+        ScheduleOptions::USAGE
+    );
+    let [func_name, loop_spec, factor_spec, rest @ ..] = args else { return Err(usage) };
+    let func = module
+        .get_func_by_name(func_name)
+        .ok_or_else(|| format!("Function '{}' not found in module.", func_name))?;
+
+    let mut options = ScheduleOptions::new();
+    let mut show = false;
+    let mut output = None;
+    let mut rest = rest.iter();
+    while let Some(flag) = rest.next() {
+        if flag == "--show" {
+            show = true;
+            continue;
+        }
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", flag, usage))?;
+        if flag == "--output" {
+            output = Some(value);
+        } else if !options.apply(flag, value)? {
+            return Err(format!("Unknown option '{}'\n{}", flag, usage));
+        }
+    }
+
+    let loop_info = LoopInfo::new(func);
+    let l = loop_info
+        .find(loop_spec)
+        .ok_or_else(|| format!("No loop with header or source line '{}' in '{}'", loop_spec, func_name))?;
+    let trip_count = loop_info.constant_trip_count(l);
+    let factor = match factor_spec.as_str() {
+        "full" => trip_count.ok_or_else(|| format!("Trip count of {} is not constant, give an explicit factor", loop_info.describe(l)))?,
+        f => f.parse().map_err(|_| format!("Invalid unroll factor '{}'\n{}", f, usage))?,
+    };
+    let unrolled = unroll(func, l, factor)?;
+
+    let trips = trip_count.map_or("unknown".to_string(), |n| n.to_string());
+    let kind = if Some(factor) == trip_count { ", fully unrolled" } else { "" };
+    println!("Unrolled {} in '{}' by {} (trip count {}{})", loop_info.describe(l), func_name, factor, trips, kind);
+    println!("  Basic blocks: {} -> {}\n", func.basic_blocks.len(), unrolled.basic_blocks.len());
+
+    // The loop's blocks and their copies, to find what is left of the loop after merging
+    let header = loop_info.cfg.names[loop_info.loops[l].header];
+    let body: HashSet<Name> = loop_info.loops[l]
+        .blocks
+        .iter()
+        .flat_map(|&b| {
+            let name = loop_info.cfg.names[b];
+            (0..factor).map(move |k| copy_name(name, k))
+        })
+        .collect();
+    if show {
+        for bb in unrolled.basic_blocks.iter().filter(|bb| body.contains(&bb.name)) {
+            println!("{}:", block_label(&bb.name));
+            for instr in &bb.instrs {
+                println!("  {}", instr_text(instr));
+            }
+            println!("  {}\n", bb.term.to_string().replace(" (with debugloc)", ""));
+        }
+    }
+
+    let before = opcode_counts(func);
+    let after = opcode_counts(&unrolled);
+    println!("  {:<15} {:>8} {:>8} {:>8}", "Opcode", "Before", "After", "Delta");
+    let opcodes: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for op in opcodes {
+        let (b, a) = (before.get(op).copied().unwrap_or(0), after.get(op).copied().unwrap_or(0));
+        println!("  {:<15} {:>8} {:>8} {:>+8}", op, b, a, a as i64 - b as i64);
+    }
+    let (b, a): (usize, usize) = (before.values().sum(), after.values().sum());
+    println!("  {:<15} {:>8} {:>8} {:>+8}\n", "Total", b, a, a as i64 - b as i64);
+
+    let schedules_before = schedule_function(module, func, &options.model, options.clock);
+    let schedules_after = schedule_function(module, &unrolled, &options.model, options.clock);
+    let unrolled_info = LoopInfo::new(&unrolled);
+    let res_before = resources::estimate(module, func, &schedules_before);
+    let res_after = resources::estimate(module, &unrolled, &schedules_after);
+    let cycles = |c: Option<u64>| c.map_or("?".to_string(), |c| c.to_string());
+    let loop_before = loop_latency(&loop_info, &schedules_before, l);
+    let loop_after = rewritten_loop_latency(&unrolled_info, &schedules_after, header, &body);
+
+    println!("  {:<28} {:>10} {:>10}", "Estimate", "Before", "After");
+    println!("  {:<28} {:>10} {:>10}", "Loop latency (cycles)", cycles(loop_before), cycles(loop_after));
+    println!(
+        "  {:<28} {:>10} {:>10}",
+        "Function latency (cycles)",
+        cycles(function_latency(&loop_info, &schedules_before)),
+        cycles(function_latency(&unrolled_info, &schedules_after))
+    );
+    println!("  {:<28} {:>10} {:>10}", "BRAM_18K", res_before.bram, res_after.bram);
+    println!("  {:<28} {:>10} {:>10}", "DSP", res_before.dsp, res_after.dsp);
+    println!("  {:<28} {:>10} {:>10}", "FF", res_before.ff, res_after.ff);
+    println!("  {:<28} {:>10} {:>10}", "LUT", res_before.lut, res_after.lut);
+
+    if let Some(path) = output {
+        // The whole module with the function replaced, ready for clang, opt or Vitis
+        let mut rewritten = module.clone();
+        if let Some(f) = rewritten.functions.iter_mut().find(|f| f.name == func.name) {
+            *f = unrolled;
+        }
+        write_module(&rewritten, path)?;
+        println!("\nWrote the unrolled module to {}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{int_value, Interpreter};
+
+    // `s += i * 3` for i in 0..8, as clang emits it at -O0
+    const ACC: &str = "
+define i32 @acc() {
+entry:
+  %i = alloca i32
+  %s = alloca i32
+  store i32 0, ptr %i
+  store i32 0, ptr %s
+  br label %for.cond
+for.cond:
+  %0 = load i32, ptr %i
+  %cmp = icmp slt i32 %0, 8
+  br i1 %cmp, label %for.body, label %for.end
+for.body:
+  %1 = load i32, ptr %s
+  %mul = mul i32 %0, 3
+  %add = add i32 %1, %mul
+  store i32 %add, ptr %s
+  %inc = add i32 %0, 1
+  store i32 %inc, ptr %i
+  br label %for.cond
+for.end:
+  %2 = load i32, ptr %s
+  ret i32 %2
+}
+";
+
+    // Unrolls the loop and runs the result in the interpreter
+    fn unrolled(factor: u64) -> (Module, Option<u64>) {
+        let module = Module::from_ir_str(ACC).unwrap();
+        let mut work = module.clone();
+        work.functions[0] = unroll(&module.functions[0], 0, factor).unwrap();
+        let result = Interpreter::new(&work).unwrap().call_function("acc", Vec::new()).unwrap();
+        assert_eq!(result, Some(int_value(32, 84)), "factor {}", factor);
+        let info = LoopInfo::new(&work.functions[0]);
+        let trips = (!info.loops.is_empty()).then(|| info.constant_trip_count(0)).flatten();
+        (work, trips)
+    }
+
+    #[test]
+    fn partial_unroll_keeps_the_result() {
+        let (work, trips) = unrolled(2);
+        assert_eq!(trips, Some(4));
+        assert_eq!(opcode_counts(&work.functions[0])["Mul"], 2);
+        // 8 is not a multiple of 3, so every copy keeps its exit test
+        unrolled(3);
+    }
+
+    #[test]
+    fn full_unroll_removes_the_loop() {
+        let (work, _) = unrolled(8);
+        assert!(LoopInfo::new(&work.functions[0]).loops.is_empty());
+        assert_eq!(opcode_counts(&work.functions[0])["Mul"], 8);
+    }
+
+    #[test]
+    fn rejects_factors_above_the_trip_count() {
+        let module = Module::from_ir_str(ACC).unwrap();
+        assert!(unroll(&module.functions[0], 0, 9).is_err());
+    }
+}
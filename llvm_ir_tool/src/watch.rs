@@ -0,0 +1,351 @@
+use crate::ir_util::opcode_counts;
+use crate::loops::LoopInfo;
+use crate::resources::{self, Resources};
+use crate::schedule::{function_latency, schedule_function, ScheduleOptions};
+use crate::source::source_name;
+use llvm_ir::Module;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+/// What a run records about one function, to compare against the next run.
+struct Summary {
+    opcodes: BTreeMap<String, usize>,
+    latency: Option<u64>,
+    resources: Resources,
+}
+
+impl Summary {
+    fn total(&self) -> usize {
+        self.opcodes.values().sum()
+    }
+
+    fn estimates(&self) -> [(&'static str, Option<u64>); 5] {
+        [
+            ("Latency", self.latency),
+            ("BRAM_18K", Some(self.resources.bram)),
+            ("DSP", Some(self.resources.dsp)),
+            ("FF", Some(self.resources.ff)),
+            ("LUT", Some(self.resources.lut)),
+        ]
+    }
+
+    fn one_line(&self) -> String {
+        format!(
+            "{} instructions, latency {}, BRAM_18K {}, DSP {}, FF {}, LUT {}",
+            self.total(),
+            self.latency.map_or("unknown".to_string(), |l| format!("{} cycles", l)),
+            self.resources.bram,
+            self.resources.dsp,
+            self.resources.ff,
+            self.resources.lut
+        )
+    }
+}
+
+/// Everything one run produced: per-function summaries and the output of each `--run` analysis.
+struct Snapshot {
+    functions: BTreeMap<String, Summary>,
+    outputs: Vec<Vec<String>>,
+}
+
+struct Options {
+    func: Option<String>,
+    sources: Vec<String>,
+    compile: Option<String>,
+    analyses: Vec<Vec<String>>,
+    interval: Duration,
+    schedule: ScheduleOptions,
+}
+
+/// Modification time and size of a file, or None while it doesn't exist.
+fn stamp(path: &str) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+fn stamps(paths: &[&str]) -> Vec<Option<(SystemTime, u64)>> {
+    paths.iter().map(|p| stamp(p)).collect()
+}
+
+/// Runs the compile command, letting its output through so compiler errors show up.
+fn compile(command: &str) -> bool {
+    println!("Compiling: {}", command);
+    match Command::new("sh").arg("-c").arg(command).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            println!("Compile failed ({}), waiting for the next change", status);
+            false
+        }
+        Err(e) => {
+            println!("Failed to run '{}': {}, waiting for the next change", command, e);
+            false
+        }
+    }
+}
+
+fn summarize(module: &Module, options: &Options) -> BTreeMap<String, Summary> {
+    let mut functions = BTreeMap::new();
+    for func in &module.functions {
+        if let Some(filter) = &options.func
+            && *filter != func.name
+            && *filter != source_name(&func.name)
+        {
+            continue;
+        }
+        let schedules = schedule_function(module, func, &options.schedule.model, options.schedule.clock);
+        let loop_info = LoopInfo::new(func);
+        let summary = Summary {
+            opcodes: opcode_counts(func),
+            latency: function_latency(&loop_info, &schedules),
+            resources: resources::estimate(module, func, &schedules),
+        };
+        functions.insert(func.name.clone(), summary);
+    }
+    functions
+}
+
+/// Runs one analysis mode on the file in a child process and returns its output lines.
+/// A child keeps a failing or panicking analysis from ending the watch.
+fn run_analysis(filename: &str, analysis: &[String]) -> Vec<String> {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return vec![format!("Failed to find the tool's executable: {}", e)],
+    };
+    match Command::new(exe).arg(filename).args(analysis).output() {
+        Ok(output) => {
+            let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+            text.lines().map(|l| l.to_string()).collect()
+        }
+        Err(e) => vec![format!("Failed to run {}: {}", analysis.join(" "), e)],
+    }
+}
+
+fn signed(delta: i128) -> String {
+    if delta > 0 { format!("+{}", delta) } else { delta.to_string() }
+}
+
+fn change_line(name: &str, old: Option<u64>, new: Option<u64>) -> Option<String> {
+    if old == new {
+        return None;
+    }
+    let show = |v: Option<u64>| v.map_or("unknown".to_string(), |v| v.to_string());
+    let delta = match (old, new) {
+        (Some(old), Some(new)) => format!(" ({})", signed(new as i128 - old as i128)),
+        _ => String::new(),
+    };
+    Some(format!("    {:<15} {} -> {}{}", name, show(old), show(new), delta))
+}
+
+fn print_function_delta(name: &str, old: &Summary, new: &Summary) -> bool {
+    let mut lines = Vec::new();
+    let mut opcodes: Vec<&String> = old.opcodes.keys().chain(new.opcodes.keys()).collect();
+    opcodes.sort();
+    opcodes.dedup();
+    for op in opcodes {
+        let count = |s: &Summary| s.opcodes.get(op).map(|&c| c as u64);
+        lines.extend(change_line(op, count(old).or(Some(0)), count(new).or(Some(0))));
+    }
+    lines.extend(change_line("Total", Some(old.total() as u64), Some(new.total() as u64)));
+    for ((label, old), (_, new)) in old.estimates().into_iter().zip(new.estimates()) {
+        lines.extend(change_line(label, old, new));
+    }
+    if lines.is_empty() {
+        return false;
+    }
+    println!("  {}", name);
+    for line in lines {
+        println!("{}", line);
+    }
+    true
+}
+
+/// Lines only in the old output and lines only in the new one, each in its own order.
+/// Lines are matched by content and count, so a line that only moved is not reported.
+fn line_delta<'a>(old: &'a [String], new: &'a [String]) -> (Vec<&'a String>, Vec<&'a String>) {
+    let mut counts: HashMap<&String, isize> = HashMap::new();
+    for line in old {
+        *counts.entry(line).or_insert(0) += 1;
+    }
+    let mut added = Vec::new();
+    for line in new {
+        let count = counts.entry(line).or_insert(0);
+        if *count > 0 {
+            *count -= 1;
+        } else {
+            added.push(line);
+        }
+    }
+    let mut removed = Vec::new();
+    for line in old.iter().rev() {
+        let count = counts.get_mut(line).unwrap();
+        if *count > 0 {
+            *count -= 1;
+            removed.push(line);
+        }
+    }
+    removed.reverse();
+    (removed, added)
+}
+
+fn print_first(snapshot: &Snapshot, analyses: &[Vec<String>]) {
+    for (name, summary) in &snapshot.functions {
+        println!("  {}: {}", name, summary.one_line());
+    }
+    for (analysis, output) in analyses.iter().zip(&snapshot.outputs) {
+        println!("\n  {}", analysis.join(" "));
+        for line in output {
+            println!("    {}", line);
+        }
+    }
+}
+
+fn print_delta(previous: &Snapshot, snapshot: &Snapshot, analyses: &[Vec<String>]) {
+    let mut changed = false;
+    for (name, old) in &previous.functions {
+        match snapshot.functions.get(name) {
+            Some(new) => changed |= print_function_delta(name, old, new),
+            None => {
+                println!("  - {}: removed", name);
+                changed = true;
+            }
+        }
+    }
+    for (name, new) in snapshot.functions.iter().filter(|(name, _)| !previous.functions.contains_key(*name)) {
+        println!("  + {}: {}", name, new.one_line());
+        changed = true;
+    }
+    if !changed {
+        println!("  No opcode or estimate changes");
+    }
+    for (analysis, (old, new)) in analyses.iter().zip(previous.outputs.iter().zip(&snapshot.outputs)) {
+        let (removed, added) = line_delta(old, new);
+        if removed.is_empty() && added.is_empty() {
+            println!("  {}: unchanged", analysis.join(" "));
+            continue;
+        }
+        println!("  {}: {} lines removed, {} added", analysis.join(" "), removed.len(), added.len());
+        for line in removed {
+            println!("    - {}", line);
+        }
+        for line in added {
+            println!("    + {}", line);
+        }
+    }
+}
+
+fn elapsed(start: Instant) -> String {
+    let secs = start.elapsed().as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// `--watch [function] [--source <file.c>]... [--compile <command>] [--run "<--mode> [args]"]... [--interval <ms>]`
+pub fn run(filename: &str, args: &[String]) -> Result<(), String> {
+    let usage = format!(
+        "Usage: cargo run <filename.ll> --watch [function] [--source <file.c>]... [--compile <command>] [--run \"--<mode> [args]\"]... [--interval <ms>] {}",
+        ScheduleOptions::USAGE
+    );
+    let mut options = Options {
+        func: None,
+        sources: Vec::new(),
+        compile: None,
+        analyses: Vec::new(),
+        interval: Duration::from_millis(500),
+        schedule: ScheduleOptions::new(),
+    };
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        if !arg.starts_with("--") {
+            if options.func.is_some() {
+                return Err(usage);
+            }
+            options.func = Some(arg.clone());
+            continue;
+        }
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}\n{}", arg, usage))?;
+        match arg.as_str() {
+            "--source" => options.sources.push(value.clone()),
+            "--compile" => options.compile = Some(value.clone()),
+            "--run" => {
+                let analysis: Vec<String> = value.split_whitespace().map(|s| s.to_string()).collect();
+                if !analysis.first().is_some_and(|m| m.starts_with("--")) || ["--watch", "--repl", "--tui"].contains(&analysis[0].as_str()) {
+                    return Err(format!("--run takes an analysis mode with its arguments, like \"--bursts main\"\n{}", usage));
+                }
+                options.analyses.push(analysis);
+            }
+            "--interval" => {
+                let ms = value.parse().map_err(|_| format!("Invalid interval '{}'", value))?;
+                options.interval = Duration::from_millis(ms);
+            }
+            flag => {
+                if !options.schedule.apply(flag, value)? {
+                    return Err(format!("Unknown option '{}'\n{}", flag, usage));
+                }
+            }
+        }
+    }
+    if options.compile.is_some() && options.sources.is_empty() {
+        return Err(format!("--compile needs the files it reads, given with --source\n{}", usage));
+    }
+
+    let sources: Vec<&str> = options.sources.iter().map(|s| s.as_str()).collect();
+    let mut watched = sources.clone();
+    watched.push(filename);
+    println!("Watching {} (Ctrl-C to stop)", watched.join(", "));
+
+    let start = Instant::now();
+    let mut previous: Option<Snapshot> = None;
+    let mut runs = 0;
+    // The first pass compiles (so the IR matches the source) and analyzes unconditionally
+    let mut source_changed = true;
+    loop {
+        let compiled = match &options.compile {
+            Some(command) if source_changed => compile(command),
+            _ => true,
+        };
+        if compiled {
+            runs += 1;
+            println!("\n=== Run {} at {} ===", runs, elapsed(start));
+            match Module::from_ir_path(filename) {
+                Ok(module) => {
+                    let functions = summarize(&module, &options);
+                    if functions.is_empty()
+                        && let Some(name) = &options.func
+                    {
+                        println!("  Function '{}' not found in module.", name);
+                    }
+                    let outputs = options.analyses.iter().map(|a| run_analysis(filename, a)).collect();
+                    let snapshot = Snapshot { functions, outputs };
+                    match &previous {
+                        Some(previous) => print_delta(previous, &snapshot, &options.analyses),
+                        None => print_first(&snapshot, &options.analyses),
+                    }
+                    previous = Some(snapshot);
+                }
+                // Keep the last good run so the next delta is against it
+                Err(e) => println!("  Failed to parse LLVM module: {}", e),
+            }
+        }
+        // The compile rewrote the .ll file, which shouldn't count as a new change
+        let before = stamps(&watched);
+
+        // Wait for a change, then for the files to settle so a half-written file isn't read
+        let mut now = before.clone();
+        while now == before {
+            sleep(options.interval);
+            now = stamps(&watched);
+        }
+        loop {
+            sleep(options.interval);
+            let settled = stamps(&watched);
+            if settled == now {
+                break;
+            }
+            now = settled;
+        }
+        source_changed = now[..sources.len()] != before[..sources.len()];
+    }
+}